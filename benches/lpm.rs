@@ -0,0 +1,103 @@
+#![allow(clippy::redundant_field_names)]
+
+extern crate chucker;
+
+use std::hint::black_box;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::time::Instant;
+
+use chucker::lpm::{self, PrefixMap, RouteAddr};
+
+// route lookups, the trie against a linear scan over the same prefixes
+//
+//   cargo bench --bench lpm
+//
+// Prefixes and addresses come from a fixed xorshift seed, so runs compare.
+
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+// longest prefix first, the first match wins
+struct Linear<A> {
+    routes: Vec<(u128, u8, usize)>,
+    addr: ::std::marker::PhantomData<A>
+}
+
+impl<A: RouteAddr> Linear<A> {
+    fn new(mut routes: Vec<(u128, u8, usize)>) -> Linear<A> {
+        routes.sort_by_key(|&(_, prefix, _)| ::std::cmp::Reverse(prefix));
+        Linear { routes: routes, addr: ::std::marker::PhantomData }
+    }
+
+    fn lookup(&self, addr: A) -> Option<usize> {
+        let bits = addr.to_bits();
+        self.routes.iter()
+            .find(|&&(dst, prefix, _)| (bits ^ dst) & lpm::mask(prefix) == 0)
+            .map(|&(_, _, val)| val)
+    }
+}
+
+// prefix lengths roughly as in a full table: mostly /16 to /24 for ipv4,
+// /32 to /48 for ipv6
+fn prefixes<A: RouteAddr>(rng: &mut Rng, count: usize) -> Vec<(u128, u8, usize)> {
+    (0..count).map(|idx| {
+        let (min, spread) = if A::BITS == 32 { (16, 9) } else { (32, 17) };
+        let prefix = min + (rng.next() % spread) as u8;
+        let bits = ((rng.next() as u128) << 64 | rng.next() as u128) & lpm::mask(prefix);
+        (bits, prefix, idx)
+    }).collect()
+}
+
+fn time<F: FnMut() -> usize>(name: &str, lookups: usize, mut f: F) {
+    let start = Instant::now();
+    let found = black_box(f());
+    let elapsed = start.elapsed();
+    println!("  {: <28} {: >8.1} ns/lookup  ({} of {} matched)", name,
+             elapsed.as_secs_f64() * 1e9 / lookups as f64, found, lookups);
+}
+
+fn bench<A: RouteAddr>(family: &str, routes: usize, lookups: usize) {
+    let mut rng = Rng(0x2545_F491_4F6C_DD1D);
+    let prefixes = prefixes::<A>(&mut rng, routes);
+    let mut trie = PrefixMap::new();
+    for &(bits, prefix, val) in &prefixes {
+        trie.insert(A::from_bits(bits), prefix, val);
+    }
+    let linear = Linear::<A>::new(prefixes.clone());
+
+    // half the addresses under a known prefix, half anywhere
+    let addrs: Vec<A> = (0..lookups).map(|idx| {
+        let noise = (rng.next() as u128) << 64 | rng.next() as u128;
+        if idx % 2 == 0 {
+            let (bits, prefix, _) = prefixes[idx % prefixes.len()];
+            A::from_bits(bits | (noise & !lpm::mask(prefix)))
+        } else {
+            A::from_bits(noise)
+        }
+    }).collect();
+
+    println!("{}, {} routes:", family, routes);
+    time("trie", addrs.len(), || {
+        addrs.iter().filter(|&&addr| trie.lookup(black_box(addr)).is_some()).count()
+    });
+    // a linear scan over a big table takes forever, fewer lookups do
+    let scanned = &addrs[..(addrs.len() * 1000 / routes).clamp(1000, addrs.len())];
+    time("linear scan", scanned.len(), || {
+        scanned.iter().filter(|&&addr| linear.lookup(black_box(addr)).is_some()).count()
+    });
+}
+
+fn main() {
+    for &routes in &[16, 1000, 100_000] {
+        bench::<Ipv4Addr>("ipv4", routes, 1_000_000);
+        bench::<Ipv6Addr>("ipv6", routes, 1_000_000);
+    }
+}
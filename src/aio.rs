@@ -0,0 +1,1718 @@
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use libc;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::io::unix::AsyncFd;
+use tokio::sync::mpsc;
+use tokio::time::Sleep;
+
+use iface::Tap;
+use packet::arp;
+use packet::builder::{tcp_flags, tcp_mss, tcp_options, EthBuilder, ETH_LEN, IPV4_LEN, TCP_LEN,
+                      UDP_LEN};
+use packet::eth::Eth;
+use packet::frag::{self, Ipv4Reassembler, Limits, MAX_IPV4_LEN};
+use packet::ipv4::Ipv4;
+use packet::tcp::Tcp;
+use packet::udp::Udp;
+use util;
+
+// tokio integration, behind the `tokio` feature
+//
+// `AsyncLinkDevice` is a tap whose frames can be awaited. On top of it sits
+// a minimal ipv4 host, `Stack`, that answers ARP for its address and hands
+// out `UdpSocket`s, `TcpStream`s and `TcpListener`s. The stack doesn't do
+// anything on its own: spawn the `Driver` it comes with on the runtime. TCP
+// retransmits on tokio timers, so a runtime using it needs time enabled.
+// Datagrams bigger than the mtu are fragmented on the way out and
+// reassembled on the way in.
+//
+// The TCP is the bare RFC 793 / 1122 one: mss but no other options, no
+// window scaling, go-back-N retransmission, and out of order segments are
+// dropped rather than queued. Enough to talk to a kernel over a tap, not
+// to move bulk data fast.
+//
+// Everything here is 2015 edition, so instead of async fns the methods
+// return small hand written futures; `.await` them as usual.
+
+pub struct AsyncLinkDevice {
+    fd: AsyncFd<Tap>
+}
+
+impl AsyncLinkDevice {
+    // needs to be called from within a tokio runtime
+    pub fn new(tap: Tap) -> io::Result<AsyncLinkDevice> {
+        tap.set_nonblocking(true)?;
+        Ok(AsyncLinkDevice { fd: AsyncFd::new(tap)? })
+    }
+
+    pub fn get_ref(&self) -> &Tap {
+        self.fd.get_ref()
+    }
+
+    pub fn poll_recv(&self, cx: &mut Context, buff: &mut [u8]) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = match self.fd.poll_read_ready(cx) {
+                Poll::Ready(Ok(guard)) => guard,
+                Poll::Ready(Err(e))    => return Poll::Ready(Err(e)),
+                Poll::Pending          => return Poll::Pending
+            };
+            match guard.try_io(|tap| tap.get_ref().read(buff)) {
+                Ok(res) => return Poll::Ready(res),
+                Err(_would_block) => continue
+            }
+        }
+    }
+
+    pub fn poll_send(&self, cx: &mut Context, buff: &[u8]) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = match self.fd.poll_write_ready(cx) {
+                Poll::Ready(Ok(guard)) => guard,
+                Poll::Ready(Err(e))    => return Poll::Ready(Err(e)),
+                Poll::Pending          => return Poll::Pending
+            };
+            match guard.try_io(|tap| tap.get_ref().write(buff)) {
+                Ok(res) => return Poll::Ready(res),
+                Err(_would_block) => continue
+            }
+        }
+    }
+
+    // send without waiting, a full device queue drops the frame
+    pub fn try_send(&self, buff: &[u8]) -> io::Result<usize> {
+        self.fd.get_ref().write(buff)
+    }
+
+    // receive one frame into `buff`, resolves to its length
+    pub fn recv<'a>(&'a self, buff: &'a mut [u8]) -> Recv<'a> {
+        Recv { dev: self, buff: buff }
+    }
+
+    // send one frame
+    pub fn send<'a>(&'a self, buff: &'a [u8]) -> SendFrame<'a> {
+        SendFrame { dev: self, buff: buff }
+    }
+}
+
+pub struct Recv<'a> {
+    dev: &'a AsyncLinkDevice,
+    buff: &'a mut [u8]
+}
+
+impl<'a> Future for Recv<'a> {
+    type Output = io::Result<usize>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.dev.poll_recv(cx, this.buff)
+    }
+}
+
+pub struct SendFrame<'a> {
+    dev: &'a AsyncLinkDevice,
+    buff: &'a [u8]
+}
+
+impl<'a> Future for SendFrame<'a> {
+    type Output = io::Result<usize>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<usize>> {
+        self.dev.poll_send(cx, self.buff)
+    }
+}
+
+// the stack
+
+const BROADCAST: [u8; 6] = [0xFF; 6];
+
+const EPHEMERAL_PORTS: (u16, u16) = (49152, 65535);
+
+pub struct Datagram {
+    pub data: Vec<u8>,
+    pub from: SocketAddrV4
+}
+
+struct State {
+    sockets: HashMap<u16, mpsc::UnboundedSender<Datagram>>,
+    listeners: HashMap<u16, Listener>,
+    connections: HashMap<TcpKey, Tcb>,
+    // learned from ARP and from incoming ipv4 frames
+    neighbors: HashMap<Ipv4Addr, [u8; 6]>,
+    next_port: u16,
+    next_ident: u16,
+    next_iss: u32,
+    frags: Ipv4Reassembler,
+    // to have the driver pick up timers armed from a socket
+    driver: Option<Waker>
+}
+
+impl State {
+    // a free port from the ephemeral range, udp and tcp draw from the same one
+    fn pick_port<F: Fn(&State, u16) -> bool>(&mut self, in_use: F) -> Option<u16> {
+        let (low, high) = EPHEMERAL_PORTS;
+        let range = (high - low) as usize + 1;
+        for _ in 0..range {
+            let candidate = self.next_port;
+            self.next_port = if candidate == high { low } else { candidate + 1 };
+            if !in_use(self, candidate) {
+                return Some(candidate)
+            }
+        }
+        None
+    }
+
+    fn tcp_port_in_use(&self, port: u16) -> bool {
+        self.listeners.contains_key(&port) || self.connections.keys().any(|key| key.0 == port)
+    }
+
+    // Initial sequence numbers only need to differ between incarnations of
+    // a connection, nothing here defends against spoofing.
+    fn pick_iss(&mut self) -> u32 {
+        let iss = self.next_iss;
+        self.next_iss = self.next_iss.wrapping_add(0x0001_0000 + clock_nanos() % 0xFFFF);
+        iss
+    }
+
+    fn next_hop(&mut self, dst: &Ipv4Addr) -> ([u8; 6], u16) {
+        let ident = self.next_ident;
+        self.next_ident = self.next_ident.wrapping_add(1);
+        match self.neighbors.get(dst) {
+            Some(mac) => (*mac, ident),
+            // linux takes unicast ip in a broadcast frame just fine
+            None      => (BROADCAST, ident)
+        }
+    }
+
+    fn wake_driver(&self) {
+        if let Some(ref waker) = self.driver {
+            waker.wake_by_ref();
+        }
+    }
+}
+
+fn clock_nanos() -> u32 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0)
+}
+
+struct Inner {
+    dev: AsyncLinkDevice,
+    mac: [u8; 6],
+    addr: Ipv4Addr,
+    state: Mutex<State>
+}
+
+#[derive(Clone)]
+pub struct Stack {
+    inner: Arc<Inner>
+}
+
+impl Stack {
+    // `mac` and `addr` are the stack's own, not those of the kernel side of
+    // the tap; `addr` should be in the subnet configured on the interface
+    pub fn new(dev: AsyncLinkDevice, mac: [u8; 6], addr: Ipv4Addr) -> (Stack, Driver) {
+        let inner = Arc::new(Inner {
+            dev: dev,
+            mac: mac,
+            addr: addr,
+            state: Mutex::new(State {
+                sockets: HashMap::new(),
+                listeners: HashMap::new(),
+                connections: HashMap::new(),
+                neighbors: HashMap::new(),
+                next_port: EPHEMERAL_PORTS.0,
+                next_ident: 1,
+                next_iss: clock_nanos(),
+                frags: Ipv4Reassembler::new(Limits::ipv4()),
+                driver: None
+            })
+        });
+        let frame_size = inner.dev.get_ref().frame_size();
+        let driver = Driver { inner: inner.clone(), buff: vec![0u8; frame_size], sleep: None };
+        (Stack { inner: inner }, driver)
+    }
+
+    pub fn device(&self) -> &AsyncLinkDevice {
+        &self.inner.dev
+    }
+
+    // bind a udp socket to `port` on the stack's address, 0 picks a free one
+    pub fn bind_udp(&self, port: u16) -> io::Result<UdpSocket> {
+        let mut state = self.inner.state.lock().unwrap();
+
+        let port = if port != 0 {
+            if state.sockets.contains_key(&port) {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse,
+                                          format!("udp port {} in use", port)))
+            }
+            port
+        } else {
+            match state.pick_port(|state, port| state.sockets.contains_key(&port)) {
+                Some(port) => port,
+                None => return Err(io::Error::new(io::ErrorKind::AddrInUse,
+                                                  "out of udp ports"))
+            }
+        };
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        state.sockets.insert(port, tx);
+        Ok(UdpSocket { inner: self.inner.clone(), port: port, rx: rx })
+    }
+
+    // accept tcp connections on `port`, 0 picks a free one
+    pub fn listen_tcp(&self, port: u16) -> io::Result<TcpListener> {
+        let mut state = self.inner.state.lock().unwrap();
+
+        let port = if port != 0 {
+            if state.tcp_port_in_use(port) {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse,
+                                          format!("tcp port {} in use", port)))
+            }
+            port
+        } else {
+            match state.pick_port(|state, port| state.tcp_port_in_use(port)) {
+                Some(port) => port,
+                None => return Err(io::Error::new(io::ErrorKind::AddrInUse,
+                                                  "out of tcp ports"))
+            }
+        };
+
+        state.listeners.insert(port, Listener { queue: VecDeque::new(), waker: None });
+        Ok(TcpListener { inner: self.inner.clone(), port: port })
+    }
+
+    // open a tcp connection from a free port, resolves once it's established
+    pub fn connect_tcp(&self, dst: SocketAddrV4) -> Connect {
+        let mut state = self.inner.state.lock().unwrap();
+        let port = match state.pick_port(|state, port| state.tcp_port_in_use(port)) {
+            Some(port) => port,
+            None => return Connect {
+                stream: Some(Err(io::Error::new(io::ErrorKind::AddrInUse, "out of tcp ports")))
+            }
+        };
+
+        let key = (port, dst);
+        let iss = state.pick_iss();
+        let mss = tcp_mss(self.inner.dev.get_ref().mtu(), false);
+        let tcb = Tcb::new(TcpState::SynSent, iss, mss, Owner::Stream, Instant::now());
+        let syn = vec!(tcb.syn());
+        state.connections.insert(key, tcb);
+        self.inner.send_segments(&mut state, &key, syn);
+        state.wake_driver();
+        Connect { stream: Some(Ok(TcpStream { inner: self.inner.clone(), key: key })) }
+    }
+}
+
+impl Inner {
+    fn handle_frame(&self, frame: &mut [u8]) {
+        if frame.len() < ETH_LEN {
+            return
+        }
+        let eth = Eth { offset: 0 };
+        match eth.get_ethertype(frame) {
+            0x0806 => self.handle_arp(frame),
+            0x0800 => self.handle_ipv4(frame),
+            _      => ()
+        }
+    }
+
+    fn handle_arp(&self, frame: &mut [u8]) {
+        if frame.len() < ETH_LEN + 28 {
+            return
+        }
+        // ethernet and ipv4 is all we resolve, anything else isn't for us
+        let arp = arp::Arp { offset: ETH_LEN };
+        let arp_buff = &frame[ETH_LEN..];
+        if arp.get_htype(arp_buff) != 1 || arp.get_ptype(arp_buff) != 0x0800
+            || arp.get_hlen(arp_buff) != 6 || arp.get_plen(arp_buff) != 4 {
+            return
+        }
+        let sha = arp.get_sha(arp_buff);
+        let spa = Ipv4Addr::from(arp.get_spa(arp_buff));
+        let tpa = Ipv4Addr::from(arp.get_tpa(arp_buff));
+        self.state.lock().unwrap().neighbors.insert(spa, sha);
+
+        if arp.get_oper(arp_buff) != arp::REQUEST || tpa != self.addr {
+            return
+        }
+
+        let eth = Eth { offset: 0 };
+        eth.set_dst(frame, sha);
+        eth.set_src(frame, self.mac);
+        arp.make_reply(&mut frame[ETH_LEN..], self.mac);
+        let _ = self.dev.try_send(&frame[..ETH_LEN + 28]);
+    }
+
+    fn handle_ipv4(&self, frame: &mut [u8]) {
+        if frame.len() < ETH_LEN + IPV4_LEN {
+            return
+        }
+        let eth = Eth { offset: 0 };
+        let ip = Ipv4 { offset: ETH_LEN };
+        let ip_buff = &frame[ETH_LEN..];
+        if ip.get_version(ip_buff) != 4 {
+            return
+        }
+        match ip.get_protocol(ip_buff) {
+            0x06 | 0x11 => (),
+            _           => return
+        }
+        let dst = Ipv4Addr::from(ip.get_dst(ip_buff));
+        if dst != self.addr {
+            return
+        }
+
+        let ip_len = (ip.get_len(ip_buff) as usize).min(ip_buff.len());
+        let ihl = ip.get_ihl(ip_buff) as usize * 4;
+        if ihl < IPV4_LEN || ihl > ip_len {
+            return
+        }
+        let mac = eth.get_src(frame);
+        if !ip.is_fragment(ip_buff) {
+            return self.handle_transport(mac, &ip_buff[..ip_len])
+        }
+
+        let pushed = self.state.lock().unwrap().frags.push(&ip_buff[..ip_len], Instant::now());
+        if let Ok(Some(datagram)) = pushed {
+            self.handle_transport(mac, &datagram);
+        }
+    }
+
+    // `packet` is a whole ipv4 packet, checked up to the ip header
+    fn handle_transport(&self, mac: [u8; 6], packet: &[u8]) {
+        let ip = Ipv4 { offset: 0 };
+        if ip.get_protocol(packet) == 0x06 {
+            self.handle_tcp(mac, packet)
+        } else {
+            self.handle_udp(mac, packet)
+        }
+    }
+
+    fn handle_udp(&self, mac: [u8; 6], packet: &[u8]) {
+        let ip = Ipv4 { offset: 0 };
+        let src = Ipv4Addr::from(ip.get_src(packet));
+        let ihl = ip.get_ihl(packet) as usize * 4;
+        if ihl + UDP_LEN > packet.len() {
+            return
+        }
+        let udp = Udp { offset: ihl };
+        let udp_buff = &packet[udp.offset..];
+        let udp_len = udp.get_len(udp_buff) as usize;
+        if udp_len < UDP_LEN || udp_len > udp_buff.len() {
+            return
+        }
+        // zero means the sender didn't compute one, RFC 768
+        let pseudo = util::pseudo_sum_v4(ip.get_src(packet), ip.get_dst(packet), 0x11, udp_len);
+        if udp.get_chk(udp_buff) != 0
+            && util::checksum_finish(util::checksum_add(pseudo, &udp_buff[..udp_len])) != 0 {
+            return
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.neighbors.insert(src, mac);
+
+        let port = udp.get_dst_port(udp_buff);
+        let delivered = match state.sockets.get(&port) {
+            Some(tx) => tx.send(Datagram {
+                data: udp_buff[UDP_LEN..udp_len].to_vec(),
+                from: SocketAddrV4::new(src, udp.get_src_port(udp_buff))
+            }).is_ok(),
+            None => true
+        };
+        if !delivered {
+            state.sockets.remove(&port);
+        }
+    }
+
+    fn handle_tcp(&self, mac: [u8; 6], packet: &[u8]) {
+        let ip = Ipv4 { offset: 0 };
+        let src = Ipv4Addr::from(ip.get_src(packet));
+        let ihl = ip.get_ihl(packet) as usize * 4;
+        let tcp = Tcp { offset: ihl };
+        let tcp_buff = &packet[ihl..];
+        let header_len = match tcp.header_len(tcp_buff) {
+            Ok(len) => len,
+            Err(_)  => return
+        };
+        let pseudo = util::pseudo_sum_v4(ip.get_src(packet), ip.get_dst(packet), 0x06,
+                                         tcp_buff.len());
+        if util::checksum_finish(util::checksum_add(pseudo, tcp_buff)) != 0 {
+            return
+        }
+
+        let segment = Incoming {
+            seq: tcp.get_seq(tcp_buff),
+            ack_nr: tcp.get_ack_nr(tcp_buff),
+            flags: tcp_buff[13] & 0x3F,
+            win: tcp.get_win(tcp_buff),
+            mss: parse_mss(&tcp_buff[TCP_LEN..header_len]),
+            payload: &tcp_buff[header_len..]
+        };
+        let key = (tcp.get_dst_port(tcp_buff), SocketAddrV4::new(src, tcp.get_src_port(tcp_buff)));
+        let now = Instant::now();
+
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        state.neighbors.insert(src, mac);
+        let mut out = Vec::new();
+
+        if let Some(tcb) = state.connections.get_mut(&key) {
+            let was = tcb.state;
+            tcb.on_segment(&segment, now, &mut out);
+            // data or a FIN may have come along with the handshake's ACK
+            let established = was == TcpState::SynReceived
+                && tcb.state != TcpState::SynReceived && tcb.state != TcpState::Closed;
+            if established {
+                match state.listeners.get_mut(&key.0) {
+                    Some(listener) => {
+                        listener.queue.push_back(key);
+                        if let Some(waker) = listener.waker.take() {
+                            waker.wake();
+                        }
+                    },
+                    // the listener's gone
+                    None => state.connections.get_mut(&key).unwrap().abort(&mut out)
+                }
+            }
+            self.reap(state, &key);
+        } else if segment.flags & (tcp_flags::SYN | tcp_flags::ACK | tcp_flags::RST) == tcp_flags::SYN {
+            let listening = state.listeners.get(&key.0).is_some_and(|l| l.queue.len() < BACKLOG);
+            if !listening {
+                out.push(Segment::reset_for(&segment));
+            } else {
+                let iss = state.pick_iss();
+                let mss = tcp_mss(self.dev.get_ref().mtu(), false);
+                let mut tcb = Tcb::new(TcpState::SynReceived, iss, mss, Owner::Listener, now);
+                tcb.accept_syn(&segment);
+                out.push(tcb.syn());
+                state.connections.insert(key, tcb);
+            }
+        } else if segment.flags & tcp_flags::RST == 0 {
+            out.push(Segment::reset_for(&segment));
+        }
+
+        self.send_segments(state, &key, out);
+    }
+
+    // forget a connection nobody can use anymore
+    fn reap(&self, state: &mut State, key: &TcpKey) {
+        let dead = state.connections.get(key)
+            .is_some_and(|tcb| tcb.state == TcpState::Closed && tcb.owner != Owner::Stream);
+        if dead {
+            state.connections.remove(key);
+        }
+    }
+
+    // Segments go out without waiting on the device, a full queue drops
+    // them and retransmission covers for it.
+    fn send_segments(&self, state: &mut State, key: &TcpKey, segments: Vec<Segment>) {
+        for segment in segments {
+            let (dst_mac, ident) = state.next_hop(key.1.ip());
+            let mut tcp = EthBuilder::new(self.mac, dst_mac)
+                .ipv4(self.addr, *key.1.ip())
+                .ident(ident)
+                .dont_fragment(true)
+                .tcp(key.0, key.1.port())
+                .seq(segment.seq)
+                .ack_nr(segment.ack_nr)
+                .flags(segment.flags)
+                .win(segment.win);
+            if let Some(mss) = segment.mss {
+                tcp = tcp.mss(mss);
+            }
+            let _ = self.dev.try_send(&tcp.build(&segment.payload).data);
+        }
+    }
+
+    // run the tcp timers that are due, returns when the next one is
+    fn run_timers(&self, now: Instant) -> Option<Instant> {
+        let mut state = self.state.lock().unwrap();
+        let due: Vec<TcpKey> = state.connections.iter()
+            .filter(|&(_, tcb)| tcb.timer.is_some_and(|at| at <= now))
+            .map(|(key, _)| *key)
+            .collect();
+        for key in due {
+            let mut out = Vec::new();
+            state.connections.get_mut(&key).unwrap().on_timer(now, &mut out);
+            self.reap(&mut state, &key);
+            self.send_segments(&mut state, &key, out);
+        }
+        state.connections.values().filter_map(|tcb| tcb.timer).min()
+    }
+
+    // one frame per fragment, a single one if it fits in the mtu
+    fn build_udp(&self, src_port: u16, dst: &SocketAddrV4, payload: &[u8]) -> Vec<Vec<u8>> {
+        let (dst_mac, ident) = self.state.lock().unwrap().next_hop(dst.ip());
+
+        let mtu = self.dev.get_ref().mtu();
+        let fits = IPV4_LEN + UDP_LEN + payload.len() <= mtu;
+        let frame = EthBuilder::new(self.mac, dst_mac)
+            .ipv4(self.addr, *dst.ip())
+            .ident(ident)
+            .dont_fragment(fits)
+            .udp(src_port, dst.port())
+            .build(payload)
+            .data;
+        if fits {
+            return vec!(frame)
+        }
+
+        // we made it with room for a header, the mtu's at least 68
+        frag::fragment_ipv4(&frame[ETH_LEN..], mtu).unwrap().into_iter()
+            .map(|ip| {
+                let mut frag = frame[..ETH_LEN].to_vec();
+                frag.extend_from_slice(&ip);
+                frag
+            })
+            .collect()
+    }
+}
+
+// Feeds incoming frames to the stack and runs the tcp timers. Never
+// finishes unless the device errors, so spawn it.
+pub struct Driver {
+    inner: Arc<Inner>,
+    buff: Vec<u8>,
+    // made on the first timer, so udp alone works without tokio's time
+    sleep: Option<Pin<Box<Sleep>>>
+}
+
+impl Future for Driver {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.inner.state.lock().unwrap().driver = Some(cx.waker().clone());
+        loop {
+            match this.inner.dev.poll_recv(cx, &mut this.buff) {
+                Poll::Ready(Ok(len))  => {
+                    this.inner.handle_frame(&mut this.buff[..len]);
+                    continue
+                },
+                Poll::Ready(Err(e))   => return Poll::Ready(Err(e)),
+                Poll::Pending         => ()
+            }
+
+            let next = match this.inner.run_timers(Instant::now()) {
+                Some(next) => tokio::time::Instant::from_std(next),
+                None       => return Poll::Pending
+            };
+            let sleep = this.sleep.get_or_insert_with(|| Box::pin(tokio::time::sleep_until(next)));
+            sleep.as_mut().reset(next);
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending
+            }
+        }
+    }
+}
+
+pub struct UdpSocket {
+    inner: Arc<Inner>,
+    port: u16,
+    rx: mpsc::UnboundedReceiver<Datagram>
+}
+
+impl UdpSocket {
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.inner.addr, self.port)
+    }
+
+    // a datagram longer than `buff` is truncated, like with a real socket
+    pub fn poll_recv_from(&mut self, cx: &mut Context, buff: &mut [u8])
+                          -> Poll<io::Result<(usize, SocketAddrV4)>> {
+        match self.rx.poll_recv(cx) {
+            Poll::Ready(Some(dgram)) => {
+                let len = dgram.data.len().min(buff.len());
+                buff[..len].copy_from_slice(&dgram.data[..len]);
+                Poll::Ready(Ok((len, dgram.from)))
+            },
+            Poll::Ready(None) => Poll::Ready(Err(io::Error::new(io::ErrorKind::NotConnected,
+                                                                 "socket was unbound"))),
+            Poll::Pending => Poll::Pending
+        }
+    }
+
+    pub fn recv_from<'a>(&'a mut self, buff: &'a mut [u8]) -> RecvFrom<'a> {
+        RecvFrom { sock: self, buff: buff }
+    }
+
+    // a datagram that doesn't fit in an ip packet at all fails with EMSGSIZE
+    pub fn send_to(&self, payload: &[u8], dst: SocketAddrV4) -> SendTo {
+        let too_big = IPV4_LEN + UDP_LEN + payload.len() > MAX_IPV4_LEN;
+        let frames = if too_big {
+            Vec::new()
+        } else {
+            self.inner.build_udp(self.port, &dst, payload)
+        };
+        SendTo { inner: self.inner.clone(), frames: frames, sent: 0,
+                 len: payload.len(), too_big: too_big }
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        self.inner.state.lock().unwrap().sockets.remove(&self.port);
+    }
+}
+
+pub struct RecvFrom<'a> {
+    sock: &'a mut UdpSocket,
+    buff: &'a mut [u8]
+}
+
+impl<'a> Future for RecvFrom<'a> {
+    type Output = io::Result<(usize, SocketAddrV4)>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        this.sock.poll_recv_from(cx, this.buff)
+    }
+}
+
+pub struct SendTo {
+    inner: Arc<Inner>,
+    frames: Vec<Vec<u8>>,
+    sent: usize,
+    len: usize,
+    too_big: bool
+}
+
+impl Future for SendTo {
+    type Output = io::Result<usize>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<usize>> {
+        if self.too_big {
+            return Poll::Ready(Err(io::Error::from_raw_os_error(libc::EMSGSIZE)))
+        }
+        let this = self.get_mut();
+        while this.sent < this.frames.len() {
+            match this.inner.dev.poll_send(cx, &this.frames[this.sent]) {
+                Poll::Ready(Ok(_))  => this.sent += 1,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending       => return Poll::Pending
+            }
+        }
+        Poll::Ready(Ok(this.len))
+    }
+}
+
+// tcp
+
+// local port, remote address
+type TcpKey = (u16, SocketAddrV4);
+
+// for both directions; without window scaling the window can't be bigger
+const TCP_BUFFER: usize = 65535;
+// what to assume when the peer doesn't send the option, RFC 1122 4.2.2.6
+const DEFAULT_MSS: usize = 536;
+// RFC 6298 2.1, doubled on every retransmission
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MAX_RTO: Duration = Duration::from_secs(60);
+const MAX_RETRIES: u32 = 8;
+// how long a closed connection stays around to ack a retransmitted FIN
+const TIME_WAIT: Duration = Duration::from_secs(60);
+// established connections waiting to be accepted
+const BACKLOG: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TcpState {
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+    Closed
+}
+
+// who a connection belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Owner {
+    // not accepted yet
+    Listener,
+    Stream,
+    // the stream was dropped, the connection closes on its own
+    Nobody
+}
+
+struct Listener {
+    queue: VecDeque<TcpKey>,
+    waker: Option<Waker>
+}
+
+// a segment as it came in
+struct Incoming<'a> {
+    seq: u32,
+    ack_nr: u32,
+    flags: u8,
+    win: u16,
+    mss: Option<u16>,
+    payload: &'a [u8]
+}
+
+// and one to go out
+#[derive(Debug)]
+struct Segment {
+    seq: u32,
+    ack_nr: u32,
+    flags: u8,
+    win: u16,
+    mss: Option<u16>,
+    payload: Vec<u8>
+}
+
+impl Segment {
+    // the answer to a segment that has no connection to go to, RFC 793 p.65
+    fn reset_for(incoming: &Incoming) -> Segment {
+        let mut reset = Segment {
+            seq: 0, ack_nr: 0, flags: tcp_flags::RST, win: 0, mss: None, payload: Vec::new()
+        };
+        if incoming.flags & tcp_flags::ACK != 0 {
+            reset.seq = incoming.ack_nr;
+        } else {
+            let len = incoming.payload.len() as u32
+                + (incoming.flags & tcp_flags::SYN != 0) as u32
+                + (incoming.flags & tcp_flags::FIN != 0) as u32;
+            reset.ack_nr = incoming.seq.wrapping_add(len);
+            reset.flags |= tcp_flags::ACK;
+        }
+        reset
+    }
+}
+
+// the mss option's value, if it's there and well formed
+fn parse_mss(options: &[u8]) -> Option<u16> {
+    let mut idx = 0;
+    while idx < options.len() {
+        match options[idx] {
+            tcp_options::END => break,
+            tcp_options::NOP => idx += 1,
+            kind => {
+                let len = *options.get(idx + 1)? as usize;
+                if len < 2 || idx + len > options.len() {
+                    return None
+                }
+                if kind == tcp_options::MSS && len == 4 {
+                    return Some((options[idx + 2] as u16) << 8 | options[idx + 3] as u16)
+                }
+                idx += len;
+            }
+        }
+    }
+    None
+}
+
+// sequence number order, modulo 2^32
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+// transmission control block, RFC 793 3.2
+struct Tcb {
+    state: TcpState,
+    owner: Owner,
+    snd_una: u32,
+    snd_nxt: u32,
+    snd_wnd: u32,
+    // the smaller of the peer's and ours
+    mss: usize,
+    our_mss: u16,
+    // everything from snd_una on; the SYN and FIN take a sequence number
+    // but have no byte in here
+    send_buf: VecDeque<u8>,
+    fin_queued: bool,
+    rcv_nxt: u32,
+    recv_buf: VecDeque<u8>,
+    peer_fin: bool,
+    rto: Duration,
+    retries: u32,
+    // retransmission, or the end of TIME-WAIT or of an orphaned FIN-WAIT-2
+    timer: Option<Instant>,
+    error: Option<io::ErrorKind>,
+    read_waker: Option<Waker>,
+    // writers, and the connect future
+    write_waker: Option<Waker>
+}
+
+impl Tcb {
+    // in SYN-SENT or SYN-RECEIVED, the caller sends `syn()`
+    fn new(state: TcpState, iss: u32, our_mss: u16, owner: Owner, now: Instant) -> Tcb {
+        Tcb {
+            state: state,
+            owner: owner,
+            snd_una: iss,
+            snd_nxt: iss.wrapping_add(1),
+            snd_wnd: 0,
+            mss: DEFAULT_MSS.min(our_mss as usize),
+            our_mss: our_mss,
+            send_buf: VecDeque::new(),
+            fin_queued: false,
+            rcv_nxt: 0,
+            recv_buf: VecDeque::new(),
+            peer_fin: false,
+            rto: INITIAL_RTO,
+            retries: 0,
+            timer: Some(now + INITIAL_RTO),
+            error: None,
+            read_waker: None,
+            write_waker: None
+        }
+    }
+
+    // take the peer's side of things from its SYN
+    fn accept_syn(&mut self, syn: &Incoming) {
+        self.rcv_nxt = syn.seq.wrapping_add(1);
+        self.snd_wnd = syn.win as u32;
+        let mss = syn.mss.map_or(DEFAULT_MSS, |mss| mss as usize);
+        self.mss = mss.clamp(1, self.our_mss as usize);
+    }
+
+    fn window(&self) -> u16 {
+        (TCP_BUFFER - self.recv_buf.len()) as u16
+    }
+
+    fn segment(&self, flags: u8, seq: u32, payload: Vec<u8>) -> Segment {
+        Segment {
+            seq: seq, ack_nr: self.rcv_nxt, flags: flags, win: self.window(), mss: None,
+            payload: payload
+        }
+    }
+
+    // our SYN, or the SYN-ACK to the peer's
+    fn syn(&self) -> Segment {
+        let mut syn = if self.state == TcpState::SynSent {
+            let mut syn = self.segment(tcp_flags::SYN, self.snd_una, Vec::new());
+            syn.ack_nr = 0;
+            syn
+        } else {
+            self.segment(tcp_flags::SYN | tcp_flags::ACK, self.snd_una, Vec::new())
+        };
+        syn.mss = Some(self.our_mss);
+        syn
+    }
+
+    fn ack(&self) -> Segment {
+        self.segment(tcp_flags::ACK, self.snd_nxt, Vec::new())
+    }
+
+    fn wake_reader(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn wake_writer(&mut self) {
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn established(&mut self) {
+        self.state = TcpState::Established;
+        self.timer = None;
+        self.retries = 0;
+        self.rto = INITIAL_RTO;
+        self.wake_writer();
+    }
+
+    fn time_wait(&mut self, now: Instant) {
+        self.state = TcpState::TimeWait;
+        self.timer = Some(now + TIME_WAIT);
+    }
+
+    fn close(&mut self, error: Option<io::ErrorKind>) {
+        self.state = TcpState::Closed;
+        self.timer = None;
+        if error.is_some() {
+            self.error = error;
+        }
+        self.wake_reader();
+        self.wake_writer();
+    }
+
+    // reset the connection from our end
+    fn abort(&mut self, out: &mut Vec<Segment>) {
+        out.push(self.segment(tcp_flags::RST, self.snd_nxt, Vec::new()));
+        self.close(Some(io::ErrorKind::ConnectionAborted));
+    }
+
+    // send what the peer's window allows, and the FIN once the data's out
+    fn push(&mut self, now: Instant, out: &mut Vec<Segment>) {
+        match self.state {
+            TcpState::Established | TcpState::CloseWait | TcpState::FinWait1
+                | TcpState::Closing | TcpState::LastAck => (),
+            _ => return
+        }
+        let mut sent = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+        if sent > self.send_buf.len() {
+            // the FIN's out already
+            return
+        }
+
+        // a shut window gets probed with a byte, retransmitted until it opens
+        let window = if self.snd_wnd == 0 && sent == 0 { 1 } else { self.snd_wnd as usize };
+        while sent < self.send_buf.len() && sent < window {
+            let len = (self.send_buf.len() - sent).min(window - sent).min(self.mss);
+            let payload = self.send_buf.range(sent..sent + len).cloned().collect();
+            let seq = self.snd_una.wrapping_add(sent as u32);
+            out.push(self.segment(tcp_flags::ACK | tcp_flags::PSH, seq, payload));
+            sent += len;
+        }
+        if self.fin_queued && sent == self.send_buf.len() {
+            let seq = self.snd_una.wrapping_add(sent as u32);
+            out.push(self.segment(tcp_flags::FIN | tcp_flags::ACK, seq, Vec::new()));
+            sent += 1;
+            self.state = match self.state {
+                TcpState::Established => TcpState::FinWait1,
+                TcpState::CloseWait   => TcpState::LastAck,
+                state                 => state
+            };
+        }
+
+        self.snd_nxt = self.snd_una.wrapping_add(sent as u32);
+        if sent > 0 && self.timer.is_none() {
+            self.timer = Some(now + self.rto);
+        }
+    }
+
+    fn on_timer(&mut self, now: Instant, out: &mut Vec<Segment>) {
+        self.timer = None;
+        match self.state {
+            TcpState::TimeWait | TcpState::FinWait2 => return self.close(None),
+            TcpState::Closed => return,
+            _ => ()
+        }
+
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            return self.close(Some(io::ErrorKind::TimedOut))
+        }
+        self.rto = (self.rto * 2).min(MAX_RTO);
+        match self.state {
+            TcpState::SynSent | TcpState::SynReceived => {
+                out.push(self.syn());
+                self.timer = Some(now + self.rto);
+            },
+            // go back N
+            _ => {
+                self.snd_nxt = self.snd_una;
+                self.push(now, out);
+            }
+        }
+    }
+
+    // RFC 793 3.9 "SEGMENT ARRIVES", minus the parts we don't do
+    fn on_segment(&mut self, segment: &Incoming, now: Instant, out: &mut Vec<Segment>) {
+        if segment.flags & tcp_flags::RST != 0 {
+            if self.state == TcpState::SynSent {
+                if segment.flags & tcp_flags::ACK != 0 && segment.ack_nr == self.snd_nxt {
+                    self.close(Some(io::ErrorKind::ConnectionRefused));
+                }
+            } else if segment.seq == self.rcv_nxt && self.state != TcpState::Closed {
+                // only an exact match, RFC 5961 3.2
+                self.close(Some(io::ErrorKind::ConnectionReset));
+            }
+            return
+        }
+
+        match self.state {
+            TcpState::Closed => return,
+            TcpState::SynSent => {
+                if segment.flags & tcp_flags::ACK != 0 && segment.ack_nr != self.snd_nxt {
+                    out.push(Segment::reset_for(segment));
+                } else if segment.flags & (tcp_flags::SYN | tcp_flags::ACK)
+                          == tcp_flags::SYN | tcp_flags::ACK {
+                    // no simultaneous open, a bare SYN is ignored
+                    self.accept_syn(segment);
+                    self.snd_una = self.snd_nxt;
+                    self.established();
+                    out.push(self.ack());
+                }
+                return
+            },
+            _ => ()
+        }
+
+        // the peer didn't get our SYN-ACK, or our ACK of its SYN-ACK
+        if segment.flags & tcp_flags::SYN != 0 {
+            out.push(if self.state == TcpState::SynReceived { self.syn() } else { self.ack() });
+            return
+        }
+        if segment.flags & tcp_flags::ACK == 0 {
+            return
+        }
+
+        if self.state == TcpState::SynReceived {
+            if segment.ack_nr != self.snd_nxt {
+                out.push(Segment::reset_for(segment));
+                return
+            }
+            self.snd_una = self.snd_nxt;
+            self.established();
+        }
+
+        // an ack for something we haven't sent gets an ack back, old ones
+        // are harmless
+        if seq_lt(self.snd_nxt, segment.ack_nr) {
+            out.push(self.ack());
+            return
+        }
+        if !seq_lt(segment.ack_nr, self.snd_una) {
+            self.snd_wnd = segment.win as u32;
+            let acked = segment.ack_nr.wrapping_sub(self.snd_una) as usize;
+            if acked > 0 {
+                self.on_ack(acked, now);
+            }
+        }
+
+        self.receive(segment, now, out);
+        self.push(now, out);
+    }
+
+    fn on_ack(&mut self, acked: usize, now: Instant) {
+        let data = acked.min(self.send_buf.len());
+        self.send_buf.drain(..data);
+        self.snd_una = self.snd_una.wrapping_add(acked as u32);
+        self.rto = INITIAL_RTO;
+        self.retries = 0;
+        self.timer = if self.snd_una == self.snd_nxt { None } else { Some(now + self.rto) };
+
+        // and our FIN with it
+        if acked > data {
+            match self.state {
+                TcpState::FinWait1 => {
+                    self.state = TcpState::FinWait2;
+                    // nobody's left to read, don't wait on the peer forever
+                    if self.owner == Owner::Nobody {
+                        self.timer = Some(now + TIME_WAIT);
+                    }
+                },
+                TcpState::Closing => self.time_wait(now),
+                TcpState::LastAck => self.close(None),
+                _ => ()
+            }
+        }
+        self.wake_writer();
+    }
+
+    // the segment's data and FIN
+    fn receive(&mut self, segment: &Incoming, now: Instant, out: &mut Vec<Segment>) {
+        let mut payload = segment.payload;
+        let mut fin = segment.flags & tcp_flags::FIN != 0;
+
+        if seq_lt(segment.seq, self.rcv_nxt) {
+            // a retransmission, keep what's new in it if anything
+            let dup = self.rcv_nxt.wrapping_sub(segment.seq) as usize;
+            if dup > payload.len() || (dup == payload.len() && !fin) {
+                out.push(self.ack());
+                return
+            }
+            payload = &payload[dup..];
+        } else if segment.seq != self.rcv_nxt {
+            // out of order, we don't queue those; the ack says where we're at
+            if !payload.is_empty() || fin {
+                out.push(self.ack());
+            }
+            return
+        }
+        if payload.is_empty() && !fin {
+            return
+        }
+
+        let receiving = matches!(self.state,
+                                 TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2);
+        fin = fin && receiving;
+        if !payload.is_empty() && receiving {
+            // nobody's going to read it, RFC 1122 4.2.2.13
+            if self.owner == Owner::Nobody {
+                return self.abort(out)
+            }
+            let take = payload.len().min(TCP_BUFFER - self.recv_buf.len());
+            self.recv_buf.extend(&payload[..take]);
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(take as u32);
+            fin = fin && take == payload.len();
+            self.wake_reader();
+        }
+
+        if fin {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.peer_fin = true;
+            self.wake_reader();
+            match self.state {
+                TcpState::Established => self.state = TcpState::CloseWait,
+                TcpState::FinWait1    => self.state = TcpState::Closing,
+                TcpState::FinWait2    => self.time_wait(now),
+                _ => ()
+            }
+        }
+        out.push(self.ack());
+    }
+}
+
+pub struct TcpStream {
+    inner: Arc<Inner>,
+    key: TcpKey
+}
+
+impl TcpStream {
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.inner.addr, self.key.0)
+    }
+
+    pub fn peer_addr(&self) -> SocketAddrV4 {
+        self.key.1
+    }
+
+    // the connection stays in the table for as long as its stream exists
+    fn with_tcb<R, F>(&self, f: F) -> R
+        where F: FnOnce(&mut Tcb, &mut Vec<Segment>) -> R
+    {
+        let mut state = self.inner.state.lock().unwrap();
+        let mut out = Vec::new();
+        let res = f(state.connections.get_mut(&self.key).unwrap(), &mut out);
+        if !out.is_empty() {
+            self.inner.send_segments(&mut state, &self.key, out);
+            // it'll have a retransmission timer to look at
+            state.wake_driver();
+        }
+        res
+    }
+}
+
+impl AsyncRead for TcpStream {
+    // reads nothing once the peer's closed its side
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buff: &mut ReadBuf)
+                 -> Poll<io::Result<()>> {
+        self.with_tcb(|tcb, out| {
+            if !tcb.recv_buf.is_empty() {
+                let before = tcb.window() as usize;
+                let len = tcb.recv_buf.len().min(buff.remaining());
+                let data: Vec<u8> = tcb.recv_buf.drain(..len).collect();
+                buff.put_slice(&data);
+                // a window update when it opens up, RFC 1122 4.2.3.3
+                if before < TCP_BUFFER / 2 && tcb.window() as usize >= TCP_BUFFER / 2 {
+                    out.push(tcb.ack());
+                }
+                return Poll::Ready(Ok(()))
+            }
+            if let Some(kind) = tcb.error {
+                return Poll::Ready(Err(kind.into()))
+            }
+            if tcb.peer_fin || tcb.state == TcpState::Closed {
+                return Poll::Ready(Ok(()))
+            }
+            tcb.read_waker = Some(cx.waker().clone());
+            Poll::Pending
+        })
+    }
+}
+
+impl AsyncWrite for TcpStream {
+    // accepted bytes are buffered until the peer acks them
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, data: &[u8])
+                  -> Poll<io::Result<usize>> {
+        self.with_tcb(|tcb, out| {
+            if let Some(kind) = tcb.error {
+                return Poll::Ready(Err(kind.into()))
+            }
+            match tcb.state {
+                TcpState::Established | TcpState::CloseWait if !tcb.fin_queued => (),
+                _ => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+            }
+            let len = data.len().min(TCP_BUFFER - tcb.send_buf.len());
+            if len == 0 && !data.is_empty() {
+                tcb.write_waker = Some(cx.waker().clone());
+                return Poll::Pending
+            }
+            tcb.send_buf.extend(&data[..len]);
+            tcb.push(Instant::now(), out);
+            Poll::Ready(Ok(len))
+        })
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    // sends a FIN after the buffered data, reading still works
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        self.with_tcb(|tcb, out| {
+            if !tcb.fin_queued && tcb.error.is_none() {
+                tcb.fin_queued = true;
+                tcb.push(Instant::now(), out);
+            }
+            Poll::Ready(Ok(()))
+        })
+    }
+}
+
+// closes the connection, or resets it if there was unread data
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        let mut guard = self.inner.state.lock().unwrap();
+        let state = &mut *guard;
+        let now = Instant::now();
+        let mut out = Vec::new();
+        if let Some(tcb) = state.connections.get_mut(&self.key) {
+            tcb.owner = Owner::Nobody;
+            if !tcb.recv_buf.is_empty() {
+                tcb.abort(&mut out);
+            } else {
+                match tcb.state {
+                    TcpState::SynSent  => tcb.close(None),
+                    TcpState::FinWait2 => tcb.timer = Some(now + TIME_WAIT),
+                    _ => {
+                        tcb.fin_queued = true;
+                        tcb.push(now, &mut out);
+                    }
+                }
+            }
+        }
+        self.inner.reap(state, &self.key);
+        self.inner.send_segments(state, &self.key, out);
+        state.wake_driver();
+    }
+}
+
+pub struct Connect {
+    // None once it's resolved
+    stream: Option<io::Result<TcpStream>>
+}
+
+impl Future for Connect {
+    type Output = io::Result<TcpStream>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<TcpStream>> {
+        let this = self.get_mut();
+        let stream = match this.stream.take().expect("Connect polled after it resolved") {
+            Ok(stream) => stream,
+            Err(e)     => return Poll::Ready(Err(e))
+        };
+        let result = stream.with_tcb(|tcb, _| match tcb.state {
+            TcpState::SynSent => {
+                tcb.write_waker = Some(cx.waker().clone());
+                None
+            },
+            _ => Some(tcb.error)
+        });
+        match result {
+            None => {
+                this.stream = Some(Ok(stream));
+                Poll::Pending
+            },
+            Some(Some(kind)) => Poll::Ready(Err(kind.into())),
+            Some(None)       => Poll::Ready(Ok(stream))
+        }
+    }
+}
+
+pub struct TcpListener {
+    inner: Arc<Inner>,
+    port: u16
+}
+
+impl TcpListener {
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.inner.addr, self.port)
+    }
+
+    pub fn poll_accept(&self, cx: &mut Context) -> Poll<io::Result<(TcpStream, SocketAddrV4)>> {
+        let mut guard = self.inner.state.lock().unwrap();
+        let state = &mut *guard;
+        let listener = state.listeners.get_mut(&self.port).unwrap();
+        while let Some(key) = listener.queue.pop_front() {
+            // it may have been reset while it waited
+            let tcb = state.connections.get_mut(&key).filter(|tcb| tcb.owner == Owner::Listener);
+            if let Some(tcb) = tcb {
+                tcb.owner = Owner::Stream;
+                let stream = TcpStream { inner: self.inner.clone(), key: key };
+                return Poll::Ready(Ok((stream, key.1)))
+            }
+        }
+        listener.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    pub fn accept<'a>(&'a self) -> Accept<'a> {
+        Accept { listener: self }
+    }
+}
+
+// resets the connections nobody accepted
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        let mut guard = self.inner.state.lock().unwrap();
+        let state = &mut *guard;
+        let listener = state.listeners.remove(&self.port).unwrap();
+        for key in listener.queue {
+            let mut out = Vec::new();
+            if let Some(tcb) = state.connections.get_mut(&key) {
+                if tcb.owner == Owner::Listener {
+                    tcb.abort(&mut out);
+                }
+            }
+            self.inner.reap(state, &key);
+            self.inner.send_segments(state, &key, out);
+        }
+    }
+}
+
+pub struct Accept<'a> {
+    listener: &'a TcpListener
+}
+
+impl<'a> Future for Accept<'a> {
+    type Output = io::Result<(TcpStream, SocketAddrV4)>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.listener.poll_accept(cx)
+    }
+}
+
+
+// testing
+#[cfg(test)]
+const STACK_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
+#[cfg(test)]
+const PEER_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x02];
+#[cfg(test)]
+const STACK_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+#[cfg(test)]
+const PEER_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+
+// a stack on a socketpair, the test plays the kernel on the returned end
+#[cfg(test)]
+fn test_net() -> (tokio::runtime::Runtime, Stack, ::std::fs::File) {
+    use std::os::unix::io::AsRawFd;
+
+    let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    let (tap, peer) = Tap::pair(::config::DevType::Tap, 1500).unwrap();
+    let (stack, driver) = {
+        let _guard = rt.enter();
+        Stack::new(AsyncLinkDevice::new(tap).unwrap(), STACK_MAC, STACK_IP)
+    };
+    rt.spawn(driver);
+    unsafe { libc::fcntl(peer.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK) };
+    (rt, stack, peer)
+}
+
+// the next frame the stack sends, running the runtime while we wait
+#[cfg(test)]
+fn next_frame(rt: &tokio::runtime::Runtime, peer: &::std::fs::File) -> Vec<u8> {
+    use std::io::Read;
+
+    let _guard = rt.enter();
+    let mut buff = [0u8; 2048];
+    for _ in 0..200 {
+        match (&*peer).read(&mut buff) {
+            Ok(len) => return buff[..len].to_vec(),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock =>
+                rt.block_on(tokio::time::sleep(Duration::from_millis(5))),
+            Err(e) => panic!("{}", e)
+        }
+    }
+    panic!("the stack sent nothing")
+}
+
+#[cfg(test)]
+fn send_frame(peer: &::std::fs::File, frame: &[u8]) {
+    use std::io::Write;
+    assert_eq!((&*peer).write(frame).unwrap(), frame.len());
+}
+
+#[cfg(test)]
+fn peer_tcp(src_port: u16, dst_port: u16, seq: u32, ack_nr: u32, flags: u8, payload: &[u8])
+            -> Vec<u8> {
+    EthBuilder::new(PEER_MAC, STACK_MAC)
+        .ipv4(PEER_IP, STACK_IP)
+        .tcp(src_port, dst_port)
+        .seq(seq)
+        .ack_nr(ack_nr)
+        .flags(flags)
+        .mss(1000)
+        .build(payload)
+        .data
+}
+
+// (seq, ack_nr, flags, payload) of a frame the stack sent
+#[cfg(test)]
+fn tcp_fields(frame: &[u8]) -> (u32, u32, u8, Vec<u8>) {
+    let seg = &frame[ETH_LEN + IPV4_LEN..];
+    let tcp = Tcp { offset: 0 };
+    let header_len = tcp.header_len(seg).unwrap();
+    (tcp.get_seq(seg), tcp.get_ack_nr(seg), seg[13] & 0x3F, seg[header_len..].to_vec())
+}
+
+#[cfg(test)]
+fn read_stream(rt: &tokio::runtime::Runtime, stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut buff = [0u8; 256];
+    let len = rt.block_on(::std::future::poll_fn(|cx| {
+        let mut read = ReadBuf::new(&mut buff);
+        match Pin::new(&mut *stream).poll_read(cx, &mut read) {
+            Poll::Ready(Ok(()))  => Poll::Ready(Ok(read.filled().len())),
+            Poll::Ready(Err(e))  => Poll::Ready(Err(e)),
+            Poll::Pending        => Poll::Pending
+        }
+    }))?;
+    Ok(buff[..len].to_vec())
+}
+
+#[cfg(test)]
+fn write_stream(rt: &tokio::runtime::Runtime, stream: &mut TcpStream, data: &[u8]) -> usize {
+    rt.block_on(::std::future::poll_fn(|cx| Pin::new(&mut *stream).poll_write(cx, data))).unwrap()
+}
+
+#[cfg(test)]
+fn arp_request(sha: [u8; 6], spa: Ipv4Addr, tpa: Ipv4Addr) -> Vec<u8> {
+    let mut frame = vec![0u8; ETH_LEN + 28];
+    let eth = Eth { offset: 0 };
+    eth.set_dst(&mut frame, BROADCAST);
+    eth.set_src(&mut frame, sha);
+    eth.set_ethertype(&mut frame, 0x0806);
+    let arp = arp::Arp { offset: ETH_LEN };
+    let buff = &mut frame[ETH_LEN..];
+    arp.set_htype(buff, 1);
+    arp.set_ptype(buff, 0x0800);
+    arp.set_hlen(buff, 6);
+    arp.set_plen(buff, 4);
+    arp.set_oper(buff, arp::REQUEST);
+    arp.set_sha(buff, sha);
+    arp.set_spa(buff, spa.octets());
+    arp.set_tpa(buff, tpa.octets());
+    frame
+}
+
+#[test]
+fn test_udp_send_and_receive() {
+    let (rt, stack, peer) = test_net();
+    let mut sock = stack.bind_udp(7).unwrap();
+    send_frame(&peer, &EthBuilder::new(PEER_MAC, STACK_MAC).ipv4(PEER_IP, STACK_IP)
+               .udp(5000, 7).build(b"ping").data);
+
+    let mut buff = [0u8; 16];
+    let (len, from) = rt.block_on(sock.recv_from(&mut buff)).unwrap();
+    assert_eq!((&buff[..len], from), (&b"ping"[..], SocketAddrV4::new(PEER_IP, 5000)));
+
+    assert_eq!(rt.block_on(sock.send_to(b"pong", from)).unwrap(), 4);
+    let reply = next_frame(&rt, &peer);
+    // the peer's mac came with its datagram
+    assert_eq!(Eth { offset: 0 }.get_dst(&reply), PEER_MAC);
+    let udp_buff = &reply[ETH_LEN + IPV4_LEN..];
+    let udp = Udp { offset: 0 };
+    assert_eq!((udp.get_src_port(udp_buff), udp.get_dst_port(udp_buff)), (7, 5000));
+    assert_eq!(&udp_buff[UDP_LEN..], b"pong");
+}
+
+#[test]
+fn test_udp_checksums() {
+    let (rt, stack, peer) = test_net();
+    let mut sock = stack.bind_udp(7).unwrap();
+    let datagram = |payload: &[u8]| EthBuilder::new(PEER_MAC, STACK_MAC).ipv4(PEER_IP, STACK_IP)
+        .udp(5000, 7).build(payload).data;
+
+    let mut bad = datagram(b"bad");
+    *bad.last_mut().unwrap() ^= 1;
+    let mut unchecked = datagram(b"unchecked");
+    Udp { offset: 0 }.set_chk(&mut unchecked[ETH_LEN + IPV4_LEN..], 0);
+    send_frame(&peer, &bad);
+    send_frame(&peer, &unchecked);
+    send_frame(&peer, &datagram(b"good"));
+
+    let mut buff = [0u8; 16];
+    let (len, _) = rt.block_on(sock.recv_from(&mut buff)).unwrap();
+    assert_eq!(&buff[..len], b"unchecked");
+    let (len, _) = rt.block_on(sock.recv_from(&mut buff)).unwrap();
+    assert_eq!(&buff[..len], b"good");
+}
+
+#[test]
+fn test_arp_resolution() {
+    let (rt, stack, peer) = test_net();
+    let other_ip = Ipv4Addr::new(10, 0, 0, 9);
+
+    // not ethernet, so neither answered nor learned from
+    let mut odd = arp_request([0x02, 0, 0, 0, 0, 0x09], other_ip, STACK_IP);
+    arp::Arp { offset: ETH_LEN }.set_htype(&mut odd[ETH_LEN..], 6);
+    send_frame(&peer, &odd);
+    send_frame(&peer, &arp_request(PEER_MAC, PEER_IP, STACK_IP));
+
+    let reply = next_frame(&rt, &peer);
+    let eth = Eth { offset: 0 };
+    assert_eq!((eth.get_dst(&reply), eth.get_src(&reply), eth.get_ethertype(&reply)),
+               (PEER_MAC, STACK_MAC, 0x0806));
+    let arp = arp::Arp { offset: ETH_LEN };
+    let buff = &reply[ETH_LEN..];
+    assert_eq!(arp.get_oper(buff), arp::REPLY);
+    assert_eq!((arp.get_sha(buff), Ipv4Addr::from(arp.get_spa(buff))), (STACK_MAC, STACK_IP));
+    assert_eq!((arp.get_tha(buff), Ipv4Addr::from(arp.get_tpa(buff))), (PEER_MAC, PEER_IP));
+
+    let sock = stack.bind_udp(0).unwrap();
+    rt.block_on(sock.send_to(b"x", SocketAddrV4::new(PEER_IP, 9))).unwrap();
+    assert_eq!(eth.get_dst(&next_frame(&rt, &peer)), PEER_MAC);
+    rt.block_on(sock.send_to(b"x", SocketAddrV4::new(other_ip, 9))).unwrap();
+    assert_eq!(eth.get_dst(&next_frame(&rt, &peer)), BROADCAST);
+}
+
+#[test]
+fn test_tcp_connect_exchange_and_close() {
+    let (rt, stack, peer) = test_net();
+    let connect = stack.connect_tcp(SocketAddrV4::new(PEER_IP, 80));
+
+    let syn = next_frame(&rt, &peer);
+    let (iss, _, flags, _) = tcp_fields(&syn);
+    assert_eq!(flags, tcp_flags::SYN);
+    let seg = &syn[ETH_LEN + IPV4_LEN..];
+    let tcp = Tcp { offset: 0 };
+    assert_eq!(parse_mss(&seg[TCP_LEN..tcp.header_len(seg).unwrap()]), Some(1460));
+    let port = tcp.get_src_port(seg);
+
+    send_frame(&peer, &peer_tcp(80, port, 1000, iss + 1, tcp_flags::SYN | tcp_flags::ACK, b""));
+    let mut stream = rt.block_on(connect).unwrap();
+    assert_eq!(stream.local_addr(), SocketAddrV4::new(STACK_IP, port));
+    assert_eq!(stream.peer_addr(), SocketAddrV4::new(PEER_IP, 80));
+    assert_eq!(tcp_fields(&next_frame(&rt, &peer)), (iss + 1, 1001, tcp_flags::ACK, vec!()));
+
+    assert_eq!(write_stream(&rt, &mut stream, b"hello"), 5);
+    assert_eq!(tcp_fields(&next_frame(&rt, &peer)),
+               (iss + 1, 1001, tcp_flags::ACK | tcp_flags::PSH, b"hello".to_vec()));
+
+    // the peer acks, answers and closes in one go
+    let flags = tcp_flags::ACK | tcp_flags::PSH | tcp_flags::FIN;
+    send_frame(&peer, &peer_tcp(80, port, 1001, iss + 6, flags, b"world"));
+    assert_eq!(read_stream(&rt, &mut stream).unwrap(), b"world");
+    assert_eq!(read_stream(&rt, &mut stream).unwrap(), b"");
+    assert_eq!(tcp_fields(&next_frame(&rt, &peer)), (iss + 6, 1007, tcp_flags::ACK, vec!()));
+
+    // dropping it sends our FIN, and once that's acked it's gone
+    drop(stream);
+    assert_eq!(tcp_fields(&next_frame(&rt, &peer)),
+               (iss + 6, 1007, tcp_flags::FIN | tcp_flags::ACK, vec!()));
+    send_frame(&peer, &peer_tcp(80, port, 1007, iss + 7, tcp_flags::ACK, b""));
+    let _guard = rt.enter();
+    rt.block_on(tokio::time::sleep(Duration::from_millis(20)));
+    assert!(stack.inner.state.lock().unwrap().connections.is_empty());
+}
+
+#[test]
+fn test_tcp_listen_and_accept() {
+    let (rt, stack, peer) = test_net();
+    let listener = stack.listen_tcp(80).unwrap();
+    assert_eq!(stack.listen_tcp(80).err().map(|e| e.kind()), Some(io::ErrorKind::AddrInUse));
+
+    send_frame(&peer, &peer_tcp(40000, 80, 5000, 0, tcp_flags::SYN, b""));
+    let (iss, ack_nr, flags, _) = tcp_fields(&next_frame(&rt, &peer));
+    assert_eq!((ack_nr, flags), (5001, tcp_flags::SYN | tcp_flags::ACK));
+
+    // data along with the handshake's ACK, before anyone accepted
+    send_frame(&peer, &peer_tcp(40000, 80, 5001, iss + 1, tcp_flags::ACK, b"hi"));
+    let (mut stream, from) = rt.block_on(listener.accept()).unwrap();
+    assert_eq!(from, SocketAddrV4::new(PEER_IP, 40000));
+    assert_eq!(read_stream(&rt, &mut stream).unwrap(), b"hi");
+    assert_eq!(tcp_fields(&next_frame(&rt, &peer)), (iss + 1, 5003, tcp_flags::ACK, vec!()));
+
+    // cut to the peer's mss of 1000
+    assert_eq!(write_stream(&rt, &mut stream, &[7u8; 2500]), 2500);
+    let lens: Vec<(u32, usize)> = (0..3)
+        .map(|_| tcp_fields(&next_frame(&rt, &peer)))
+        .map(|(seq, _, _, payload)| (seq - iss, payload.len()))
+        .collect();
+    assert_eq!(lens, vec!((1, 1000), (1001, 1000), (2001, 500)));
+
+    // a reset makes the stream fail
+    send_frame(&peer, &peer_tcp(40000, 80, 5003, 0, tcp_flags::RST, b""));
+    let read = read_stream(&rt, &mut stream);
+    assert_eq!(read.err().map(|e| e.kind()), Some(io::ErrorKind::ConnectionReset));
+}
+
+#[test]
+fn test_tcp_resets_closed_ports() {
+    let (rt, _stack, peer) = test_net();
+
+    // a bad checksum gets nothing, a good one a reset
+    let mut corrupt = peer_tcp(40000, 81, 1, 0, tcp_flags::SYN, b"");
+    *corrupt.last_mut().unwrap() ^= 1;
+    send_frame(&peer, &corrupt);
+    send_frame(&peer, &peer_tcp(40000, 81, 100, 0, tcp_flags::SYN, b""));
+    assert_eq!(tcp_fields(&next_frame(&rt, &peer)),
+               (0, 101, tcp_flags::RST | tcp_flags::ACK, vec!()));
+
+    send_frame(&peer, &peer_tcp(40000, 81, 100, 777, tcp_flags::ACK, b"data"));
+    assert_eq!(tcp_fields(&next_frame(&rt, &peer)), (777, 0, tcp_flags::RST, vec!()));
+}
+
+// a connection that just got its SYN-ACK, with a 4000 byte window and mss 1000
+#[cfg(test)]
+fn established_tcb(now: Instant) -> Tcb {
+    let mut tcb = Tcb::new(TcpState::SynSent, 100, 1460, Owner::Stream, now);
+    let syn_ack = Incoming {
+        seq: 1000, ack_nr: 101, flags: tcp_flags::SYN | tcp_flags::ACK, win: 4000,
+        mss: Some(1000), payload: &[]
+    };
+    let mut out = Vec::new();
+    tcb.on_segment(&syn_ack, now, &mut out);
+    assert_eq!(tcb.state, TcpState::Established);
+    assert_eq!((out[0].seq, out[0].ack_nr, out[0].flags), (101, 1001, tcp_flags::ACK));
+    tcb
+}
+
+#[test]
+fn test_tcp_retransmits_what_was_not_acked() {
+    let now = Instant::now();
+    let mut tcb = established_tcb(now);
+    let mut out = Vec::new();
+    tcb.send_buf.extend(&[7u8; 2500][..]);
+    tcb.push(now, &mut out);
+    let sent: Vec<(u32, usize)> = out.iter().map(|s| (s.seq, s.payload.len())).collect();
+    assert_eq!(sent, vec!((101, 1000), (1101, 1000), (2101, 500)));
+    assert_eq!(tcb.timer, Some(now + INITIAL_RTO));
+
+    // only the first made it
+    out.clear();
+    let ack = Incoming { seq: 1001, ack_nr: 1101, flags: tcp_flags::ACK, win: 4000, mss: None,
+                         payload: &[] };
+    tcb.on_segment(&ack, now, &mut out);
+    assert!(out.is_empty());
+    assert_eq!((tcb.snd_una, tcb.send_buf.len()), (1101, 1500));
+
+    let at = tcb.timer.unwrap();
+    tcb.on_timer(at, &mut out);
+    let resent: Vec<(u32, usize)> = out.iter().map(|s| (s.seq, s.payload.len())).collect();
+    assert_eq!(resent, vec!((1101, 1000), (2101, 500)));
+    assert_eq!(tcb.timer, Some(at + INITIAL_RTO * 2));
+
+    // and it gives up eventually
+    for _ in 0..MAX_RETRIES {
+        let at = tcb.timer.unwrap();
+        tcb.on_timer(at, &mut out);
+    }
+    assert_eq!((tcb.state, tcb.error), (TcpState::Closed, Some(io::ErrorKind::TimedOut)));
+}
+
+#[test]
+fn test_tcp_drops_out_of_order_data() {
+    let now = Instant::now();
+    let mut tcb = established_tcb(now);
+    let mut out = Vec::new();
+    let segment = |seq, payload| Incoming {
+        seq: seq, ack_nr: 101, flags: tcp_flags::ACK, win: 4000, mss: None, payload: payload
+    };
+
+    tcb.on_segment(&segment(1005, b"late"), now, &mut out);
+    assert!(tcb.recv_buf.is_empty());
+    tcb.on_segment(&segment(1001, b"abcd"), now, &mut out);
+    // a retransmission that overlaps what we have
+    tcb.on_segment(&segment(1003, b"cdef"), now, &mut out);
+    tcb.on_segment(&segment(1001, b"abcd"), now, &mut out);
+    let acks: Vec<u32> = out.iter().map(|s| s.ack_nr).collect();
+    assert_eq!(acks, vec!(1001, 1005, 1007, 1007));
+    assert_eq!(tcb.recv_buf.iter().cloned().collect::<Vec<u8>>(), b"abcdef");
+}
@@ -0,0 +1,970 @@
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use toml;
+
+use firewall::Rule;
+use nat::DnatRule;
+use netem::Impairment;
+use packet::filter::Filter;
+use rewrite;
+use packet::pkt;
+
+// configuration
+//
+// Settings come from three places, later ones winning:
+//   1. the defaults below (tap0, 10.0.0.1/24, reflect mode)
+//   2. a TOML file given with -c/--config
+//   3. command line options
+//
+// Config file layout:
+//
+//   mode = "reflect"        # capture | reflect | serve | forward | replay
+//   replay = "dump.pcap"    # file to play back in replay mode
+//   verbosity = 1
+//   filter = "tcp and dst port 80" # only print, or replay, what matches
+//   routes = ["default via 10.0.0.1", "192.168.0.0/16 dev tap1"]
+//   rewrite = ["udp -> swap ipv4.src ipv4.dst; dec ipv4.ttl; checksums"]
+//
+//   [interface]
+//   name = "tap0"
+//   type = "tap"            # tap | tun
+//   ipv4 = "10.0.0.1/24"    # the host's end of the link
+//   ipv6 = "fd00::1/64"
+//   peer_ipv4 = "10.0.0.2"  # ours, for serve and forward mode
+//   peer_ipv6 = "fd00::2"
+//   mtu = 1500
+//
+//   [[interfaces]]          # more of them, to forward between
+//   name = "tap1"
+//   ipv4 = "10.0.1.1/24"
+//   peer_ipv4 = "10.0.1.2"
+//
+//   [nat]                   # forward mode only
+//   masquerade = "tap1"     # source nat what's routed out of tap1 to its peer_ipv4
+//   dnat = ["tcp 192.0.2.1:8080 to 10.0.0.5:80", "192.0.2.10 to 10.0.0.7"]
+//   nat64 = "64:ff9b::/96"  # ipv4 as seen from ipv6, translated at the border
+//   nat64_pool = "192.0.2.64" # ipv6 hosts share this ipv4 address (stateful),
+//                           # or have their own in the prefix (SIIT) without
+//                           # it. Routed to us, and not the masquerading one.
+//
+//   [firewall]              # forward mode only, first match decides
+//   ingress = ["accept ct established,related", "drop iface tap1 ct new"]
+//   egress = ["reject tcp dport 25"]
+//
+//   [netem]                 # forward mode only, first match decides
+//   ingress = ["udp -> delay 100ms 20ms normal loss 1%"]
+//   egress = ["loss gemodel 1% 30%", "rate 10mbit"]
+//   seed = 42               # the same losses every run
+//
+//   [privileges]            # drop root for good once the device is open,
+//   user = "nobody"         # the rules are only reloaded if this file can
+//                           # still be read then
+//   group = "nogroup"
+
+pub const USAGE: &str = "\
+usage: chucker [options] [capture | reflect | serve | forward | replay <file.pcap>]
+
+modes:
+  capture             print incoming frames, don't send anything
+  reflect             send frames back with ip src and dst swapped, or
+                      as the --rewrite rules say (default)
+  serve               bring the interface up and handle frames locally,
+                      forwarding SRv6 packets on to their next segment
+  forward             route ip between the interfaces
+  replay <file>       write the frames in a pcap file to the interface
+
+options:
+  -c, --config <file> read settings from a TOML file
+  -i, --iface <name>  interface name (default tap0), repeat for more
+                      interfaces; the options below apply to the last one
+      --tap           create a TAP (ethernet) device (default)
+      --tun           create a TUN (ip) device
+  -4, --ipv4 <a/len>  interface IPv4 address and prefix (default 10.0.0.1/24)
+  -6, --ipv6 <a/len>  interface IPv6 address and prefix
+      --peer4 <addr>  our IPv4 address on the link
+      --peer6 <addr>  our IPv6 address on the link
+  -m, --mtu <bytes>   interface MTU, 68 to 65535 (default 1500)
+  -r, --route <route> add a route: <dst/len | default> [via <addr>] [dev <name>]
+      --masquerade <name>
+                      source nat what's routed out of this interface
+      --dnat <rule>   forward a port or address to a host inside:
+                      [tcp | udp | icmp] <addr>[:port] to <addr>[:port]
+      --nat64 <prefix/96>
+                      translate between ipv6 and the ipv4 addresses
+                      embedded in this prefix (64:ff9b::/96 is the usual)
+      --nat64-pool <addr>
+                      ipv6 hosts go out as this ipv4 address (stateful)
+      --ingress <rule>
+      --egress <rule> add a firewall rule for what comes in or goes out:
+                      accept | drop | reject | log | count, then any of
+                      iface <name>, ether src|dst <mac>, ethertype <type>,
+                      vlan <id>, src|dst <addr/len>, tcp | udp | icmp |
+                      icmp6 | proto <nr>, sport|dport <port[-port]>,
+                      flags <syn,!ack,..>, type <icmp type>,
+                      ct <new,established,related,closing,untracked>;
+                      rules in the config file are reread when it changes,
+                      if it can still be read after -u and -g
+      --rewrite <rule>
+                      what reflect mode does to frames, instead of the
+                      swap: [<filter> ->] <action>; <action>.. with
+                      set <field> <value>, swap <field> <field>,
+                      inc | dec <field> [n], push vlan <id>, pop vlan,
+                      truncate | pad <n>, checksums; fields are named
+                      like ipv4.ttl or udp.dst_port, frames that match
+                      no rule aren't sent back
+      --netem-in <rule>
+      --netem-out <rule>
+                      impair what comes in or goes out when forwarding:
+                      [<filter> ->] then any of delay <time> [<jitter>
+                      [uniform | normal]], loss <p%>, loss gemodel <p%>
+                      [<r%> [<1-h%> [<1-k%>]]], reorder <p%>,
+                      duplicate <p%>, corrupt <p%>, rate <n>[k|m|g]bit
+      --seed <n>      for the impairments, random if not given
+  -u, --user <name>   run as this user once the interface is set up
+  -g, --group <name>  run as this group once the interface is set up
+  -f, --filter <expr> only print packets that match, and only replay those:
+                      tcp, udp, icmp, icmp6, ip, ip6, arp, vlan [id],
+                      [src | dst] host | net | port | portrange <arg>,
+                      ether src | dst | host <mac>, ether proto <type>,
+                      proto <nr>, less | greater <len>, combined with
+                      not, and, or and parentheses, as in tcpdump
+  -v, --verbose       print more, can be repeated (-vv)
+  -q, --quiet         don't print packets
+  -h, --help          show this message";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mode {
+    Capture,
+    Reflect,
+    Serve,
+    Forward,
+    Replay(String)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DevType {
+    Tap,
+    Tun
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ipv4Cidr {
+    pub addr: Ipv4Addr,
+    pub prefix: u8
+}
+
+impl Ipv4Cidr {
+    pub fn netmask(&self) -> Ipv4Addr {
+        let mask = if self.prefix == 0 { 0 } else { !0u32 << (32 - self.prefix) };
+        Ipv4Addr::from(mask)
+    }
+}
+
+impl FromStr for Ipv4Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Ipv4Cidr, String> {
+        let (addr, prefix) = split_prefix(s, 24, 32)?;
+        let addr = addr.parse::<Ipv4Addr>()
+            .map_err(|_| format!("bad ipv4 address: {}", s))?;
+        Ok(Ipv4Cidr { addr: addr, prefix: prefix })
+    }
+}
+
+impl fmt::Display for Ipv4Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ipv6Cidr {
+    pub addr: Ipv6Addr,
+    pub prefix: u8
+}
+
+impl FromStr for Ipv6Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Ipv6Cidr, String> {
+        let (addr, prefix) = split_prefix(s, 64, 128)?;
+        let addr = addr.parse::<Ipv6Addr>()
+            .map_err(|_| format!("bad ipv6 address: {}", s))?;
+        Ok(Ipv6Cidr { addr: addr, prefix: prefix })
+    }
+}
+
+impl fmt::Display for Ipv6Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IpCidr {
+    V4(Ipv4Cidr),
+    V6(Ipv6Cidr)
+}
+
+// A static route, in the same words `ip route` uses:
+//
+//   192.168.0.0/16 via 10.0.1.1
+//   fd00:1::/64 dev tap1
+//   default via 10.0.0.1 dev tap0
+//
+// A route without a gateway is directly connected. "default" takes its
+// address family from the gateway.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteConfig {
+    pub dst: IpCidr,
+    pub via: Option<IpAddr>,
+    pub dev: Option<String>
+}
+
+impl FromStr for RouteConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RouteConfig, String> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        let (dst, mut rest) = match words.split_first() {
+            Some((dst, rest)) => (*dst, rest),
+            None => return Err("empty route".to_string())
+        };
+
+        let mut via = None;
+        let mut dev = None;
+        while let Some((word, tail)) = rest.split_first() {
+            let arg = match tail.first() {
+                Some(arg) => *arg,
+                None => return Err(format!("{} needs an argument in route: {}", word, s))
+            };
+            match *word {
+                "via" => via = Some(arg.parse::<IpAddr>()
+                    .map_err(|_| format!("bad gateway in route: {}", s))?),
+                "dev" => dev = Some(arg.to_string()),
+                _ => return Err(format!("unexpected {} in route: {}", word, s))
+            }
+            rest = &tail[1..];
+        }
+
+        let dst = match (dst, via) {
+            ("default", Some(IpAddr::V4(_))) =>
+                IpCidr::V4(Ipv4Cidr { addr: Ipv4Addr::new(0, 0, 0, 0), prefix: 0 }),
+            ("default", Some(IpAddr::V6(_))) =>
+                IpCidr::V6(Ipv6Cidr { addr: Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), prefix: 0 }),
+            ("default", None) => return Err(format!("default route needs a gateway: {}", s)),
+            // a bare address is a host route
+            (dst, _) if dst.contains(':') => {
+                let (addr, prefix) = split_prefix(dst, 128, 128)?;
+                let addr = addr.parse::<Ipv6Addr>()
+                    .map_err(|_| format!("bad destination in route: {}", s))?;
+                IpCidr::V6(Ipv6Cidr { addr: addr, prefix: prefix })
+            },
+            (dst, _) => {
+                let (addr, prefix) = split_prefix(dst, 32, 32)?;
+                let addr = addr.parse::<Ipv4Addr>()
+                    .map_err(|_| format!("bad destination in route: {}", s))?;
+                IpCidr::V4(Ipv4Cidr { addr: addr, prefix: prefix })
+            }
+        };
+        match (dst, via) {
+            (IpCidr::V4(_), Some(IpAddr::V6(_))) |
+            (IpCidr::V6(_), Some(IpAddr::V4(_))) =>
+                return Err(format!("gateway and destination disagree on ip version: {}", s)),
+            _ => ()
+        }
+        if via.is_none() && dev.is_none() {
+            return Err(format!("route needs a gateway or a device: {}", s))
+        }
+        Ok(RouteConfig { dst: dst, via: via, dev: dev })
+    }
+}
+
+// "addr/len" -> (addr, len), with a default length if there's no slash
+fn split_prefix(s: &str, default: u8, max: u8) -> Result<(&str, u8), String> {
+    match s.find('/') {
+        None => Ok((s, default)),
+        Some(idx) => {
+            let prefix = s[idx + 1..].parse::<u8>()
+                .map_err(|_| format!("bad prefix length: {}", s))?;
+            if prefix > max {
+                return Err(format!("prefix length out of range: {}", s))
+            }
+            Ok((&s[..idx], prefix))
+        }
+    }
+}
+
+// packet filter rules when forwarding, each chain in order
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct FirewallConfig {
+    pub ingress: Vec<Rule>,
+    pub egress: Vec<Rule>
+}
+
+// network impairments when forwarding, per direction
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct NetemConfig {
+    pub ingress: Vec<Impairment>,
+    pub egress: Vec<Impairment>,
+    pub seed: Option<u64>
+}
+
+impl NetemConfig {
+    // the one given, or one from the clock
+    pub fn seed(&self) -> u64 {
+        self.seed.unwrap_or_else(|| {
+            SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos() as u64)
+        })
+    }
+}
+
+// address translation when forwarding
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct NatConfig {
+    // the interface whose peer_ipv4 everything routed out of it gets
+    // masqueraded as
+    pub masquerade: Option<String>,
+    // port forwards and one to one nat, in order
+    pub dnat: Vec<DnatRule>,
+    // the /96 ipv4 addresses are embedded in on the ipv6 side
+    pub nat64: Option<Ipv6Cidr>,
+    // stateful nat64's ipv4 address, stateless translation without it
+    pub nat64_pool: Option<Ipv4Addr>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IfaceConfig {
+    pub name: String,
+    pub dev_type: DevType,
+    pub ipv4: Option<Ipv4Cidr>,
+    pub ipv6: Option<Ipv6Cidr>,
+    // where chucker itself sits on the link, the host's neighbour
+    pub peer_ipv4: Option<Ipv4Addr>,
+    pub peer_ipv6: Option<Ipv6Addr>,
+    pub mtu: usize
+}
+
+impl Default for IfaceConfig {
+    fn default() -> IfaceConfig {
+        IfaceConfig {
+            name: "tap0".to_string(),
+            dev_type: DevType::Tap,
+            ipv4: Some(Ipv4Cidr { addr: Ipv4Addr::new(10, 0, 0, 1), prefix: 24 }),
+            ipv6: None,
+            peer_ipv4: None,
+            peer_ipv6: None,
+            mtu: pkt::DEFAULT_MTU
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub mode: Mode,
+    pub iface: IfaceConfig,
+    // the ones after the first, only opened in forward mode
+    pub extra_ifaces: Vec<IfaceConfig>,
+    pub routes: Vec<RouteConfig>,
+    pub nat: NatConfig,
+    pub firewall: FirewallConfig,
+    pub netem: NetemConfig,
+    // the file the settings were read from, if any
+    pub file: Option<String>,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub verbosity: u8,
+    // which packets are printed or replayed, all of them without one
+    pub filter: Option<Filter>,
+    // what reflect mode does instead of swapping addresses
+    pub rewrite: Vec<rewrite::Rule>
+}
+
+impl Config {
+    // do we give up privileges completely after setup
+    pub fn drops_privileges(&self) -> bool {
+        self.user.is_some() || self.group.is_some()
+    }
+
+    pub fn ifaces(&self) -> Vec<&IfaceConfig> {
+        let mut ifaces = vec!(&self.iface);
+        ifaces.extend(self.extra_ifaces.iter());
+        ifaces
+    }
+
+    // the interface the per-interface options apply to
+    fn last_iface_mut(&mut self) -> &mut IfaceConfig {
+        match self.extra_ifaces.last_mut() {
+            Some(iface) => iface,
+            None => &mut self.iface
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            mode: Mode::Reflect,
+            iface: IfaceConfig::default(),
+            extra_ifaces: Vec::new(),
+            routes: Vec::new(),
+            nat: NatConfig::default(),
+            firewall: FirewallConfig::default(),
+            netem: NetemConfig::default(),
+            file: None,
+            user: None,
+            group: None,
+            verbosity: 1,
+            filter: None,
+            rewrite: Vec::new()
+        }
+    }
+}
+
+// command line
+//
+// Returns Ok(None) if the user asked for help.
+pub fn from_args(args: &[String]) -> Result<Option<Config>, String> {
+    let mut config = Config::default();
+
+    // the config file goes first, so that the other options override it
+    let mut idx = 0;
+    while idx < args.len() {
+        match &args[idx][..] {
+            "-c" | "--config" => {
+                let path = next_arg(args, &mut idx)?;
+                load_file(path, &mut config)?;
+                config.file = Some(path.to_string());
+            },
+            "-h" | "--help" => return Ok(None),
+            _ => ()
+        }
+        idx += 1;
+    }
+
+    let mut idx = 0;
+    let mut mode = None;
+    let mut named = false;
+    while idx < args.len() {
+        match &args[idx][..] {
+            "-c" | "--config" => idx += 1,
+            "-i" | "--iface" => {
+                let name = next_arg(args, &mut idx)?.to_string();
+                // the first one renames the configured interface
+                if named {
+                    config.extra_ifaces.push(IfaceConfig {
+                        name: name, ipv4: None, ..IfaceConfig::default()
+                    });
+                } else {
+                    config.iface.name = name;
+                    named = true;
+                }
+            },
+            "--tap" => config.last_iface_mut().dev_type = DevType::Tap,
+            "--tun" => config.last_iface_mut().dev_type = DevType::Tun,
+            "-4" | "--ipv4" =>
+                config.last_iface_mut().ipv4 = Some(next_arg(args, &mut idx)?.parse()?),
+            "-6" | "--ipv6" =>
+                config.last_iface_mut().ipv6 = Some(next_arg(args, &mut idx)?.parse()?),
+            "--peer4" =>
+                config.last_iface_mut().peer_ipv4 = Some(parse_addr(next_arg(args, &mut idx)?)?),
+            "--peer6" =>
+                config.last_iface_mut().peer_ipv6 = Some(parse_addr(next_arg(args, &mut idx)?)?),
+            "-m" | "--mtu" =>
+                config.last_iface_mut().mtu = parse_mtu(next_arg(args, &mut idx)?)?,
+            "-r" | "--route" =>
+                config.routes.push(next_arg(args, &mut idx)?.parse()?),
+            "--masquerade" =>
+                config.nat.masquerade = Some(next_arg(args, &mut idx)?.to_string()),
+            "--dnat" =>
+                config.nat.dnat.push(next_arg(args, &mut idx)?.parse()?),
+            "--nat64" =>
+                config.nat.nat64 = Some(next_arg(args, &mut idx)?.parse()?),
+            "--nat64-pool" =>
+                config.nat.nat64_pool = Some(parse_addr(next_arg(args, &mut idx)?)?),
+            "--ingress" =>
+                config.firewall.ingress.push(next_arg(args, &mut idx)?.parse()?),
+            "--egress" =>
+                config.firewall.egress.push(next_arg(args, &mut idx)?.parse()?),
+            "--rewrite" =>
+                config.rewrite.push(next_arg(args, &mut idx)?.parse()?),
+            "--netem-in" =>
+                config.netem.ingress.push(next_arg(args, &mut idx)?.parse()?),
+            "--netem-out" =>
+                config.netem.egress.push(next_arg(args, &mut idx)?.parse()?),
+            "--seed" =>
+                config.netem.seed = Some(next_arg(args, &mut idx)?.parse()
+                                         .map_err(|_| "bad seed".to_string())?),
+            "-u" | "--user" =>
+                config.user = Some(next_arg(args, &mut idx)?.to_string()),
+            "-g" | "--group" =>
+                config.group = Some(next_arg(args, &mut idx)?.to_string()),
+            "-f" | "--filter" =>
+                config.filter = Some(next_arg(args, &mut idx)?.parse()?),
+            "--verbose" => config.verbosity = config.verbosity.saturating_add(1),
+            // -v, -vv, -vvv
+            opt if opt.len() > 1 && opt[1..].bytes().all(|b| b == b'v') =>
+                config.verbosity = config.verbosity.saturating_add((opt.len() - 1).min(255) as u8),
+            "-q" | "--quiet" => config.verbosity = 0,
+            opt if opt.starts_with('-') =>
+                return Err(format!("unknown option: {}", opt)),
+            cmd => {
+                if mode.is_some() {
+                    return Err(format!("unexpected argument: {}", cmd))
+                }
+                mode = Some(if cmd == "replay" {
+                    Mode::Replay(next_arg(args, &mut idx)?.to_string())
+                } else {
+                    parse_mode(cmd, None)?
+                });
+            }
+        }
+        idx += 1;
+    }
+
+    if let Some(mode) = mode {
+        config.mode = mode;
+    }
+    // nothing else goes through them
+    if config.mode != Mode::Forward && config.netem != NetemConfig::default() {
+        return Err("impairments only apply when forwarding".to_string())
+    }
+    check_ifaces(&config)?;
+    Ok(Some(config))
+}
+
+fn check_ifaces(config: &Config) -> Result<(), String> {
+    let ifaces = config.ifaces();
+    for (idx, iface) in ifaces.iter().enumerate() {
+        // the kernel drops ipv6 from an interface with a smaller mtu
+        if iface.ipv6.is_some() && iface.mtu < pkt::MIN_IPV6_MTU {
+            return Err(format!("ipv6 needs an mtu of at least {}", pkt::MIN_IPV6_MTU))
+        }
+        if ifaces[..idx].iter().any(|other| other.name == iface.name) {
+            return Err(format!("interface {} given twice", iface.name))
+        }
+    }
+    for route in &config.routes {
+        if let Some(ref dev) = route.dev {
+            if !ifaces.iter().any(|iface| iface.name == *dev) {
+                return Err(format!("route through unknown interface {}", dev))
+            }
+        }
+    }
+    if let Some(ref name) = config.nat.masquerade {
+        match ifaces.iter().find(|iface| iface.name == *name) {
+            Some(iface) if iface.peer_ipv4.is_none() =>
+                return Err(format!("masquerading needs our ipv4 address on {}", name)),
+            // both would hand out ports on it
+            Some(iface) if iface.peer_ipv4.is_some() && iface.peer_ipv4 == config.nat.nat64_pool =>
+                return Err(format!("nat64 pool {} is the masquerading address on {}",
+                                   iface.peer_ipv4.unwrap(), name)),
+            Some(_) => (),
+            None => return Err(format!("masquerading out of unknown interface {}", name))
+        }
+    }
+    match (config.nat.nat64, config.nat.nat64_pool) {
+        (Some(prefix), _) if prefix.prefix != 96 =>
+            return Err(format!("nat64 prefix {} isn't a /96", prefix)),
+        (None, Some(_)) => return Err("nat64 pool without a nat64 prefix".to_string()),
+        _ => ()
+    }
+    for rule in config.firewall.ingress.iter().chain(&config.firewall.egress) {
+        if let Some(name) = rule.iface() {
+            if !ifaces.iter().any(|iface| iface.name == name) {
+                return Err(format!("firewall rule for unknown interface {}: {}", name, rule))
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse_addr<A: FromStr>(addr: &str) -> Result<A, String> {
+    addr.parse::<A>().map_err(|_| format!("bad address: {}", addr))
+}
+
+fn next_arg<'a>(args: &'a [String], idx: &mut usize) -> Result<&'a str, String> {
+    let opt = &args[*idx];
+    *idx += 1;
+    args.get(*idx)
+        .map(|arg| &arg[..])
+        .ok_or(format!("{} needs an argument", opt))
+}
+
+fn parse_mode(name: &str, replay: Option<&str>) -> Result<Mode, String> {
+    match name {
+        "capture" => Ok(Mode::Capture),
+        "reflect" => Ok(Mode::Reflect),
+        "serve"   => Ok(Mode::Serve),
+        "forward" => Ok(Mode::Forward),
+        "replay"  => match replay {
+            Some(file) => Ok(Mode::Replay(file.to_string())),
+            None       => Err("replay mode needs a pcap file".to_string())
+        },
+        _         => Err(format!("unknown mode: {}", name))
+    }
+}
+
+fn parse_dev_type(name: &str) -> Result<DevType, String> {
+    match name {
+        "tap" => Ok(DevType::Tap),
+        "tun" => Ok(DevType::Tun),
+        _     => Err(format!("unknown device type: {}", name))
+    }
+}
+
+fn parse_mtu(mtu: &str) -> Result<usize, String> {
+    let mtu = mtu.parse::<usize>().map_err(|_| format!("bad mtu: {}", mtu))?;
+    check_mtu(mtu)
+}
+
+fn check_mtu(mtu: usize) -> Result<usize, String> {
+    if !(pkt::MIN_MTU..=pkt::MAX_MTU).contains(&mtu) {
+        return Err(format!("mtu {} out of range {}-{}", mtu, pkt::MIN_MTU, pkt::MAX_MTU))
+    }
+    Ok(mtu)
+}
+
+// config file
+pub fn load_file(path: &str, config: &mut Config) -> Result<(), String> {
+    let mut contents = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .map_err(|e| format!("can't read {}: {}", path, e))?;
+    apply_toml(&contents, config).map_err(|e| format!("{}: {}", path, e))
+}
+
+pub fn apply_toml(contents: &str, config: &mut Config) -> Result<(), String> {
+    let mut parser = toml::Parser::new(contents);
+    let table = match parser.parse() {
+        Some(table) => toml::Value::Table(table),
+        None => {
+            let descs: Vec<String> = parser.errors.iter().map(|err| {
+                let (line, col) = parser.to_linecol(err.lo);
+                format!("{}:{}: {}", line + 1, col + 1, err.desc)
+            }).collect();
+            return Err(descs.join(", "))
+        }
+    };
+
+    if let Some(mode) = get_str(&table, "mode")? {
+        let replay = get_str(&table, "replay")?;
+        config.mode = parse_mode(mode, replay)?;
+    }
+    if let Some(verbosity) = get_int(&table, "verbosity")? {
+        if verbosity > u8::MAX as i64 {
+            return Err("verbosity should be at most 255".to_string())
+        }
+        config.verbosity = verbosity as u8;
+    }
+
+    if let Some(filter) = get_str(&table, "filter")? {
+        config.filter = Some(filter.parse()?);
+    }
+
+    if let Some(user) = get_str(&table, "privileges.user")? {
+        config.user = Some(user.to_string());
+    }
+    if let Some(group) = get_str(&table, "privileges.group")? {
+        config.group = Some(group.to_string());
+    }
+
+    if let Some(iface) = table.lookup("interface") {
+        apply_iface(iface, "interface", &mut config.iface)?;
+    }
+    if let Some(ifaces) = get_array(&table, "interfaces")? {
+        for (idx, iface) in ifaces.iter().enumerate() {
+            let mut extra = IfaceConfig { ipv4: None, ..IfaceConfig::default() };
+            apply_iface(iface, &format!("interfaces[{}]", idx), &mut extra)?;
+            config.extra_ifaces.push(extra);
+        }
+    }
+    if let Some(iface) = get_str(&table, "nat.masquerade")? {
+        config.nat.masquerade = Some(iface.to_string());
+    }
+    if let Some(rules) = get_array(&table, "nat.dnat")? {
+        for rule in rules {
+            match rule.as_str() {
+                Some(rule) => config.nat.dnat.push(rule.parse()?),
+                None => return Err("nat.dnat should be strings".to_string())
+            }
+        }
+    }
+    if let Some(prefix) = get_str(&table, "nat.nat64")? {
+        config.nat.nat64 = Some(prefix.parse()?);
+    }
+    if let Some(pool) = get_str(&table, "nat.nat64_pool")? {
+        config.nat.nat64_pool = Some(parse_addr(pool)?);
+    }
+    for (key, chain) in [("firewall.ingress", &mut config.firewall.ingress),
+                         ("firewall.egress", &mut config.firewall.egress)] {
+        if let Some(rules) = get_array(&table, key)? {
+            for rule in rules {
+                match rule.as_str() {
+                    Some(rule) => chain.push(rule.parse()?),
+                    None => return Err(format!("{} should be strings", key))
+                }
+            }
+        }
+    }
+    for (key, rules) in [("netem.ingress", &mut config.netem.ingress),
+                         ("netem.egress", &mut config.netem.egress)] {
+        if let Some(array) = get_array(&table, key)? {
+            for rule in array {
+                match rule.as_str() {
+                    Some(rule) => rules.push(rule.parse()?),
+                    None => return Err(format!("{} should be strings", key))
+                }
+            }
+        }
+    }
+    if let Some(seed) = get_int(&table, "netem.seed")? {
+        config.netem.seed = Some(seed as u64);
+    }
+    if let Some(rules) = get_array(&table, "rewrite")? {
+        for rule in rules {
+            match rule.as_str() {
+                Some(rule) => config.rewrite.push(rule.parse()?),
+                None => return Err("rewrite should be strings".to_string())
+            }
+        }
+    }
+    if let Some(routes) = get_array(&table, "routes")? {
+        for route in routes {
+            match route.as_str() {
+                Some(route) => config.routes.push(route.parse()?),
+                None => return Err("routes should be strings".to_string())
+            }
+        }
+    }
+    check_ifaces(config)
+}
+
+// `section` is only for the error messages
+fn apply_iface(table: &toml::Value, section: &str, iface: &mut IfaceConfig) -> Result<(), String> {
+    let key = |name: &str| format!("{}.{}", section, name);
+    if let Some(name) = get_str(table, "name")? {
+        iface.name = name.to_string();
+    }
+    if let Some(dev_type) = get_str(table, "type")? {
+        iface.dev_type = parse_dev_type(dev_type)?;
+    }
+    if let Some(addr) = get_str(table, "ipv4")? {
+        iface.ipv4 = Some(addr.parse()?);
+    }
+    if let Some(addr) = get_str(table, "ipv6")? {
+        iface.ipv6 = Some(addr.parse()?);
+    }
+    if let Some(addr) = get_str(table, "peer_ipv4")? {
+        iface.peer_ipv4 = Some(parse_addr(addr)?);
+    }
+    if let Some(addr) = get_str(table, "peer_ipv6")? {
+        iface.peer_ipv6 = Some(parse_addr(addr)?);
+    }
+    if let Some(mtu) = get_int(table, "mtu")? {
+        iface.mtu = check_mtu(mtu.max(0) as usize).map_err(|e| format!("{}: {}", key("mtu"), e))?;
+    }
+    Ok(())
+}
+
+fn get_str<'a>(table: &'a toml::Value, key: &str) -> Result<Option<&'a str>, String> {
+    match table.lookup(key) {
+        None => Ok(None),
+        Some(val) => val.as_str()
+            .map(Some)
+            .ok_or(format!("{} should be a string", key))
+    }
+}
+
+fn get_array<'a>(table: &'a toml::Value, key: &str) -> Result<Option<&'a [toml::Value]>, String> {
+    match table.lookup(key) {
+        None => Ok(None),
+        Some(val) => val.as_slice()
+            .map(Some)
+            .ok_or(format!("{} should be an array", key))
+    }
+}
+
+fn get_int(table: &toml::Value, key: &str) -> Result<Option<i64>, String> {
+    match table.lookup(key) {
+        None => Ok(None),
+        Some(val) => match val.as_integer() {
+            Some(int) if int >= 0 => Ok(Some(int)),
+            _ => Err(format!("{} should be a positive integer", key))
+        }
+    }
+}
+
+
+// testing
+#[cfg(test)]
+fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|s| s.to_string()).collect()
+}
+
+#[test]
+fn test_config_file_and_args() {
+    let mut config = Config::default();
+    apply_toml(r#"
+        mode = "capture"
+
+        [interface]
+        name = "tun3"
+        type = "tun"
+        ipv6 = "fd00::1/48"
+        mtu = 9000
+    "#, &mut config).unwrap();
+
+    assert_eq!(config.mode, Mode::Capture);
+    assert_eq!(config.iface.name, "tun3");
+    assert_eq!(config.iface.dev_type, DevType::Tun);
+    assert_eq!(config.iface.ipv6, Some("fd00::1/48".parse().unwrap()));
+    assert_eq!(config.iface.mtu, 9000);
+
+    let config = from_args(&args(&["-i", "tap1", "-4", "192.168.1.1/16", "-vv", "serve"]));
+    assert_eq!(config.unwrap().unwrap().verbosity, 3);
+    assert_eq!(from_args(&args(&["-vx"])),
+               Err("unknown option: -vx".to_string()));
+
+    let config = from_args(&args(&["-i", "tap1", "-4", "192.168.1.1/16", "-v",
+                                   "replay", "dump.pcap"])).unwrap().unwrap();
+    assert_eq!(config.mode, Mode::Replay("dump.pcap".to_string()));
+    assert_eq!(config.iface.name, "tap1");
+    assert_eq!(config.iface.ipv4.unwrap().netmask(), Ipv4Addr::new(255, 255, 0, 0));
+    assert_eq!(config.verbosity, 2);
+
+    assert_eq!(from_args(&args(&["-m", "70000"])),
+               Err("mtu 70000 out of range 68-65535".to_string()));
+    assert_eq!(from_args(&args(&["-6", "fd00::1", "-m", "576"])),
+               Err("ipv6 needs an mtu of at least 1280".to_string()));
+
+    // later interfaces take the options that follow them
+    let config = from_args(&args(&["-i", "tap0", "--peer4", "10.0.0.2", "-i", "tap1", "--tun",
+                                   "-4", "10.0.1.1/24", "-r", "default via 10.0.0.1",
+                                   "-r", "fd00:1::/64 dev tap1", "forward"])).unwrap().unwrap();
+    assert_eq!(config.mode, Mode::Forward);
+    assert_eq!(config.iface.peer_ipv4, Some(Ipv4Addr::new(10, 0, 0, 2)));
+    assert_eq!(config.extra_ifaces[0].dev_type, DevType::Tun);
+    assert_eq!(config.ifaces()[1].ipv4, Some("10.0.1.1/24".parse().unwrap()));
+    assert_eq!(config.routes[0].dst, IpCidr::V4(Ipv4Cidr { addr: Ipv4Addr::new(0, 0, 0, 0), prefix: 0 }));
+    assert_eq!(config.routes[1].dev, Some("tap1".to_string()));
+
+    assert_eq!("default".parse::<RouteConfig>(),
+               Err("default route needs a gateway: default".to_string()));
+    assert_eq!("10.1.0.0/16 via fd00::1".parse::<RouteConfig>(),
+               Err("gateway and destination disagree on ip version: 10.1.0.0/16 via fd00::1".to_string()));
+    let mut config = Config::default();
+    assert_eq!(apply_toml(r#"routes = ["10.1.0.0/16 dev tap9"]"#, &mut config),
+               Err("route through unknown interface tap9".to_string()));
+}
+
+#[test]
+fn test_masquerading_needs_our_address() {
+    assert_eq!(from_args(&args(&["-i", "tap0", "-i", "tap1", "--masquerade", "tap1", "forward"])),
+               Err("masquerading needs our ipv4 address on tap1".to_string()));
+}
+
+#[test]
+fn test_dnat_rules_from_the_config_file() {
+    let mut config = Config::default();
+    apply_toml(r#"
+        [nat]
+        dnat = ["udp 192.0.2.1:53 to 10.0.0.6", "192.0.2.10 to 10.0.0.7"]
+    "#, &mut config).unwrap();
+    assert_eq!(config.nat.dnat[0].port, Some(53));
+    assert_eq!(config.nat.dnat[1].proto, None);
+}
+
+#[test]
+fn test_nat64_prefix_is_a_96() {
+    assert_eq!(from_args(&args(&["--nat64", "64:ff9b::/64"])),
+               Err("nat64 prefix 64:ff9b::/64 isn't a /96".to_string()));
+}
+
+#[test]
+fn test_firewall_rules_from_the_config_file() {
+    let mut config = Config::default();
+    apply_toml(r#"
+        [firewall]
+        ingress = ["accept ct established,related", "drop tcp flags syn,!ack"]
+        egress = ["reject dst 192.0.2.0/24 udp dport 1000-2000"]
+    "#, &mut config).unwrap();
+    assert_eq!(config.firewall.ingress.len(), 2);
+    assert_eq!(config.firewall.egress[0].to_string(), "reject dst 192.0.2.0/24 udp dport 1000-2000");
+}
+
+#[test]
+fn test_firewall_rules_name_known_interfaces() {
+    assert_eq!(from_args(&args(&["--ingress", "drop iface tap7"])),
+               Err("firewall rule for unknown interface tap7: drop iface tap7".to_string()));
+}
+
+#[test]
+fn test_filter_from_args_and_file() {
+    assert_eq!(from_args(&args(&["-f", "tcp and (port 80", "capture"])),
+               Err("unclosed ( in filter: tcp and (port 80".to_string()));
+    let mut config = Config::default();
+    apply_toml(r#"filter = "vlan 100 and udp""#, &mut config).unwrap();
+    assert_eq!(config.filter.unwrap().to_string(), "vlan 100 and udp");
+}
+
+#[test]
+fn test_rewrite_rules_from_args_and_file() {
+    let mut config = Config::default();
+    apply_toml(r#"rewrite = ["udp and dst port 7 -> swap udp.src_port udp.dst_port;dec ipv4.ttl 2"]"#,
+               &mut config).unwrap();
+    assert_eq!(config.rewrite[0].to_string(),
+               "udp and dst port 7 -> swap udp.src_port udp.dst_port; dec ipv4.ttl 2");
+    assert_eq!(from_args(&args(&["--rewrite", "set ipv4.src 10.0.0.300"])),
+               Err("bad value in rewrite action: set ipv4.src 10.0.0.300".to_string()));
+}
+
+#[test]
+fn test_impairments_from_args_and_file() {
+    let mut config = Config::default();
+    apply_toml(r#"
+        [netem]
+        ingress = ["udp -> delay 100ms 20ms normal"]
+        egress = ["loss gemodel 1% 30%", "rate 10mbit"]
+        seed = 42
+    "#, &mut config).unwrap();
+    assert_eq!(config.netem.ingress[0].to_string(), "udp -> delay 100ms 20ms normal");
+    assert_eq!(config.netem.egress.len(), 2);
+    assert_eq!(config.netem.seed(), 42);
+    assert_eq!(from_args(&args(&["--netem-out", "duplicate 5", "forward"])),
+               Err("bad duplicate in impairment: duplicate 5".to_string()));
+}
+
+#[test]
+fn test_impairments_only_when_forwarding() {
+    let err = Err("impairments only apply when forwarding".to_string());
+    assert_eq!(from_args(&args(&["--netem-in", "delay 5ms", "reflect"])), err);
+    assert_eq!(from_args(&args(&["--seed", "1", "capture"])), err);
+    assert!(from_args(&args(&["--netem-out", "loss 1%", "forward"])).is_ok());
+}
+
+#[test]
+fn test_nat64_pool_apart_from_masquerading() {
+    let toml = |pool: &str| format!(r#"
+        mode = "forward"
+        [[interfaces]]
+        name = "tap1"
+        ipv4 = "10.0.1.1/24"
+        peer_ipv4 = "10.0.1.2"
+        [nat]
+        masquerade = "tap1"
+        nat64 = "64:ff9b::/96"
+        nat64_pool = "{}"
+    "#, pool);
+    let mut config = Config::default();
+    assert_eq!(apply_toml(&toml("10.0.1.2"), &mut config),
+               Err("nat64 pool 10.0.1.2 is the masquerading address on tap1".to_string()));
+    let mut config = Config::default();
+    apply_toml(&toml("192.0.2.64"), &mut config).unwrap();
+    assert_eq!(config.nat.nat64_pool, Some(Ipv4Addr::new(192, 0, 2, 64)));
+}
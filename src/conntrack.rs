@@ -0,0 +1,423 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use nat::{Proto, Timeouts};
+use packet::builder::{tcp_flags, ICMP_LEN, PROTO_ICMPV4, PROTO_ICMPV6, PROTO_TCP, PROTO_UDP};
+use packet::icmpv4;
+use packet::icmpv6;
+use packet::ipv4::Ipv4;
+use packet::ipv6::{header_types, Ipv6};
+use packet::pkt::{self, HasNetworkLayer};
+
+// connection tracking
+//
+// Follows the flows going through the router by their 5-tuple: protocol,
+// and address and port at each end, the echo identifier standing in for
+// the ports of ICMP. A flow is known by the tuple of its first packet, and
+// packets with the reverse tuple are its replies; packets and bytes are
+// counted each way.
+//
+// UDP and ICMP echo are established once a reply comes back. TCP is once
+// the side that opened acks after hearing back, and closing from the first
+// FIN or RST on. Idle flows go after the nat timeouts for their state.
+// ICMP errors aren't flows of their own, they're related to the flow of
+// the datagram they quote.
+//
+// The router takes tuples after destination nat and before source nat,
+// so a flow has the same tuple both ways through a translator.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Tuple {
+    pub proto: Proto,
+    pub src: SocketAddr,
+    pub dst: SocketAddr
+}
+
+impl Tuple {
+    pub fn reverse(&self) -> Tuple {
+        Tuple { proto: self.proto, src: self.dst, dst: self.src }
+    }
+}
+
+impl fmt::Display for Tuple {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} -> {}", self.proto, self.src, self.dst)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    New,
+    Established,
+    Closing
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            State::New         => "new",
+            State::Established => "established",
+            State::Closing     => "closing"
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Flow {
+    // as its first packet had it
+    pub tuple: Tuple,
+    pub state: State,
+    // original direction first, then replies
+    pub packets: [u64; 2],
+    pub bytes: [u64; 2],
+    pub started: Instant,
+    pub last_seen: Instant
+}
+
+impl Flow {
+    fn new(tuple: Tuple, now: Instant) -> Flow {
+        Flow {
+            tuple: tuple,
+            state: State::New,
+            packets: [0, 0],
+            bytes: [0, 0],
+            started: now,
+            last_seen: now
+        }
+    }
+
+    pub fn timeout(&self, timeouts: &Timeouts) -> Duration {
+        match (self.tuple.proto, self.state) {
+            (Proto::Tcp, State::Established) => timeouts.tcp_established,
+            (Proto::Tcp, _)                  => timeouts.tcp_transitory,
+            (Proto::Udp, _)                  => timeouts.udp,
+            (Proto::Icmp, _)                 => timeouts.icmp
+        }
+    }
+
+    // `flags` are tcp's
+    fn saw(&mut self, reply: bool, flags: u8, len: usize, now: Instant) {
+        let dir = reply as usize;
+        self.packets[dir] += 1;
+        self.bytes[dir] += len as u64;
+        self.last_seen = now;
+        match self.tuple.proto {
+            Proto::Tcp if flags & (tcp_flags::FIN | tcp_flags::RST) != 0 =>
+                self.state = State::Closing,
+            Proto::Tcp => {
+                let acked = flags & tcp_flags::ACK != 0 && flags & tcp_flags::SYN == 0;
+                if self.state == State::New && !reply && acked && self.packets[1] > 0 {
+                    self.state = State::Established;
+                }
+            },
+            _ => if self.state == State::New && reply {
+                self.state = State::Established;
+            }
+        }
+    }
+}
+
+impl fmt::Display for Flow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}, {}/{} packets, {}/{} bytes", self.tuple, self.state,
+               self.packets[0], self.packets[1], self.bytes[0], self.bytes[1])
+    }
+}
+
+// what a packet is to the table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tracked {
+    // part of the flow with this tuple, one way or the other, and the
+    // state that flow is in now
+    Flow { tuple: Tuple, reply: bool, state: State },
+    // an icmp error about the flow with this tuple
+    Related(Tuple),
+    // other protocols, fragments past the first, icmp other than echo,
+    // replies nobody asked for and errors about flows we don't know
+    Untracked
+}
+
+pub struct Conntrack {
+    timeouts: Timeouts,
+    flows: HashMap<Tuple, Flow>,
+    // reply tuples to the flow's own
+    replies: HashMap<Tuple, Tuple>
+}
+
+impl Conntrack {
+    pub fn new(timeouts: Timeouts) -> Conntrack {
+        Conntrack { timeouts: timeouts, flows: HashMap::new(), replies: HashMap::new() }
+    }
+
+    // Counts `packet`, an ip datagram, against its flow, starting one if
+    // it's the first.
+    pub fn track(&mut self, packet: &[u8], now: Instant) -> Result<Tracked, pkt::DissectError> {
+        let (tuple, flags, opens) = match parse(packet)? {
+            Parsed::Flow { tuple, flags, opens } => (tuple, flags, opens),
+            Parsed::Error { quote } => return Ok(match parse(&packet[quote..]) {
+                Ok(Parsed::Flow { tuple, .. }) => match self.find(&tuple, now) {
+                    Some((tuple, _)) => Tracked::Related(tuple),
+                    None => Tracked::Untracked
+                },
+                _ => Tracked::Untracked
+            }),
+            Parsed::Other => return Ok(Tracked::Untracked)
+        };
+        let (key, reply) = match self.find(&tuple, now) {
+            Some(found) => found,
+            None if opens => {
+                self.flows.insert(tuple, Flow::new(tuple, now));
+                self.replies.insert(tuple.reverse(), tuple);
+                (tuple, false)
+            },
+            None => return Ok(Tracked::Untracked)
+        };
+        let flow = self.flows.get_mut(&key).expect("flow went missing");
+        flow.saw(reply, flags, packet.len(), now);
+        Ok(Tracked::Flow { tuple: key, reply: reply, state: flow.state })
+    }
+
+    // the flow `tuple` goes either way of
+    pub fn get(&self, tuple: &Tuple) -> Option<&Flow> {
+        match self.flows.get(tuple) {
+            Some(flow) => Some(flow),
+            None => self.replies.get(tuple).and_then(|key| self.flows.get(key))
+        }
+    }
+
+    // The flow's tuple and whether `tuple` is its reply, forgetting a flow
+    // that timed out and hasn't been expired yet: its tuple may start a new
+    // one.
+    fn find(&mut self, tuple: &Tuple, now: Instant) -> Option<(Tuple, bool)> {
+        let (key, reply) = self.key(tuple)?;
+        if !self.live(&key, now) {
+            self.flows.remove(&key);
+            self.replies.remove(&key.reverse());
+            return None
+        }
+        Some((key, reply))
+    }
+
+    fn key(&self, tuple: &Tuple) -> Option<(Tuple, bool)> {
+        match self.flows.contains_key(tuple) {
+            true  => Some((*tuple, false)),
+            false => self.replies.get(tuple).map(|key| (*key, true))
+        }
+    }
+
+    fn live(&self, key: &Tuple, now: Instant) -> bool {
+        let flow = &self.flows[key];
+        flow.last_seen + flow.timeout(&self.timeouts) > now
+    }
+
+    // What `track` would make of `packet`, leaving the table be.
+    pub fn peek(&self, packet: &[u8], now: Instant) -> Result<Tracked, pkt::DissectError> {
+        let find = |tuple: &Tuple| self.key(tuple).filter(|&(key, _)| self.live(&key, now));
+        Ok(match parse(packet)? {
+            Parsed::Flow { tuple, flags, opens } => match find(&tuple) {
+                Some((key, reply)) => {
+                    let mut flow = self.flows[&key].clone();
+                    flow.saw(reply, flags, packet.len(), now);
+                    Tracked::Flow { tuple: key, reply: reply, state: flow.state }
+                },
+                None if opens => Tracked::Flow { tuple: tuple, reply: false, state: State::New },
+                None => Tracked::Untracked
+            },
+            Parsed::Error { quote } => match parse(&packet[quote..]) {
+                Ok(Parsed::Flow { tuple, .. }) => match find(&tuple) {
+                    Some((tuple, _)) => Tracked::Related(tuple),
+                    None => Tracked::Untracked
+                },
+                _ => Tracked::Untracked
+            },
+            Parsed::Other => Tracked::Untracked
+        })
+    }
+
+    // drops idle flows, handing them back
+    pub fn expire(&mut self, now: Instant) -> Vec<Flow> {
+        let timeouts = self.timeouts;
+        let mut gone = Vec::new();
+        let replies = &mut self.replies;
+        self.flows.retain(|_, flow| {
+            let alive = flow.last_seen + flow.timeout(&timeouts) > now;
+            if !alive {
+                replies.remove(&flow.tuple.reverse());
+                gone.push(flow.clone());
+            }
+            alive
+        });
+        gone
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Flow> {
+        self.flows.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.flows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flows.is_empty()
+    }
+}
+
+enum Parsed {
+    // `opens` if it can start a flow, anything but an echo reply
+    Flow { tuple: Tuple, flags: u8, opens: bool },
+    // an icmp error, quoting a datagram from this offset on
+    Error { quote: usize },
+    Other
+}
+
+fn parse(packet: &[u8]) -> Result<Parsed, pkt::DissectError> {
+    pkt::check_len("ip", packet, 1)?;
+    let (src, dst, protocol, start) = match packet[0] >> 4 {
+        4 => {
+            let ip = Ipv4 { offset: 0 };
+            let hlen = ip.header_len(packet)?;
+            if ip.get_frag_offs(packet) != 0 {
+                return Ok(Parsed::Other)
+            }
+            (IpAddr::V4(Ipv4Addr::from(ip.get_src(packet))),
+             IpAddr::V4(Ipv4Addr::from(ip.get_dst(packet))), ip.get_protocol(packet), hlen)
+        },
+        6 => {
+            pkt::check_len("ipv6", packet, Ipv6::HEADER_LEN)?;
+            let ip = Ipv6 { offset: 0 };
+            let mut headers = ip.ext_headers(packet);
+            for header in headers.by_ref() {
+                header?;
+            }
+            // behind a fragment header past the first fragment
+            if headers.protocol() == header_types::NO_NEXT {
+                return Ok(Parsed::Other)
+            }
+            (IpAddr::V6(Ipv6Addr::from(ip.get_src(packet))),
+             IpAddr::V6(Ipv6Addr::from(ip.get_dst(packet))), headers.protocol(), headers.end())
+        },
+        _ => return Ok(Parsed::Other)
+    };
+
+    let trans = &packet[start..];
+    let word = |at: usize| (trans[at] as u16) << 8 | trans[at + 1] as u16;
+    let (proto, src_port, dst_port, opens) = match protocol {
+        PROTO_TCP | PROTO_UDP => {
+            pkt::check_len("transport", trans, 4)?;
+            let proto = if protocol == PROTO_TCP { Proto::Tcp } else { Proto::Udp };
+            (proto, word(0), word(2), true)
+        },
+        PROTO_ICMPV4 | PROTO_ICMPV6 => {
+            pkt::check_len("icmp", trans, ICMP_LEN)?;
+            let (request, reply) = if protocol == PROTO_ICMPV4 {
+                (icmpv4::ECHO_REQUEST, icmpv4::ECHO_REPLY)
+            } else {
+                (icmpv6::ECHO_REQUEST, icmpv6::ECHO_REPLY)
+            };
+            let error = match protocol {
+                PROTO_ICMPV4 => trans[0] == icmpv4::DEST_UNREACH || trans[0] == icmpv4::TIME_EXCEEDED
+                    || trans[0] == icmpv4::PARAM_PROBLEM,
+                // the informational messages start at 128
+                _ => trans[0] < 128
+            };
+            match trans[0] {
+                kind if kind == request || kind == reply =>
+                    (Proto::Icmp, word(4), word(4), kind == request),
+                _ if error => return Ok(Parsed::Error { quote: start + ICMP_LEN }),
+                _ => return Ok(Parsed::Other)
+            }
+        },
+        _ => return Ok(Parsed::Other)
+    };
+    Ok(Parsed::Flow {
+        tuple: Tuple {
+            proto: proto,
+            src: SocketAddr::new(src, src_port),
+            dst: SocketAddr::new(dst, dst_port)
+        },
+        flags: if proto == Proto::Tcp { trans.get(13).cloned().unwrap_or(0) } else { 0 },
+        opens: opens
+    })
+}
+
+
+// testing
+#[cfg(test)]
+use packet::builder::{Ipv4Builder, Ipv6Builder, IPV4_LEN, TCP_LEN};
+#[cfg(test)]
+use packet::builder::tcp_flags::{ACK, FIN, SYN};
+
+#[cfg(test)]
+use util::{A, B};
+
+#[cfg(test)]
+fn tcp(src: Ipv4Addr, dst: Ipv4Addr, sport: u16, dport: u16, flags: u8) -> Vec<u8> {
+    Ipv4Builder::new(src, dst).tcp(sport, dport).flags(flags).build(b"").data
+}
+
+// a handshake from A port 40000 to B port 80, and the flow it made
+#[cfg(test)]
+fn handshake(ct: &mut Conntrack, now: Instant) -> Tuple {
+    let tuple = match ct.track(&tcp(A, B, 40000, 80, SYN), now).unwrap() {
+        Tracked::Flow { tuple, reply: false, state: State::New } => tuple,
+        other => panic!("{:?}", other)
+    };
+    assert_eq!(ct.track(&tcp(B, A, 80, 40000, SYN | ACK), now),
+               Ok(Tracked::Flow { tuple: tuple, reply: true, state: State::New }));
+    assert_eq!(ct.track(&tcp(A, B, 40000, 80, ACK), now),
+               Ok(Tracked::Flow { tuple: tuple, reply: false, state: State::Established }));
+    tuple
+}
+
+#[test]
+fn test_tcp_handshake_establishes() {
+    let now = Instant::now();
+    let mut ct = Conntrack::new(Timeouts::default());
+    let tuple = handshake(&mut ct, now);
+    assert_eq!(tuple.to_string(), "tcp 10.0.0.2:40000 -> 192.0.2.7:80");
+    let flow = ct.get(&tuple.reverse()).unwrap();
+    assert_eq!(flow.packets, [2, 1]);
+    assert_eq!(flow.bytes, [2 * (IPV4_LEN + TCP_LEN) as u64, (IPV4_LEN + TCP_LEN) as u64]);
+}
+
+#[test]
+fn test_errors_are_related_to_what_they_quote() {
+    let now = Instant::now();
+    let mut ct = Conntrack::new(Timeouts::default());
+    let tuple = handshake(&mut ct, now);
+    let syn = tcp(A, B, 40000, 80, SYN);
+    let error = Ipv4Builder::new(B, A).icmp(icmpv4::DEST_UNREACH, 1)
+        .build(&syn[..IPV4_LEN + 8]).data;
+    assert_eq!(ct.track(&error, now), Ok(Tracked::Related(tuple)));
+}
+
+#[test]
+fn test_closing_flows_expire_sooner() {
+    let now = Instant::now();
+    let mut ct = Conntrack::new(Timeouts::default());
+    let tuple = handshake(&mut ct, now);
+    assert_eq!(ct.track(&tcp(B, A, 80, 40000, FIN | ACK), now),
+               Ok(Tracked::Flow { tuple: tuple, reply: true, state: State::Closing }));
+    assert!(ct.expire(now + Duration::from_secs(3 * 60)).is_empty());
+    assert_eq!(ct.expire(now + Duration::from_secs(4 * 60)).len(), 1);
+    assert!(ct.is_empty());
+}
+
+#[test]
+fn test_echo_replies_alone_start_nothing() {
+    let now = Instant::now();
+    let mut ct = Conntrack::new(Timeouts::default());
+    let c = "fd00::2".parse::<Ipv6Addr>().unwrap();
+    let d = "fd01::2".parse::<Ipv6Addr>().unwrap();
+    let echo = |src, dst, kind| Ipv6Builder::new(src, dst).icmp(kind, 0).ident(9).build(b"").data;
+    assert_eq!(ct.track(&echo(d, c, icmpv6::ECHO_REPLY), now), Ok(Tracked::Untracked));
+    ct.track(&echo(c, d, icmpv6::ECHO_REQUEST), now).unwrap();
+    match ct.track(&echo(d, c, icmpv6::ECHO_REPLY), now).unwrap() {
+        Tracked::Flow { reply: true, state: State::Established, .. } => (),
+        other => panic!("{:?}", other)
+    }
+    assert_eq!(ct.len(), 1);
+}
@@ -0,0 +1,692 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use conntrack::{State, Tracked};
+use icmp::{self, IcmpError};
+use lpm::{self, RouteAddr};
+use neigh::Mac;
+use packet::builder::{tcp_flags, Ipv4Builder, Ipv6Builder, ETHERTYPE_IPV4, ETHERTYPE_IPV6,
+                      IPV6_LEN, PROTO_ICMPV4, PROTO_ICMPV6, PROTO_TCP, PROTO_UDP};
+use packet::eth::ETHERTYPE_VLAN;
+use packet::filter::{parse_mac, parse_num};
+use packet::ipv4::Ipv4;
+use packet::ipv6::{header_types, Ipv6};
+use packet::pkt::{self, HasNetworkLayer};
+use packet::tcp::Tcp;
+
+// packet filter
+//
+// Two chains of rules, one for every frame that comes in and one for
+// every packet routed out. A chain goes through its rules in order and the
+// first accept, drop or reject that matches decides; log and count rules
+// note the packet and go on. What no rule decides is accepted.
+//
+// A rule is an action and what to match, all of which has to, in words:
+//
+//   drop iface tap1 tcp dport 23
+//   reject src 10.0.0.0/24 udp dport 1000-2000
+//   accept ct established,related
+//   log tcp flags syn,!ack
+//   count ether src 02:00:00:00:00:07 vlan 5
+//
+// Ethernet and vlan matches only see frames coming in on tap links, the
+// outgoing ones get their link header after the filter. Every rule counts
+// the packets and bytes it matched.
+
+const ETHERTYPE_ARP: u16 = 0x0806;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Accept,
+    Drop,
+    // drop, and tell the sender with a tcp reset or an icmp error
+    Reject,
+    // print the packet and go on
+    Log,
+    // just go on, the rule's counter is all it's for
+    Count
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Action::Accept => "accept",
+            Action::Drop   => "drop",
+            Action::Reject => "reject",
+            Action::Log    => "log",
+            Action::Count  => "count"
+        })
+    }
+}
+
+// conntrack states, as bits of a set
+pub mod ct_states {
+    pub const NEW:         u8 = 0x01;
+    pub const ESTABLISHED: u8 = 0x02;
+    pub const CLOSING:     u8 = 0x04;
+    pub const RELATED:     u8 = 0x08;
+    pub const UNTRACKED:   u8 = 0x10;
+}
+
+const CT_NAMES: [(&str, u8); 5] = [
+    ("new", ct_states::NEW),
+    ("established", ct_states::ESTABLISHED),
+    ("closing", ct_states::CLOSING),
+    ("related", ct_states::RELATED),
+    ("untracked", ct_states::UNTRACKED)
+];
+
+const FLAG_NAMES: [(&str, u8); 6] = [
+    ("fin", tcp_flags::FIN),
+    ("syn", tcp_flags::SYN),
+    ("rst", tcp_flags::RST),
+    ("psh", tcp_flags::PSH),
+    ("ack", tcp_flags::ACK),
+    ("urg", tcp_flags::URG)
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Match {
+    Iface(String),
+    EtherSrc(Mac),
+    EtherDst(Mac),
+    Ethertype(u16),
+    Vlan(u16),
+    Src(IpAddr, u8),
+    Dst(IpAddr, u8),
+    Protocol(u8),
+    SrcPort(u16, u16),
+    DstPort(u16, u16),
+    // tcp flags that have to be set, and ones that have to be clear
+    Flags { set: u8, clear: u8 },
+    IcmpType(u8),
+    Ct(u8)
+}
+
+impl fmt::Display for Match {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mac = |mac: &Mac| mac.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(":");
+        let range = |f: &mut fmt::Formatter, lo: u16, hi: u16| match lo == hi {
+            true  => write!(f, "{}", lo),
+            false => write!(f, "{}-{}", lo, hi)
+        };
+        match *self {
+            Match::Iface(ref name)   => write!(f, "iface {}", name),
+            Match::EtherSrc(ref m)   => write!(f, "ether src {}", mac(m)),
+            Match::EtherDst(ref m)   => write!(f, "ether dst {}", mac(m)),
+            Match::Ethertype(kind)   => write!(f, "ethertype 0x{:04x}", kind),
+            Match::Vlan(id)          => write!(f, "vlan {}", id),
+            Match::Src(addr, len)    => write!(f, "src {}/{}", addr, len),
+            Match::Dst(addr, len)    => write!(f, "dst {}/{}", addr, len),
+            Match::Protocol(PROTO_TCP)    => f.write_str("tcp"),
+            Match::Protocol(PROTO_UDP)    => f.write_str("udp"),
+            Match::Protocol(PROTO_ICMPV4) => f.write_str("icmp"),
+            Match::Protocol(PROTO_ICMPV6) => f.write_str("icmp6"),
+            Match::Protocol(proto)   => write!(f, "proto {}", proto),
+            Match::SrcPort(lo, hi)   => { f.write_str("sport ")?; range(f, lo, hi) },
+            Match::DstPort(lo, hi)   => { f.write_str("dport ")?; range(f, lo, hi) },
+            Match::Flags { set, clear } => {
+                let names: Vec<String> = FLAG_NAMES.iter()
+                    .filter(|&&(_, bit)| (set | clear) & bit != 0)
+                    .map(|&(name, bit)| if clear & bit != 0 { format!("!{}", name) } else { name.to_string() })
+                    .collect();
+                write!(f, "flags {}", names.join(","))
+            },
+            Match::IcmpType(kind)    => write!(f, "type {}", kind),
+            Match::Ct(states) => {
+                let names: Vec<&str> = CT_NAMES.iter()
+                    .filter(|&&(_, bit)| states & bit != 0)
+                    .map(|&(name, _)| name)
+                    .collect();
+                write!(f, "ct {}", names.join(","))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub action: Action,
+    pub matches: Vec<Match>
+}
+
+impl Rule {
+    // the interface the rule is for, if it's for one
+    pub fn iface(&self) -> Option<&str> {
+        self.matches.iter().filter_map(|m| match *m {
+            Match::Iface(ref name) => Some(&name[..]),
+            _ => None
+        }).next()
+    }
+
+    fn matches(&self, fields: &Fields, iface: &str, ct: Tracked) -> bool {
+        self.matches.iter().all(|m| fields.matches(m, iface, ct))
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Rule, String> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        let (action, mut rest) = match words.split_first() {
+            Some((action, rest)) => (*action, rest),
+            None => return Err("empty firewall rule".to_string())
+        };
+        let action = match action {
+            "accept" => Action::Accept,
+            "drop"   => Action::Drop,
+            "reject" => Action::Reject,
+            "log"    => Action::Log,
+            "count"  => Action::Count,
+            other    => return Err(format!("unknown action {} in firewall rule: {}", other, s))
+        };
+
+        let bad = |what: &str| format!("bad {} in firewall rule: {}", what, s);
+        let mut matches = Vec::new();
+        while let Some((&word, tail)) = rest.split_first() {
+            // the protocols stand on their own
+            let proto = match word {
+                "tcp"   => Some(PROTO_TCP),
+                "udp"   => Some(PROTO_UDP),
+                "icmp"  => Some(PROTO_ICMPV4),
+                "icmp6" => Some(PROTO_ICMPV6),
+                _       => None
+            };
+            if let Some(proto) = proto {
+                matches.push(Match::Protocol(proto));
+                rest = tail;
+                continue
+            }
+            // `ether` takes two words
+            let (word, tail) = match (word, tail.split_first()) {
+                ("ether", Some((&"src", tail))) => ("ether src", tail),
+                ("ether", Some((&"dst", tail))) => ("ether dst", tail),
+                ("ether", _) => return Err(bad("ether match")),
+                _ => (word, tail)
+            };
+            let arg = match tail.first() {
+                Some(arg) => *arg,
+                None => return Err(format!("{} needs an argument in firewall rule: {}", word, s))
+            };
+            matches.push(match word {
+                "iface"     => Match::Iface(arg.to_string()),
+                "ether src" => Match::EtherSrc(parse_mac(arg).ok_or_else(|| bad("mac address"))?),
+                "ether dst" => Match::EtherDst(parse_mac(arg).ok_or_else(|| bad("mac address"))?),
+                "ethertype" => Match::Ethertype(match arg {
+                    "ipv4" => ETHERTYPE_IPV4,
+                    "ipv6" => ETHERTYPE_IPV6,
+                    "arp"  => ETHERTYPE_ARP,
+                    "vlan" => ETHERTYPE_VLAN,
+                    num    => parse_num(num).ok_or_else(|| bad("ethertype"))?
+                }),
+                "vlan"  => Match::Vlan(arg.parse().ok().filter(|&id| id < 4096)
+                                          .ok_or_else(|| bad("vlan id"))?),
+                "src"   => { let (a, l) = parse_prefix(arg).ok_or_else(|| bad("source"))?; Match::Src(a, l) },
+                "dst"   => { let (a, l) = parse_prefix(arg).ok_or_else(|| bad("destination"))?; Match::Dst(a, l) },
+                "proto" => Match::Protocol(arg.parse().map_err(|_| bad("protocol"))?),
+                "sport" => { let (lo, hi) = parse_range(arg).ok_or_else(|| bad("port"))?; Match::SrcPort(lo, hi) },
+                "dport" => { let (lo, hi) = parse_range(arg).ok_or_else(|| bad("port"))?; Match::DstPort(lo, hi) },
+                "flags" => {
+                    let (mut set, mut clear) = (0, 0);
+                    for name in arg.split(',') {
+                        let (negated, name) = match name.strip_prefix('!') {
+                            Some(name) => (true, name),
+                            None => (false, name)
+                        };
+                        let bit = FLAG_NAMES.iter().find(|&&(n, _)| n == name)
+                            .map(|&(_, bit)| bit).ok_or_else(|| bad("tcp flag"))?;
+                        if negated { clear |= bit } else { set |= bit }
+                    }
+                    Match::Flags { set: set, clear: clear }
+                },
+                "type"  => Match::IcmpType(arg.parse().map_err(|_| bad("icmp type"))?),
+                "ct"    => {
+                    let mut states = 0;
+                    for name in arg.split(',') {
+                        states |= CT_NAMES.iter().find(|&&(n, _)| n == name)
+                            .map(|&(_, bit)| bit).ok_or_else(|| bad("conntrack state"))?;
+                    }
+                    Match::Ct(states)
+                },
+                other => return Err(format!("unexpected {} in firewall rule: {}", other, s))
+            });
+            rest = &tail[1..];
+        }
+        Ok(Rule { action: action, matches: matches })
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.action)?;
+        for m in &self.matches {
+            write!(f, " {}", m)?;
+        }
+        Ok(())
+    }
+}
+
+// a bare address is a host
+fn parse_prefix(s: &str) -> Option<(IpAddr, u8)> {
+    let (addr, len) = match s.find('/') {
+        Some(at) => (&s[..at], Some(s[at + 1..].parse::<u8>().ok()?)),
+        None => (s, None)
+    };
+    let addr = addr.parse::<IpAddr>().ok()?;
+    let max = if addr.is_ipv4() { <Ipv4Addr as RouteAddr>::BITS } else { <Ipv6Addr as RouteAddr>::BITS };
+    match len {
+        Some(len) if len > max => None,
+        len => Some((addr, len.unwrap_or(max)))
+    }
+}
+
+fn parse_range(s: &str) -> Option<(u16, u16)> {
+    match s.find('-') {
+        Some(at) => {
+            let (lo, hi) = (s[..at].parse().ok()?, s[at + 1..].parse().ok()?);
+            if lo <= hi { Some((lo, hi)) } else { None }
+        },
+        None => s.parse().ok().map(|port| (port, port))
+    }
+}
+
+// what the rules look at, as much of it as the packet has
+#[derive(Debug, Default)]
+struct Fields {
+    eth_src: Option<Mac>,
+    eth_dst: Option<Mac>,
+    ethertype: Option<u16>,
+    vlan: Option<u16>,
+    src: Option<IpAddr>,
+    dst: Option<IpAddr>,
+    protocol: Option<u8>,
+    // where the transport header starts in the ip packet
+    trans: Option<usize>,
+    ports: Option<(u16, u16)>,
+    flags: Option<u8>,
+    icmp_type: Option<u8>
+}
+
+impl Fields {
+    // `data` starts at the link header if `eth`, at the ip header if not
+    fn parse(data: &[u8], eth: bool) -> Fields {
+        let mut fields = Fields::default();
+        let mut ip = data;
+        if eth {
+            if data.len() < 14 {
+                return fields
+            }
+            let mac = |at: usize| {
+                let mut mac = [0u8; 6];
+                mac.copy_from_slice(&data[at..at + 6]);
+                mac
+            };
+            fields.eth_dst = Some(mac(0));
+            fields.eth_src = Some(mac(6));
+            let mut ethertype = (data[12] as u16) << 8 | data[13] as u16;
+            let mut start = 14;
+            if ethertype == ETHERTYPE_VLAN && data.len() >= 18 {
+                fields.vlan = Some(((data[14] as u16) << 8 | data[15] as u16) & 0xFFF);
+                ethertype = (data[16] as u16) << 8 | data[17] as u16;
+                start = 18;
+            }
+            fields.ethertype = Some(ethertype);
+            if ethertype != ETHERTYPE_IPV4 && ethertype != ETHERTYPE_IPV6 {
+                return fields
+            }
+            ip = &data[start..];
+        }
+        // what's cut short is left out
+        let _ = fields.parse_ip(ip);
+        fields
+    }
+
+    fn parse_ip(&mut self, ip: &[u8]) -> Result<(), pkt::DissectError> {
+        pkt::check_len("ip", ip, 1)?;
+        let (start, first) = match ip[0] >> 4 {
+            4 => {
+                let hdr = Ipv4 { offset: 0 };
+                let hlen = hdr.header_len(ip)?;
+                self.ethertype = self.ethertype.or(Some(ETHERTYPE_IPV4));
+                self.src = Some(IpAddr::V4(Ipv4Addr::from(hdr.get_src(ip))));
+                self.dst = Some(IpAddr::V4(Ipv4Addr::from(hdr.get_dst(ip))));
+                self.protocol = Some(hdr.get_protocol(ip));
+                (hlen, hdr.get_frag_offs(ip) == 0)
+            },
+            6 => {
+                pkt::check_len("ipv6", ip, IPV6_LEN)?;
+                let hdr = Ipv6 { offset: 0 };
+                self.ethertype = self.ethertype.or(Some(ETHERTYPE_IPV6));
+                self.src = Some(IpAddr::V6(Ipv6Addr::from(hdr.get_src(ip))));
+                self.dst = Some(IpAddr::V6(Ipv6Addr::from(hdr.get_dst(ip))));
+                let mut headers = hdr.ext_headers(ip);
+                for header in headers.by_ref() {
+                    header?;
+                }
+                let first = headers.protocol() != header_types::NO_NEXT;
+                if first {
+                    self.protocol = Some(headers.protocol());
+                }
+                (headers.end(), first)
+            },
+            _ => return Ok(())
+        };
+        if !first {
+            return Ok(())
+        }
+        self.trans = Some(start);
+        let trans = &ip[start..];
+        match self.protocol {
+            Some(PROTO_TCP) | Some(PROTO_UDP) => {
+                pkt::check_len("transport", trans, 4)?;
+                self.ports = Some(((trans[0] as u16) << 8 | trans[1] as u16,
+                                   (trans[2] as u16) << 8 | trans[3] as u16));
+                if self.protocol == Some(PROTO_TCP) {
+                    pkt::check_len("tcp", trans, 14)?;
+                    self.flags = Some(trans[13]);
+                }
+            },
+            Some(PROTO_ICMPV4) | Some(PROTO_ICMPV6) => {
+                pkt::check_len("icmp", trans, 1)?;
+                self.icmp_type = Some(trans[0]);
+            },
+            _ => ()
+        }
+        Ok(())
+    }
+
+    fn matches(&self, m: &Match, iface: &str, ct: Tracked) -> bool {
+        match *m {
+            Match::Iface(ref name)   => name == iface,
+            Match::EtherSrc(mac)     => self.eth_src == Some(mac),
+            Match::EtherDst(mac)     => self.eth_dst == Some(mac),
+            Match::Ethertype(kind)   => self.ethertype == Some(kind),
+            Match::Vlan(id)          => self.vlan == Some(id),
+            Match::Src(prefix, len)  => self.src.is_some_and(|addr| lpm::covers(prefix, len, addr)),
+            Match::Dst(prefix, len)  => self.dst.is_some_and(|addr| lpm::covers(prefix, len, addr)),
+            Match::Protocol(proto)   => self.protocol == Some(proto),
+            Match::SrcPort(lo, hi)   => self.ports.is_some_and(|(port, _)| lo <= port && port <= hi),
+            Match::DstPort(lo, hi)   => self.ports.is_some_and(|(_, port)| lo <= port && port <= hi),
+            Match::Flags { set, clear } =>
+                self.flags.is_some_and(|flags| flags & set == set && flags & clear == 0),
+            Match::IcmpType(kind)    => self.icmp_type == Some(kind),
+            Match::Ct(states) => states & match ct {
+                Tracked::Flow { state: State::New, .. }         => ct_states::NEW,
+                Tracked::Flow { state: State::Established, .. } => ct_states::ESTABLISHED,
+                Tracked::Flow { state: State::Closing, .. }     => ct_states::CLOSING,
+                Tracked::Related(_)                             => ct_states::RELATED,
+                Tracked::Untracked                              => ct_states::UNTRACKED
+            } != 0
+        }
+    }
+}
+
+impl fmt::Display for Fields {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (src, dst) = match (self.src, self.dst) {
+            (Some(src), Some(dst)) => (src, dst),
+            _ => return write!(f, "ethertype 0x{:04x}", self.ethertype.unwrap_or(0))
+        };
+        match (self.protocol, self.ports) {
+            (Some(PROTO_TCP), Some((sport, dport))) =>
+                write!(f, "tcp {}:{} -> {}:{} flags 0x{:02x}", src, sport, dst, dport,
+                       self.flags.unwrap_or(0)),
+            (Some(PROTO_UDP), Some((sport, dport))) =>
+                write!(f, "udp {}:{} -> {}:{}", src, sport, dst, dport),
+            (Some(proto), _) if self.icmp_type.is_some() =>
+                write!(f, "{} {} -> {} type {}", if proto == PROTO_ICMPV4 { "icmp" } else { "icmp6" },
+                       src, dst, self.icmp_type.unwrap_or(0)),
+            (Some(proto), _) => write!(f, "proto {} {} -> {}", proto, src, dst),
+            (None, _) => write!(f, "{} -> {}", src, dst)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counter {
+    pub packets: u64,
+    pub bytes: u64
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    // by the rule at this index
+    Drop(usize),
+    Reject(usize)
+}
+
+pub struct Chain {
+    name: &'static str,
+    rules: Vec<Rule>,
+    counters: Vec<Counter>
+}
+
+impl Chain {
+    pub fn new(name: &'static str, rules: Vec<Rule>) -> Chain {
+        let counters = vec![Counter::default(); rules.len()];
+        Chain { name: name, rules: rules, counters: counters }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    // `data` is a frame from the link header on if `eth`, an ip packet if
+    // not; `iface` is the name of the link it came in or goes out on
+    pub fn check(&mut self, data: &[u8], eth: bool, iface: &str, ct: Tracked) -> Verdict {
+        if self.rules.is_empty() {
+            return Verdict::Accept
+        }
+        let fields = Fields::parse(data, eth);
+        for (idx, rule) in self.rules.iter().enumerate() {
+            if !rule.matches(&fields, iface, ct) {
+                continue
+            }
+            let counter = &mut self.counters[idx];
+            counter.packets += 1;
+            counter.bytes += data.len() as u64;
+            match rule.action {
+                Action::Accept => return Verdict::Accept,
+                Action::Drop   => return Verdict::Drop(idx),
+                Action::Reject => return Verdict::Reject(idx),
+                Action::Log    => println!("\n{} rule {} on {}: {}", self.name, idx, iface, fields),
+                Action::Count  => ()
+            }
+        }
+        Verdict::Accept
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    // the rules and what they've matched so far
+    pub fn iter(&self) -> impl Iterator<Item = (&Rule, &Counter)> {
+        self.rules.iter().zip(self.counters.iter())
+    }
+
+    // New rules in place of the old ones. A rule that's still there keeps
+    // its counter.
+    pub fn replace(&mut self, rules: Vec<Rule>) {
+        let counters = rules.iter().map(|rule| {
+            self.rules.iter().position(|old| old == rule)
+                .map_or(Counter::default(), |idx| self.counters[idx])
+        }).collect();
+        self.rules = rules;
+        self.counters = counters;
+    }
+}
+
+pub struct Firewall {
+    pub ingress: Chain,
+    pub egress: Chain
+}
+
+impl Firewall {
+    pub fn new(ingress: Vec<Rule>, egress: Vec<Rule>) -> Firewall {
+        Firewall { ingress: Chain::new("ingress", ingress), egress: Chain::new("egress", egress) }
+    }
+
+    pub fn reload(&mut self, ingress: Vec<Rule>, egress: Vec<Rule>) {
+        self.ingress.replace(ingress);
+        self.egress.replace(egress);
+    }
+}
+
+// The answer to a rejected ip packet: a reset for tcp, as if from where
+// it was going, and an administratively prohibited error from `local` for
+// the rest. Nothing for resets and icmp errors, nobody answers those.
+pub fn reject(packet: &[u8], local: IpAddr) -> Option<Vec<u8>> {
+    let packet = icmp::unpadded(packet)?;
+    let fields = Fields::parse(packet, false);
+    let (src, dst) = (fields.src?, fields.dst?);
+
+    if let (Some(PROTO_TCP), Some(start)) = (fields.protocol, fields.trans) {
+        let tcp = Tcp { offset: 0 };
+        let seg = &packet[start..];
+        let hlen = tcp.header_len(seg).ok()?;
+        let flags = fields.flags.unwrap_or(0);
+        if flags & tcp_flags::RST != 0 {
+            return None
+        }
+        // RFC 793, 3.4: take the ack if there is one, ack the segment if not
+        let (seq, ack, rst) = if flags & tcp_flags::ACK != 0 {
+            (tcp.get_ack_nr(seg), 0, tcp_flags::RST)
+        } else {
+            let len = (seg.len() - hlen) as u32 + (flags & tcp_flags::SYN != 0) as u32
+                + (flags & tcp_flags::FIN != 0) as u32;
+            (0, tcp.get_seq(seg).wrapping_add(len), tcp_flags::RST | tcp_flags::ACK)
+        };
+        let (sport, dport) = (tcp.get_src_port(seg), tcp.get_dst_port(seg));
+        return Some(match (dst, src) {
+            (IpAddr::V4(d), IpAddr::V4(s)) => Ipv4Builder::new(d, s).tcp(dport, sport)
+                .seq(seq).ack_nr(ack).flags(rst).win(0).build(&[]).data,
+            (IpAddr::V6(d), IpAddr::V6(s)) => Ipv6Builder::new(d, s).tcp(dport, sport)
+                .seq(seq).ack_nr(ack).flags(rst).win(0).build(&[]).data,
+            _ => return None
+        })
+    }
+
+    icmp::icmp_error(packet, local, IcmpError::Prohibited)
+}
+
+
+// testing
+#[cfg(test)]
+use conntrack::Tuple;
+#[cfg(test)]
+use nat::Proto;
+#[cfg(test)]
+use packet::builder::{EthBuilder, IPV4_LEN};
+#[cfg(test)]
+use packet::icmpv6;
+#[cfg(test)]
+use std::net::SocketAddr;
+
+#[cfg(test)]
+use util::{A, B};
+
+// a telnet connection from A to B starting, and going on
+#[cfg(test)]
+fn syn() -> Vec<u8> {
+    Ipv4Builder::new(A, B).tcp(40000, 23).seq(1000).flags(tcp_flags::SYN).build(b"").data
+}
+
+#[cfg(test)]
+fn ack() -> Vec<u8> {
+    Ipv4Builder::new(A, B).tcp(40000, 23).ack_nr(77).flags(tcp_flags::ACK).build(b"hi").data
+}
+
+#[cfg(test)]
+fn chain(rules: &[&str]) -> Chain {
+    Chain::new("ingress", rules.iter().map(|rule| rule.parse().unwrap()).collect())
+}
+
+#[test]
+fn test_rules_print_as_parsed() {
+    for rule in &["drop iface tap1 src 10.0.0.0/8 tcp dport 20-23 flags syn,!ack",
+                  "count ether src 02:00:00:00:00:07 vlan 5"] {
+        assert_eq!(rule.parse::<Rule>().unwrap().to_string(), *rule);
+    }
+    assert_eq!("accept ct bogus".parse::<Rule>(),
+               Err("bad conntrack state in firewall rule: accept ct bogus".to_string()));
+    assert!("drop sport".parse::<Rule>().is_err());
+}
+
+#[test]
+fn test_first_matching_rule_decides() {
+    let established = Tracked::Flow {
+        tuple: Tuple {
+            proto: Proto::Tcp,
+            src: SocketAddr::new(IpAddr::V4(A), 40000),
+            dst: SocketAddr::new(IpAddr::V4(B), 23)
+        },
+        reply: false,
+        state: State::Established
+    };
+    let mut chain = chain(&["accept ct established,related",
+                            "count tcp",
+                            "drop iface tap1 src 10.0.0.0/8 tcp dport 20-23 flags syn,!ack",
+                            "reject iface tap1 tcp"]);
+    assert_eq!(chain.check(&syn(), false, "tap0", Tracked::Untracked), Verdict::Accept);
+    assert_eq!(chain.check(&syn(), false, "tap1", Tracked::Untracked), Verdict::Drop(2));
+    assert_eq!(chain.check(&ack(), false, "tap1", established), Verdict::Accept);
+    assert_eq!(chain.check(&ack(), false, "tap1", Tracked::Untracked), Verdict::Reject(3));
+    let counters: Vec<u64> = chain.iter().map(|(_, counter)| counter.packets).collect();
+    assert_eq!(counters, [1, 3, 1, 1]);
+}
+
+#[test]
+fn test_counters_outlast_a_reload() {
+    let mut chain = chain(&["drop udp", "count tcp"]);
+    chain.check(&syn(), false, "tap0", Tracked::Untracked);
+    chain.check(&ack(), false, "tap0", Tracked::Untracked);
+    // kept for the rules that stayed
+    chain.replace(vec!["count tcp".parse().unwrap()]);
+    assert_eq!(chain.iter().next().unwrap().1.bytes, (syn().len() + ack().len()) as u64);
+}
+
+#[test]
+fn test_vlan_rules_look_past_the_tag() {
+    let mut frame = EthBuilder::new([2, 0, 0, 0, 0, 7], [2, 0, 0, 0, 0, 1]).ipv4(A, B).udp(5000, 53)
+        .build(b"hi").data;
+    frame.splice(12..12, [0x81, 0x00, 0x00, 0x05].iter().cloned());
+    let mut chain = chain(&["accept vlan 4", "drop vlan 5 src 10.0.0.2 udp dport 53"]);
+    assert_eq!(chain.check(&frame, true, "tap0", Tracked::Untracked), Verdict::Drop(1));
+    frame[15] = 4;
+    assert_eq!(chain.check(&frame, true, "tap0", Tracked::Untracked), Verdict::Accept);
+    assert_eq!(chain.iter().next().unwrap().1.packets, 1);
+}
+
+#[test]
+fn test_reject_resets_tcp() {
+    // with the ack if there is one, acking the syn if not
+    let tcp = Tcp { offset: 0 };
+    let rst = reject(&syn(), IpAddr::V4(A)).unwrap();
+    let seg = &rst[IPV4_LEN..];
+    assert_eq!(Ipv4 { offset: 0 }.get_src(&rst), B.octets());
+    assert_eq!((tcp.get_src_port(seg), tcp.get_dst_port(seg)), (23, 40000));
+    assert_eq!((tcp.get_rst(seg), tcp.get_ack(seg), tcp.get_ack_nr(seg)), (1, 1, 1001));
+    let rst = reject(&ack(), IpAddr::V4(A)).unwrap();
+    assert_eq!((tcp.get_ack(&rst[IPV4_LEN..]), tcp.get_seq(&rst[IPV4_LEN..])), (0, 77));
+    // not a reset
+    assert_eq!(reject(&rst, IpAddr::V4(A)), None);
+}
+
+#[test]
+fn test_reject_answers_the_rest_with_icmp() {
+    let udp = Ipv6Builder::new("fd00::2".parse().unwrap(), "fd01::2".parse().unwrap())
+        .udp(5000, 53).build(&[0; 2000]).data;
+    let error = reject(&udp, "fd00::1".parse().unwrap()).unwrap();
+    assert_eq!(error.len(), pkt::MIN_IPV6_MTU);
+    assert_eq!((error[IPV6_LEN], error[IPV6_LEN + 1]), (icmpv6::DEST_UNREACH, 1));
+    // but not an error
+    assert_eq!(reject(&error, "fd01::1".parse().unwrap()), None);
+}
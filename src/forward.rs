@@ -0,0 +1,1119 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Instant;
+
+use config::{Config, DevType, IpCidr};
+use conntrack::{Conntrack, Flow, Tracked};
+use firewall::{self, Firewall, Verdict};
+use icmp::{self, IcmpError};
+use iface::Tap;
+use nat::{Dnat, Nat, NatError, Timeouts};
+use nat64::{self, Nat64, Siit, Translator, XlatError};
+use neigh::{self, Mac, Neighbors, ndp_options};
+use netem::{Lost, Netem};
+use packet::arp::{self, Arp};
+use packet::builder::{ETH_LEN, ETHERTYPE_IPV4, ETHERTYPE_IPV6, IPV6_LEN, ICMP_LEN, PROTO_ICMPV6};
+use packet::eth::Eth;
+use packet::frag::{self, FragError};
+use packet::icmpv6;
+use packet::ipv4::Ipv4;
+use packet::ipv6::Ipv6;
+use packet::pkt::{self, HasLinkLayer, HasNetworkLayer, Network};
+use packet::view::PacketView;
+use route::{Route, Table};
+use util;
+
+// forwarding
+//
+// Routes ip between interfaces the way a router with static routes does:
+// look up the destination, count down the ttl or hop limit and send the
+// packet to the next hop on the outgoing link. Tap links answer ARP and
+// neighbour solicitations for our address on them, and find the next hop's
+// ethernet address the same way; tun links just get the packet. A vlan
+// tag is looked past, what's routed goes out untagged; keeping a vlan out
+// is what the firewall is for.
+//
+// Packets for us or for a multicast group aren't forwarded, they come back
+// as `Outcome::Local` for the caller to deal with.
+//
+// With masquerading on, ipv4 routed out of the upstream link from any other
+// goes out from our address there, and what comes back to that address is
+// translated back before it's routed. Destination nat rules are applied
+// before routing, and undone on the replies after it. When a forwarded
+// port is reached from the link its host is on, the connection also goes
+// out from our address on that link, or the host would answer straight
+// back past us (hairpinning, RFC 5382 REQ-8).
+//
+// With nat64 on, ipv6 for an address in its prefix is translated to ipv4
+// and routed on as that, and ipv4 for the pool address comes back the
+// other way. Without a pool it's stateless: ipv6 goes over when the ipv4
+// address in it has a route, ipv4 when its own address has none but the
+// ipv6 one it embeds into does.
+//
+// What can't be forwarded, for running out of ttl, for lack of a route or
+// for not fitting the next link, is answered with an icmp error from our
+// address on the link it came in on. The error quotes the packet as it came
+// in, before any translation.
+//
+// Every packet routed is tracked in `conntrack`, between the destination
+// and source translations. Flows through nat64 are tracked as ipv6.
+//
+// The `firewall` sees every frame that comes in before anything else
+// does, and every packet routed right after it's tracked, on the link it's
+// going out of. Coming in, it goes by what the packet would be to
+// conntrack as it is, before any translation is undone.
+//
+// What's routed goes out through the egress impairments of `netem`, which
+// may hold it back for `release` to send later. The ingress ones are up
+// to the caller, before `handle_frame`.
+
+// the start of a packet as it came in, kept for an icmp error to quote:
+// as much as an ipv6 one can take, ipv4 errors quote less (RFC 4443 2.4)
+const QUOTE_LEN: usize = pkt::MIN_IPV6_MTU - IPV6_LEN - ICMP_LEN;
+
+struct Quote {
+    buff: [u8; QUOTE_LEN],
+    len: usize
+}
+
+impl Quote {
+    fn new(packet: &[u8]) -> Quote {
+        let len = packet.len().min(QUOTE_LEN);
+        let mut buff = [0u8; QUOTE_LEN];
+        buff[..len].copy_from_slice(&packet[..len]);
+        Quote { buff: buff, len: len }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        &self.buff[..self.len]
+    }
+}
+
+// locally administered, one per link
+fn link_mac(idx: usize) -> Mac {
+    let nr = (idx as u32 + 1).to_be_bytes();
+    [0x02, 0x00, nr[0], nr[1], nr[2], nr[3]]
+}
+
+pub struct Link {
+    pub tap: Tap,
+    pub mac: Mac,
+    // our addresses on the link
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>
+}
+
+impl Link {
+    fn has_eth(&self) -> bool {
+        self.tap.dev_type() == DevType::Tap
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Forwarded { iface: usize, next_hop: IpAddr },
+    // waiting for the next hop's link layer address
+    Queued { iface: usize, next_hop: IpAddr },
+    // for us, or for nobody in particular
+    Local
+}
+
+#[derive(Debug)]
+pub enum Dropped {
+    // a unicast frame for some other ethernet address
+    NotForUs,
+    NoRoute(IpAddr),
+    TtlExceeded,
+    TooBig { mtu: usize },
+    // in the ipv4 header
+    Checksum,
+    // addresses that have no business being forwarded
+    Martian(IpAddr),
+    // the outgoing link has no address of ours to ask for the next hop from
+    NoAddress(usize),
+    Nat(NatError),
+    Xlat(XlatError),
+    // by rule `rule` of the firewall chain
+    Filtered { chain: &'static str, rule: usize },
+    Lost(Lost),
+    // vlan tagged, or anything else we don't do
+    Ethertype(u16),
+    IpVersion(u8),
+    Truncated(pkt::Truncated),
+    HeaderLen { layer: &'static str, len: usize },
+    // an ipv4 total length short of its own header
+    TotalLen(usize),
+    Write(io::Error)
+}
+
+impl fmt::Display for Dropped {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Dropped::NotForUs          => f.write_str("not addressed to us"),
+            Dropped::NoRoute(dst)      => write!(f, "no route to {}", dst),
+            Dropped::TtlExceeded       => f.write_str("ttl exceeded in transit"),
+            Dropped::TooBig { mtu }    => write!(f, "too big for the outgoing mtu of {}", mtu),
+            Dropped::Checksum          => f.write_str("bad ipv4 header checksum"),
+            Dropped::Martian(addr)     => write!(f, "won't forward {}", addr),
+            Dropped::NoAddress(iface)  => write!(f, "no address on interface {} to resolve from", iface),
+            Dropped::Nat(ref e)        => write!(f, "{}", e),
+            Dropped::Xlat(ref e)       => write!(f, "{}", e),
+            Dropped::Filtered { chain, rule } => write!(f, "filtered by {} rule {}", chain, rule),
+            Dropped::Lost(ref e)       => write!(f, "{}", e),
+            Dropped::Ethertype(kind)   => write!(f, "can't handle ethertype 0x{:04x}", kind),
+            Dropped::IpVersion(version) => write!(f, "can't handle ip version {}", version),
+            Dropped::Truncated(ref e)  => write!(f, "{}", e),
+            Dropped::HeaderLen { layer, len } => write!(f, "{} header length of {} is too short", layer, len),
+            Dropped::TotalLen(len)     => write!(f, "ipv4 total length of {} doesn't cover the header", len),
+            Dropped::Write(ref e)      => write!(f, "write failed: {}", e)
+        }
+    }
+}
+
+impl error::Error for Dropped {}
+
+impl From<pkt::DissectError> for Dropped {
+    fn from(e: pkt::DissectError) -> Dropped {
+        match e {
+            pkt::DissectError::Truncated(e)              => Dropped::Truncated(e),
+            pkt::DissectError::HeaderLen { layer, len }  => Dropped::HeaderLen { layer: layer, len: len },
+            pkt::DissectError::UnknownEthertype(kind)    => Dropped::Ethertype(kind),
+            pkt::DissectError::UnknownIpVersion(version) => Dropped::IpVersion(version)
+        }
+    }
+}
+
+impl From<io::Error> for Dropped {
+    fn from(e: io::Error) -> Dropped {
+        Dropped::Write(e)
+    }
+}
+
+// source nat out of one link
+pub struct Masquerade {
+    pub iface: usize,
+    pub nat: Nat
+}
+
+pub struct Router {
+    pub links: Vec<Link>,
+    pub v4: Table<Ipv4Addr>,
+    pub v6: Table<Ipv6Addr>,
+    pub masquerade: Option<Masquerade>,
+    pub dnat: Dnat,
+    pub nat64: Option<Translator>,
+    pub conntrack: Conntrack,
+    pub firewall: Firewall,
+    pub netem: Netem,
+    // per link, for hairpinned connections
+    hairpin: Vec<Option<Nat>>,
+    arp: Neighbors<Ipv4Addr>,
+    ndp: Neighbors<Ipv6Addr>
+}
+
+impl Router {
+    // `taps` are the opened `config.ifaces()`, in the same order. Every
+    // interface gets a connected route for its prefix; static routes
+    // without a device go out where their gateway is connected.
+    pub fn new(taps: Vec<Tap>, config: &Config) -> Result<Router, String> {
+        let mut router = Router {
+            links: Vec::new(),
+            v4: Table::new(),
+            v6: Table::new(),
+            masquerade: None,
+            dnat: Dnat::new(config.nat.dnat.clone()),
+            nat64: match (config.nat.nat64, config.nat.nat64_pool) {
+                (Some(prefix), Some(pool)) =>
+                    Some(Translator::Stateful(Box::new(Nat64::new(prefix.addr, pool, Timeouts::default())))),
+                (Some(prefix), None) => Some(Translator::Stateless(Siit::new(prefix.addr))),
+                _ => None
+            },
+            conntrack: Conntrack::new(Timeouts::default()),
+            firewall: Firewall::new(config.firewall.ingress.clone(), config.firewall.egress.clone()),
+            netem: Netem::new(config.netem.ingress.clone(), config.netem.egress.clone(), config.netem.seed()),
+            hairpin: Vec::new(),
+            arp: Neighbors::new(),
+            ndp: Neighbors::new()
+        };
+        if let Some(Translator::Stateful(ref mut nat64)) = router.nat64 {
+            nat64.bindings.reserve(&router.dnat);
+        }
+        let ifaces = config.ifaces();
+        for (idx, (tap, iface)) in taps.into_iter().zip(&ifaces).enumerate() {
+            if let Some(cidr) = iface.ipv4 {
+                router.v4.add(Route { dst: cidr.addr, prefix: cidr.prefix, via: None, iface: idx });
+            }
+            if let Some(cidr) = iface.ipv6 {
+                router.v6.add(Route { dst: cidr.addr, prefix: cidr.prefix, via: None, iface: idx });
+            }
+            router.links.push(Link {
+                tap: tap,
+                mac: link_mac(idx),
+                ipv4: iface.peer_ipv4,
+                ipv6: iface.peer_ipv6
+            });
+        }
+
+        for route in &config.routes {
+            let dev = match route.dev {
+                Some(ref name) => ifaces.iter().position(|iface| iface.name == *name),
+                None => None
+            };
+            let connected = |via: IpAddr| match via {
+                IpAddr::V4(via) => router.v4.lookup(via).filter(|r| r.via.is_none()).map(|r| r.iface),
+                IpAddr::V6(via) => router.v6.lookup(via).filter(|r| r.via.is_none()).map(|r| r.iface)
+            };
+            let iface = match dev.or_else(|| route.via.and_then(&connected)) {
+                Some(iface) => iface,
+                None => return Err(format!("gateway {} isn't on any link",
+                                           route.via.map(|via| via.to_string()).unwrap_or_default()))
+            };
+            match (route.dst, route.via) {
+                (IpCidr::V4(dst), via) => router.v4.add(Route {
+                    dst: dst.addr, prefix: dst.prefix, iface: iface,
+                    via: match via { Some(IpAddr::V4(via)) => Some(via), _ => None }
+                }),
+                (IpCidr::V6(dst), via) => router.v6.add(Route {
+                    dst: dst.addr, prefix: dst.prefix, iface: iface,
+                    via: match via { Some(IpAddr::V6(via)) => Some(via), _ => None }
+                })
+            }
+        }
+
+        if let Some(ref name) = config.nat.masquerade {
+            let found = ifaces.iter().position(|iface| iface.name == *name)
+                .and_then(|idx| router.links[idx].ipv4.map(|addr| (idx, addr)));
+            let (iface, external) = match found {
+                Some(found) => found,
+                None => return Err(format!("can't masquerade out of {}", name))
+            };
+            let mut nat = Nat::new(external, Timeouts::default());
+            nat.bindings.reserve(&router.dnat);
+            router.masquerade = Some(Masquerade { iface: iface, nat: nat });
+        }
+        // the upstream link's address is the masquerading one's already
+        for (idx, link) in router.links.iter().enumerate() {
+            let upstream = router.masquerade.as_ref().map(|masq| masq.iface) == Some(idx);
+            router.hairpin.push(match link.ipv4 {
+                Some(addr) if !router.dnat.is_empty() && !upstream => {
+                    let mut nat = Nat::new(addr, Timeouts::default());
+                    nat.bindings.reserve(&router.dnat);
+                    Some(nat)
+                },
+                _ => None
+            });
+        }
+        Ok(router)
+    }
+
+    // `frame` is as read from `links[iface]`
+    pub fn handle_frame(&mut self, iface: usize, frame: &mut [u8],
+                        now: Instant) -> Result<Outcome, Dropped> {
+        let has_eth = self.links[iface].has_eth();
+        if has_eth {
+            pkt::check_len("eth", frame, ETH_LEN).map_err(Dropped::Truncated)?;
+            let dst = Eth { offset: 0 }.get_dst(frame);
+            if dst[0] & 1 == 0 && dst != self.links[iface].mac {
+                return Err(Dropped::NotForUs)
+            }
+        }
+        // past the vlan tag, if there's one
+        let start = match has_eth {
+            true  => Eth { offset: 0 }.header_len(frame).map_err(Dropped::Truncated)?,
+            false => 0
+        };
+        if !self.firewall.ingress.is_empty() {
+            let ct = match frame.get(start).map(|b| b >> 4) {
+                Some(4) | Some(6) if !has_eth || [ETHERTYPE_IPV4, ETHERTYPE_IPV6]
+                    .contains(&Eth { offset: 0 }.get_payload_type(frame)) =>
+                    self.conntrack.peek(&frame[start..], now).unwrap_or(Tracked::Untracked),
+                _ => Tracked::Untracked
+            };
+            let verdict = self.firewall.ingress.check(frame, has_eth, self.links[iface].tap.name(), ct);
+            self.enforce("ingress", verdict, iface, &frame[start..], now)?;
+        }
+        let link = match has_eth {
+            true  => pkt::Link::EthLink(Eth { offset: 0 }),
+            false => pkt::Link::RawLink
+        };
+        let (net, len) = {
+            let view = PacketView::with_link(frame, &link)?;
+            (view.net, view.len())
+        };
+        let frame = &mut frame[..len];
+
+        let (unicast, eth_src) = match link {
+            pkt::Link::EthLink(eth) => (eth.get_dst(frame)[0] & 1 == 0, Some(eth.get_src(frame))),
+            _ => (true, None)
+        };
+
+        match net {
+            Network::ArpNet(arp) => self.handle_arp(iface, frame, arp, now),
+            Network::Ipv4Net(ip) => {
+                // RFC 1812, 5.2.2; the ethertype said ipv4, the header may not
+                let packet = &frame[ip.offset..];
+                let version = ip.get_version(packet);
+                if version != 4 {
+                    return Err(Dropped::IpVersion(version))
+                }
+                let header_len = ip.header_len(packet)?;
+                if util::checksum(&packet[..header_len]) != 0 {
+                    return Err(Dropped::Checksum)
+                }
+                let total_len = ip.get_len(packet) as usize;
+                if total_len < header_len {
+                    return Err(Dropped::TotalLen(total_len))
+                }
+                if !unicast {
+                    return Ok(Outcome::Local)
+                }
+                self.forward_v4(iface, &mut frame[ip.offset..], None, now)
+            },
+            Network::Ipv6Net(ip) => self.forward_v6(iface, &mut frame[ip.offset..], unicast,
+                                                    eth_src, now)
+        }
+    }
+
+    // Sends requests again for next hops that haven't answered, and forgets
+    // the ones that went quiet, along with idle nat mappings and flows. The
+    // flows that ended come back.
+    pub fn expire(&mut self, now: Instant) -> Result<Vec<Flow>, Dropped> {
+        if let Some(ref mut masq) = self.masquerade {
+            masq.nat.expire(now);
+        }
+        for nat in self.hairpin.iter_mut().flatten() {
+            nat.expire(now);
+        }
+        if let Some(ref mut xlat) = self.nat64 {
+            xlat.expire(now);
+        }
+        for (iface, addr) in self.arp.expire(now) {
+            self.solicit(iface, IpAddr::V4(addr))?;
+        }
+        for (iface, addr) in self.ndp.expire(now) {
+            self.solicit(iface, IpAddr::V6(addr))?;
+        }
+        Ok(self.conntrack.expire(now))
+    }
+
+    fn is_local(&self, addr: IpAddr) -> bool {
+        self.links.iter().any(|link| match addr {
+            IpAddr::V4(addr) => link.ipv4 == Some(addr),
+            IpAddr::V6(addr) => link.ipv6 == Some(addr)
+        })
+    }
+
+    // `tracked` is what was tracked as ipv6 already, if it was
+    fn forward_v4(&mut self, iface: usize, packet: &mut [u8], tracked: Option<Tracked>,
+                  now: Instant) -> Result<Outcome, Dropped> {
+        let ip = Ipv4 { offset: 0 };
+        let quote = Quote::new(packet);
+        // back to whoever a translated flow started from; what's left for
+        // our address after that is for us
+        let dst = Ipv4Addr::from(ip.get_dst(packet));
+        if let Some(Translator::Stateful(ref mut nat64)) = self.nat64 {
+            if dst == nat64.pool() {
+                if let Some(mut packet) = nat64.to_ipv6(packet, now).map_err(Dropped::Xlat)? {
+                    return self.forward_v6(iface, &mut packet, true, None, now)
+                }
+            }
+        }
+        if let Some(ref mut masq) = self.masquerade {
+            if iface == masq.iface && dst == masq.nat.external() {
+                masq.nat.inbound(packet, now).map_err(Dropped::Nat)?;
+            }
+        }
+        if let Some(ref mut nat) = self.hairpin[iface] {
+            if dst == nat.external() {
+                nat.inbound(packet, now).map_err(Dropped::Nat)?;
+            }
+        }
+        let dnatted = self.dnat.destination(packet).map_err(Dropped::Nat)?;
+
+        let src = Ipv4Addr::from(ip.get_src(packet));
+        let dst = Ipv4Addr::from(ip.get_dst(packet));
+        if self.is_local(IpAddr::V4(dst)) || dst.is_multicast() || dst.is_broadcast() {
+            return Ok(Outcome::Local)
+        }
+        for &addr in &[src, dst] {
+            if addr.is_loopback() || addr.is_unspecified() || addr.is_link_local() {
+                return Err(Dropped::Martian(IpAddr::V4(addr)))
+            }
+        }
+        let route = match self.v4.lookup(dst) {
+            Some(route) => *route,
+            None => {
+                let mut translated = match self.nat64 {
+                    Some(Translator::Stateless(ref siit))
+                        if self.v6.lookup(nat64::embed(siit.prefix(), dst)).is_some() =>
+                        siit.to_ipv6(packet).map_err(Dropped::Xlat)?,
+                    _ => {
+                        self.answer(iface, quote.bytes(), IcmpError::NoRoute, now)?;
+                        return Err(Dropped::NoRoute(IpAddr::V4(dst)))
+                    }
+                };
+                return self.forward_v6(iface, &mut translated, true, None, now)
+            }
+        };
+
+        let ttl = ip.get_ttl(packet);
+        if ttl <= 1 {
+            self.answer(iface, quote.bytes(), IcmpError::TimeExceeded, now)?;
+            return Err(Dropped::TtlExceeded)
+        }
+        let ct = match tracked {
+            Some(ct) => ct,
+            None => self.conntrack.track(packet, now)?
+        };
+        self.filter_out(iface, route.iface, packet, ct, now)?;
+        if dnatted && route.iface == iface {
+            if let Some(ref mut nat) = self.hairpin[iface] {
+                nat.outbound(packet, now).map_err(Dropped::Nat)?;
+            }
+        } else if !self.dnat.source(packet).map_err(Dropped::Nat)? {
+            if let Some(ref mut masq) = self.masquerade {
+                if route.iface == masq.iface && iface != masq.iface {
+                    masq.nat.outbound(packet, now).map_err(Dropped::Nat)?;
+                }
+            }
+        }
+        // the ttl shares its checksum word with the protocol
+        let old = (ttl as u16) << 8 | ip.get_protocol(packet) as u16;
+        ip.set_ttl(packet, ttl - 1);
+        let chk = util::checksum_adjust(ip.get_header_chk(packet), old, old - 0x100);
+        ip.set_header_chk(packet, chk);
+
+        let next_hop = IpAddr::V4(route.next_hop(dst));
+        let mtu = self.links[route.iface].tap.mtu();
+        if packet.len() <= mtu {
+            return self.send(route.iface, next_hop, packet, now)
+        }
+        let frags = match frag::fragment_ipv4(packet, mtu) {
+            Ok(frags) => frags,
+            Err(FragError::DontFragment) => {
+                self.answer(iface, quote.bytes(), IcmpError::TooBig(mtu), now)?;
+                return Err(Dropped::TooBig { mtu: mtu })
+            },
+            Err(_) => return Err(Dropped::TooBig { mtu: mtu })
+        };
+        let mut outcome = Outcome::Local;
+        for frag in frags {
+            outcome = self.send(route.iface, next_hop, &frag, now)?;
+        }
+        Ok(outcome)
+    }
+
+    fn forward_v6(&mut self, iface: usize, packet: &mut [u8], unicast: bool, eth_src: Option<Mac>,
+                  now: Instant) -> Result<Outcome, Dropped> {
+        let ip = Ipv6 { offset: 0 };
+        // neighbour discovery never has extension headers
+        if ip.get_nxt_header(packet) == PROTO_ICMPV6 && packet.len() >= IPV6_LEN + ICMP_LEN {
+            let icmp_type = packet[IPV6_LEN];
+            if icmp_type == icmpv6::NEIGHBOR_SOLICIT || icmp_type == icmpv6::NEIGHBOR_ADVERT {
+                return self.handle_ndp(iface, packet, eth_src, now)
+            }
+        }
+
+        let quote = Quote::new(packet);
+        let src = Ipv6Addr::from(ip.get_src(packet));
+        let dst = Ipv6Addr::from(ip.get_dst(packet));
+        if !unicast || self.is_local(IpAddr::V6(dst)) || dst.is_multicast() {
+            return Ok(Outcome::Local)
+        }
+        for &addr in &[src, dst] {
+            let link_local = addr.segments()[0] & 0xFFC0 == 0xFE80;
+            if addr.is_loopback() || addr.is_unspecified() || link_local {
+                return Err(Dropped::Martian(IpAddr::V6(addr)))
+            }
+        }
+        if let Some(ref mut xlat) = self.nat64 {
+            let over = match nat64::extract(xlat.prefix(), dst) {
+                Some(addr) => match *xlat {
+                    Translator::Stateful(_) => true,
+                    Translator::Stateless(_) => self.v4.lookup(addr).is_some()
+                },
+                None => false
+            };
+            if over {
+                let ct = self.conntrack.track(packet, now)?;
+                let mut packet = xlat.to_ipv4(packet, now).map_err(Dropped::Xlat)?;
+                return self.forward_v4(iface, &mut packet, Some(ct), now)
+            }
+        }
+        let route = match self.v6.lookup(dst) {
+            Some(route) => *route,
+            None => {
+                self.answer(iface, quote.bytes(), IcmpError::NoRoute, now)?;
+                return Err(Dropped::NoRoute(IpAddr::V6(dst)))
+            }
+        };
+
+        let hop_limit = ip.get_hop_limit(packet);
+        if hop_limit <= 1 {
+            self.answer(iface, quote.bytes(), IcmpError::TimeExceeded, now)?;
+            return Err(Dropped::TtlExceeded)
+        }
+        ip.set_hop_limit(packet, hop_limit - 1);
+        let ct = self.conntrack.track(packet, now)?;
+        self.filter_out(iface, route.iface, packet, ct, now)?;
+
+        // only the source fragments in ipv6
+        let mtu = self.links[route.iface].tap.mtu();
+        if packet.len() > mtu {
+            self.answer(iface, quote.bytes(), IcmpError::TooBig(mtu), now)?;
+            return Err(Dropped::TooBig { mtu: mtu })
+        }
+        self.send(route.iface, IpAddr::V6(route.next_hop(dst)), packet, now)
+    }
+
+    // the egress chain, for `packet` going from link `from` out of `to`
+    fn filter_out(&mut self, from: usize, to: usize, packet: &[u8], ct: Tracked,
+                  now: Instant) -> Result<(), Dropped> {
+        let verdict = self.firewall.egress.check(packet, false, self.links[to].tap.name(), ct);
+        self.enforce("egress", verdict, from, packet, now)
+    }
+
+    // Lets `packet`, an ip datagram that came in on `iface`, on if `chain`
+    // accepted it. Rejecting answers it.
+    fn enforce(&mut self, chain: &'static str, verdict: Verdict, iface: usize, packet: &[u8],
+               now: Instant) -> Result<(), Dropped> {
+        let rule = match verdict {
+            Verdict::Accept => return Ok(()),
+            Verdict::Drop(rule) => rule,
+            Verdict::Reject(rule) => {
+                self.reply(iface, packet, now, firewall::reject)?;
+                rule
+            }
+        };
+        Err(Dropped::Filtered { chain: chain, rule: rule })
+    }
+
+    // `error` about `packet`, which came in on `iface`
+    fn answer(&mut self, iface: usize, packet: &[u8], error: IcmpError,
+              now: Instant) -> Result<(), Dropped> {
+        self.reply(iface, packet, now, |packet, local| icmp::icmp_error(packet, local, error))
+    }
+
+    // What `make` has to say about `packet`, from our address on `iface`,
+    // routed back as any other packet. Nothing goes to an address of ours
+    // or one nat64 stands in for, that's us again.
+    fn reply<F>(&mut self, iface: usize, packet: &[u8], now: Instant, make: F) -> Result<(), Dropped>
+        where F: FnOnce(&[u8], IpAddr) -> Option<Vec<u8>> {
+        let link = &self.links[iface];
+        let (local, dst) = match packet.first().map(|b| b >> 4) {
+            Some(4) if packet.len() >= Ipv4::HEADER_LEN => match link.ipv4 {
+                Some(local) => (IpAddr::V4(local), IpAddr::V4(Ipv4Addr::from(Ipv4 { offset: 0 }.get_src(packet)))),
+                None => return Ok(())
+            },
+            Some(6) if packet.len() >= IPV6_LEN => match link.ipv6 {
+                Some(local) => (IpAddr::V6(local), IpAddr::V6(Ipv6Addr::from(Ipv6 { offset: 0 }.get_src(packet)))),
+                None => return Ok(())
+            },
+            _ => return Ok(())
+        };
+        if self.is_local(dst) || self.behind_nat64(dst) {
+            return Ok(())
+        }
+        let route = match dst {
+            IpAddr::V4(dst) => self.v4.lookup(dst).map(|route| (route.iface, IpAddr::V4(route.next_hop(dst)))),
+            IpAddr::V6(dst) => self.v6.lookup(dst).map(|route| (route.iface, IpAddr::V6(route.next_hop(dst))))
+        };
+        if let (Some((out, next_hop)), Some(reply)) = (route, make(packet, local)) {
+            self.send(out, next_hop, &reply, now)?;
+        }
+        Ok(())
+    }
+
+    // whether `addr` is one nat64 translates to or from
+    fn behind_nat64(&self, addr: IpAddr) -> bool {
+        match (self.nat64.as_ref(), addr) {
+            (Some(Translator::Stateful(nat64)), IpAddr::V4(addr)) => addr == nat64.pool(),
+            (Some(xlat), IpAddr::V6(addr)) => nat64::extract(xlat.prefix(), addr).is_some(),
+            _ => false
+        }
+    }
+
+    fn send(&mut self, iface: usize, next_hop: IpAddr, packet: &[u8],
+            now: Instant) -> Result<Outcome, Dropped> {
+        let link = &self.links[iface];
+        if !link.has_eth() {
+            self.transmit(iface, packet, now)?;
+            return Ok(Outcome::Forwarded { iface: iface, next_hop: next_hop })
+        }
+
+        let mut frame = vec![0u8; ETH_LEN];
+        frame.extend_from_slice(packet);
+        let eth = Eth { offset: 0 };
+        eth.set_src(&mut frame, link.mac);
+        let known = match next_hop {
+            IpAddr::V4(addr) => {
+                eth.set_ethertype(&mut frame, ETHERTYPE_IPV4);
+                self.arp.get(iface, addr, now)
+            },
+            IpAddr::V6(addr) => {
+                eth.set_ethertype(&mut frame, ETHERTYPE_IPV6);
+                self.ndp.get(iface, addr, now)
+            }
+        };
+        if let Some(mac) = known {
+            eth.set_dst(&mut frame, mac);
+            self.transmit(iface, &frame, now)?;
+            return Ok(Outcome::Forwarded { iface: iface, next_hop: next_hop })
+        }
+
+        let solicit = match next_hop {
+            IpAddr::V4(addr) => self.arp.queue(iface, addr, frame, now),
+            IpAddr::V6(addr) => self.ndp.queue(iface, addr, frame, now)
+        };
+        if solicit {
+            self.solicit(iface, next_hop)?;
+        }
+        Ok(Outcome::Queued { iface: iface, next_hop: next_hop })
+    }
+
+    fn solicit(&self, iface: usize, addr: IpAddr) -> Result<(), Dropped> {
+        let link = &self.links[iface];
+        let frame = match addr {
+            IpAddr::V4(addr) => match link.ipv4 {
+                Some(src) => neigh::arp_request(link.mac, src, addr),
+                None => return Err(Dropped::NoAddress(iface))
+            },
+            IpAddr::V6(addr) => match link.ipv6 {
+                Some(src) => neigh::neighbor_solicit(link.mac, src, addr),
+                None => return Err(Dropped::NoAddress(iface))
+            }
+        };
+        link.tap.write(&frame)?;
+        Ok(())
+    }
+
+    // the frames that were waiting for a neighbour
+    fn send_all(&mut self, iface: usize, frames: Vec<Vec<u8>>, now: Instant) -> Result<(), Dropped> {
+        for frame in frames {
+            self.transmit(iface, &frame, now)?;
+        }
+        Ok(())
+    }
+
+    // `frame` out of `links[iface]`, now or when the impairments say
+    fn transmit(&mut self, iface: usize, frame: &[u8], now: Instant) -> Result<(), Dropped> {
+        let link = if self.links[iface].has_eth() {
+            pkt::Link::EthLink(Eth { offset: 0 })
+        } else {
+            pkt::Link::RawLink
+        };
+        if !self.netem.egress.push(iface, frame, &link, now).map_err(Dropped::Lost)? {
+            self.links[iface].tap.write(frame)?;
+        }
+        self.release(now)
+    }
+
+    // sends what the egress impairments held back until `now`
+    pub fn release(&mut self, now: Instant) -> Result<(), Dropped> {
+        while let Some((iface, frame)) = self.netem.egress.pop(now) {
+            self.links[iface].tap.write(&frame)?;
+        }
+        Ok(())
+    }
+
+    // RFC 826: note down the sender if it's asking us or we asked about
+    // it, answer if it's asking us. `arp` is where the request is in
+    // `frame`, past any vlan tag, which the reply keeps.
+    fn handle_arp(&mut self, iface: usize, frame: &mut [u8], arp: Arp,
+                  now: Instant) -> Result<Outcome, Dropped> {
+        let mac = self.links[iface].mac;
+        let (oper, sha, spa, tpa) = {
+            let buff = &frame[arp.offset..];
+            if arp.get_htype(buff) != 1 || arp.get_ptype(buff) != ETHERTYPE_IPV4
+                || arp.get_hlen(buff) != 6 || arp.get_plen(buff) != 4 {
+                return Ok(Outcome::Local)
+            }
+            (arp.get_oper(buff), arp.get_sha(buff),
+             Ipv4Addr::from(arp.get_spa(buff)), Ipv4Addr::from(arp.get_tpa(buff)))
+        };
+        let ours = self.links[iface].ipv4 == Some(tpa);
+
+        if !spa.is_unspecified() && (ours || self.arp.knows(iface, spa)) {
+            let frames = self.arp.learn(iface, spa, sha, now);
+            self.send_all(iface, frames, now)?;
+        }
+        if ours && oper == arp::REQUEST {
+            let eth = Eth { offset: 0 };
+            eth.set_dst(frame, sha);
+            eth.set_src(frame, mac);
+            arp.make_reply(&mut frame[arp.offset..], mac);
+            self.links[iface].tap.write(frame)?;
+        }
+        Ok(Outcome::Local)
+    }
+
+    // RFC 4861, 7.2: answer solicitations for our address, and take note of
+    // advertisements for neighbours we asked about
+    fn handle_ndp(&mut self, iface: usize, packet: &[u8], eth_src: Option<Mac>,
+                  now: Instant) -> Result<Outcome, Dropped> {
+        let ip = Ipv6 { offset: 0 };
+        // from off link otherwise
+        if ip.get_hop_limit(packet) != 255 || !self.links[iface].has_eth() {
+            return Ok(Outcome::Local)
+        }
+        let src = Ipv6Addr::from(ip.get_src(packet));
+        let body = &packet[IPV6_LEN + ICMP_LEN..];
+
+        if packet[IPV6_LEN] == icmpv6::NEIGHBOR_ADVERT {
+            if let Some((target, mac)) = neigh::parse_ndp(body, ndp_options::TGT_LINK_ADDR) {
+                if let Some(mac) = mac.or(eth_src) {
+                    if self.ndp.knows(iface, target) {
+                        let frames = self.ndp.learn(iface, target, mac, now);
+                        self.send_all(iface, frames, now)?;
+                    }
+                }
+            }
+            return Ok(Outcome::Local)
+        }
+
+        let (target, mac) = match neigh::parse_ndp(body, ndp_options::SRC_LINK_ADDR) {
+            Some(parsed) => parsed,
+            None => return Ok(Outcome::Local)
+        };
+        let link = &self.links[iface];
+        if link.ipv6 != Some(target) {
+            return Ok(Outcome::Local)
+        }
+        let advert = if src.is_unspecified() {
+            // duplicate address detection, tell everyone
+            let all_nodes = Ipv6Addr::new(0xFF02, 0, 0, 0, 0, 0, 0, 1);
+            neigh::neighbor_advert(link.mac, target, all_nodes, neigh::multicast_mac(all_nodes),
+                                   neigh::NA_ROUTER | neigh::NA_OVERRIDE)
+        } else {
+            let dst_mac = match mac.or(eth_src) {
+                Some(mac) => mac,
+                None => return Ok(Outcome::Local)
+            };
+            neigh::neighbor_advert(link.mac, target, src, dst_mac,
+                                   neigh::NA_ROUTER | neigh::NA_SOLICITED | neigh::NA_OVERRIDE)
+        };
+        link.tap.write(&advert)?;
+
+        if let (false, Some(mac)) = (src.is_unspecified(), mac) {
+            let frames = self.ndp.learn(iface, src, mac, now);
+            self.send_all(iface, frames, now)?;
+        }
+        Ok(Outcome::Local)
+    }
+}
+
+
+// testing
+#[cfg(test)]
+use std::fs::File;
+#[cfg(test)]
+use packet::builder::{EthBuilder, Ipv4Builder, Ipv6Builder, IPV4_LEN};
+#[cfg(test)]
+use packet::icmpv4;
+
+#[cfg(test)]
+const HOST_MAC: Mac = [0x02, 0, 0, 0, 0, 0x10];
+
+// A router on socketpairs with two links, 10.0.0.0/24 and fd00::/64 on the
+// first, 10.0.1.0/24 and fd00:1::/64 on the second. We're .1 and ::1 on
+// both, the test plays the hosts on the returned ends.
+#[cfg(test)]
+fn test_router(dev_type: DevType, mtus: [usize; 2]) -> (Router, Vec<File>) {
+    use std::os::unix::io::AsRawFd;
+    use iface::Tap;
+
+    let mut config = Config::default();
+    for (idx, &mtu) in mtus.iter().enumerate() {
+        let mut iface = config.iface.clone();
+        iface.name = format!("tap{}", idx);
+        iface.dev_type = dev_type;
+        iface.ipv4 = Some(format!("10.0.{}.2/24", idx).parse().unwrap());
+        iface.ipv6 = Some(format!("fd00:{}::2/64", idx).parse().unwrap());
+        iface.peer_ipv4 = Some(format!("10.0.{}.1", idx).parse().unwrap());
+        iface.peer_ipv6 = Some(format!("fd00:{}::1", idx).parse().unwrap());
+        iface.mtu = mtu;
+        match idx {
+            0 => config.iface = iface,
+            _ => config.extra_ifaces.push(iface)
+        }
+    }
+    let (taps, peers): (Vec<Tap>, Vec<File>) = mtus.iter()
+        .map(|&mtu| Tap::pair(dev_type, mtu).unwrap())
+        .unzip();
+    for peer in &peers {
+        unsafe { libc::fcntl(peer.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK) };
+    }
+    (Router::new(taps, &config).unwrap(), peers)
+}
+
+// what the router sent the host on the other end, if anything
+#[cfg(test)]
+fn recv(peer: &File) -> Option<Vec<u8>> {
+    use std::io::Read;
+
+    let mut buff = [0u8; 2048];
+    match (&*peer).read(&mut buff) {
+        Ok(len) => Some(buff[..len].to_vec()),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => None,
+        Err(e) => panic!("{}", e)
+    }
+}
+
+#[test]
+fn test_link_macs_stay_apart() {
+    assert_eq!(link_mac(0), [0x02, 0, 0, 0, 0, 1]);
+    assert_eq!(link_mac(254), [0x02, 0, 0, 0, 0, 0xFF]);
+    assert_eq!(link_mac(255), [0x02, 0, 0, 0, 1, 0]);
+}
+
+#[test]
+fn test_forward_counts_down_the_ttl() {
+    let (mut router, peers) = test_router(DevType::Tun, [1500, 1500]);
+    let dst = Ipv4Addr::new(10, 0, 1, 2);
+    let mut packet = Ipv4Builder::new(Ipv4Addr::new(10, 0, 0, 2), dst).udp(5000, 53).build(b"hi").data;
+    assert_eq!(router.handle_frame(0, &mut packet, Instant::now()).unwrap(),
+               Outcome::Forwarded { iface: 1, next_hop: IpAddr::V4(dst) });
+
+    let out = recv(&peers[1]).unwrap();
+    assert_eq!(Ipv4 { offset: 0 }.get_ttl(&out), 63);
+    // the checksum was adjusted, not left as it was
+    assert_eq!(util::checksum(&out[..IPV4_LEN]), 0);
+    assert_eq!(recv(&peers[0]), None);
+}
+
+#[test]
+fn test_ttl_exceeded_is_answered() {
+    let (mut router, peers) = test_router(DevType::Tun, [1500, 1500]);
+    let src = Ipv4Addr::new(10, 0, 0, 2);
+    let mut packet = Ipv4Builder::new(src, Ipv4Addr::new(10, 0, 1, 2)).ttl(1).udp(5000, 53).build(b"hi").data;
+    let sent = packet.clone();
+    assert!(matches!(router.handle_frame(0, &mut packet, Instant::now()), Err(Dropped::TtlExceeded)));
+    assert_eq!(recv(&peers[1]), None);
+
+    let error = recv(&peers[0]).unwrap();
+    let ip = Ipv4 { offset: 0 };
+    assert_eq!((ip.get_src(&error), ip.get_dst(&error)), ([10, 0, 0, 1], src.octets()));
+    assert_eq!(&error[IPV4_LEN..IPV4_LEN + 2], &[icmpv4::TIME_EXCEEDED, 0]);
+    assert_eq!(&error[IPV4_LEN + ICMP_LEN..], &sent[..]);
+
+    // nor for ipv6
+    let src = "fd00::2".parse().unwrap();
+    let mut packet = Ipv6Builder::new(src, "fd00:1::2".parse().unwrap()).hop_limit(1).udp(5000, 53)
+        .build(b"hi").data;
+    assert!(matches!(router.handle_frame(0, &mut packet, Instant::now()), Err(Dropped::TtlExceeded)));
+    let error = recv(&peers[0]).unwrap();
+    assert_eq!(Ipv6 { offset: 0 }.get_dst(&error), src.octets());
+    assert_eq!(&error[IPV6_LEN..IPV6_LEN + 2], &[icmpv6::TIME_EXCEEDED, 0]);
+}
+
+#[test]
+fn test_no_route_is_answered() {
+    let (mut router, peers) = test_router(DevType::Tun, [1500, 1500]);
+    let dst = Ipv4Addr::new(192, 0, 2, 1);
+    let mut packet = Ipv4Builder::new(Ipv4Addr::new(10, 0, 0, 2), dst).udp(5000, 53).build(b"hi").data;
+    match router.handle_frame(0, &mut packet, Instant::now()) {
+        Err(Dropped::NoRoute(addr)) => assert_eq!(addr, IpAddr::V4(dst)),
+        other => panic!("{:?}", other)
+    }
+    let error = recv(&peers[0]).unwrap();
+    assert_eq!(&error[IPV4_LEN..IPV4_LEN + 2], &[icmpv4::DEST_UNREACH, 0]);
+
+    // but not an icmp error
+    let mut error = Ipv4Builder::new(Ipv4Addr::new(10, 0, 0, 2), dst)
+        .icmp(icmpv4::DEST_UNREACH, 3)
+        .build(&error[IPV4_LEN + ICMP_LEN..])
+        .data;
+    assert!(matches!(router.handle_frame(0, &mut error, Instant::now()), Err(Dropped::NoRoute(_))));
+    assert_eq!(recv(&peers[0]), None);
+}
+
+#[test]
+fn test_too_big_is_answered() {
+    let (mut router, peers) = test_router(DevType::Tun, [1500, 1280]);
+    let src = Ipv4Addr::new(10, 0, 0, 2);
+    let dst = Ipv4Addr::new(10, 0, 1, 2);
+    let mut packet = Ipv4Builder::new(src, dst).udp(5000, 53).build(&[0; 1400]).data;
+    assert!(matches!(router.handle_frame(0, &mut packet, Instant::now()),
+                     Err(Dropped::TooBig { mtu: 1280 })));
+    let error = recv(&peers[0]).unwrap();
+    // fragmentation needed, with the mtu in the second half of the rest
+    assert_eq!(&error[IPV4_LEN..IPV4_LEN + 2], &[icmpv4::DEST_UNREACH, 4]);
+    assert_eq!(&error[IPV4_LEN + 6..IPV4_LEN + 8], &[0x05, 0x00]);
+    assert_eq!(error.len(), 576);
+
+    // what may be fragmented is
+    let mut packet = Ipv4Builder::new(src, dst).dont_fragment(false).udp(5000, 53).build(&[0; 1400]).data;
+    assert!(router.handle_frame(0, &mut packet, Instant::now()).is_ok());
+    assert!(recv(&peers[1]).unwrap().len() <= 1280);
+    assert!(recv(&peers[1]).is_some());
+    assert_eq!(recv(&peers[0]), None);
+
+    // ipv6 has its own message for it
+    let mut packet = Ipv6Builder::new("fd00::2".parse().unwrap(), "fd00:1::2".parse().unwrap())
+        .udp(5000, 53).build(&[0; 1400]).data;
+    let sent = packet.clone();
+    assert!(matches!(router.handle_frame(0, &mut packet, Instant::now()),
+                     Err(Dropped::TooBig { mtu: 1280 })));
+    let error = recv(&peers[0]).unwrap();
+    assert_eq!(&error[IPV6_LEN..IPV6_LEN + 2], &[icmpv6::PACKET_TOO_BIG, 0]);
+    assert_eq!(&error[IPV6_LEN + 4..IPV6_LEN + 8], &[0, 0, 0x05, 0x00]);
+    // quoting as much of it as fits the minimum mtu
+    assert_eq!(error.len(), pkt::MIN_IPV6_MTU);
+    assert_eq!(&error[IPV6_LEN + ICMP_LEN..], &sent[..QUOTE_LEN]);
+    assert_eq!(recv(&peers[1]), None);
+}
+
+#[test]
+fn test_bad_header_checksum_is_dropped() {
+    let (mut router, peers) = test_router(DevType::Tun, [1500, 1500]);
+    let mut packet = Ipv4Builder::new(Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 1, 2))
+        .udp(5000, 53).build(b"hi").data;
+    packet[11] ^= 0xFF;
+    assert!(matches!(router.handle_frame(0, &mut packet, Instant::now()), Err(Dropped::Checksum)));
+    assert_eq!(recv(&peers[0]), None);
+    assert_eq!(recv(&peers[1]), None);
+}
+
+#[test]
+fn test_bad_header_fields_are_dropped() {
+    let (mut router, peers) = test_router(DevType::Tap, [1500, 1500]);
+    let now = Instant::now();
+    // an experimental protocol, no transport header for a short total
+    // length to cut into
+    let frame = EthBuilder::new(HOST_MAC, link_mac(0))
+        .ipv4(Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 1, 2))
+        .build(253, b"hi").data;
+    let ip = Ipv4 { offset: ETH_LEN };
+    // each with the checksum fixed up to match
+    let broken = |version: u8, ihl: u8, len: u16| {
+        let mut frame = frame.clone();
+        ip.set_version(&mut frame[ETH_LEN..], version);
+        ip.set_ihl(&mut frame[ETH_LEN..], ihl);
+        ip.set_len(&mut frame[ETH_LEN..], len);
+        ip.set_header_chk(&mut frame[ETH_LEN..], 0);
+        let chk = util::checksum(&frame[ETH_LEN..ETH_LEN + IPV4_LEN]);
+        ip.set_header_chk(&mut frame[ETH_LEN..], chk);
+        frame
+    };
+    let len = frame.len() as u16 - ETH_LEN as u16;
+
+    assert!(matches!(router.handle_frame(0, &mut broken(6, 5, len), now), Err(Dropped::IpVersion(6))));
+    assert!(matches!(router.handle_frame(0, &mut broken(4, 0, len), now),
+                     Err(Dropped::HeaderLen { layer: "ipv4", len: 0 })));
+    assert!(matches!(router.handle_frame(0, &mut broken(4, 5, 12), now), Err(Dropped::TotalLen(12))));
+    assert_eq!(recv(&peers[0]), None);
+    assert_eq!(recv(&peers[1]), None);
+}
+
+// `frame` with a tag for `vlan` put in
+#[cfg(test)]
+fn tagged(frame: &[u8], vlan: u16) -> Vec<u8> {
+    let mut tagged = frame[..12].to_vec();
+    tagged.extend_from_slice(&[0x81, 0x00, (vlan >> 8) as u8, vlan as u8]);
+    tagged.extend_from_slice(&frame[12..]);
+    tagged
+}
+
+#[test]
+fn test_tagged_frames_are_routed_unless_filtered() {
+    let (mut router, peers) = test_router(DevType::Tap, [1500, 1500]);
+    let now = Instant::now();
+    let (src, dst) = (Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 1, 2));
+    router.firewall.reload(vec!["drop vlan 6".parse().unwrap(), "reject vlan 7".parse().unwrap()],
+                           Vec::new());
+
+    // asked who we are, tagged, and answered with the tag kept
+    let mut request = tagged(&neigh::arp_request(HOST_MAC, src, Ipv4Addr::new(10, 0, 0, 1)), 5);
+    assert_eq!(router.handle_frame(0, &mut request, now).unwrap(), Outcome::Local);
+    let reply = recv(&peers[0]).unwrap();
+    let eth = Eth { offset: 0 };
+    assert_eq!(eth.get_vlan(&reply), Some(5));
+    let arp = Arp { offset: eth.get_payload_offset(&reply) };
+    assert_eq!(arp.get_oper(&reply[arp.offset..]), arp::REPLY);
+    assert_eq!(arp.get_sha(&reply[arp.offset..]), link_mac(0));
+
+    let frame = EthBuilder::new(HOST_MAC, link_mac(0)).ipv4(src, dst).udp(5000, 53).build(b"hi").data;
+    assert_eq!(router.handle_frame(0, &mut tagged(&frame, 5), now).unwrap(),
+               Outcome::Queued { iface: 1, next_hop: IpAddr::V4(dst) });
+    assert!(matches!(router.handle_frame(0, &mut tagged(&frame, 6), now),
+                     Err(Dropped::Filtered { chain: "ingress", rule: 0 })));
+    assert_eq!(recv(&peers[0]), None);
+
+    // the rejection quotes the packet, not the tag
+    assert!(matches!(router.handle_frame(0, &mut tagged(&frame, 7), now),
+                     Err(Dropped::Filtered { chain: "ingress", rule: 1 })));
+    let error = recv(&peers[0]).unwrap();
+    assert_eq!(eth.get_dst(&error), HOST_MAC);
+    assert_eq!(&error[ETH_LEN + IPV4_LEN..ETH_LEN + IPV4_LEN + 2], &[icmpv4::DEST_UNREACH, 13]);
+    assert_eq!(&error[ETH_LEN + IPV4_LEN + ICMP_LEN..], &frame[ETH_LEN..]);
+}
+
+#[test]
+fn test_arp_resolves_the_next_hop() {
+    let (mut router, peers) = test_router(DevType::Tap, [1500, 1500]);
+    let now = Instant::now();
+    let dst = Ipv4Addr::new(10, 0, 1, 2);
+    let frame = EthBuilder::new(HOST_MAC, link_mac(0))
+        .ipv4(Ipv4Addr::new(10, 0, 0, 2), dst)
+        .udp(5000, 53)
+        .build(b"hi")
+        .data;
+    for _ in 0..2 {
+        assert_eq!(router.handle_frame(0, &mut frame.clone(), now).unwrap(),
+                   Outcome::Queued { iface: 1, next_hop: IpAddr::V4(dst) });
+    }
+
+    // asked once, for both
+    let mut request = recv(&peers[1]).unwrap();
+    assert_eq!(recv(&peers[1]), None);
+    let arp = Arp { offset: ETH_LEN };
+    assert_eq!(arp.get_oper(&request[ETH_LEN..]), arp::REQUEST);
+    assert_eq!(arp.get_spa(&request[ETH_LEN..]), [10, 0, 1, 1]);
+    assert_eq!(arp.get_tpa(&request[ETH_LEN..]), dst.octets());
+
+    arp.make_reply(&mut request[ETH_LEN..], HOST_MAC);
+    let eth = Eth { offset: 0 };
+    eth.set_dst(&mut request, link_mac(1));
+    eth.set_src(&mut request, HOST_MAC);
+    assert_eq!(router.handle_frame(1, &mut request, now).unwrap(), Outcome::Local);
+    for _ in 0..2 {
+        let out = recv(&peers[1]).unwrap();
+        assert_eq!((eth.get_dst(&out), eth.get_src(&out)), (HOST_MAC, link_mac(1)));
+        assert_eq!(Ipv4 { offset: 0 }.get_ttl(&out[ETH_LEN..]), 63);
+    }
+    assert_eq!(recv(&peers[1]), None);
+
+    // and the next one goes straight out
+    assert_eq!(router.handle_frame(0, &mut frame.clone(), now).unwrap(),
+               Outcome::Forwarded { iface: 1, next_hop: IpAddr::V4(dst) });
+}
+
+#[test]
+fn test_ndp_resolves_the_next_hop() {
+    let (mut router, peers) = test_router(DevType::Tap, [1500, 1500]);
+    let now = Instant::now();
+    let dst: Ipv6Addr = "fd00:1::2".parse().unwrap();
+    let mut frame = EthBuilder::new(HOST_MAC, link_mac(0))
+        .ipv6("fd00::2".parse().unwrap(), dst)
+        .udp(5000, 53)
+        .build(b"hi")
+        .data;
+    assert_eq!(router.handle_frame(0, &mut frame, now).unwrap(),
+               Outcome::Queued { iface: 1, next_hop: IpAddr::V6(dst) });
+
+    let solicit = recv(&peers[1]).unwrap();
+    let eth = Eth { offset: 0 };
+    assert_eq!(eth.get_dst(&solicit), neigh::multicast_mac(neigh::solicited_node(dst)));
+    assert_eq!(solicit[ETH_LEN + IPV6_LEN], icmpv6::NEIGHBOR_SOLICIT);
+    let body = &solicit[ETH_LEN + IPV6_LEN + ICMP_LEN..];
+    assert_eq!(neigh::parse_ndp(body, ndp_options::SRC_LINK_ADDR), Some((dst, Some(link_mac(1)))));
+
+    let mut advert = neigh::neighbor_advert(HOST_MAC, dst, "fd00:1::1".parse().unwrap(), link_mac(1),
+                                            neigh::NA_SOLICITED | neigh::NA_OVERRIDE);
+    assert_eq!(router.handle_frame(1, &mut advert, now).unwrap(), Outcome::Local);
+    let out = recv(&peers[1]).unwrap();
+    assert_eq!(eth.get_dst(&out), HOST_MAC);
+    assert_eq!(Ipv6 { offset: 0 }.get_hop_limit(&out[ETH_LEN..]), 63);
+}
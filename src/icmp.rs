@@ -0,0 +1,122 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use packet::builder::{Ipv4Builder, Ipv6Builder, ICMP_LEN, IPV4_LEN, IPV6_LEN, PROTO_ICMPV4,
+                      PROTO_ICMPV6};
+use packet::icmpv4;
+use packet::icmpv6;
+use packet::ipv4::Ipv4;
+use packet::ipv6::{header_types, Ipv6};
+use packet::pkt::{self, HasNetworkLayer};
+
+// icmp errors
+//
+// What a router sends back about a packet it didn't deliver, for both ip
+// versions. Forwarding answers with these when there's no route, the ttl
+// runs out or a packet doesn't fit, the firewall when it rejects.
+
+// icmp errors by what they say, the numbers differ between versions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IcmpError {
+    NoRoute,
+    Prohibited,
+    TimeExceeded,
+    // the packet doesn't fit the next link's mtu, and can't be fragmented
+    TooBig(usize)
+}
+
+impl IcmpError {
+    // type, code and the rest of the header
+    fn v4(self) -> (u8, u8, u32) {
+        match self {
+            IcmpError::NoRoute      => (icmpv4::DEST_UNREACH, 0, 0),
+            IcmpError::Prohibited   => (icmpv4::DEST_UNREACH, 13, 0),
+            IcmpError::TimeExceeded => (icmpv4::TIME_EXCEEDED, 0, 0),
+            // fragmentation needed, with the next hop mtu (RFC 1191)
+            IcmpError::TooBig(mtu)  => (icmpv4::DEST_UNREACH, 4, mtu.min(0xFFFF) as u32)
+        }
+    }
+
+    fn v6(self) -> (u8, u8, u32) {
+        match self {
+            IcmpError::NoRoute      => (icmpv6::DEST_UNREACH, 0, 0),
+            IcmpError::Prohibited   => (icmpv6::DEST_UNREACH, 1, 0),
+            IcmpError::TimeExceeded => (icmpv6::TIME_EXCEEDED, 0, 0),
+            IcmpError::TooBig(mtu)  => (icmpv6::PACKET_TOO_BIG, 0, mtu as u32)
+        }
+    }
+}
+
+// without what the link padded it with
+pub fn unpadded(packet: &[u8]) -> Option<&[u8]> {
+    let len = match packet.first()? >> 4 {
+        4 => Ipv4 { offset: 0 }.get_len(packet.get(..IPV4_LEN)?) as usize,
+        _ => IPV6_LEN + Ipv6 { offset: 0 }.get_payload_len(packet.get(..IPV6_LEN)?) as usize
+    };
+    Some(&packet[..len.min(packet.len())])
+}
+
+// Where `packet` came from, and its icmp type if it's icmp. Nothing for a
+// fragment but the first, or anything we can't see the transport of.
+fn origin(packet: &[u8]) -> Option<(IpAddr, Option<u8>)> {
+    let (src, start, icmp) = match packet.first()? >> 4 {
+        4 => {
+            let ip = Ipv4 { offset: 0 };
+            let start = ip.header_len(packet).ok()?;
+            if ip.get_frag_offs(packet) != 0 {
+                return None
+            }
+            (IpAddr::V4(Ipv4Addr::from(ip.get_src(packet))), start,
+             ip.get_protocol(packet) == PROTO_ICMPV4)
+        },
+        6 => {
+            pkt::check_len("ipv6", packet, IPV6_LEN).ok()?;
+            let ip = Ipv6 { offset: 0 };
+            let mut headers = ip.ext_headers(packet);
+            for header in headers.by_ref() {
+                header.ok()?;
+            }
+            if headers.protocol() == header_types::NO_NEXT {
+                return None
+            }
+            (IpAddr::V6(Ipv6Addr::from(ip.get_src(packet))), headers.end(),
+             headers.protocol() == PROTO_ICMPV6)
+        },
+        _ => return None
+    };
+    match icmp {
+        true  => Some((src, Some(*packet.get(start)?))),
+        false => Some((src, None))
+    }
+}
+
+// `error` about `packet`, from `local` back to where it came from. Nothing
+// about icmp errors, and nothing about a fragment but the first, as RFC
+// 1812 4.3.2.7 and RFC 4443 2.4 have it.
+pub fn icmp_error(packet: &[u8], local: IpAddr, error: IcmpError) -> Option<Vec<u8>> {
+    let packet = unpadded(packet)?;
+    let (src, icmp_type) = origin(packet)?;
+
+    // as much of the packet as fits in the minimum datagram, RFC 1812
+    // 4.3.2.3 and RFC 4443 2.4
+    match (local, src) {
+        (IpAddr::V4(from), IpAddr::V4(to)) => {
+            let errors = [icmpv4::DEST_UNREACH, icmpv4::TIME_EXCEEDED, icmpv4::PARAM_PROBLEM];
+            if icmp_type.is_some_and(|kind| errors.contains(&kind)) {
+                return None
+            }
+            let (kind, code, rest) = error.v4();
+            let quote = &packet[..packet.len().min(576 - IPV4_LEN - ICMP_LEN)];
+            Some(Ipv4Builder::new(from, to).icmp(kind, code).rest_of_header(rest).build(quote).data)
+        },
+        (IpAddr::V6(from), IpAddr::V6(to)) => {
+            // the informational messages start at 128
+            if icmp_type.is_some_and(|kind| kind < 128) {
+                return None
+            }
+            let (kind, code, rest) = error.v6();
+            let quote = &packet[..packet.len().min(pkt::MIN_IPV6_MTU - IPV6_LEN - ICMP_LEN)];
+            Some(Ipv6Builder::new(from, to).icmp(kind, code).rest_of_header(rest).build(quote).data)
+        },
+        _ => None
+    }
+}
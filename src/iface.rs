@@ -0,0 +1,294 @@
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use libc;
+use libc::{c_char, c_int, c_short, c_ulong};
+
+use config::{DevType, IfaceConfig, Ipv4Cidr, Ipv6Cidr};
+use packet::eth::{Eth, VLAN_TAG_LEN};
+
+// tun/tap devices
+//
+// We talk to /dev/net/tun directly, and configure the interface through the
+// usual SIOC* ioctls on a throwaway socket, so that we're not limited to what
+// the `ip` tool or a wrapper crate supports.
+
+const IFNAMSIZ: usize = 16;
+
+const TUNSETIFF:      c_ulong = 0x400454ca;
+const SIOCGIFFLAGS:   c_ulong = 0x8913;
+const SIOCSIFFLAGS:   c_ulong = 0x8914;
+const SIOCSIFADDR:    c_ulong = 0x8916;
+const SIOCSIFNETMASK: c_ulong = 0x891c;
+const SIOCGIFMTU:     c_ulong = 0x8921;
+const SIOCSIFMTU:     c_ulong = 0x8922;
+const SIOCGIFINDEX:   c_ulong = 0x8933;
+
+const IFF_TUN:     c_short = 0x0001;
+const IFF_TAP:     c_short = 0x0002;
+const IFF_NO_PI:   c_short = 0x1000;
+const IFF_UP:      c_short = 0x0001;
+const IFF_RUNNING: c_short = 0x0040;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+union IfReqData {
+    flags: c_short,
+    ivalue: c_int,
+    addr: libc::sockaddr_in,
+    pad: [u8; 24]
+}
+
+#[repr(C)]
+struct IfReq {
+    name: [c_char; IFNAMSIZ],
+    data: IfReqData
+}
+
+impl IfReq {
+    fn new(name: &str) -> io::Result<IfReq> {
+        let cname = CString::new(name)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput,
+                                        "interface name contains a nul byte"))?;
+        let bytes = cname.as_bytes();
+        if bytes.len() >= IFNAMSIZ {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "interface name too long"))
+        }
+
+        let mut req = IfReq { name: [0; IFNAMSIZ], data: IfReqData { pad: [0; 24] } };
+        for (dst, src) in req.name.iter_mut().zip(bytes) {
+            *dst = *src as c_char;
+        }
+        Ok(req)
+    }
+}
+
+#[repr(C)]
+struct In6IfReq {
+    addr: libc::in6_addr,
+    prefixlen: u32,
+    ifindex: c_int
+}
+
+fn ioctl<T>(fd: RawFd, req: c_ulong, arg: &mut T) -> io::Result<()> {
+    match unsafe { libc::ioctl(fd, req as _, arg as *mut T) } {
+        -1 => Err(io::Error::last_os_error()),
+        _  => Ok(())
+    }
+}
+
+// a socket to hang interface ioctls off
+struct CtlSocket {
+    fd: RawFd
+}
+
+impl CtlSocket {
+    fn new(family: c_int) -> io::Result<CtlSocket> {
+        match unsafe { libc::socket(family, libc::SOCK_DGRAM, 0) } {
+            -1 => Err(io::Error::last_os_error()),
+            fd => Ok(CtlSocket { fd: fd })
+        }
+    }
+}
+
+impl Drop for CtlSocket {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+fn sockaddr_v4(addr: [u8; 4]) -> libc::sockaddr_in {
+    let mut sin: libc::sockaddr_in = unsafe { mem::zeroed() };
+    sin.sin_family = libc::AF_INET as libc::sa_family_t;
+    sin.sin_addr.s_addr = u32::from_ne_bytes(addr);
+    sin
+}
+
+pub struct Tap {
+    file: File,
+    name: String,
+    dev_type: DevType,
+    mtu: usize
+}
+
+impl Tap {
+    // create (or attach to) a tun/tap device, the name may contain a %d
+    // which the kernel fills in
+    pub fn open(name: &str, dev_type: DevType) -> io::Result<Tap> {
+        let file = OpenOptions::new().read(true).write(true).open("/dev/net/tun")?;
+
+        let mut req = IfReq::new(name)?;
+        req.data.flags = IFF_NO_PI | match dev_type {
+            DevType::Tap => IFF_TAP,
+            DevType::Tun => IFF_TUN
+        };
+        ioctl(file.as_raw_fd(), TUNSETIFF, &mut req)?;
+
+        let name = req.name.iter()
+            .take_while(|&&c| c != 0)
+            .map(|&c| c as u8 as char)
+            .collect();
+        let mut tap = Tap { file: file, name: name, dev_type: dev_type, mtu: 0 };
+        // an existing device keeps whatever it was set to
+        tap.mtu = tap.get_mtu()?;
+        Ok(tap)
+    }
+
+    // A tun/tap look-alike on one end of a socketpair, the other end plays
+    // the kernel side. Seqpacket keeps frame boundaries, and it needs no root.
+    #[cfg(test)]
+    pub fn pair(dev_type: DevType, mtu: usize) -> io::Result<(Tap, File)> {
+        use std::os::unix::io::FromRawFd;
+
+        let mut fds = [0 as c_int; 2];
+        let res = unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_SEQPACKET, 0, fds.as_mut_ptr()) };
+        if res == -1 {
+            return Err(io::Error::last_os_error())
+        }
+        let (file, peer) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        let tap = Tap { file: file, name: "pair".to_string(), dev_type: dev_type, mtu: mtu };
+        Ok((tap, peer))
+    }
+
+    // open the device and bring it up with the configured addresses
+    pub fn from_config(config: &IfaceConfig) -> io::Result<Tap> {
+        let mut tap = Tap::open(&config.name, config.dev_type)?;
+        tap.set_mtu(config.mtu)?;
+        if let Some(ref cidr) = config.ipv4 {
+            tap.set_ipv4(cidr)?;
+        }
+        tap.set_up()?;
+        // the kernel only accepts ipv6 addresses on an interface that's up
+        if let Some(ref cidr) = config.ipv6 {
+            tap.set_ipv6(cidr)?;
+        }
+        Ok(tap)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn dev_type(&self) -> DevType {
+        self.dev_type
+    }
+
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    // the biggest frame a read can return: the mtu plus the link header,
+    // with room for an 802.1Q tag. Receive buffers need to be at least this
+    // big, or reads get cut short.
+    pub fn frame_size(&self) -> usize {
+        match self.dev_type {
+            DevType::Tap => self.mtu + Eth::HEADER_LEN + VLAN_TAG_LEN,
+            DevType::Tun => self.mtu
+        }
+    }
+
+    // each read or write is exactly one frame
+    pub fn read(&self, buff: &mut [u8]) -> io::Result<usize> {
+        (&self.file).read(buff)
+    }
+
+    pub fn write(&self, buff: &[u8]) -> io::Result<usize> {
+        (&self.file).write(buff)
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        let fd = self.file.as_raw_fd();
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags == -1 {
+            return Err(io::Error::last_os_error())
+        }
+        let flags = if nonblocking {
+            flags | libc::O_NONBLOCK
+        } else {
+            flags & !libc::O_NONBLOCK
+        };
+        match unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } {
+            -1 => Err(io::Error::last_os_error()),
+            _  => Ok(())
+        }
+    }
+
+    pub fn set_ipv4(&self, cidr: &Ipv4Cidr) -> io::Result<()> {
+        let sock = CtlSocket::new(libc::AF_INET)?;
+
+        let mut req = IfReq::new(&self.name)?;
+        req.data.addr = sockaddr_v4(cidr.addr.octets());
+        ioctl(sock.fd, SIOCSIFADDR, &mut req)?;
+
+        let mut req = IfReq::new(&self.name)?;
+        req.data.addr = sockaddr_v4(cidr.netmask().octets());
+        ioctl(sock.fd, SIOCSIFNETMASK, &mut req)
+    }
+
+    pub fn set_ipv6(&self, cidr: &Ipv6Cidr) -> io::Result<()> {
+        let sock = CtlSocket::new(libc::AF_INET6)?;
+
+        let mut req = In6IfReq {
+            addr: libc::in6_addr { s6_addr: cidr.addr.octets() },
+            prefixlen: cidr.prefix as u32,
+            ifindex: self.index()?
+        };
+        ioctl(sock.fd, SIOCSIFADDR, &mut req)
+    }
+
+    pub fn set_mtu(&mut self, mtu: usize) -> io::Result<()> {
+        let sock = CtlSocket::new(libc::AF_INET)?;
+        let mut req = IfReq::new(&self.name)?;
+        req.data.ivalue = mtu as c_int;
+        ioctl(sock.fd, SIOCSIFMTU, &mut req)?;
+        self.mtu = mtu;
+        Ok(())
+    }
+
+    fn get_mtu(&self) -> io::Result<usize> {
+        let sock = CtlSocket::new(libc::AF_INET)?;
+        let mut req = IfReq::new(&self.name)?;
+        ioctl(sock.fd, SIOCGIFMTU, &mut req)?;
+        Ok(unsafe { req.data.ivalue } as usize)
+    }
+
+    pub fn set_up(&self) -> io::Result<()> {
+        let sock = CtlSocket::new(libc::AF_INET)?;
+        let mut req = IfReq::new(&self.name)?;
+        ioctl(sock.fd, SIOCGIFFLAGS, &mut req)?;
+        unsafe { req.data.flags |= IFF_UP | IFF_RUNNING };
+        ioctl(sock.fd, SIOCSIFFLAGS, &mut req)
+    }
+
+    pub fn index(&self) -> io::Result<c_int> {
+        let sock = CtlSocket::new(libc::AF_INET)?;
+        let mut req = IfReq::new(&self.name)?;
+        ioctl(sock.fd, SIOCGIFINDEX, &mut req)?;
+        Ok(unsafe { req.data.ivalue })
+    }
+}
+
+impl AsRawFd for Tap {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+
+// testing
+#[test]
+fn test_frame_size_fits_a_tagged_frame() {
+    let tap = |dev_type| Tap {
+        file: File::open("/dev/null").unwrap(),
+        name: "test".to_string(),
+        dev_type: dev_type,
+        mtu: 1500
+    };
+    assert_eq!(tap(DevType::Tap).frame_size(), 1500 + 14 + 4);
+    assert_eq!(tap(DevType::Tun).frame_size(), 1500);
+}
@@ -0,0 +1,29 @@
+// field: field is how this crate has always been written
+#![allow(clippy::redundant_field_names)]
+
+extern crate libc;
+extern crate toml;
+extern crate paste;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+
+pub mod packet;
+pub mod util;
+pub mod root;
+pub mod config;
+pub mod iface;
+pub mod pcap;
+pub mod reactor;
+pub mod lpm;
+pub mod route;
+pub mod neigh;
+pub mod icmp;
+pub mod nat;
+pub mod nat64;
+pub mod conntrack;
+pub mod firewall;
+pub mod rewrite;
+pub mod netem;
+pub mod forward;
+#[cfg(feature = "tokio")]
+pub mod aio;
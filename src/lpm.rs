@@ -0,0 +1,346 @@
+use std::fmt;
+use std::marker::PhantomData;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+// longest prefix match
+//
+// A map from ip prefixes to values, for routing tables and address based
+// filters. It's a path compressed binary trie: every node holds a prefix,
+// and a node only exists where a prefix was inserted or where two branches
+// part, so a lookup visits at most one node per distinct prefix length on
+// its path rather than one per bit.
+//
+// Addresses are kept left aligned in a u128, which lets one trie serve
+// ipv4 and ipv6 alike.
+
+pub trait RouteAddr: Copy + Eq + fmt::Display + fmt::Debug {
+    const BITS: u8;
+
+    // the address left aligned in a u128
+    fn to_bits(&self) -> u128;
+    fn from_bits(bits: u128) -> Self;
+}
+
+impl RouteAddr for Ipv4Addr {
+    const BITS: u8 = 32;
+
+    fn to_bits(&self) -> u128 {
+        (u32::from(*self) as u128) << 96
+    }
+
+    fn from_bits(bits: u128) -> Ipv4Addr {
+        Ipv4Addr::from((bits >> 96) as u32)
+    }
+}
+
+impl RouteAddr for Ipv6Addr {
+    const BITS: u8 = 128;
+
+    fn to_bits(&self) -> u128 {
+        u128::from(*self)
+    }
+
+    fn from_bits(bits: u128) -> Ipv6Addr {
+        Ipv6Addr::from(bits)
+    }
+}
+
+pub fn mask(prefix: u8) -> u128 {
+    match prefix {
+        0 => 0,
+        n => !0u128 << (128 - n as u32)
+    }
+}
+
+// whether `addr` is in `prefix`/`len`, for a lone prefix that doesn't need
+// a map
+pub fn covers(prefix: IpAddr, len: u8, addr: IpAddr) -> bool {
+    let mask = mask(len);
+    match (prefix, addr) {
+        (IpAddr::V4(p), IpAddr::V4(a)) => (RouteAddr::to_bits(&p) ^ RouteAddr::to_bits(&a)) & mask == 0,
+        (IpAddr::V6(p), IpAddr::V6(a)) => (RouteAddr::to_bits(&p) ^ RouteAddr::to_bits(&a)) & mask == 0,
+        _ => false
+    }
+}
+
+// bit `idx` counting from the left
+fn bit(bits: u128, idx: u8) -> usize {
+    ((bits >> (127 - idx as u32)) & 1) as usize
+}
+
+// how many leading bits `a` and `b` share, at most `max`
+fn common(a: u128, b: u128, max: u8) -> u8 {
+    ((a ^ b).leading_zeros() as u8).min(max)
+}
+
+struct Node<V> {
+    bits: u128,
+    prefix: u8,
+    value: Option<V>,
+    children: [Option<Box<Node<V>>>; 2]
+}
+
+impl<V> Node<V> {
+    fn new(bits: u128, prefix: u8, value: Option<V>) -> Box<Node<V>> {
+        Box::new(Node { bits: bits, prefix: prefix, value: value, children: [None, None] })
+    }
+
+    fn covers(&self, bits: u128) -> bool {
+        (bits ^ self.bits) & mask(self.prefix) == 0
+    }
+}
+
+pub struct PrefixMap<A, V> {
+    root: Option<Box<Node<V>>>,
+    len: usize,
+    addr: PhantomData<A>
+}
+
+impl<A: RouteAddr, V> PrefixMap<A, V> {
+    pub fn new() -> PrefixMap<A, V> {
+        PrefixMap { root: None, len: 0, addr: PhantomData }
+    }
+
+    // Host bits past the prefix are ignored. Returns the value that was
+    // there for the same prefix.
+    pub fn insert(&mut self, addr: A, prefix: u8, value: V) -> Option<V> {
+        let prefix = prefix.min(A::BITS);
+        let bits = addr.to_bits() & mask(prefix);
+        let old = insert(&mut self.root, bits, prefix, value);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    pub fn remove(&mut self, addr: A, prefix: u8) -> Option<V> {
+        let prefix = prefix.min(A::BITS);
+        let bits = addr.to_bits() & mask(prefix);
+        let old = remove(&mut self.root, bits, prefix);
+        if old.is_some() {
+            self.len -= 1;
+        }
+        old
+    }
+
+    // the value for exactly this prefix
+    pub fn get(&self, addr: A, prefix: u8) -> Option<&V> {
+        let prefix = prefix.min(A::BITS);
+        let bits = addr.to_bits() & mask(prefix);
+        let mut node = self.root.as_ref();
+        while let Some(n) = node {
+            if n.prefix > prefix || !n.covers(bits) {
+                return None
+            }
+            if n.prefix == prefix {
+                return n.value.as_ref()
+            }
+            node = n.children[bit(bits, n.prefix)].as_ref();
+        }
+        None
+    }
+
+    // the longest prefix covering `addr`, and its value
+    pub fn lookup(&self, addr: A) -> Option<(A, u8, &V)> {
+        let bits = addr.to_bits();
+        let mut best = None;
+        let mut node = self.root.as_ref();
+        while let Some(n) = node {
+            if !n.covers(bits) {
+                break
+            }
+            if let Some(ref value) = n.value {
+                best = Some((A::from_bits(n.bits), n.prefix, value));
+            }
+            if n.prefix >= A::BITS {
+                break
+            }
+            node = n.children[bit(bits, n.prefix)].as_ref();
+        }
+        best
+    }
+
+    pub fn contains(&self, addr: A) -> bool {
+        self.lookup(addr).is_some()
+    }
+
+    // in address order, shorter prefixes first
+    pub fn iter(&self) -> Iter<'_, A, V> {
+        Iter { stack: self.root.iter().map(|node| &**node).collect(), addr: PhantomData }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.root = None;
+        self.len = 0;
+    }
+}
+
+impl<A: RouteAddr, V> Default for PrefixMap<A, V> {
+    fn default() -> PrefixMap<A, V> {
+        PrefixMap::new()
+    }
+}
+
+fn insert<V>(slot: &mut Option<Box<Node<V>>>, bits: u128, prefix: u8, value: V) -> Option<V> {
+    let node = match *slot {
+        Some(ref mut node) => node,
+        None => {
+            *slot = Some(Node::new(bits, prefix, Some(value)));
+            return None
+        }
+    };
+
+    let shared = common(bits, node.bits, prefix.min(node.prefix));
+    if shared == node.prefix {
+        if prefix == node.prefix {
+            return node.value.replace(value)
+        }
+        // below this node
+        let child = bit(bits, node.prefix);
+        return insert(&mut node.children[child], bits, prefix, value)
+    }
+
+    // the new prefix parts from this node's path above it: a new node
+    // goes in at the point they part, with the old one under it
+    let old = slot.take().unwrap();
+    let mut fork = if shared == prefix {
+        Node::new(bits, prefix, Some(value))
+    } else {
+        let mut fork = Node::new(bits & mask(shared), shared, None);
+        fork.children[bit(bits, shared)] = Some(Node::new(bits, prefix, Some(value)));
+        fork
+    };
+    let side = bit(old.bits, shared);
+    fork.children[side] = Some(old);
+    *slot = Some(fork);
+    None
+}
+
+fn remove<V>(slot: &mut Option<Box<Node<V>>>, bits: u128, prefix: u8) -> Option<V> {
+    let old = {
+        let node = match *slot {
+            Some(ref mut node) => node,
+            None => return None
+        };
+        if node.prefix > prefix || !node.covers(bits) {
+            return None
+        }
+        if node.prefix == prefix {
+            node.value.take()
+        } else {
+            let child = bit(bits, node.prefix);
+            remove(&mut node.children[child], bits, prefix)
+        }
+    };
+
+    // a node without a value is only worth keeping where two paths part
+    if old.is_some() {
+        let mut node = slot.take().unwrap();
+        *slot = match (node.value.is_some(), node.children[0].take(), node.children[1].take()) {
+            (false, None, None) => None,
+            (false, Some(child), None) | (false, None, Some(child)) => Some(child),
+            (_, left, right) => {
+                node.children = [left, right];
+                Some(node)
+            }
+        };
+    }
+    old
+}
+
+pub struct Iter<'a, A, V: 'a> {
+    stack: Vec<&'a Node<V>>,
+    addr: PhantomData<A>
+}
+
+impl<'a, A: RouteAddr, V> Iterator for Iter<'a, A, V> {
+    type Item = (A, u8, &'a V);
+
+    fn next(&mut self) -> Option<(A, u8, &'a V)> {
+        while let Some(node) = self.stack.pop() {
+            for child in node.children.iter().rev() {
+                if let Some(ref child) = *child {
+                    self.stack.push(child);
+                }
+            }
+            if let Some(ref value) = node.value {
+                return Some((A::from_bits(node.bits), node.prefix, value))
+            }
+        }
+        None
+    }
+}
+
+
+// testing
+#[test]
+fn test_prefix_map_against_linear_scan() {
+    // xorshift, so the test sees the same prefixes every time
+    let mut state = 0x2545_F491_4F6C_DD1Du64;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+
+    let mut map = PrefixMap::new();
+    let mut linear: Vec<(u32, u8, usize)> = Vec::new();
+    for idx in 0..2000 {
+        // few distinct top bits, so prefixes nest and share paths
+        let addr = (next() as u32) & 0xF0FF_FF00;
+        let prefix = (next() % 33) as u8;
+        let addr = addr & (mask(prefix) >> 96) as u32;
+        map.insert(Ipv4Addr::from(addr), prefix, idx);
+        linear.retain(|&(a, p, _)| (a, p) != (addr, prefix));
+        linear.push((addr, prefix, idx));
+        if idx % 3 == 0 {
+            let (a, p, _) = linear.remove((next() % linear.len() as u64) as usize);
+            assert!(map.remove(Ipv4Addr::from(a), p).is_some());
+        }
+    }
+    assert_eq!(map.len(), linear.len());
+    assert_eq!(map.iter().count(), linear.len());
+
+    for _ in 0..10000 {
+        let addr = (next() as u32) & 0xF0FF_FFFF;
+        let expected = linear.iter()
+            .filter(|&&(a, p, _)| addr & (mask(p) >> 96) as u32 == a)
+            .max_by_key(|&&(_, p, _)| p)
+            .map(|&(a, p, v)| (Ipv4Addr::from(a), p, v));
+        assert_eq!(map.lookup(Ipv4Addr::from(addr)).map(|(a, p, v)| (a, p, *v)), expected);
+    }
+}
+
+#[test]
+fn test_prefix_map_ipv6() {
+    let mut map6 = PrefixMap::new();
+    map6.insert("::".parse::<Ipv6Addr>().unwrap(), 0, "default");
+    map6.insert("fd00::".parse().unwrap(), 8, "ula");
+    map6.insert("fd00:1::".parse().unwrap(), 32, "site");
+    assert_eq!(map6.lookup("fd00:1::5".parse().unwrap()).map(|(_, _, v)| *v), Some("site"));
+    assert_eq!(map6.lookup("fd99::".parse().unwrap()).map(|(_, _, v)| *v), Some("ula"));
+    assert_eq!(map6.remove("fd00::".parse().unwrap(), 8), Some("ula"));
+    assert_eq!(map6.lookup("fd99::".parse().unwrap()).map(|(_, p, _)| p), Some(0));
+    assert_eq!(map6.get("fd00:1::".parse().unwrap(), 32), Some(&"site"));
+}
+
+#[test]
+fn test_covers() {
+    let addr = |s: &str| s.parse::<IpAddr>().unwrap();
+    assert!(covers(addr("10.1.0.0"), 16, addr("10.1.255.3")));
+    assert!(!covers(addr("10.1.0.0"), 16, addr("10.2.0.1")));
+    assert!(covers(addr("0.0.0.0"), 0, addr("192.0.2.1")));
+    assert!(covers(addr("fd00::"), 8, addr("fdff::1")));
+    assert!(!covers(addr("fd00::1"), 128, addr("fd00::2")));
+    // the families don't mix
+    assert!(!covers(addr("::"), 0, addr("10.0.0.1")));
+}
@@ -1,56 +1,491 @@
-#![feature(plugin)]
-#![plugin(net_bits)]
+extern crate chucker;
 
-extern crate libc;
-extern crate tuntap;
+use std::env;
+use std::fs;
+use std::io;
+use std::net::Ipv6Addr;
+use std::os::unix::io::AsRawFd;
+use std::process;
+use std::time::{Duration, Instant, SystemTime};
 
-mod packet;
-mod util;
-mod root;
+use chucker::{config, iface, pcap, reactor, rewrite, root, util};
+use chucker::forward::{Dropped, Outcome, Router};
+use chucker::packet::{eth, pkt};
+use chucker::packet::builder::{EthBuilder, Ipv6Builder, IPV6_LEN, ICMP_LEN};
+use chucker::packet::eth::Eth;
+use chucker::packet::frag::{Ipv4Reassembler, Ipv6Reassembler, FragError, Limits};
+use chucker::packet::ipv4::Ipv4;
+use chucker::packet::ipv6::Ipv6;
+use chucker::packet::srv6::{self, Endpoint};
+use chucker::packet::pool::BufferPool;
+use chucker::packet::view::{PacketView, PacketViewMut};
+use chucker::config::{Config, DevType, Mode};
+use chucker::reactor::{Event, Interest, Reactor};
 
-use std::time::Duration;
-use std::thread;
+const TAP: reactor::Token = 0;
 
-use packet::pkt;
-use packet::eth;
+// granularity of the timer wheel
+const TICK: Duration = Duration::from_millis(10);
+
+// receive buffers kept around between frames
+const POOL_SIZE: usize = 64;
+
+// how often incomplete datagrams are checked for timing out
+const FRAG_EXPIRY: Duration = Duration::from_secs(1);
+
+// how often neighbours are asked again, or forgotten
+const NEIGH_EXPIRY: Duration = Duration::from_secs(1);
+
+// things we get woken up for by the reactor
+enum Timer {
+    FragExpiry,
+    NeighExpiry
+}
 
 // mainzy
 fn main() {
     root::condescend();
-    let as_root = root::Root::new();
 
-    let mut tap = tuntap::TunTap::create_named_from_address(
-        tuntap::Tap, "tap0", "10.0.0.1"
-    );
+    let args: Vec<String> = env::args().skip(1).collect();
+    let config = match config::from_args(&args) {
+        Ok(Some(config)) => config,
+        Ok(None) => {
+            println!("{}", config::USAGE);
+            return
+        },
+        Err(msg) => {
+            eprintln!("chucker: {}\n\n{}", msg, config::USAGE);
+            process::exit(2)
+        }
+    };
+
+    // only forwarding has a use for more than the first interface
+    let ifaces = match config.mode {
+        Mode::Forward => config.ifaces(),
+        _             => vec!(&config.iface)
+    };
+    let mut taps: Vec<iface::Tap> = {
+        let _as_root = root::Root::new().unwrap_or_else(|e| {
+            eprintln!("chucker: can't get privileges to set up {}: {}",
+                      config.iface.name, e);
+            process::exit(1)
+        });
+        ifaces.iter().map(|iface| {
+            iface::Tap::from_config(iface).unwrap_or_else(|e| {
+                eprintln!("chucker: can't set up {}: {}", iface.name, e);
+                process::exit(1)
+            })
+        }).collect()
+    };
+
+    if config.drops_privileges() {
+        let user = config.user.as_ref().map(|s| &s[..]);
+        let group = config.group.as_ref().map(|s| &s[..]);
+        root::drop_to(user, group).unwrap_or_else(|e| {
+            eprintln!("chucker: can't drop privileges: {}", e);
+            process::exit(1)
+        });
+    }
+
+    match config.mode {
+        Mode::Replay(ref path) => replay(&mut taps[0], path, &config),
+        Mode::Forward          => forward(taps, &config, &args),
+        _                      => run(&mut taps[0], &config)
+    }
+}
+
+fn link_for(dev_type: DevType) -> pkt::Link {
+    match dev_type {
+        DevType::Tap => pkt::Link::EthLink(eth::Eth { offset: 0 }),
+        DevType::Tun => pkt::Link::RawLink
+    }
+}
+
+fn print_packet(packet: &PacketView, config: &Config) {
+    if config.verbosity == 0 || !wanted(packet, config) {
+        return
+    }
+    println!("\n-----\n");
+    packet.print();
+    if config.verbosity > 1 {
+        println!("\n[{}]", util::to_hex_string(packet.data()));
+    }
+}
+
+fn wanted(packet: &PacketView, config: &Config) -> bool {
+    config.filter.as_ref().is_none_or(|filter| filter.matches(packet))
+}
+
+fn report_undissected(e: &pkt::DissectError, config: &Config) {
+    if config.verbosity > 0 {
+        println!("\n-----\n\ndropped: {}", e);
+    }
+}
+
+fn run(tap: &mut iface::Tap, config: &Config) {
+    tap.set_nonblocking(true).unwrap();
+    let mut reactor: Reactor<Timer> = Reactor::new(TICK).unwrap();
+    reactor.register(tap.as_raw_fd(), TAP, Interest::Read).unwrap();
+
+    let mut pool = BufferPool::new(tap.frame_size(), POOL_SIZE);
+    let link = link_for(tap.dev_type());
+    let mut frags = Frags {
+        v4: Ipv4Reassembler::new(Limits::ipv4()),
+        v6: Ipv6Reassembler::new(Limits::ipv6())
+    };
+    reactor.schedule(FRAG_EXPIRY, Timer::FragExpiry);
+
+    loop {
+        for event in reactor.poll(None).unwrap() {
+            match event {
+                Event::Readable(TAP) => loop {
+                    let mut buffer = pool.get();
+                    match tap.read(&mut buffer) {
+                        Ok(len) => {
+                            match PacketViewMut::with_link(&mut buffer[..len], &link) {
+                                Ok(packet) => handle_packet(tap, packet, &mut frags, config),
+                                Err(e) => report_undissected(&e, config)
+                            }
+                        },
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            pool.put(buffer);
+                            break
+                        },
+                        Err(e) => panic!("reading from {}: {}", tap.name(), e)
+                    }
+                    pool.put(buffer);
+                },
+                Event::Closed(TAP) => panic!("{} went away", tap.name()),
+                Event::Timer(_, Timer::FragExpiry) => {
+                    let now = Instant::now();
+                    frags.v4.expire(now);
+                    frags.v6.expire(now);
+                    reactor.schedule(FRAG_EXPIRY, Timer::FragExpiry);
+                },
+                _ => ()
+            }
+        }
+    }
+}
+
+// when the config file was last changed, if there is one we can look at
+fn modified(config: &Config) -> Option<SystemTime> {
+    config.file.as_ref().and_then(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+}
+
+// `args` are read again to pick up changed firewall rules
+fn forward(taps: Vec<iface::Tap>, config: &Config, args: &[String]) {
+    let mut router = Router::new(taps, config).unwrap_or_else(|e| {
+        eprintln!("chucker: {}", e);
+        process::exit(2)
+    });
+    let mut reactor: Reactor<Timer> = Reactor::new(TICK).unwrap();
+    let mut frame_size = 0;
+    for (idx, link) in router.links.iter().enumerate() {
+        link.tap.set_nonblocking(true).unwrap();
+        reactor.register(link.tap.as_raw_fd(), idx as reactor::Token, Interest::Read).unwrap();
+        frame_size = frame_size.max(link.tap.frame_size());
+    }
+    let mut pool = BufferPool::new(frame_size, POOL_SIZE);
+    reactor.schedule(NEIGH_EXPIRY, Timer::NeighExpiry);
+    // the rules are reread as whoever we run as by now
+    let reload = config.file.as_ref().is_some_and(|path| match fs::File::open(path) {
+        Ok(_) => true,
+        Err(e) => {
+            eprintln!("chucker: can't read {}, firewall rules won't be reloaded: {}", path, e);
+            false
+        }
+    });
+    let mut last_modified = modified(config);
 
-    // thread::sleep(Duration::from_millis(2000000));
     loop {
+        // the wheel's tick is too coarse for what the impairments hold back
+        let timeout = router.netem.next_due().map(|due| due.saturating_duration_since(Instant::now()));
+        for event in reactor.poll(timeout).unwrap() {
+            match event {
+                Event::Readable(token) => loop {
+                    let idx = token as usize;
+                    let mut buffer = pool.get();
+                    let len = match router.links[idx].tap.read(&mut buffer) {
+                        Ok(len) => len,
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            pool.put(buffer);
+                            break
+                        },
+                        Err(e) => panic!("reading from {}: {}", router.links[idx].tap.name(), e)
+                    };
+                    let link = link_for(router.links[idx].tap.dev_type());
+                    if let Ok(packet) = PacketView::with_link(&buffer[..len], &link) {
+                        print_packet(&packet, config);
+                    }
+                    let now = Instant::now();
+                    match router.netem.ingress.push(idx, &buffer[..len], &link, now) {
+                        Ok(true) => (),
+                        Ok(false) => {
+                            let outcome = router.handle_frame(idx, &mut buffer[..len], now);
+                            report_forwarded(&router, outcome, config);
+                        },
+                        Err(e) => report_forwarded(&router, Err(Dropped::Lost(e)), config)
+                    }
+                    pool.put(buffer);
+                },
+                Event::Closed(token) =>
+                    panic!("{} went away", router.links[token as usize].tap.name()),
+                Event::Timer(_, Timer::NeighExpiry) => {
+                    match router.expire(Instant::now()) {
+                        Ok(ended) => if config.verbosity > 1 {
+                            for flow in ended {
+                                println!("\nflow ended: {}", flow);
+                            }
+                        },
+                        Err(e) => eprintln!("chucker: {}", e)
+                    }
+                    // the rest of a changed config only takes on a restart
+                    let now_modified = modified(config);
+                    if reload && now_modified != last_modified {
+                        last_modified = now_modified;
+                        match config::from_args(args) {
+                            Ok(Some(new)) => {
+                                router.firewall.reload(new.firewall.ingress, new.firewall.egress);
+                                if config.verbosity > 0 {
+                                    println!("\nreloaded firewall rules");
+                                }
+                            },
+                            Ok(None) => (),
+                            Err(e) => eprintln!("chucker: keeping the old firewall rules: {}", e)
+                        }
+                    }
+                    reactor.schedule(NEIGH_EXPIRY, Timer::NeighExpiry);
+                },
+                _ => ()
+            }
+        }
+
+        // whatever the impairments held back that's due by now
+        let now = Instant::now();
+        while let Some((idx, mut frame)) = router.netem.ingress.pop(now) {
+            let outcome = router.handle_frame(idx, &mut frame, now);
+            report_forwarded(&router, outcome, config);
+        }
+        if let Err(e) = router.release(now) {
+            report_forwarded(&router, Err(e), config);
+        }
+    }
+}
 
-        let mut buffer = vec![0u8; pkt::MTU_SIZE];
-        let len = tap.read(&mut buffer).unwrap();
-        let mut packet = pkt::make_eth_packet(buffer, len);
-        println!("\n-----\n");
-        packet.print();
+fn report_forwarded(router: &Router, outcome: Result<Outcome, Dropped>,
+                    config: &Config) {
+    if config.verbosity == 0 {
+        return
+    }
+    match outcome {
+        Ok(Outcome::Forwarded { iface, next_hop }) =>
+            println!("\nforwarded to {} on {}", next_hop, router.links[iface].tap.name()),
+        Ok(Outcome::Queued { iface, next_hop }) =>
+            println!("\nwaiting for {} on {}", next_hop, router.links[iface].tap.name()),
+        Ok(Outcome::Local) => (),
+        Err(e) => println!("\ndropped: {}", e)
+    }
+}
 
-        match packet.net {
-            pkt::Network::Ipv6Net(ref mut ipv6) => {
-                let data = &mut packet.data[ipv6.offset..];
+fn handle_packet(tap: &mut iface::Tap, packet: PacketViewMut,
+                 frags: &mut Frags, config: &Config) {
+    print_packet(&packet.as_view(), config);
+    reassemble(&packet.as_view(), frags, config);
 
-                let src = ipv6.get_src(data);
-                let dst = ipv6.get_dst(data);
+    match config.mode {
+        Mode::Reflect => reflect(tap, packet, config),
+        Mode::Serve   => serve(tap, packet, config),
+        _             => ()
+    }
+}
 
-                ipv6.set_src(data, dst);
-                ipv6.set_dst(data, src)
+fn reflect(tap: &mut iface::Tap, mut packet: PacketViewMut, config: &Config) {
+    if !config.rewrite.is_empty() {
+        match rewrite::rewrite(&config.rewrite, &packet.as_view()) {
+            Ok(Some(frame)) => if let Err(e) = tap.write(&frame) {
+                eprintln!("chucker: writing to {}: {}", tap.name(), e);
             },
-            pkt::Network::Ipv4Net(ref mut ipv4) => {
-                let data = &mut packet.data[ipv4.offset..];
-                let src = ipv4.get_src(data);
-                let dst = ipv4.get_dst(data);
+            Ok(None) => (),
+            Err(e) => if config.verbosity > 0 {
+                println!("\ndropped: {}", e);
+            }
+        }
+        return
+    }
+    if let Some(mut ipv6) = packet.ipv6() {
+        let src = ipv6.get(Ipv6::get_src);
+        let dst = ipv6.get(Ipv6::get_dst);
+
+        ipv6.set(Ipv6::set_src, dst);
+        ipv6.set(Ipv6::set_dst, src);
+    } else if let Some(mut ipv4) = packet.ipv4() {
+        let src = ipv4.get(Ipv4::get_src);
+        let dst = ipv4.get(Ipv4::get_dst);
+
+        ipv4.set(Ipv4::set_src, dst);
+        ipv4.set(Ipv4::set_dst, src);
+    } else {
+        // nothing sensible to bounce back
+        return
+    }
+    if let Err(e) = tap.write(packet.data()) {
+        eprintln!("chucker: writing to {}: {}", tap.name(), e);
+    }
+}
+
+struct Frags {
+    v4: Ipv4Reassembler,
+    v6: Ipv6Reassembler
+}
 
-                ipv4.set_src(data, dst);
-                ipv4.set_dst(data, src)
+// The part of serving done here rather than by a socket: acting as an
+// SRv6 endpoint for our ipv6 address. Packets forwarded to the next
+// segment go back out to whoever sent them, the host routes them on.
+fn serve(tap: &mut iface::Tap, mut packet: PacketViewMut, config: &Config) {
+    let local = match config.iface.peer_ipv6 {
+        Some(addr) => addr,
+        None => return
+    };
+    let result = match packet.ipv6() {
+        Some(mut ipv6) => {
+            if Ipv6Addr::from(ipv6.get(Ipv6::get_dst)) != local {
+                return
+            }
+            if let Err(e) = ipv6.get(Ipv6::check_options) {
+                if config.verbosity > 0 {
+                    println!("\ndropped: {}", e);
+                }
+                if let Some(icmp) = e.icmp(false) {
+                    send_icmpv6_error(tap, &packet.as_view(), local, icmp);
+                }
+                return
+            }
+            srv6::process_routing(ipv6.bytes())
+        },
+        None => return
+    };
+
+    match result {
+        Ok(Endpoint::Local) => (),
+        Ok(Endpoint::Forward(next)) => {
+            if config.verbosity > 0 {
+                println!("\nforwarded to {}", next);
+            }
+            if let Some(mut eth) = packet.eth_header() {
+                let src = eth.get(Eth::get_src);
+                let dst = eth.get(Eth::get_dst);
+                eth.set(Eth::set_src, dst);
+                eth.set(Eth::set_dst, src);
+            }
+            if let Err(e) = tap.write(packet.data()) {
+                eprintln!("chucker: writing to {}: {}", tap.name(), e);
+            }
+        },
+        Err(e) => {
+            if config.verbosity > 0 {
+                println!("\ndropped: {}", e);
+            }
+            if let Some(icmp) = e.icmp() {
+                send_icmpv6_error(tap, &packet.as_view(), local, icmp);
             }
         }
-        let res = tap.write(&packet.data[..packet.len]);
+    }
+}
+
+// `icmp` is (type, code, rest of header). RFC 4443: as much of the
+// offending packet as fits in the minimum mtu.
+fn send_icmpv6_error(tap: &mut iface::Tap, packet: &PacketView, local: Ipv6Addr,
+                     icmp: (u8, u8, u32)) {
+    let (icmp_type, code, rest) = icmp;
+    let ipv6 = match packet.ipv6() {
+        Some(ipv6) => ipv6,
+        None => return
+    };
+    let src = Ipv6Addr::from(ipv6.get(Ipv6::get_src));
+    let invoking = ipv6.bytes();
+    let invoking = &invoking[..invoking.len().min(pkt::MIN_IPV6_MTU - IPV6_LEN - ICMP_LEN)];
+
+    let ip = match packet.eth_header() {
+        Some(eth) => EthBuilder::new(eth.get(Eth::get_dst), eth.get(Eth::get_src))
+            .ipv6(local, src),
+        None => Ipv6Builder::new(local, src)
+    };
+    let reply = ip.icmp(icmp_type, code).rest_of_header(rest).build(invoking);
+    if let Err(e) = tap.write(&reply.data) {
+        eprintln!("chucker: writing to {}: {}", tap.name(), e);
+    }
+}
+
+// fragments are printed as they come in, the datagram once it's whole
+fn reassemble(packet: &PacketView, frags: &mut Frags, config: &Config) {
+    let now = Instant::now();
+    let result = if let Some(ipv6) = packet.ipv6() {
+        if !ipv6.get(Ipv6::is_fragment) {
+            return
+        }
+        frags.v6.push(ipv6.bytes(), now)
+    } else {
+        match packet.ipv4() {
+            Some(ref ipv4) if ipv4.get(Ipv4::is_fragment) => frags.v4.push(ipv4.bytes(), now),
+            _ => return
+        }
+    };
+    print_reassembled(result, config)
+}
+
+fn print_reassembled(result: Result<Option<Vec<u8>>, FragError>, config: &Config) {
+    match result {
+        Ok(Some(datagram)) => match PacketView::ip(&datagram) {
+            Ok(whole) => {
+                if config.verbosity > 0 {
+                    println!("\nreassembled:");
+                }
+                print_packet(&whole, config)
+            },
+            Err(e) => report_undissected(&e, config)
+        },
+        Ok(None) => (),
+        Err(e) => if config.verbosity > 0 {
+            println!("\ndropped fragments: {}", e);
+        }
+    }
+}
+
+fn replay(tap: &mut iface::Tap, path: &str, config: &Config) {
+    let mut reader = pcap::Reader::open(path).unwrap_or_else(|e| {
+        eprintln!("chucker: can't open {}: {}", path, e);
+        process::exit(1)
+    });
+
+    let expected = match tap.dev_type() {
+        DevType::Tap => pcap::LINKTYPE_ETHERNET,
+        DevType::Tun => pcap::LINKTYPE_RAW
+    };
+    if reader.link_type != expected {
+        eprintln!("chucker: {} has link type {}, {} needs {}",
+                  path, reader.link_type, tap.name(), expected);
+        process::exit(1)
+    }
+
+    let link = link_for(tap.dev_type());
+    let mut nr = 0;
+    while let Some(record) = reader.next_record().unwrap() {
+        nr += 1;
+        let packet = PacketView::with_link(&record.data, &link);
+        // what can't be dissected can't match a filter either
+        match packet {
+            Ok(ref packet) if !wanted(packet, config) => continue,
+            Err(ref e) if config.filter.is_some() => {
+                eprintln!("chucker: not replaying record {} of {}, it can't be filtered: {}", nr, path, e);
+                continue
+            },
+            _ => ()
+        }
+        tap.write(&record.data).unwrap();
+        match packet {
+            Ok(packet) => print_packet(&packet, config),
+            Err(e) => report_undissected(&e, config)
+        }
     }
 }
@@ -0,0 +1,893 @@
+use std::collections::{HashMap, HashSet};
+use std::error;
+use std::fmt;
+use std::hash::Hash;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use packet::builder::{tcp_flags, PROTO_ICMPV4, PROTO_TCP, PROTO_UDP};
+use packet::icmpv4::{self, Icmpv4};
+use packet::ipv4::Ipv4;
+use packet::pkt::{self, HasNetworkLayer};
+use util;
+
+// network address translation
+//
+// Masquerading (RFC 3022's NAPT) for a private network behind one public
+// ipv4 address. The first packet of a flow out of the private side gets a
+// mapping from its source address and port to a port of ours, and the
+// packets after it are rewritten by that mapping both ways: the source on
+// the way out, the destination on the way back in. Mappings are endpoint
+// independent (RFC 4787, 4.1), an internal address and port goes out as
+// the same external port whoever it talks to, and whoever has been told
+// about that port can send to it while the mapping lasts. Ports that
+// destination nat forwards from the external address are never mapped.
+//
+// ICMP echo is mapped by its identifier in place of a port (RFC 5508), and
+// ICMP errors about a mapped flow get the datagram they quote translated
+// along with the outer header. Fragments aren't translated, only the first
+// one carries the ports.
+//
+// Checksums are patched for the words that changed (RFC 1624) rather than
+// summed over again, except for the icmp checksum of an error, whose quote
+// changes in too many places.
+
+// how long a mapping lives without traffic
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub tcp_established: Duration,
+    // before the handshake is done, and after either end closed
+    pub tcp_transitory: Duration,
+    pub udp: Duration,
+    pub icmp: Duration
+}
+
+impl Default for Timeouts {
+    // RFC 5382, REQ-5; RFC 4787, REQ-5; RFC 5508, REQ-1
+    fn default() -> Timeouts {
+        Timeouts {
+            tcp_established: Duration::from_secs(2 * 60 * 60 + 4 * 60),
+            tcp_transitory: Duration::from_secs(4 * 60),
+            udp: Duration::from_secs(5 * 60),
+            icmp: Duration::from_secs(60)
+        }
+    }
+}
+
+// external ports are handed out from here up, below are the well known
+// ones a host might serve itself
+pub const FIRST_PORT: u16 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Proto {
+    Tcp,
+    Udp,
+    // echo, keyed by the identifier
+    Icmp
+}
+
+impl fmt::Display for Proto {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Proto::Tcp  => "tcp",
+            Proto::Udp  => "udp",
+            Proto::Icmp => "icmp"
+        })
+    }
+}
+
+// which end of a packet gets rewritten
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Src,
+    Dst
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TcpState {
+    Opening,
+    Established,
+    Closing
+}
+
+// `A` is the internal end, an address and port
+#[derive(Debug, Clone, Copy)]
+pub struct Mapping<A> {
+    pub proto: Proto,
+    pub internal: A,
+    pub external: SocketAddrV4,
+    tcp: TcpState,
+    last_seen: Instant
+}
+
+impl<A> Mapping<A> {
+    fn timeout(&self, timeouts: &Timeouts) -> Duration {
+        match (self.proto, self.tcp) {
+            (Proto::Tcp, TcpState::Established) => timeouts.tcp_established,
+            (Proto::Tcp, _)                     => timeouts.tcp_transitory,
+            (Proto::Udp, _)                     => timeouts.udp,
+            (Proto::Icmp, _)                    => timeouts.icmp
+        }
+    }
+
+    // handshake done once the side that opened acks without a syn, a fin
+    // or reset from either side starts the end
+    fn saw_tcp(&mut self, flags: u8) {
+        if flags & (tcp_flags::FIN | tcp_flags::RST) != 0 {
+            self.tcp = TcpState::Closing;
+        } else if self.tcp == TcpState::Opening && flags & tcp_flags::ACK != 0
+            && flags & tcp_flags::SYN == 0 {
+            self.tcp = TcpState::Established;
+        }
+    }
+}
+
+impl<A: fmt::Display> fmt::Display for Mapping<A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} -> {}", self.proto, self.internal, self.external)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatError {
+    // every external port for the protocol is taken
+    Exhausted(Proto),
+    // nothing to map by: other protocols, icmp other than echo and the
+    // errors, and fragments
+    Protocol(u8),
+    IcmpType(u8),
+    Fragment,
+    // an icmp error from the private side about a flow we don't know, it
+    // would give the private address away
+    NoMapping,
+    Dissect(pkt::DissectError)
+}
+
+impl fmt::Display for NatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NatError::Exhausted(proto)  => write!(f, "out of external {} ports", proto),
+            NatError::Protocol(proto)   => write!(f, "can't translate protocol {}", proto),
+            NatError::IcmpType(kind)    => write!(f, "can't translate icmp type {}", kind),
+            NatError::Fragment          => f.write_str("can't translate a fragment"),
+            NatError::NoMapping         => f.write_str("icmp error about an unknown flow"),
+            NatError::Dissect(ref e)    => write!(f, "{}", e)
+        }
+    }
+}
+
+impl error::Error for NatError {}
+
+impl From<pkt::Truncated> for NatError {
+    fn from(e: pkt::Truncated) -> NatError {
+        NatError::Dissect(pkt::DissectError::Truncated(e))
+    }
+}
+
+impl From<pkt::DissectError> for NatError {
+    fn from(e: pkt::DissectError) -> NatError {
+        NatError::Dissect(e)
+    }
+}
+
+// The mappings of a napt, from internal ends of type `A` to ports on one
+// external address. Translators keep theirs here and do the rewriting.
+pub struct Bindings<A> {
+    external: Ipv4Addr,
+    timeouts: Timeouts,
+    // by protocol and external port
+    mappings: HashMap<(Proto, u16), Mapping<A>>,
+    // the external port, by protocol and internal end
+    ports: HashMap<(Proto, A), u16>,
+    // what destination nat forwards, never mapped
+    reserved: HashSet<(Proto, u16)>,
+    // where the search for a free port starts
+    next_port: u16
+}
+
+impl<A: Copy + Eq + Hash> Bindings<A> {
+    pub fn new(external: Ipv4Addr, timeouts: Timeouts) -> Bindings<A> {
+        Bindings {
+            external: external,
+            timeouts: timeouts,
+            mappings: HashMap::new(),
+            ports: HashMap::new(),
+            reserved: HashSet::new(),
+            next_port: FIRST_PORT
+        }
+    }
+
+    // Keeps the ports `dnat` forwards from our external address out of the
+    // mappings, or a mapping would take what's meant for the forward.
+    pub fn reserve(&mut self, dnat: &Dnat) {
+        let external = self.external;
+        for rule in dnat.rules().iter().filter(|rule| rule.dst == external) {
+            if let (Some(proto), Some(port)) = (rule.proto, rule.port) {
+                self.reserved.insert((proto, port));
+            }
+        }
+    }
+
+    fn is_free(&self, proto: Proto, port: u16) -> bool {
+        !self.mappings.contains_key(&(proto, port)) && !self.reserved.contains(&(proto, port))
+    }
+
+    pub fn external(&self) -> Ipv4Addr {
+        self.external
+    }
+
+    // The external port for `internal`, mapped now if it isn't yet. A new
+    // mapping keeps the internal port, `preferred`, if it's free (RFC 4787,
+    // REQ-3), and takes the next free one otherwise. Reserved ports are
+    // never free.
+    pub fn map(&mut self, proto: Proto, internal: A, preferred: u16,
+               now: Instant) -> Result<u16, NatError> {
+        if let Some(&port) = self.ports.get(&(proto, internal)) {
+            return Ok(port)
+        }
+        let port = if preferred >= FIRST_PORT && self.is_free(proto, preferred) {
+            preferred
+        } else {
+            let count = (u16::MAX - FIRST_PORT) as usize + 1;
+            let mut found = None;
+            for _ in 0..count {
+                let port = self.next_port;
+                self.next_port = if port == u16::MAX { FIRST_PORT } else { port + 1 };
+                if self.is_free(proto, port) {
+                    found = Some(port);
+                    break
+                }
+            }
+            match found {
+                Some(port) => port,
+                None => return Err(NatError::Exhausted(proto))
+            }
+        };
+
+        self.mappings.insert((proto, port), Mapping {
+            proto: proto,
+            internal: internal,
+            external: SocketAddrV4::new(self.external, port),
+            tcp: TcpState::Opening,
+            last_seen: now
+        });
+        self.ports.insert((proto, internal), port);
+        Ok(port)
+    }
+
+    // the external port `internal` has, if it has one
+    pub fn port(&self, proto: Proto, internal: A) -> Option<u16> {
+        self.ports.get(&(proto, internal)).cloned()
+    }
+
+    // who has external port `port`
+    pub fn internal(&self, proto: Proto, port: u16) -> Option<A> {
+        self.mappings.get(&(proto, port)).map(|mapping| mapping.internal)
+    }
+
+    // keeps a mapping alive, `flags` are the tcp ones
+    pub fn touch(&mut self, proto: Proto, port: u16, flags: u8, now: Instant) {
+        if let Some(mapping) = self.mappings.get_mut(&(proto, port)) {
+            mapping.last_seen = now;
+            if proto == Proto::Tcp {
+                mapping.saw_tcp(flags);
+            }
+        }
+    }
+
+    pub fn expire(&mut self, now: Instant) {
+        let timeouts = self.timeouts;
+        let ports = &mut self.ports;
+        self.mappings.retain(|_, mapping| {
+            let alive = mapping.last_seen + mapping.timeout(&timeouts) > now;
+            if !alive {
+                ports.remove(&(mapping.proto, mapping.internal));
+            }
+            alive
+        });
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Mapping<A>> {
+        self.mappings.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.mappings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mappings.is_empty()
+    }
+}
+
+pub struct Nat {
+    pub bindings: Bindings<SocketAddrV4>
+}
+
+impl Nat {
+    pub fn new(external: Ipv4Addr, timeouts: Timeouts) -> Nat {
+        Nat { bindings: Bindings::new(external, timeouts) }
+    }
+
+    pub fn external(&self) -> Ipv4Addr {
+        self.bindings.external()
+    }
+
+    // Translates `packet`, an ipv4 datagram from the private side on its
+    // way out, mapping its flow if it's new.
+    pub fn outbound(&mut self, packet: &mut [u8], now: Instant) -> Result<(), NatError> {
+        let external = self.external();
+        match classify(packet, icmpv4::ECHO_REQUEST)? {
+            Kind::Flow { proto, src_port, flags, .. } => {
+                let src = Ipv4Addr::from(Ipv4 { offset: 0 }.get_src(packet));
+                let internal = SocketAddrV4::new(src, src_port);
+                let port = self.bindings.map(proto, internal, src_port, now)?;
+                self.bindings.touch(proto, port, flags, now);
+                rewrite(packet, Side::Src, external, Some(port))
+            },
+            // about something we let in, so the quote's destination is the
+            // internal end of a mapping
+            Kind::Error { quote } => {
+                let (proto, _, dst) = quoted_flow(&packet[quote..])?;
+                let port = match self.bindings.port(proto, dst) {
+                    Some(port) => port,
+                    None => return Err(NatError::NoMapping)
+                };
+                rewrite_quote(packet, quote, Side::Dst, external, Some(port))?;
+                rewrite(packet, Side::Src, external, None)
+            }
+        }
+    }
+
+    // Translates `packet`, addressed to our external address, back to the
+    // internal end of its mapping. Ok(false) if there's no mapping for it,
+    // then it's for us.
+    pub fn inbound(&mut self, packet: &mut [u8], now: Instant) -> Result<bool, NatError> {
+        let kind = match classify(packet, icmpv4::ECHO_REPLY) {
+            Ok(kind) => kind,
+            Err(NatError::Dissect(e)) => return Err(NatError::Dissect(e)),
+            Err(_) => return Ok(false)
+        };
+        match kind {
+            Kind::Flow { proto, dst_port, flags, .. } => {
+                let internal = match self.bindings.internal(proto, dst_port) {
+                    Some(internal) => internal,
+                    None => return Ok(false)
+                };
+                self.bindings.touch(proto, dst_port, flags, now);
+                rewrite(packet, Side::Dst, *internal.ip(), Some(internal.port()))?;
+            },
+            // about something we sent out, so the quote's source is ours
+            Kind::Error { quote } => {
+                let (proto, src, _) = match quoted_flow(&packet[quote..]) {
+                    Ok(flow) => flow,
+                    Err(_) => return Ok(false)
+                };
+                let internal = match self.bindings.internal(proto, src.port()) {
+                    Some(internal) if *src.ip() == self.external() => internal,
+                    _ => return Ok(false)
+                };
+                rewrite_quote(packet, quote, Side::Src, *internal.ip(), Some(internal.port()))?;
+                rewrite(packet, Side::Dst, *internal.ip(), None)?;
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn expire(&mut self, now: Instant) {
+        self.bindings.expire(now)
+    }
+
+    pub fn len(&self) -> usize {
+        self.bindings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bindings.is_empty()
+    }
+}
+
+// destination nat
+//
+// A rule sends what's addressed to an address, or a port on it, on to a
+// host inside:
+//
+//   tcp 192.0.2.1:8080 to 10.0.0.5:80
+//   udp 192.0.2.1:53 to 10.0.0.6
+//   192.0.2.10 to 10.0.0.7
+//
+// The last one has neither protocol nor port, which makes it one to one
+// nat: 192.0.2.10 is 10.0.0.7, whichever way the packets go. Rules keep no
+// state and work both ways, what the inside host sends from the address
+// and port a rule sends to goes out from the address and port the rule
+// matched. The first rule that matches wins. Fragments only match rules
+// without a port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DnatRule {
+    // any protocol if none, a port needs tcp or udp
+    pub proto: Option<Proto>,
+    pub dst: Ipv4Addr,
+    pub port: Option<u16>,
+    pub to: Ipv4Addr,
+    // the same port if none
+    pub to_port: Option<u16>
+}
+
+impl FromStr for DnatRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<DnatRule, String> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        let (proto, words) = match words.split_first() {
+            Some((&"tcp", rest))  => (Some(Proto::Tcp), rest),
+            Some((&"udp", rest))  => (Some(Proto::Udp), rest),
+            Some((&"icmp", rest)) => (Some(Proto::Icmp), rest),
+            _ => (None, &words[..])
+        };
+        let (dst, to) = match *words {
+            [dst, "to", to] => (dst, to),
+            _ => return Err(format!("nat rule should be [tcp | udp | icmp] <addr>[:port] to <addr>[:port]: {}", s))
+        };
+        let (dst, port) = parse_endpoint(dst, s)?;
+        let (to, to_port) = parse_endpoint(to, s)?;
+
+        if port.is_some() && proto != Some(Proto::Tcp) && proto != Some(Proto::Udp) {
+            return Err(format!("ports need tcp or udp in nat rule: {}", s))
+        }
+        if to_port.is_some() && port.is_none() {
+            return Err(format!("no port to translate from in nat rule: {}", s))
+        }
+        Ok(DnatRule { proto: proto, dst: dst, port: port, to: to, to_port: to_port })
+    }
+}
+
+// "addr[:port]", `rule` is for the error message
+fn parse_endpoint(s: &str, rule: &str) -> Result<(Ipv4Addr, Option<u16>), String> {
+    let (addr, port) = match s.find(':') {
+        Some(idx) => {
+            let port = s[idx + 1..].parse::<u16>()
+                .map_err(|_| format!("bad port in nat rule: {}", rule))?;
+            (&s[..idx], Some(port))
+        },
+        None => (s, None)
+    };
+    let addr = addr.parse::<Ipv4Addr>()
+        .map_err(|_| format!("bad address in nat rule: {}", rule))?;
+    Ok((addr, port))
+}
+
+impl fmt::Display for DnatRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(proto) = self.proto {
+            write!(f, "{} ", proto)?;
+        }
+        write!(f, "{}", self.dst)?;
+        if let Some(port) = self.port {
+            write!(f, ":{}", port)?;
+        }
+        write!(f, " to {}", self.to)?;
+        if let Some(port) = self.to_port {
+            write!(f, ":{}", port)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Dnat {
+    rules: Vec<DnatRule>
+}
+
+impl Dnat {
+    pub fn new(rules: Vec<DnatRule>) -> Dnat {
+        Dnat { rules: rules }
+    }
+
+    pub fn rules(&self) -> &[DnatRule] {
+        &self.rules
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    // Sends `packet`, an ipv4 datagram, on to the inside host if a rule
+    // matches its destination. Before routing.
+    pub fn destination(&self, packet: &mut [u8]) -> Result<bool, NatError> {
+        self.translate(packet, Side::Dst)
+    }
+
+    // Has `packet` come from where a rule sent the inside host's traffic
+    // to. After routing.
+    pub fn source(&self, packet: &mut [u8]) -> Result<bool, NatError> {
+        self.translate(packet, Side::Src)
+    }
+
+    // What a packet with `addr` and `port` at `side` gets instead, `side`
+    // being the destination on the way in and the source on the way out.
+    fn lookup(&self, side: Side, proto: Option<Proto>, addr: Ipv4Addr,
+              port: Option<u16>) -> Option<(Ipv4Addr, Option<u16>)> {
+        for rule in &self.rules {
+            if rule.proto.is_some() && rule.proto != proto {
+                continue
+            }
+            let (from, from_port, to, to_port) = match side {
+                Side::Dst => (rule.dst, rule.port, rule.to, rule.to_port),
+                Side::Src => (rule.to, rule.to_port.or(rule.port), rule.dst, rule.to_port.and(rule.port))
+            };
+            if from != addr || from_port.is_some() && from_port != port {
+                continue
+            }
+            return Some((to, to_port))
+        }
+        None
+    }
+
+    fn translate(&self, packet: &mut [u8], side: Side) -> Result<bool, NatError> {
+        let ip = Ipv4 { offset: 0 };
+        let hlen = ip.header_len(packet)?;
+        let protocol = ip.get_protocol(packet);
+        let flow = if ip.get_frag_offs(packet) == 0 {
+            ports(protocol, &packet[hlen..]).ok()
+        } else {
+            None
+        };
+
+        // an icmp error is about a packet that went the other way, the
+        // quote gets translated at its other end
+        let error = match packet.get(hlen) {
+            Some(&kind) => protocol == PROTO_ICMPV4 && flow.is_some() && is_error(kind),
+            None => false
+        };
+        if error {
+            let quote = hlen + Icmpv4::HEADER_LEN;
+            let (proto, src, dst) = match quoted_flow(&packet[quote..]) {
+                Ok(flow) => flow,
+                Err(_) => return Ok(false)
+            };
+            let (inner, quoted) = match side {
+                Side::Dst => (Side::Src, src),
+                Side::Src => (Side::Dst, dst)
+            };
+            return match self.lookup(side, Some(proto), *quoted.ip(), Some(quoted.port())) {
+                Some((addr, port)) => {
+                    rewrite_quote(packet, quote, inner, addr, port)?;
+                    rewrite(packet, side, addr, None)?;
+                    Ok(true)
+                },
+                None => Ok(false)
+            }
+        }
+
+        let (addr, port) = match side {
+            Side::Src => (ip.get_src(packet), flow.map(|(_, src, _)| src)),
+            Side::Dst => (ip.get_dst(packet), flow.map(|(_, _, dst)| dst))
+        };
+        let proto = flow.map(|(proto, _, _)| proto);
+        match self.lookup(side, proto, Ipv4Addr::from(addr), port) {
+            Some((addr, port)) => {
+                rewrite(packet, side, addr, port)?;
+                Ok(true)
+            },
+            None => Ok(false)
+        }
+    }
+}
+
+fn is_error(icmp_type: u8) -> bool {
+    icmp_type == icmpv4::DEST_UNREACH || icmp_type == icmpv4::TIME_EXCEEDED
+        || icmp_type == icmpv4::PARAM_PROBLEM
+}
+
+// what a datagram is to the translator
+enum Kind {
+    // tcp, udp or icmp echo, the identifier standing in for both ports
+    Flow { proto: Proto, src_port: u16, dst_port: u16, flags: u8 },
+    // an icmp error, quoting a datagram from this offset on
+    Error { quote: usize }
+}
+
+// `echo` is the echo message that can go this way
+fn classify(packet: &[u8], echo: u8) -> Result<Kind, NatError> {
+    let ip = Ipv4 { offset: 0 };
+    let hlen = ip.header_len(packet)?;
+    if ip.is_fragment(packet) {
+        return Err(NatError::Fragment)
+    }
+    let protocol = ip.get_protocol(packet);
+    let trans = &packet[hlen..];
+    if protocol == PROTO_ICMPV4 {
+        pkt::check_len("icmpv4", trans, Icmpv4::HEADER_LEN)?;
+        let icmp = Icmpv4 { offset: hlen };
+        match icmp.get_icmp_type(trans) {
+            kind if is_error(kind) => return Ok(Kind::Error { quote: hlen + Icmpv4::HEADER_LEN }),
+            kind if kind != echo => return Err(NatError::IcmpType(kind)),
+            _ => ()
+        }
+    }
+    let (proto, src_port, dst_port) = ports(protocol, trans)?;
+    let flags = if proto == Proto::Tcp {
+        pkt::check_len("tcp", trans, 14)?;
+        trans[13]
+    } else {
+        0
+    };
+    Ok(Kind::Flow { proto: proto, src_port: src_port, dst_port: dst_port, flags: flags })
+}
+
+// the first 8 bytes of the transport header, which is all an icmp error
+// has to quote
+fn ports(protocol: u8, trans: &[u8]) -> Result<(Proto, u16, u16), NatError> {
+    let proto = match protocol {
+        PROTO_TCP    => Proto::Tcp,
+        PROTO_UDP    => Proto::Udp,
+        PROTO_ICMPV4 => Proto::Icmp,
+        other        => return Err(NatError::Protocol(other))
+    };
+    pkt::check_len("transport", trans, 8)?;
+    let word = |at: usize| (trans[at] as u16) << 8 | trans[at + 1] as u16;
+    Ok(match proto {
+        Proto::Icmp => (proto, word(4), word(4)),
+        _           => (proto, word(0), word(2))
+    })
+}
+
+// the flow of a datagram quoted in an icmp error, as (protocol, src, dst)
+fn quoted_flow(quote: &[u8]) -> Result<(Proto, SocketAddrV4, SocketAddrV4), NatError> {
+    let ip = Ipv4 { offset: 0 };
+    let hlen = ip.header_len(quote)?;
+    let protocol = ip.get_protocol(quote);
+    if protocol == PROTO_ICMPV4 {
+        match quote.get(hlen) {
+            Some(&icmpv4::ECHO_REQUEST) | Some(&icmpv4::ECHO_REPLY) => (),
+            Some(&kind) => return Err(NatError::IcmpType(kind)),
+            None => pkt::check_len("icmpv4", &quote[hlen..], 1)?
+        }
+    }
+    let (proto, src_port, dst_port) = ports(protocol, &quote[hlen..])?;
+    Ok((proto,
+        SocketAddrV4::new(Ipv4Addr::from(ip.get_src(quote)), src_port),
+        SocketAddrV4::new(Ipv4Addr::from(ip.get_dst(quote)), dst_port)))
+}
+
+// Sets the address, and the port if there is one, at one end of `packet`,
+// an ipv4 datagram, and patches the checksums to match. For icmp echo the
+// port is the identifier. A datagram quoted in an icmp error may stop
+// before the transport checksum, it's left alone then.
+pub fn rewrite(packet: &mut [u8], side: Side, addr: Ipv4Addr,
+               port: Option<u16>) -> Result<(), NatError> {
+    let ip = Ipv4 { offset: 0 };
+    let hlen = ip.header_len(packet)?;
+    let old_addr = match side {
+        Side::Src => ip.get_src(packet),
+        Side::Dst => ip.get_dst(packet)
+    };
+    let new_addr = addr.octets();
+    match side {
+        Side::Src => ip.set_src(packet, new_addr),
+        Side::Dst => ip.set_dst(packet, new_addr)
+    }
+    let chk = adjust_addr(ip.get_header_chk(packet), old_addr, new_addr);
+    ip.set_header_chk(packet, chk);
+    if ip.get_frag_offs(packet) != 0 {
+        return Ok(())
+    }
+
+    // where the port and the checksum are, and whether the checksum
+    // covers the addresses
+    let protocol = ip.get_protocol(packet);
+    let (port_at, chk_at, pseudo) = match (protocol, side) {
+        (PROTO_TCP, Side::Src) => (0, 16, true),
+        (PROTO_TCP, Side::Dst) => (2, 16, true),
+        (PROTO_UDP, Side::Src) => (0, 6, true),
+        (PROTO_UDP, Side::Dst) => (2, 6, true),
+        (PROTO_ICMPV4, _)      => (4, 2, false),
+        _ => return Ok(())
+    };
+    let trans = &mut packet[hlen..];
+    pkt::check_len("transport", trans, port_at + 2)?;
+    let old_port = (trans[port_at] as u16) << 8 | trans[port_at + 1] as u16;
+    let new_port = port.unwrap_or(old_port);
+    trans[port_at] = (new_port >> 8) as u8;
+    trans[port_at + 1] = new_port as u8;
+
+    if trans.len() < chk_at + 2 {
+        return Ok(())
+    }
+    let old_chk = (trans[chk_at] as u16) << 8 | trans[chk_at + 1] as u16;
+    // no checksum at all, udp only
+    if protocol == PROTO_UDP && old_chk == 0 {
+        return Ok(())
+    }
+    let mut chk = util::checksum_adjust(old_chk, old_port, new_port);
+    if pseudo {
+        chk = adjust_addr(chk, old_addr, new_addr);
+    }
+    if protocol == PROTO_UDP && chk == 0 {
+        chk = 0xFFFF;
+    }
+    trans[chk_at] = (chk >> 8) as u8;
+    trans[chk_at + 1] = chk as u8;
+    Ok(())
+}
+
+// rewrites the datagram quoted by the icmp error in `packet` and sums the
+// icmp message over again
+fn rewrite_quote(packet: &mut [u8], quote: usize, side: Side, addr: Ipv4Addr,
+                 port: Option<u16>) -> Result<(), NatError> {
+    rewrite(&mut packet[quote..], side, addr, port)?;
+    let icmp_at = quote - Icmpv4::HEADER_LEN;
+    let icmp = Icmpv4 { offset: icmp_at };
+    let msg = &mut packet[icmp_at..];
+    icmp.set_chk(msg, 0);
+    let chk = util::checksum(msg);
+    icmp.set_chk(msg, chk);
+    Ok(())
+}
+
+fn adjust_addr(chk: u16, old: [u8; 4], new: [u8; 4]) -> u16 {
+    let word = |addr: [u8; 4], at: usize| (addr[at] as u16) << 8 | addr[at + 1] as u16;
+    let chk = util::checksum_adjust(chk, word(old, 0), word(new, 0));
+    util::checksum_adjust(chk, word(old, 2), word(new, 2))
+}
+
+
+// testing
+#[cfg(test)]
+use packet::builder::{Ipv4Builder, IPV4_LEN, UDP_LEN};
+
+#[cfg(test)]
+fn addr(s: &str) -> Ipv4Addr {
+    s.parse().unwrap()
+}
+
+// Two hosts asking 198.51.100.7 from the same port, out from 192.0.2.1:
+// the nat and what both queries went out as.
+#[cfg(test)]
+fn masquerade(now: Instant) -> (Nat, Vec<u8>, Vec<u8>) {
+    let mut nat = Nat::new(addr("192.0.2.1"), Timeouts::default());
+    let mut first = Ipv4Builder::new(addr("10.0.0.2"), addr("198.51.100.7"))
+        .udp(5000, 53).build(b"query").data;
+    let mut second = Ipv4Builder::new(addr("10.0.0.3"), addr("198.51.100.7"))
+        .udp(5000, 53).build(b"query").data;
+    nat.outbound(&mut first, now).unwrap();
+    nat.outbound(&mut second, now).unwrap();
+    (nat, first, second)
+}
+
+#[test]
+fn test_masquerade_keeps_the_port_if_free() {
+    let (_, first, second) = masquerade(Instant::now());
+    let ip = Ipv4 { offset: 0 };
+    assert_eq!(Ipv4Addr::from(ip.get_src(&first)), addr("192.0.2.1"));
+    // the first keeps it, the second can't
+    assert_eq!(ports(PROTO_UDP, &first[IPV4_LEN..]).unwrap().1, 5000);
+    assert_eq!(ports(PROTO_UDP, &second[IPV4_LEN..]).unwrap().1, FIRST_PORT);
+    assert!(util::valid_udp(&first) && util::valid_udp(&second));
+}
+
+#[test]
+fn test_replies_go_back_to_the_host() {
+    let now = Instant::now();
+    let (mut nat, _, _) = masquerade(now);
+    let mut reply = Ipv4Builder::new(addr("198.51.100.7"), addr("192.0.2.1"))
+        .udp(53, FIRST_PORT).build(b"answer").data;
+    assert_eq!(nat.inbound(&mut reply, now), Ok(true));
+    assert_eq!(Ipv4Addr::from(Ipv4 { offset: 0 }.get_dst(&reply)), addr("10.0.0.3"));
+    assert_eq!(ports(PROTO_UDP, &reply[IPV4_LEN..]).unwrap().2, 5000);
+    assert!(util::valid_udp(&reply));
+
+    // nobody asked for this one
+    let mut stray = Ipv4Builder::new(addr("198.51.100.7"), addr("192.0.2.1"))
+        .udp(53, 6000).build(b"").data;
+    assert_eq!(nat.inbound(&mut stray, now), Ok(false));
+}
+
+#[test]
+fn test_errors_go_back_to_the_host_they_quote() {
+    let now = Instant::now();
+    let (mut nat, _, second) = masquerade(now);
+    let quoted = &second[..IPV4_LEN + UDP_LEN];
+    let mut error = Ipv4Builder::new(addr("203.0.113.9"), addr("192.0.2.1"))
+        .icmp(icmpv4::DEST_UNREACH, 3).build(quoted).data;
+    assert_eq!(nat.inbound(&mut error, now), Ok(true));
+    assert_eq!(Ipv4Addr::from(Ipv4 { offset: 0 }.get_dst(&error)), addr("10.0.0.3"));
+    let (_, src, _) = quoted_flow(&error[IPV4_LEN + Icmpv4::HEADER_LEN..]).unwrap();
+    assert_eq!(src, SocketAddrV4::new(addr("10.0.0.3"), 5000));
+    assert_eq!(util::checksum(&error[IPV4_LEN..]), 0);
+}
+
+#[test]
+fn test_idle_mappings_expire() {
+    let now = Instant::now();
+    let (mut nat, _, _) = masquerade(now);
+    assert_eq!(nat.len(), 2);
+    nat.expire(now + Duration::from_secs(4 * 60));
+    assert_eq!(nat.len(), 2);
+    nat.expire(now + Duration::from_secs(5 * 60));
+    assert!(nat.is_empty());
+}
+
+#[cfg(test)]
+fn dnat() -> Dnat {
+    Dnat::new(vec!("tcp 192.0.2.1:8080 to 10.0.0.5:80".parse().unwrap(),
+                   "192.0.2.10 to 10.0.0.7".parse().unwrap()))
+}
+
+#[test]
+fn test_dnat_rules_print_and_complain() {
+    assert_eq!(dnat().rules()[0].to_string(), "tcp 192.0.2.1:8080 to 10.0.0.5:80");
+    assert_eq!("tcp 192.0.2.1 to 10.0.0.5:80".parse::<DnatRule>(),
+               Err("no port to translate from in nat rule: tcp 192.0.2.1 to 10.0.0.5:80".to_string()));
+    assert_eq!("icmp 192.0.2.1:7 to 10.0.0.5".parse::<DnatRule>(),
+               Err("ports need tcp or udp in nat rule: icmp 192.0.2.1:7 to 10.0.0.5".to_string()));
+    assert_eq!("sctp 192.0.2.1 to 10.0.0.5".parse::<DnatRule>(),
+               Err("nat rule should be [tcp | udp | icmp] <addr>[:port] to <addr>[:port]: \
+                    sctp 192.0.2.1 to 10.0.0.5".to_string()));
+}
+
+#[test]
+fn test_port_forward_both_ways() {
+    let dnat = dnat();
+    let ip = Ipv4 { offset: 0 };
+    let mut syn = Ipv4Builder::new(addr("198.51.100.7"), addr("192.0.2.1"))
+        .tcp(40000, 8080).flags(tcp_flags::SYN).build(b"").data;
+    assert_eq!(dnat.destination(&mut syn), Ok(true));
+    assert_eq!(Ipv4Addr::from(ip.get_dst(&syn)), addr("10.0.0.5"));
+    assert_eq!(ports(PROTO_TCP, &syn[IPV4_LEN..]).unwrap().2, 80);
+
+    let mut syn_ack = Ipv4Builder::new(addr("10.0.0.5"), addr("198.51.100.7"))
+        .tcp(80, 40000).flags(tcp_flags::SYN | tcp_flags::ACK).build(b"").data;
+    assert_eq!(dnat.source(&mut syn_ack), Ok(true));
+    assert_eq!(Ipv4Addr::from(ip.get_src(&syn_ack)), addr("192.0.2.1"));
+    assert_eq!(ports(PROTO_TCP, &syn_ack[IPV4_LEN..]).unwrap().1, 8080);
+    let pseudo = util::pseudo_sum_v4(ip.get_src(&syn_ack), ip.get_dst(&syn_ack), PROTO_TCP,
+                                     syn_ack.len() - IPV4_LEN);
+    assert_eq!(util::checksum_finish(util::checksum_add(pseudo, &syn_ack[IPV4_LEN..])), 0);
+}
+
+#[test]
+fn test_other_ports_are_not_forwarded() {
+    let mut other = Ipv4Builder::new(addr("198.51.100.7"), addr("192.0.2.1"))
+        .tcp(40000, 22).build(b"").data;
+    assert_eq!(dnat().destination(&mut other), Ok(false));
+}
+
+#[test]
+fn test_one_to_one_takes_any_protocol_and_its_errors() {
+    let dnat = dnat();
+    let ip = Ipv4 { offset: 0 };
+    let mut ping = Ipv4Builder::new(addr("10.0.0.7"), addr("198.51.100.7"))
+        .icmp(icmpv4::ECHO_REQUEST, 0).ident(7).build(b"").data;
+    assert_eq!(dnat.source(&mut ping), Ok(true));
+    assert_eq!(Ipv4Addr::from(ip.get_src(&ping)), addr("192.0.2.10"));
+    let mut error = Ipv4Builder::new(addr("203.0.113.9"), addr("192.0.2.10"))
+        .icmp(icmpv4::TIME_EXCEEDED, 0).build(&ping).data;
+    assert_eq!(dnat.destination(&mut error), Ok(true));
+    assert_eq!(Ipv4Addr::from(ip.get_dst(&error)), addr("10.0.0.7"));
+    let quote = &error[IPV4_LEN + Icmpv4::HEADER_LEN..];
+    assert_eq!(Ipv4Addr::from(ip.get_src(quote)), addr("10.0.0.7"));
+    assert_eq!(util::checksum(&error[IPV4_LEN..]), 0);
+}
+
+#[test]
+fn test_forwarded_ports_are_never_mapped() {
+    let dnat = Dnat::new(vec!("tcp 192.0.2.1:8080 to 10.0.0.5:80".parse().unwrap(),
+                              "udp 192.0.2.1:1024 to 10.0.0.6".parse().unwrap(),
+                              "tcp 192.0.2.9:5000 to 10.0.0.7".parse().unwrap()));
+    let mut bindings = Bindings::new(addr("192.0.2.1"), Timeouts::default());
+    bindings.reserve(&dnat);
+    let now = Instant::now();
+    // the search for a free port steps over them
+    let host = SocketAddrV4::new(addr("10.0.0.3"), 80);
+    assert_eq!(bindings.map(Proto::Udp, host, 80, now), Ok(1025));
+
+    // and a host doesn't keep its port if that one is forwarded
+    let host = SocketAddrV4::new(addr("10.0.0.3"), 8080);
+    assert_eq!(bindings.map(Proto::Tcp, host, 8080, now), Ok(1026));
+    assert_eq!(bindings.internal(Proto::Tcp, 8080), None);
+    // other protocols and other addresses' forwards are no matter
+    assert_eq!(bindings.map(Proto::Udp, host, 8080, now), Ok(8080));
+    let host = SocketAddrV4::new(addr("10.0.0.3"), 5000);
+    assert_eq!(bindings.map(Proto::Tcp, host, 5000, now), Ok(5000));
+}
@@ -0,0 +1,841 @@
+use std::error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddrV6};
+use std::time::Instant;
+
+use nat::{Bindings, NatError, Proto, Timeouts};
+use packet::builder::{ICMP_LEN, IPV4_LEN, IPV6_LEN, PROTO_ICMPV4, PROTO_ICMPV6, PROTO_TCP,
+                      PROTO_UDP};
+use packet::frag::FRAG_HEADER_LEN;
+use packet::icmpv4;
+use packet::icmpv6;
+use packet::ipv4::Ipv4;
+use packet::ipv6::{header_types, Ipv6, Ipv6Frag, Ipv6Routing};
+use packet::pkt::{self, HasNetworkLayer};
+use util;
+
+// ipv6/ipv4 translation
+//
+// SIIT (RFC 7915) rewrites an ipv6 datagram as ipv4 and back: the headers
+// are mapped field by field, a fragment header becomes the ipv4 fragment
+// fields, ICMPv6 becomes ICMP with its types, codes and pointers mapped,
+// and the datagram an error quotes is translated along with it. ipv4
+// addresses appear on the ipv6 side in the last 32 bits of a /96 prefix
+// (RFC 6052), 64:ff9b::/96 unless told otherwise.
+//
+// Stateless, both ends need addresses in the prefix. Stateful NAT64 (RFC
+// 6146) lets ipv6 hosts with any address reach ipv4 through a pool address
+// instead, mapping their address and port to a port on it the way the
+// ipv4 napt in `nat` does.
+//
+// tcp and udp checksums are patched for the pseudo header change, only the
+// addresses differ between the two. The icmp checksums are summed again:
+// ICMPv6 covers a pseudo header, ICMP doesn't.
+
+// RFC 6052, 2.1
+pub const WELL_KNOWN_PREFIX: Ipv6Addr = Ipv6Addr::new(0x64, 0xFF9B, 0, 0, 0, 0, 0, 0);
+
+// an ipv4 error should fit in the minimum reassembly size (RFC 1812, 4.3.2.3)
+const MAX_ICMPV4_ERROR: usize = 576;
+
+// `addr` in the last 32 bits of the /96 `prefix`
+pub fn embed(prefix: Ipv6Addr, addr: Ipv4Addr) -> Ipv6Addr {
+    Ipv6Addr::from(u128::from(prefix) & !0xFFFF_FFFF | u32::from(addr) as u128)
+}
+
+// the ipv4 address in `addr`, if it's under the /96 `prefix`
+pub fn extract(prefix: Ipv6Addr, addr: Ipv6Addr) -> Option<Ipv4Addr> {
+    if (u128::from(prefix) ^ u128::from(addr)) >> 32 != 0 {
+        return None
+    }
+    Some(Ipv4Addr::from(u128::from(addr) as u32))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XlatError {
+    // no counterpart in the other version
+    IcmpType(u8),
+    Untranslatable(&'static str),
+    // stateless: outside the prefix
+    Address(IpAddr),
+    // stateful: an error about a flow we don't know
+    NoMapping,
+    Nat(NatError),
+    Dissect(pkt::DissectError)
+}
+
+impl fmt::Display for XlatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            XlatError::IcmpType(kind)      => write!(f, "no translation for icmp type {}", kind),
+            XlatError::Untranslatable(why) => write!(f, "can't translate {}", why),
+            XlatError::Address(addr)       => write!(f, "{} has no counterpart to translate to", addr),
+            XlatError::NoMapping           => f.write_str("icmp error about an unknown flow"),
+            XlatError::Nat(ref e)          => write!(f, "{}", e),
+            XlatError::Dissect(ref e)      => write!(f, "{}", e)
+        }
+    }
+}
+
+impl error::Error for XlatError {}
+
+impl From<pkt::Truncated> for XlatError {
+    fn from(e: pkt::Truncated) -> XlatError {
+        XlatError::Dissect(pkt::DissectError::Truncated(e))
+    }
+}
+
+impl From<pkt::DissectError> for XlatError {
+    fn from(e: pkt::DissectError) -> XlatError {
+        XlatError::Dissect(e)
+    }
+}
+
+impl From<NatError> for XlatError {
+    fn from(e: NatError) -> XlatError {
+        XlatError::Nat(e)
+    }
+}
+
+// the ends of a datagram as far as translation goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ends<A> {
+    pub src: A,
+    pub dst: A,
+    // tcp and udp ports, or the echo identifier as both; none for other
+    // protocols and for fragments past the first
+    pub ports: Option<(Proto, u16, u16)>,
+    // tcp's, zero otherwise
+    pub flags: u8
+}
+
+impl<A> Ends<A> {
+    fn with<B>(&self, src: B, dst: B) -> Ends<B> {
+        Ends { src: src, dst: dst, ports: self.ports, flags: self.flags }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Frag {
+    ident: u32,
+    // in 8 byte units
+    offset: u16,
+    more: bool
+}
+
+// where the upper layer header of a datagram starts, and what's around it
+#[derive(Debug, Clone, Copy)]
+struct Upper {
+    protocol: u8,
+    start: usize,
+    // by the ip header, more than there is in a quote
+    len: usize,
+    frag: Option<Frag>
+}
+
+impl Upper {
+    // only the first fragment has the upper layer header
+    fn has_header(&self) -> bool {
+        self.frag.is_none_or(|frag| frag.offset == 0)
+    }
+
+    fn fragmented(&self) -> bool {
+        self.frag.is_some_and(|frag| frag.offset != 0 || frag.more)
+    }
+}
+
+fn upper_v6(packet: &[u8]) -> Result<Upper, XlatError> {
+    pkt::check_len("ipv6", packet, IPV6_LEN)?;
+    let ip = Ipv6 { offset: 0 };
+    let mut headers = ip.ext_headers(packet);
+    let mut frag = None;
+    let mut after_frag = header_types::NO_NEXT;
+    for header in headers.by_ref() {
+        let header = header?;
+        let buff = &packet[header.offset..];
+        match header.kind {
+            header_types::FRAGMENT => {
+                let fh = Ipv6Frag { offset: header.offset };
+                frag = Some(Frag {
+                    ident: fh.get_ident(buff),
+                    offset: fh.get_frag_offs(buff),
+                    more: fh.get_flag_m(buff) != 0
+                });
+                after_frag = fh.get_nxt_header(buff);
+            },
+            // RFC 7915, 5.1: ipv4 has nowhere to send it next
+            header_types::ROUTING
+                if Ipv6Routing { offset: header.offset }.get_segments_left(buff) != 0 =>
+                return Err(XlatError::Untranslatable("a routing header with segments left")),
+            _ => ()
+        }
+    }
+    let start = headers.end();
+    let protocol = match frag {
+        Some(frag) if frag.offset != 0 => after_frag,
+        _ => headers.protocol()
+    };
+    Ok(Upper {
+        protocol: protocol,
+        start: start,
+        len: (ip.get_payload_len(packet) as usize + IPV6_LEN).saturating_sub(start),
+        frag: frag
+    })
+}
+
+fn upper_v4(packet: &[u8]) -> Result<Upper, XlatError> {
+    let ip = Ipv4 { offset: 0 };
+    let hlen = ip.header_len(packet)?;
+    let frag = if ip.is_fragment(packet) {
+        Some(Frag {
+            ident: ip.get_ident(packet) as u32,
+            offset: ip.get_frag_offs(packet),
+            more: ip.get_flag_mf(packet) != 0
+        })
+    } else {
+        None
+    };
+    Ok(Upper {
+        protocol: ip.get_protocol(packet),
+        start: hlen,
+        len: (ip.get_len(packet) as usize).saturating_sub(hlen),
+        frag: frag
+    })
+}
+
+fn word(buff: &[u8], at: usize) -> u16 {
+    (buff[at] as u16) << 8 | buff[at + 1] as u16
+}
+
+fn set_word(buff: &mut [u8], at: usize, val: u16) {
+    buff[at] = (val >> 8) as u8;
+    buff[at + 1] = val as u8;
+}
+
+// ports, tcp flags and whether it's an icmp error quoting a datagram
+fn transport(upper: &Upper, trans: &[u8]) -> (Option<(Proto, u16, u16)>, u8, bool) {
+    if !upper.has_header() || trans.len() < ICMP_LEN {
+        return (None, 0, false)
+    }
+    match (upper.protocol, trans[0]) {
+        (PROTO_TCP, _) =>
+            (Some((Proto::Tcp, word(trans, 0), word(trans, 2))), trans.get(13).cloned().unwrap_or(0), false),
+        (PROTO_UDP, _) => (Some((Proto::Udp, word(trans, 0), word(trans, 2))), 0, false),
+        (PROTO_ICMPV4, icmpv4::ECHO_REQUEST) | (PROTO_ICMPV4, icmpv4::ECHO_REPLY) |
+        (PROTO_ICMPV6, icmpv6::ECHO_REQUEST) | (PROTO_ICMPV6, icmpv6::ECHO_REPLY) =>
+            (Some((Proto::Icmp, word(trans, 4), word(trans, 4))), 0, false),
+        (PROTO_ICMPV4, kind) => (None, 0, is_error_v4(kind)),
+        // the informational messages start at 128
+        (PROTO_ICMPV6, kind) => (None, 0, kind < 128),
+        _ => (None, 0, false)
+    }
+}
+
+fn is_error_v4(kind: u8) -> bool {
+    kind == icmpv4::DEST_UNREACH || kind == icmpv4::TIME_EXCEEDED || kind == icmpv4::PARAM_PROBLEM
+}
+
+// The ends of an ipv6 datagram, and of the one it quotes if it's an icmp
+// error. Those are what a translator picks the new ends by.
+pub fn peek_ipv6(packet: &[u8]) -> Result<(Ends<Ipv6Addr>, Option<Ends<Ipv6Addr>>), XlatError> {
+    let ends_of = |packet: &[u8]| -> Result<(Ends<Ipv6Addr>, Option<usize>), XlatError> {
+        let upper = upper_v6(packet)?;
+        let ip = Ipv6 { offset: 0 };
+        let (ports, flags, error) = transport(&upper, &packet[upper.start..]);
+        let ends = Ends {
+            src: Ipv6Addr::from(ip.get_src(packet)),
+            dst: Ipv6Addr::from(ip.get_dst(packet)),
+            ports: ports,
+            flags: flags
+        };
+        Ok((ends, if error { Some(upper.start + ICMP_LEN) } else { None }))
+    };
+    let (ends, quote) = ends_of(packet)?;
+    match quote {
+        Some(at) => Ok((ends, Some(ends_of(&packet[at..])?.0))),
+        None => Ok((ends, None))
+    }
+}
+
+pub fn peek_ipv4(packet: &[u8]) -> Result<(Ends<Ipv4Addr>, Option<Ends<Ipv4Addr>>), XlatError> {
+    let ends_of = |packet: &[u8]| -> Result<(Ends<Ipv4Addr>, Option<usize>), XlatError> {
+        let upper = upper_v4(packet)?;
+        let ip = Ipv4 { offset: 0 };
+        let (ports, flags, error) = transport(&upper, &packet[upper.start..]);
+        let ends = Ends {
+            src: Ipv4Addr::from(ip.get_src(packet)),
+            dst: Ipv4Addr::from(ip.get_dst(packet)),
+            ports: ports,
+            flags: flags
+        };
+        Ok((ends, if error { Some(upper.start + ICMP_LEN) } else { None }))
+    };
+    let (ends, quote) = ends_of(packet)?;
+    match quote {
+        Some(at) => Ok((ends, Some(ends_of(&packet[at..])?.0))),
+        None => Ok((ends, None))
+    }
+}
+
+// Moves a tcp or udp header from one pseudo header, summed as `old`, to
+// another, and sets its ports. The length and protocol are the same in
+// both, so only the addresses count.
+fn retarget(trans: &mut [u8], protocol: u8, old: u32, new: u32, ports: Option<(Proto, u16, u16)>) {
+    let fold = |sum: u32| !util::checksum_finish(sum);
+    let mut changes = vec!((fold(old), fold(new)));
+    if let Some((_, src_port, dst_port)) = ports {
+        if trans.len() >= 4 {
+            changes.push((word(trans, 0), src_port));
+            changes.push((word(trans, 2), dst_port));
+            set_word(trans, 0, src_port);
+            set_word(trans, 2, dst_port);
+        }
+    }
+    let at = if protocol == PROTO_TCP { 16 } else { 6 };
+    if trans.len() < at + 2 {
+        return
+    }
+    let mut chk = word(trans, at);
+    // no checksum, udp over ipv4 only
+    if protocol == PROTO_UDP && chk == 0 {
+        return
+    }
+    for (old, new) in changes {
+        chk = util::checksum_adjust(chk, old, new);
+    }
+    if protocol == PROTO_UDP && chk == 0 {
+        chk = 0xFFFF;
+    }
+    set_word(trans, at, chk);
+}
+
+// RFC 7915, 5.2: the ICMP type, code and rest of header for an ICMPv6 one
+fn icmp_to_v4(kind: u8, code: u8, rest: u32) -> Option<(u8, u8, u32)> {
+    Some(match (kind, code) {
+        (icmpv6::ECHO_REQUEST, 0) => (icmpv4::ECHO_REQUEST, 0, rest),
+        (icmpv6::ECHO_REPLY, 0) => (icmpv4::ECHO_REPLY, 0, rest),
+        // no route, beyond scope, address unreachable: host unreachable
+        (icmpv6::DEST_UNREACH, 0) | (icmpv6::DEST_UNREACH, 2) | (icmpv6::DEST_UNREACH, 3) =>
+            (icmpv4::DEST_UNREACH, 1, 0),
+        // administratively prohibited
+        (icmpv6::DEST_UNREACH, 1) => (icmpv4::DEST_UNREACH, 10, 0),
+        (icmpv6::DEST_UNREACH, 4) => (icmpv4::DEST_UNREACH, 3, 0),
+        // fragmentation needed, for the mtu less the bigger header
+        (icmpv6::PACKET_TOO_BIG, 0) =>
+            (icmpv4::DEST_UNREACH, 4, rest.saturating_sub(20).min(0xFFFF)),
+        (icmpv6::TIME_EXCEEDED, code) => (icmpv4::TIME_EXCEEDED, code, 0),
+        (icmpv6::PARAM_PROBLEM, 0) => (icmpv4::PARAM_PROBLEM, 0, pointer_to_v4(rest)? << 24),
+        // unrecognised next header: protocol unreachable
+        (icmpv6::PARAM_PROBLEM, 1) => (icmpv4::DEST_UNREACH, 2, 0),
+        _ => return None
+    })
+}
+
+// RFC 7915, 4.2: the ICMPv6 type, code and rest of header for an ICMP one
+fn icmp_to_v6(kind: u8, code: u8, rest: u32) -> Option<(u8, u8, u32)> {
+    Some(match (kind, code) {
+        (icmpv4::ECHO_REQUEST, 0) => (icmpv6::ECHO_REQUEST, 0, rest),
+        (icmpv4::ECHO_REPLY, 0) => (icmpv6::ECHO_REPLY, 0, rest),
+        (icmpv4::DEST_UNREACH, 0) | (icmpv4::DEST_UNREACH, 1) | (icmpv4::DEST_UNREACH, 5) |
+        (icmpv4::DEST_UNREACH, 6) | (icmpv4::DEST_UNREACH, 7) | (icmpv4::DEST_UNREACH, 8) |
+        (icmpv4::DEST_UNREACH, 11) | (icmpv4::DEST_UNREACH, 12) => (icmpv6::DEST_UNREACH, 0, 0),
+        // protocol unreachable: unrecognised next header, pointing at it
+        (icmpv4::DEST_UNREACH, 2) => (icmpv6::PARAM_PROBLEM, 1, 6),
+        (icmpv4::DEST_UNREACH, 3) => (icmpv6::DEST_UNREACH, 4, 0),
+        // fragmentation needed, for the mtu plus the bigger header
+        (icmpv4::DEST_UNREACH, 4) => {
+            let mtu = (rest & 0xFFFF) + 20;
+            (icmpv6::PACKET_TOO_BIG, 0, mtu.max(pkt::MIN_IPV6_MTU as u32))
+        },
+        (icmpv4::DEST_UNREACH, 9) | (icmpv4::DEST_UNREACH, 10) | (icmpv4::DEST_UNREACH, 13) |
+        (icmpv4::DEST_UNREACH, 15) => (icmpv6::DEST_UNREACH, 1, 0),
+        (icmpv4::TIME_EXCEEDED, code) => (icmpv6::TIME_EXCEEDED, code, 0),
+        (icmpv4::PARAM_PROBLEM, 0) | (icmpv4::PARAM_PROBLEM, 2) =>
+            (icmpv6::PARAM_PROBLEM, 0, pointer_to_v6(rest >> 24)?),
+        _ => return None
+    })
+}
+
+// RFC 7915, figure 6: a field of the ipv6 header to the ipv4 one it became
+fn pointer_to_v4(pointer: u32) -> Option<u32> {
+    Some(match pointer {
+        0 | 1  => pointer,
+        4 | 5  => 2,
+        6      => 9,
+        7      => 8,
+        8..=23 => 12,
+        24..=39 => 16,
+        _      => return None
+    })
+}
+
+// RFC 7915, figure 3
+fn pointer_to_v6(pointer: u32) -> Option<u32> {
+    Some(match pointer {
+        0 | 1   => pointer,
+        2 | 3   => 4,
+        8       => 7,
+        9       => 6,
+        12..=15 => 8,
+        16..=19 => 24,
+        _       => return None
+    })
+}
+
+// The icmp message in `body` mapped by `map`, with the echo identifier
+// from `ends` and the quote, if there is one, translated by `quoted`. The
+// checksum is left for the caller.
+fn translate_icmp<A, F, Q>(body: &[u8], ends: &Ends<A>, map: F, quoted: Q)
+                           -> Result<Vec<u8>, XlatError>
+    where F: Fn(u8, u8, u32) -> Option<(u8, u8, u32)>,
+          Q: FnOnce(&[u8]) -> Result<Vec<u8>, XlatError> {
+    pkt::check_len("icmp", body, ICMP_LEN)?;
+    let rest = (word(body, 4) as u32) << 16 | word(body, 6) as u32;
+    let (kind, code, mut rest) = map(body[0], body[1], rest).ok_or(XlatError::IcmpType(body[0]))?;
+    if let Some((Proto::Icmp, ident, _)) = ends.ports {
+        rest = (ident as u32) << 16 | rest & 0xFFFF;
+    }
+    let mut out = vec!(kind, code, 0, 0,
+                       (rest >> 24) as u8, (rest >> 16) as u8, (rest >> 8) as u8, rest as u8);
+    out.extend_from_slice(&quoted(&body[ICMP_LEN..])?);
+    Ok(out)
+}
+
+// Translates `packet` from ipv6 to ipv4 with the addresses and ports in
+// `ends`, and for an icmp error the datagram it quotes with those in
+// `quote`. `ident` is for an ipv4 header that can't take one from a
+// fragment header.
+pub fn to_ipv4(packet: &[u8], ends: &Ends<Ipv4Addr>, quote: Option<&Ends<Ipv4Addr>>,
+               ident: u16) -> Result<Vec<u8>, XlatError> {
+    v6_to_v4(packet, ends, quote, ident, false)
+}
+
+pub fn to_ipv6(packet: &[u8], ends: &Ends<Ipv6Addr>,
+               quote: Option<&Ends<Ipv6Addr>>) -> Result<Vec<u8>, XlatError> {
+    v4_to_v6(packet, ends, quote, false)
+}
+
+// `quoted` when `packet` is the datagram an error quotes: it may be cut
+// short, its lengths stay what they claim and its checksums are left be
+fn v6_to_v4(packet: &[u8], ends: &Ends<Ipv4Addr>, quote: Option<&Ends<Ipv4Addr>>, ident: u16,
+            quoted: bool) -> Result<Vec<u8>, XlatError> {
+    let upper = upper_v6(packet)?;
+    let ip6 = Ipv6 { offset: 0 };
+    let mut body = packet[upper.start..].to_vec();
+    let mut protocol = upper.protocol;
+
+    if upper.has_header() {
+        match upper.protocol {
+            PROTO_TCP | PROTO_UDP => {
+                let old = util::pseudo_sum_v6(ip6.get_src(packet), ip6.get_dst(packet), 0, 0);
+                let new = util::pseudo_sum_v4(ends.src.octets(), ends.dst.octets(), 0, 0);
+                retarget(&mut body, upper.protocol, old, new, ends.ports);
+            },
+            PROTO_ICMPV6 => {
+                if upper.fragmented() && !quoted {
+                    return Err(XlatError::Untranslatable("a fragmented icmp message"))
+                }
+                let error = body.first().is_some_and(|&kind| kind < 128);
+                body = translate_icmp(&body, ends, icmp_to_v4, |inner| match quote {
+                    Some(quote) if error && !quoted => v6_to_v4(inner, quote, None, ident, true),
+                    _ => Ok(inner.to_vec())
+                })?;
+                if !quoted {
+                    if error {
+                        body.truncate(MAX_ICMPV4_ERROR - IPV4_LEN);
+                    }
+                    let chk = util::checksum(&body);
+                    set_word(&mut body, 2, chk);
+                }
+            },
+            _ => ()
+        }
+    }
+    if upper.protocol == PROTO_ICMPV6 {
+        protocol = PROTO_ICMPV4;
+    }
+
+    // RFC 7915, 5.1
+    let len = IPV4_LEN + if quoted { upper.len } else { body.len() };
+    let mut out = vec![0u8; IPV4_LEN];
+    let ip = Ipv4 { offset: 0 };
+    ip.set_version(&mut out, 4);
+    ip.set_ihl(&mut out, (IPV4_LEN / 4) as u8);
+    ip.set_tos(&mut out, ip6.get_traffic_class(packet));
+    ip.set_len(&mut out, len as u16);
+    match upper.frag {
+        Some(frag) => {
+            ip.set_ident(&mut out, frag.ident as u16);
+            ip.set_flag_mf(&mut out, frag.more as u8);
+            ip.set_frag_offs(&mut out, frag.offset);
+        },
+        // small enough to get through any ipv6 link after fragmenting, so
+        // fragmenting is left to the ipv4 routers
+        None => {
+            ip.set_ident(&mut out, ident);
+            ip.set_flag_df(&mut out, (len > 1260) as u8);
+        }
+    }
+    ip.set_ttl(&mut out, ip6.get_hop_limit(packet));
+    ip.set_protocol(&mut out, protocol);
+    ip.set_src(&mut out, ends.src.octets());
+    ip.set_dst(&mut out, ends.dst.octets());
+    let chk = util::checksum(&out);
+    ip.set_header_chk(&mut out, chk);
+    out.extend_from_slice(&body);
+    Ok(out)
+}
+
+fn v4_to_v6(packet: &[u8], ends: &Ends<Ipv6Addr>, quote: Option<&Ends<Ipv6Addr>>,
+            quoted: bool) -> Result<Vec<u8>, XlatError> {
+    let upper = upper_v4(packet)?;
+    let ip4 = Ipv4 { offset: 0 };
+    let mut body = packet[upper.start..].to_vec();
+    let mut protocol = upper.protocol;
+    let frag_len = if upper.frag.is_some() { FRAG_HEADER_LEN } else { 0 };
+
+    if upper.has_header() {
+        match upper.protocol {
+            PROTO_TCP | PROTO_UDP => {
+                let old = util::pseudo_sum_v4(ip4.get_src(packet), ip4.get_dst(packet), 0, 0);
+                let new = util::pseudo_sum_v6(ends.src.octets(), ends.dst.octets(), 0, 0);
+                retarget(&mut body, upper.protocol, old, new, ends.ports);
+                // ipv6 has no udp without a checksum (RFC 7915, 4.5)
+                if upper.protocol == PROTO_UDP && !quoted && body.len() >= 8 && word(&body, 6) == 0 {
+                    if upper.fragmented() {
+                        return Err(XlatError::Untranslatable("a udp fragment without a checksum"))
+                    }
+                    let pseudo = util::pseudo_sum_v6(ends.src.octets(), ends.dst.octets(),
+                                                     PROTO_UDP, body.len());
+                    let chk = match util::checksum_finish(util::checksum_add(pseudo, &body)) {
+                        0 => 0xFFFF,
+                        chk => chk
+                    };
+                    set_word(&mut body, 6, chk);
+                }
+            },
+            PROTO_ICMPV4 => {
+                if upper.fragmented() && !quoted {
+                    return Err(XlatError::Untranslatable("a fragmented icmp message"))
+                }
+                let error = body.first().is_some_and(|&kind| is_error_v4(kind));
+                body = translate_icmp(&body, ends, icmp_to_v6, |inner| match quote {
+                    Some(quote) if error && !quoted => v4_to_v6(inner, quote, None, true),
+                    _ => Ok(inner.to_vec())
+                })?;
+                if !quoted {
+                    // an ipv6 error has to fit the minimum mtu (RFC 4443, 2.4)
+                    if error {
+                        body.truncate(pkt::MIN_IPV6_MTU - IPV6_LEN - frag_len);
+                    }
+                    let pseudo = util::pseudo_sum_v6(ends.src.octets(), ends.dst.octets(),
+                                                     PROTO_ICMPV6, body.len());
+                    let chk = util::checksum_finish(util::checksum_add(pseudo, &body));
+                    set_word(&mut body, 2, chk);
+                }
+            },
+            _ => ()
+        }
+    }
+    if upper.protocol == PROTO_ICMPV4 {
+        protocol = PROTO_ICMPV6;
+    }
+
+    // RFC 7915, 4.1; options are dropped
+    let len = frag_len + if quoted { upper.len } else { body.len() };
+    let mut out = vec![0u8; IPV6_LEN + frag_len];
+    let ip = Ipv6 { offset: 0 };
+    ip.set_version(&mut out, 6);
+    ip.set_traffic_class(&mut out, ip4.get_tos(packet));
+    ip.set_payload_len(&mut out, len as u16);
+    ip.set_nxt_header(&mut out, if frag_len > 0 { header_types::FRAGMENT } else { protocol });
+    ip.set_hop_limit(&mut out, ip4.get_ttl(packet));
+    ip.set_src(&mut out, ends.src.octets());
+    ip.set_dst(&mut out, ends.dst.octets());
+    if let Some(frag) = upper.frag {
+        let fh = Ipv6Frag { offset: IPV6_LEN };
+        let buff = &mut out[IPV6_LEN..];
+        fh.set_nxt_header(buff, protocol);
+        fh.set_frag_offs(buff, frag.offset);
+        fh.set_flag_m(buff, frag.more as u8);
+        fh.set_ident(buff, frag.ident);
+    }
+    out.extend_from_slice(&body);
+    Ok(out)
+}
+
+// stateless, for hosts with addresses in the prefix on both sides
+pub struct Siit {
+    prefix: Ipv6Addr,
+    ident: u16
+}
+
+impl Siit {
+    pub fn new(prefix: Ipv6Addr) -> Siit {
+        Siit { prefix: prefix, ident: 0 }
+    }
+
+    pub fn prefix(&self) -> Ipv6Addr {
+        self.prefix
+    }
+
+    pub fn to_ipv4(&mut self, packet: &[u8]) -> Result<Vec<u8>, XlatError> {
+        let (ends, quote) = peek_ipv6(packet)?;
+        let prefix = self.prefix;
+        let v4 = |ends: &Ends<Ipv6Addr>| -> Result<Ends<Ipv4Addr>, XlatError> {
+            let addr = |addr| extract(prefix, addr).ok_or(XlatError::Address(IpAddr::V6(addr)));
+            Ok(ends.with(addr(ends.src)?, addr(ends.dst)?))
+        };
+        let quote = match quote {
+            Some(ref quote) => Some(v4(quote)?),
+            None => None
+        };
+        self.ident = self.ident.wrapping_add(1);
+        to_ipv4(packet, &v4(&ends)?, quote.as_ref(), self.ident)
+    }
+
+    pub fn to_ipv6(&self, packet: &[u8]) -> Result<Vec<u8>, XlatError> {
+        let (ends, quote) = peek_ipv4(packet)?;
+        let v6 = |ends: &Ends<Ipv4Addr>| ends.with(embed(self.prefix, ends.src),
+                                                    embed(self.prefix, ends.dst));
+        to_ipv6(packet, &v6(&ends), quote.as_ref().map(v6).as_ref())
+    }
+}
+
+// stateful, ipv6 hosts behind one ipv4 address
+pub struct Nat64 {
+    prefix: Ipv6Addr,
+    pub bindings: Bindings<SocketAddrV6>,
+    ident: u16
+}
+
+impl Nat64 {
+    pub fn new(prefix: Ipv6Addr, pool: Ipv4Addr, timeouts: Timeouts) -> Nat64 {
+        Nat64 { prefix: prefix, bindings: Bindings::new(pool, timeouts), ident: 0 }
+    }
+
+    pub fn prefix(&self) -> Ipv6Addr {
+        self.prefix
+    }
+
+    pub fn pool(&self) -> Ipv4Addr {
+        self.bindings.external()
+    }
+
+    // from the ipv6 side to an address in the prefix, mapping the source
+    // if it's new
+    pub fn to_ipv4(&mut self, packet: &[u8], now: Instant) -> Result<Vec<u8>, XlatError> {
+        let (ends, quote) = peek_ipv6(packet)?;
+        let pool = self.pool();
+        let prefix = self.prefix;
+        let remote = |addr| extract(prefix, addr).ok_or(XlatError::Address(IpAddr::V6(addr)));
+        self.ident = self.ident.wrapping_add(1);
+
+        let quote = match quote {
+            None => {
+                let (proto, src_port, dst_port) = match ends.ports {
+                    Some(ports) => ports,
+                    None => return Err(XlatError::Untranslatable("a datagram without ports"))
+                };
+                let internal = SocketAddrV6::new(ends.src, src_port, 0, 0);
+                let port = self.bindings.map(proto, internal, src_port, now)?;
+                self.bindings.touch(proto, port, ends.flags, now);
+                let dst_port = if proto == Proto::Icmp { port } else { dst_port };
+                let out = Ends { src: pool, dst: remote(ends.dst)?, ports: Some((proto, port, dst_port)),
+                                 flags: ends.flags };
+                return to_ipv4(packet, &out, None, self.ident)
+            },
+            Some(quote) => quote
+        };
+        // an error about a flow that came in, the quote's destination is
+        // the host behind us
+        let (proto, src_port, dst_port) = quote.ports.ok_or(XlatError::NoMapping)?;
+        let port = self.bindings.port(proto, SocketAddrV6::new(quote.dst, dst_port, 0, 0))
+            .ok_or(XlatError::NoMapping)?;
+        let src_port = if proto == Proto::Icmp { port } else { src_port };
+        let quote = Ends { src: remote(quote.src)?, dst: pool, ports: Some((proto, src_port, port)),
+                           flags: 0 };
+        let out = Ends { src: pool, dst: remote(ends.dst)?, ports: None, flags: 0 };
+        to_ipv4(packet, &out, Some(&quote), self.ident)
+    }
+
+    // From the ipv4 side to the pool address, back to whoever it's mapped
+    // for. None if nobody is.
+    pub fn to_ipv6(&mut self, packet: &[u8], now: Instant) -> Result<Option<Vec<u8>>, XlatError> {
+        let (ends, quote) = peek_ipv4(packet)?;
+        let src = embed(self.prefix, ends.src);
+        match quote {
+            None => {
+                let (proto, src_port, dst_port) = match ends.ports {
+                    Some(ports) => ports,
+                    None => return Ok(None)
+                };
+                let internal = match self.bindings.internal(proto, dst_port) {
+                    Some(internal) => internal,
+                    None => return Ok(None)
+                };
+                self.bindings.touch(proto, dst_port, ends.flags, now);
+                let src_port = if proto == Proto::Icmp { internal.port() } else { src_port };
+                let out = Ends { src: src, dst: *internal.ip(),
+                                 ports: Some((proto, src_port, internal.port())), flags: ends.flags };
+                to_ipv6(packet, &out, None).map(Some)
+            },
+            Some(quote) => {
+                // about something that went out, from the pool address
+                let (proto, src_port, dst_port) = match quote.ports {
+                    Some(ports) => ports,
+                    None => return Ok(None)
+                };
+                let internal = match self.bindings.internal(proto, src_port) {
+                    Some(internal) => internal,
+                    None => return Ok(None)
+                };
+                let dst_port = if proto == Proto::Icmp { internal.port() } else { dst_port };
+                let quote = Ends { src: *internal.ip(), dst: embed(self.prefix, quote.dst),
+                                   ports: Some((proto, internal.port(), dst_port)), flags: 0 };
+                let out = Ends { src: src, dst: *internal.ip(), ports: None, flags: 0 };
+                to_ipv6(packet, &out, Some(&quote)).map(Some)
+            }
+        }
+    }
+
+    pub fn expire(&mut self, now: Instant) {
+        self.bindings.expire(now);
+    }
+}
+
+// the state's boxed, it's a good deal bigger than a prefix
+pub enum Translator {
+    Stateless(Siit),
+    Stateful(Box<Nat64>)
+}
+
+impl Translator {
+    pub fn prefix(&self) -> Ipv6Addr {
+        match *self {
+            Translator::Stateless(ref siit) => siit.prefix(),
+            Translator::Stateful(ref nat64) => nat64.prefix()
+        }
+    }
+
+    pub fn to_ipv4(&mut self, packet: &[u8], now: Instant) -> Result<Vec<u8>, XlatError> {
+        match *self {
+            Translator::Stateless(ref mut siit) => siit.to_ipv4(packet),
+            Translator::Stateful(ref mut nat64) => nat64.to_ipv4(packet, now)
+        }
+    }
+
+    pub fn to_ipv6(&mut self, packet: &[u8], now: Instant) -> Result<Option<Vec<u8>>, XlatError> {
+        match *self {
+            Translator::Stateless(ref siit) => siit.to_ipv6(packet).map(Some),
+            Translator::Stateful(ref mut nat64) => nat64.to_ipv6(packet, now)
+        }
+    }
+
+    pub fn expire(&mut self, now: Instant) {
+        if let Translator::Stateful(ref mut nat64) = *self {
+            nat64.expire(now);
+        }
+    }
+}
+
+
+// testing
+#[cfg(test)]
+use packet::builder::{Ipv4Builder, Ipv6Builder, UDP_LEN};
+
+#[cfg(test)]
+fn v4(s: &str) -> Ipv4Addr {
+    s.parse().unwrap()
+}
+
+#[cfg(test)]
+fn v6(s: &str) -> Ipv6Addr {
+    s.parse().unwrap()
+}
+
+#[cfg(test)]
+fn valid_v6(packet: &[u8], protocol: u8) -> bool {
+    let ip = Ipv6 { offset: 0 };
+    let pseudo = util::pseudo_sum_v6(ip.get_src(packet), ip.get_dst(packet), protocol,
+                                     packet.len() - IPV6_LEN);
+    util::checksum_finish(util::checksum_add(pseudo, &packet[IPV6_LEN..])) == 0
+}
+
+// fd00::2 asking 192.0.2.7 through the pool address 10.0.1.2: the
+// translator and the query as it went out
+#[cfg(test)]
+fn query(now: Instant) -> (Nat64, Vec<u8>) {
+    let mut nat64 = Nat64::new(WELL_KNOWN_PREFIX, v4("10.0.1.2"), Timeouts::default());
+    let query = Ipv6Builder::new(v6("fd00::2"), v6("64:ff9b::c000:207"))
+        .udp(5000, 53).build(b"query").data;
+    let out = nat64.to_ipv4(&query, now).unwrap();
+    (nat64, out)
+}
+
+#[test]
+fn test_addresses_embed_in_the_prefix() {
+    assert_eq!(embed(WELL_KNOWN_PREFIX, v4("192.0.2.33")), v6("64:ff9b::c000:221"));
+    assert_eq!(extract(WELL_KNOWN_PREFIX, v6("64:ff9b::c000:221")), Some(v4("192.0.2.33")));
+    assert_eq!(extract(WELL_KNOWN_PREFIX, v6("64:ff9b::1:c000:221")), None);
+}
+
+#[test]
+fn test_ipv6_goes_out_from_the_pool() {
+    let (_, out) = query(Instant::now());
+    let ip = Ipv4 { offset: 0 };
+    assert_eq!(Ipv4Addr::from(ip.get_src(&out)), v4("10.0.1.2"));
+    assert_eq!(Ipv4Addr::from(ip.get_dst(&out)), v4("192.0.2.7"));
+    assert!(util::valid_udp(&out));
+}
+
+#[test]
+fn test_replies_come_back_as_ipv6() {
+    let now = Instant::now();
+    let (mut nat64, _) = query(now);
+    let reply = Ipv4Builder::new(v4("192.0.2.7"), v4("10.0.1.2"))
+        .udp(53, 5000).build(b"answer").data;
+    let back = nat64.to_ipv6(&reply, now).unwrap().unwrap();
+    let ip6 = Ipv6 { offset: 0 };
+    assert_eq!(Ipv6Addr::from(ip6.get_src(&back)), v6("64:ff9b::c000:207"));
+    assert_eq!(Ipv6Addr::from(ip6.get_dst(&back)), v6("fd00::2"));
+    assert_eq!(&back[IPV6_LEN + UDP_LEN..], b"answer");
+    assert!(valid_v6(&back, PROTO_UDP));
+
+    // nobody mapped for this one
+    let stray = Ipv4Builder::new(v4("192.0.2.7"), v4("10.0.1.2")).udp(53, 6000).build(b"").data;
+    assert_eq!(nat64.to_ipv6(&stray, now), Ok(None));
+}
+
+#[test]
+fn test_errors_come_back_quoting_ipv6() {
+    let now = Instant::now();
+    let (mut nat64, out) = query(now);
+    // port unreachable about the query: a quoted ipv6 datagram from the host
+    let error = Ipv4Builder::new(v4("192.0.2.7"), v4("10.0.1.2"))
+        .icmp(icmpv4::DEST_UNREACH, 3).build(&out).data;
+    let back = nat64.to_ipv6(&error, now).unwrap().unwrap();
+    assert_eq!(&back[IPV6_LEN..IPV6_LEN + 2], &[icmpv6::DEST_UNREACH, 4]);
+    assert!(valid_v6(&back, PROTO_ICMPV6));
+    let (_, quote) = peek_ipv6(&back).unwrap();
+    assert_eq!(quote.unwrap().src, v6("fd00::2"));
+    assert_eq!(quote.unwrap().ports, Some((Proto::Udp, 5000, 53)));
+}
+
+#[test]
+fn test_stateless_echo_keeps_its_identifier() {
+    let mut siit = Siit::new(WELL_KNOWN_PREFIX);
+    let ping = Ipv6Builder::new(v6("64:ff9b::a00:2"), v6("64:ff9b::c000:207"))
+        .icmp(icmpv6::ECHO_REQUEST, 0).ident(7).build(b"ping").data;
+    let out = siit.to_ipv4(&ping).unwrap();
+    assert_eq!(&out[IPV4_LEN..IPV4_LEN + 2], &[icmpv4::ECHO_REQUEST, 0]);
+    assert_eq!(&out[IPV4_LEN + 4..IPV4_LEN + 6], &[0, 7]);
+    assert_eq!(util::checksum(&out[IPV4_LEN..]), 0);
+}
+
+#[test]
+fn test_stateless_needs_an_embedded_source() {
+    let mut siit = Siit::new(WELL_KNOWN_PREFIX);
+    let udp = Ipv6Builder::new(v6("fd00::2"), v6("64:ff9b::c000:207")).udp(1, 2).build(b"").data;
+    assert_eq!(siit.to_ipv4(&udp), Err(XlatError::Address(IpAddr::V6(v6("fd00::2")))));
+}
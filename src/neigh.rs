@@ -0,0 +1,308 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+use packet::arp::{self, Arp};
+use packet::builder::{EthBuilder, ETH_LEN, ETHERTYPE_IPV4};
+use packet::eth::Eth;
+use packet::icmpv6;
+
+// neighbour resolution
+//
+// Maps next hops to ethernet addresses, per interface, for ARP (RFC 826)
+// and NDP (RFC 4861) alike. Frames for a next hop that's still being
+// resolved wait in a short queue and come back out once the answer is in;
+// the request is repeated every RETRANS_TIME, and after MAX_TRIES the
+// frames are dropped.
+
+pub type Mac = [u8; 6];
+
+pub const BROADCAST: Mac = [0xFF; 6];
+
+// how long an answer is good for
+pub const REACHABLE_TIME: Duration = Duration::from_secs(30);
+pub const RETRANS_TIME: Duration = Duration::from_secs(1);
+pub const MAX_TRIES: u32 = 3;
+// frames held per unresolved neighbour, the oldest go first
+pub const QUEUE_LEN: usize = 3;
+
+const ARP_LEN: usize = 28;
+const ETHERTYPE_ARP: u16 = 0x0806;
+
+enum State {
+    Incomplete { queue: Vec<Vec<u8>>, tries: u32, sent: Instant },
+    Reachable { mac: Mac, until: Instant }
+}
+
+pub struct Neighbors<A> {
+    entries: HashMap<(usize, A), State>
+}
+
+impl<A: Copy + Eq + Hash> Neighbors<A> {
+    pub fn new() -> Neighbors<A> {
+        Neighbors { entries: HashMap::new() }
+    }
+
+    pub fn get(&self, iface: usize, addr: A, now: Instant) -> Option<Mac> {
+        match self.entries.get(&(iface, addr)) {
+            Some(&State::Reachable { mac, until }) if until > now => Some(mac),
+            _ => None
+        }
+    }
+
+    // whether there's an entry at all, resolved or not
+    pub fn knows(&self, iface: usize, addr: A) -> bool {
+        self.entries.contains_key(&(iface, addr))
+    }
+
+    // Holds `frame` until we know where `addr` is, and says whether that's
+    // something to ask about now. The frame's destination is filled in by
+    // `learn`.
+    pub fn queue(&mut self, iface: usize, addr: A, frame: Vec<u8>, now: Instant) -> bool {
+        let entry = self.entries.entry((iface, addr)).or_insert(State::Incomplete {
+            queue: Vec::new(), tries: 0, sent: now
+        });
+        if let State::Reachable { .. } = *entry {
+            // stale, ask again
+            *entry = State::Incomplete { queue: Vec::new(), tries: 0, sent: now };
+        }
+        match *entry {
+            State::Incomplete { ref mut queue, ref mut tries, ref mut sent } => {
+                if queue.len() == QUEUE_LEN {
+                    queue.remove(0);
+                }
+                queue.push(frame);
+                let solicit = *tries == 0;
+                if solicit {
+                    *tries = 1;
+                    *sent = now;
+                }
+                solicit
+            },
+            State::Reachable { .. } => unreachable!()
+        }
+    }
+
+    // Records `mac` for `addr` and hands back whatever was waiting for it,
+    // with the destination filled in.
+    pub fn learn(&mut self, iface: usize, addr: A, mac: Mac, now: Instant) -> Vec<Vec<u8>> {
+        let old = self.entries.insert((iface, addr), State::Reachable {
+            mac: mac, until: now + REACHABLE_TIME
+        });
+        match old {
+            Some(State::Incomplete { queue, .. }) => queue.into_iter().map(|mut frame| {
+                Eth { offset: 0 }.set_dst(&mut frame, mac);
+                frame
+            }).collect(),
+            _ => Vec::new()
+        }
+    }
+
+    // Drops stale entries and the ones we gave up on, returning the
+    // neighbours to send another request to.
+    pub fn expire(&mut self, now: Instant) -> Vec<(usize, A)> {
+        let mut again = Vec::new();
+        self.entries.retain(|&key, state| match *state {
+            State::Reachable { until, .. } => until > now,
+            State::Incomplete { ref mut tries, ref mut sent, .. } => {
+                if now < *sent + RETRANS_TIME {
+                    return true
+                }
+                if *tries >= MAX_TRIES {
+                    return false
+                }
+                *tries += 1;
+                *sent = now;
+                again.push(key);
+                true
+            }
+        });
+        again
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<A: Copy + Eq + Hash> Default for Neighbors<A> {
+    fn default() -> Neighbors<A> {
+        Neighbors::new()
+    }
+}
+
+// frames
+
+// who has `target`? tell `src`
+pub fn arp_request(mac: Mac, src: Ipv4Addr, target: Ipv4Addr) -> Vec<u8> {
+    let mut frame = vec![0u8; ETH_LEN + ARP_LEN];
+    let eth = Eth { offset: 0 };
+    eth.set_dst(&mut frame, BROADCAST);
+    eth.set_src(&mut frame, mac);
+    eth.set_ethertype(&mut frame, ETHERTYPE_ARP);
+
+    let arp = Arp { offset: ETH_LEN };
+    let buff = &mut frame[ETH_LEN..];
+    arp.set_htype(buff, 1);
+    arp.set_ptype(buff, ETHERTYPE_IPV4);
+    arp.set_hlen(buff, 6);
+    arp.set_plen(buff, 4);
+    arp.set_oper(buff, arp::REQUEST);
+    arp.set_sha(buff, mac);
+    arp.set_spa(buff, src.octets());
+    arp.set_tpa(buff, target.octets());
+    frame
+}
+
+// the multicast group a neighbour solicitation for `addr` goes to
+pub fn solicited_node(addr: Ipv6Addr) -> Ipv6Addr {
+    let o = addr.octets();
+    Ipv6Addr::new(0xFF02, 0, 0, 0, 0, 1, 0xFF00 | o[13] as u16, (o[14] as u16) << 8 | o[15] as u16)
+}
+
+// RFC 2464, 7
+pub fn multicast_mac(addr: Ipv6Addr) -> Mac {
+    let o = addr.octets();
+    [0x33, 0x33, o[12], o[13], o[14], o[15]]
+}
+
+pub mod ndp_options {
+    pub const SRC_LINK_ADDR: u8 = 1;
+    pub const TGT_LINK_ADDR: u8 = 2;
+}
+
+// neighbour advertisement flags
+pub const NA_ROUTER:    u32 = 0x8000_0000;
+pub const NA_SOLICITED: u32 = 0x4000_0000;
+pub const NA_OVERRIDE:  u32 = 0x2000_0000;
+
+// target address and one link layer address option
+fn ndp_body(target: Ipv6Addr, option: u8, mac: Mac) -> Vec<u8> {
+    let mut body = target.octets().to_vec();
+    body.extend_from_slice(&[option, 1]);
+    body.extend_from_slice(&mac);
+    body
+}
+
+pub fn neighbor_solicit(mac: Mac, src: Ipv6Addr, target: Ipv6Addr) -> Vec<u8> {
+    let dst = solicited_node(target);
+    EthBuilder::new(mac, multicast_mac(dst))
+        .ipv6(src, dst)
+        .hop_limit(255)
+        .icmp(icmpv6::NEIGHBOR_SOLICIT, 0)
+        .build(&ndp_body(target, ndp_options::SRC_LINK_ADDR, mac))
+        .data
+}
+
+// we're `target`, at `mac`
+pub fn neighbor_advert(mac: Mac, target: Ipv6Addr, dst: Ipv6Addr, dst_mac: Mac,
+                       flags: u32) -> Vec<u8> {
+    EthBuilder::new(mac, dst_mac)
+        .ipv6(target, dst)
+        .hop_limit(255)
+        .icmp(icmpv6::NEIGHBOR_ADVERT, 0)
+        .rest_of_header(flags)
+        .build(&ndp_body(target, ndp_options::TGT_LINK_ADDR, mac))
+        .data
+}
+
+// The target and link layer address option of a solicitation or
+// advertisement, `body` starting after the icmpv6 header.
+pub fn parse_ndp(body: &[u8], option: u8) -> Option<(Ipv6Addr, Option<Mac>)> {
+    if body.len() < 16 {
+        return None
+    }
+    let mut target = [0u8; 16];
+    target.copy_from_slice(&body[..16]);
+
+    let mut opts = &body[16..];
+    let mut mac = None;
+    while opts.len() >= 2 {
+        let len = opts[1] as usize * 8;
+        // a zero length would have us loop forever (RFC 4861, 4.6)
+        if len == 0 || len > opts.len() {
+            return None
+        }
+        if opts[0] == option && len == 8 {
+            let mut addr = [0u8; 6];
+            addr.copy_from_slice(&opts[2..8]);
+            mac = Some(addr);
+        }
+        opts = &opts[len..];
+    }
+    Some((Ipv6Addr::from(target), mac))
+}
+
+
+// testing
+#[cfg(test)]
+const MAC: Mac = [0x02, 0, 0, 0, 0, 0x10];
+
+#[test]
+fn test_queue_keeps_the_newest_until_learned() {
+    let mut neighbors = Neighbors::new();
+    let addr = Ipv4Addr::new(10, 0, 0, 2);
+    let now = Instant::now();
+    // frames told apart by their last byte
+    for nr in 0..QUEUE_LEN as u8 + 1 {
+        let mut frame = vec![0u8; ETH_LEN];
+        frame[ETH_LEN - 1] = nr;
+        // only the first one asks
+        assert_eq!(neighbors.queue(0, addr, frame, now), nr == 0);
+    }
+    assert!(neighbors.knows(0, addr));
+    assert!(!neighbors.knows(1, addr));
+    assert_eq!(neighbors.get(0, addr, now), None);
+
+    let frames = neighbors.learn(0, addr, MAC, now);
+    let nrs: Vec<u8> = frames.iter().map(|frame| frame[ETH_LEN - 1]).collect();
+    assert_eq!(nrs, [1, 2, 3]);
+    assert!(frames.iter().all(|frame| Eth { offset: 0 }.get_dst(frame) == MAC));
+    assert_eq!(neighbors.get(0, addr, now), Some(MAC));
+    assert!(neighbors.learn(0, addr, MAC, now).is_empty());
+}
+
+#[test]
+fn test_expire_asks_again_then_gives_up() {
+    let mut neighbors = Neighbors::new();
+    let addr = Ipv4Addr::new(10, 0, 0, 2);
+    let now = Instant::now();
+    neighbors.queue(0, addr, vec![0u8; ETH_LEN], now);
+    assert!(neighbors.expire(now).is_empty());
+    for tries in 1..MAX_TRIES {
+        assert_eq!(neighbors.expire(now + RETRANS_TIME * tries), [(0, addr)]);
+    }
+    assert!(neighbors.expire(now + RETRANS_TIME * MAX_TRIES).is_empty());
+    assert!(neighbors.is_empty());
+
+    // answers go stale, and a stale one is asked about again
+    neighbors.learn(0, addr, MAC, now);
+    assert_eq!(neighbors.get(0, addr, now + REACHABLE_TIME), None);
+    assert!(neighbors.queue(0, addr, vec![0u8; ETH_LEN], now + REACHABLE_TIME));
+    neighbors.learn(0, addr, MAC, now);
+    assert!(neighbors.expire(now + REACHABLE_TIME).is_empty());
+    assert!(neighbors.is_empty());
+}
+
+#[test]
+fn test_parse_ndp() {
+    let target = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+    let body = ndp_body(target, ndp_options::SRC_LINK_ADDR, MAC);
+    assert_eq!(parse_ndp(&body, ndp_options::SRC_LINK_ADDR), Some((target, Some(MAC))));
+    // the other option isn't there
+    assert_eq!(parse_ndp(&body, ndp_options::TGT_LINK_ADDR), Some((target, None)));
+    assert_eq!(parse_ndp(&body[..16], ndp_options::SRC_LINK_ADDR), Some((target, None)));
+    assert_eq!(parse_ndp(&body[..15], ndp_options::SRC_LINK_ADDR), None);
+
+    // a zero length, and one past the end
+    let mut bad = body.clone();
+    bad[17] = 0;
+    assert_eq!(parse_ndp(&bad, ndp_options::SRC_LINK_ADDR), None);
+    bad[17] = 2;
+    assert_eq!(parse_ndp(&bad, ndp_options::SRC_LINK_ADDR), None);
+}
@@ -0,0 +1,658 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use packet::filter::Filter;
+use packet::pkt::Link;
+use packet::view::PacketView;
+
+// network impairment emulation
+//
+// Makes the forwarding path as bad as a real network can be, the way
+// netem does: each direction has its rules, an optional capture filter
+// and `->` followed by what happens to the packets that match:
+//
+//   udp -> delay 100ms 20ms normal loss 1%
+//   tcp port 80 -> loss gemodel 1% 30% rate 2mbit
+//   delay 50ms reorder 25% duplicate 1% corrupt 0.1%
+//
+//   delay <time> [<jitter> [uniform | normal]]
+//                       held back this long, give or take the jitter
+//   loss <p>            dropped at random
+//   loss gemodel <p> [<r> [<1-h> [<1-k>]]]
+//                       dropped in bursts, Gilbert-Elliott: going from the
+//                       good state to the bad one with p, back with r
+//                       (1-p), losing 1-h (100%) when bad, 1-k (0%) when good
+//   reorder <p>         sent right away instead of being delayed
+//   duplicate <p>       sent twice, each copy delayed on its own
+//   corrupt <p>         with one bit flipped, past the link header
+//   rate <n>[k|m|g]bit  sent no faster than this per link, delay coming
+//                       after
+//
+// The first rule that matches decides, packets that match none go through
+// untouched, without waiting behind anything. Whatever is held back waits
+// in a queue of at most `QUEUE_LIMIT` packets, anything more is lost. All
+// the chances come from one generator per direction, so with the same seed
+// and the same traffic the same packets are lost, duplicated and
+// corrupted.
+
+// packets held back per direction
+pub const QUEUE_LIMIT: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Jitter {
+    Uniform,
+    Normal
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Loss {
+    // chances in percent, as given
+    Random(f64),
+    Gilbert { p: f64, r: f64, bad: f64, good: f64 }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Impairment {
+    // every packet without one
+    pub filter: Option<Filter>,
+    pub delay: Duration,
+    pub jitter: Duration,
+    pub distribution: Jitter,
+    pub loss: Option<Loss>,
+    pub reorder: f64,
+    pub duplicate: f64,
+    pub corrupt: f64,
+    // bits per second
+    pub rate: Option<u64>
+}
+
+impl Default for Impairment {
+    fn default() -> Impairment {
+        Impairment {
+            filter: None,
+            delay: Duration::from_secs(0),
+            jitter: Duration::from_secs(0),
+            distribution: Jitter::Uniform,
+            loss: None,
+            reorder: 0.0,
+            duplicate: 0.0,
+            corrupt: 0.0,
+            rate: None
+        }
+    }
+}
+
+fn parse_time(s: &str) -> Option<Duration> {
+    let (num, scale) = if let Some(num) = s.strip_suffix("us") {
+        (num, 1e-6)
+    } else if let Some(num) = s.strip_suffix("ms") {
+        (num, 1e-3)
+    } else {
+        (s.strip_suffix('s')?, 1.0)
+    };
+    match num.parse::<f64>() {
+        Ok(num) if num >= 0.0 && num.is_finite() => Some(Duration::from_secs_f64(num * scale)),
+        _ => None
+    }
+}
+
+fn parse_percent(s: &str) -> Option<f64> {
+    match s.strip_suffix('%')?.parse::<f64>() {
+        Ok(p) if (0.0..=100.0).contains(&p) => Some(p),
+        _ => None
+    }
+}
+
+fn parse_rate(s: &str) -> Option<u64> {
+    let num = s.strip_suffix("bit")?;
+    let (num, scale) = match num.chars().last()? {
+        'k' => (&num[..num.len() - 1], 1_000),
+        'm' => (&num[..num.len() - 1], 1_000_000),
+        'g' => (&num[..num.len() - 1], 1_000_000_000),
+        _ => (num, 1)
+    };
+    match num.parse::<u64>() {
+        Ok(num) if num > 0 => num.checked_mul(scale),
+        _ => None
+    }
+}
+
+struct Time(Duration);
+
+impl fmt::Display for Time {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let nanos = self.0.as_nanos();
+        if nanos.is_multiple_of(1_000_000_000) {
+            write!(f, "{}s", nanos / 1_000_000_000)
+        } else if nanos.is_multiple_of(1_000_000) {
+            write!(f, "{}ms", nanos / 1_000_000)
+        } else {
+            write!(f, "{}us", nanos as f64 / 1e3)
+        }
+    }
+}
+
+struct Rate(u64);
+
+impl fmt::Display for Rate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            rate if rate.is_multiple_of(1_000_000_000) => write!(f, "{}gbit", rate / 1_000_000_000),
+            rate if rate.is_multiple_of(1_000_000)     => write!(f, "{}mbit", rate / 1_000_000),
+            rate if rate.is_multiple_of(1_000)         => write!(f, "{}kbit", rate / 1_000),
+            rate                              => write!(f, "{}bit", rate)
+        }
+    }
+}
+
+impl FromStr for Impairment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Impairment, String> {
+        let (filter, rest) = match s.find("->") {
+            Some(at) => (Some(s[..at].parse::<Filter>()?), &s[at + 2..]),
+            None => (None, s)
+        };
+        let mut imp = Impairment { filter: filter, ..Impairment::default() };
+        let bad = |what: &str| format!("bad {} in impairment: {}", what, s);
+        let words: Vec<&str> = rest.split_whitespace().collect();
+        if words.is_empty() {
+            return Err(format!("impairment does nothing: {}", s))
+        }
+        let mut idx = 0;
+        // the optional arguments that come after a keyword
+        let optional = |idx: &mut usize, parse: &dyn Fn(&str) -> bool| -> Option<&str> {
+            let word = words.get(*idx).cloned().filter(|word| parse(word))?;
+            *idx += 1;
+            Some(word)
+        };
+        while idx < words.len() {
+            let keyword = words[idx];
+            let arg = words.get(idx + 1).cloned().unwrap_or("");
+            idx += 2;
+            match keyword {
+                "delay" => {
+                    imp.delay = parse_time(arg).ok_or_else(|| bad("delay"))?;
+                    if let Some(jitter) = optional(&mut idx, &|word| parse_time(word).is_some()) {
+                        imp.jitter = parse_time(jitter).unwrap();
+                    }
+                    match optional(&mut idx, &|word| word == "uniform" || word == "normal") {
+                        Some("normal") => imp.distribution = Jitter::Normal,
+                        _ => imp.distribution = Jitter::Uniform
+                    }
+                },
+                "loss" if arg == "gemodel" => {
+                    let p = words.get(idx).and_then(|word| parse_percent(word)).ok_or_else(|| bad("loss"))?;
+                    idx += 1;
+                    let mut rest = [100.0 - p, 100.0, 0.0];
+                    for val in rest.iter_mut() {
+                        match optional(&mut idx, &|word| parse_percent(word).is_some()) {
+                            Some(word) => *val = parse_percent(word).unwrap(),
+                            None => break
+                        }
+                    }
+                    imp.loss = Some(Loss::Gilbert { p: p, r: rest[0], bad: rest[1], good: rest[2] });
+                },
+                "loss" => imp.loss = Some(Loss::Random(parse_percent(arg).ok_or_else(|| bad("loss"))?)),
+                "reorder" => imp.reorder = parse_percent(arg).ok_or_else(|| bad("reorder"))?,
+                "duplicate" => imp.duplicate = parse_percent(arg).ok_or_else(|| bad("duplicate"))?,
+                "corrupt" => imp.corrupt = parse_percent(arg).ok_or_else(|| bad("corrupt"))?,
+                "rate" => imp.rate = Some(parse_rate(arg).ok_or_else(|| bad("rate"))?),
+                _ => return Err(format!("unknown impairment {}: {}", keyword, s))
+            }
+        }
+        // there's nothing to jump ahead of otherwise
+        if imp.reorder > 0.0 && imp.delay == Duration::from_secs(0) {
+            return Err(format!("reordering needs a delay: {}", s))
+        }
+        Ok(imp)
+    }
+}
+
+impl fmt::Display for Impairment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut words = Vec::new();
+        if self.delay > Duration::from_secs(0) || self.jitter > Duration::from_secs(0) {
+            words.push(format!("delay {}", Time(self.delay)));
+            if self.jitter > Duration::from_secs(0) {
+                words.push(Time(self.jitter).to_string());
+                if self.distribution == Jitter::Normal {
+                    words.push("normal".to_string());
+                }
+            }
+        }
+        match self.loss {
+            Some(Loss::Random(p)) => words.push(format!("loss {}%", p)),
+            Some(Loss::Gilbert { p, r, bad, good }) =>
+                words.push(format!("loss gemodel {}% {}% {}% {}%", p, r, bad, good)),
+            None => ()
+        }
+        for &(name, p) in &[("reorder", self.reorder), ("duplicate", self.duplicate),
+                            ("corrupt", self.corrupt)] {
+            if p > 0.0 {
+                words.push(format!("{} {}%", name, p));
+            }
+        }
+        if let Some(rate) = self.rate {
+            words.push(format!("rate {}", Rate(rate)));
+        }
+        if let Some(ref filter) = self.filter {
+            write!(f, "{} -> ", filter)?;
+        }
+        f.write_str(&words.join(" "))
+    }
+}
+
+// xorshift64*, small and the same everywhere, which is the point
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // splitmix64, so nearby seeds don't start out alike; never 0
+        let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        Rng((z ^ (z >> 31)) | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    // in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // true `percent` % of the time
+    pub fn chance(&mut self, percent: f64) -> bool {
+        percent > 0.0 && self.next_f64() * 100.0 < percent
+    }
+
+    // standard normal, Box-Muller
+    fn normal(&mut self) -> f64 {
+        let u = 1.0 - self.next_f64();
+        let v = self.next_f64();
+        (-2.0 * u.ln()).sqrt() * (2.0 * ::std::f64::consts::PI * v).cos()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lost {
+    // by that rule
+    Rule(usize),
+    QueueFull
+}
+
+impl fmt::Display for Lost {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Lost::Rule(rule) => write!(f, "lost to impairment rule {}", rule),
+            Lost::QueueFull  => f.write_str("impairment queue full")
+        }
+    }
+}
+
+// what a rule keeps between packets
+#[derive(Default)]
+struct State {
+    // Gilbert-Elliott
+    bad: bool,
+    // when the rate limit lets the next one start, per link
+    free_at: HashMap<usize, Instant>
+}
+
+struct Pending {
+    due: Instant,
+    // first in, first out among the ones due at once
+    seq: u64,
+    iface: usize,
+    frame: Vec<u8>
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Pending) -> bool {
+        (self.due, self.seq) == (other.due, other.seq)
+    }
+}
+
+impl Eq for Pending {}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Pending) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pending {
+    fn cmp(&self, other: &Pending) -> Ordering {
+        (self.due, self.seq).cmp(&(other.due, other.seq))
+    }
+}
+
+// the impairments in one direction, and what they're holding back
+pub struct Impairer {
+    rules: Vec<Impairment>,
+    states: Vec<State>,
+    rng: Rng,
+    queue: BinaryHeap<Reverse<Pending>>,
+    seq: u64
+}
+
+impl Impairer {
+    pub fn new(rules: Vec<Impairment>, seed: u64) -> Impairer {
+        Impairer {
+            states: rules.iter().map(|_| State::default()).collect(),
+            rules: rules,
+            rng: Rng::new(seed),
+            queue: BinaryHeap::new(),
+            seq: 0
+        }
+    }
+
+    // Takes a copy of `frame`, for or from link `iface`, to come out of
+    // `pop` when its rule says it's due, unless it's lost. False if no rule
+    // has it, for the caller to send on as it is.
+    pub fn push(&mut self, iface: usize, frame: &[u8], link: &Link, now: Instant) -> Result<bool, Lost> {
+        if self.rules.is_empty() {
+            return Ok(false)
+        }
+        // what can't be dissected only meets rules for everything
+        let (rule, net_offset) = match PacketView::with_link(frame, link) {
+            Ok(view) => (self.rules.iter().position(|rule| {
+                rule.filter.as_ref().is_none_or(|filter| filter.matches(&view))
+            }), view.net.offset()),
+            Err(_) => (self.rules.iter().position(|rule| rule.filter.is_none()), 0)
+        };
+        let idx = match rule {
+            Some(idx) => idx,
+            None => return Ok(false)
+        };
+        let room = QUEUE_LIMIT - self.queue.len();
+        let rule = &self.rules[idx];
+        let state = &mut self.states[idx];
+        let rng = &mut self.rng;
+
+        let lost = match rule.loss {
+            Some(Loss::Random(p)) => rng.chance(p),
+            Some(Loss::Gilbert { p, r, bad, good }) => {
+                state.bad = if state.bad { !rng.chance(r) } else { rng.chance(p) };
+                rng.chance(if state.bad { bad } else { good })
+            },
+            None => false
+        };
+        if lost {
+            return Err(Lost::Rule(idx))
+        }
+        // before it takes up any of the link's time
+        if room == 0 {
+            return Err(Lost::QueueFull)
+        }
+
+        // serialized at the rate first, then on its way
+        let mut start = now;
+        if let Some(rate) = rule.rate {
+            let free_at = state.free_at.entry(iface).or_insert(now);
+            let bits = frame.len() as u64 * 8;
+            start = (*free_at).max(now) + Duration::from_nanos(bits * 1_000_000_000 / rate);
+            *free_at = start;
+        }
+        // a copy there's no room for isn't made
+        let copies = if rng.chance(rule.duplicate) { 2.min(room) } else { 1 };
+        let mut dues = Vec::with_capacity(copies);
+        for _ in 0..copies {
+            let due = if rng.chance(rule.reorder) {
+                start
+            } else {
+                start + delay(rule, rng)
+            };
+            let bit = if rng.chance(rule.corrupt) && frame.len() > net_offset {
+                Some(net_offset * 8 + rng.next_u64() as usize % ((frame.len() - net_offset) * 8))
+            } else {
+                None
+            };
+            dues.push((due, bit));
+        }
+        for (due, bit) in dues {
+            let mut frame = frame.to_vec();
+            if let Some(bit) = bit {
+                frame[bit / 8] ^= 0x80 >> (bit % 8);
+            }
+            self.seq += 1;
+            self.queue.push(Reverse(Pending { due: due, seq: self.seq, iface: iface, frame: frame }));
+        }
+        Ok(true)
+    }
+
+    // the next frame due by `now`, and its link
+    pub fn pop(&mut self, now: Instant) -> Option<(usize, Vec<u8>)> {
+        if self.queue.peek()?.0.due > now {
+            return None
+        }
+        self.queue.pop().map(|Reverse(pending)| (pending.iface, pending.frame))
+    }
+
+    // when `pop` will have something next
+    pub fn next_due(&self) -> Option<Instant> {
+        self.queue.peek().map(|pending| pending.0.due)
+    }
+}
+
+fn delay(rule: &Impairment, rng: &mut Rng) -> Duration {
+    let jitter = rule.jitter.as_secs_f64();
+    let offset = match rule.distribution {
+        Jitter::Uniform => jitter * (2.0 * rng.next_f64() - 1.0),
+        Jitter::Normal => jitter * rng.normal()
+    };
+    Duration::from_secs_f64((rule.delay.as_secs_f64() + offset).max(0.0))
+}
+
+// both directions of the forwarding path
+pub struct Netem {
+    // as frames come in, before anything else sees them
+    pub ingress: Impairer,
+    // as they go out, once routed
+    pub egress: Impairer
+}
+
+impl Netem {
+    pub fn new(ingress: Vec<Impairment>, egress: Vec<Impairment>, seed: u64) -> Netem {
+        Netem {
+            ingress: Impairer::new(ingress, seed),
+            egress: Impairer::new(egress, !seed)
+        }
+    }
+
+    pub fn next_due(&self) -> Option<Instant> {
+        match (self.ingress.next_due(), self.egress.next_due()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b)
+        }
+    }
+}
+
+
+// testing
+#[cfg(test)]
+use packet::builder::Ipv4Builder;
+#[cfg(test)]
+use std::net::Ipv4Addr;
+
+#[cfg(test)]
+fn udp() -> Vec<u8> {
+    Ipv4Builder::new(Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(192, 0, 2, 1)).udp(4000, 53).build(&[0; 100]).data
+}
+
+#[cfg(test)]
+fn impairer(rule: &str) -> Impairer {
+    Impairer::new(vec![rule.parse().unwrap()], 1)
+}
+
+#[cfg(test)]
+fn ms(start: Instant, n: u64) -> Instant {
+    start + Duration::from_millis(n)
+}
+
+#[test]
+fn test_rules_print_as_parsed() {
+    let rule: Impairment = "udp -> delay 100ms 20ms normal loss gemodel 1% 30% corrupt 0.5%".parse().unwrap();
+    assert_eq!(rule.to_string(), "udp -> delay 100ms 20ms normal loss gemodel 1% 30% 100% 0% corrupt 0.5%");
+    assert_eq!("rate 1500kbit delay 1.5ms duplicate 2%".parse::<Impairment>().unwrap().to_string(),
+               "delay 1500us duplicate 2% rate 1500kbit");
+    assert_eq!("reorder 5%".parse::<Impairment>(), Err("reordering needs a delay: reorder 5%".to_string()));
+    assert_eq!("loss 101%".parse::<Impairment>(), Err("bad loss in impairment: loss 101%".to_string()));
+}
+
+#[test]
+fn test_same_seed_same_impairments() {
+    let udp = udp();
+    let start = Instant::now();
+    let run = |seed: u64| {
+        let rules = vec!["udp -> delay 10ms 5ms loss 20% duplicate 10% corrupt 10%".parse().unwrap()];
+        let mut netem = Impairer::new(rules, seed);
+        let mut out = Vec::new();
+        for idx in 0..200 {
+            let _ = netem.push(idx, &udp, &Link::RawLink, start);
+        }
+        while let Some(frame) = netem.pop(ms(start, 20)) {
+            out.push(frame);
+        }
+        assert_eq!(netem.next_due(), None);
+        out
+    };
+    let out = run(7);
+    assert_eq!(out, run(7));
+    assert!(out != run(8));
+    // about 160 through and 16 twice, a few of them with a bit flipped
+    assert!(out.len() > 140 && out.len() < 210);
+    let flipped: Vec<u32> = out.iter().map(|(_, frame)| {
+        frame.iter().zip(&udp).map(|(x, y)| (x ^ y).count_ones()).sum()
+    }).collect();
+    let corrupted = flipped.iter().filter(|&&bits| bits == 1).count();
+    assert!(corrupted > 0 && corrupted < 40);
+    assert_eq!(flipped.iter().filter(|&&bits| bits > 1).count(), 0);
+}
+
+#[test]
+fn test_unmatched_frames_are_passed_back() {
+    let tcp = Ipv4Builder::new(Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(192, 0, 2, 1))
+        .tcp(4000, 80).build(b"").data;
+    let mut netem = impairer("udp -> delay 10ms");
+    let start = Instant::now();
+    assert_eq!(netem.push(0, &tcp, &Link::RawLink, start), Ok(false));
+    assert_eq!(netem.next_due(), None);
+    // however full the queue is
+    for _ in 0..QUEUE_LIMIT {
+        assert_eq!(netem.push(0, &udp(), &Link::RawLink, start), Ok(true));
+    }
+    assert_eq!(netem.push(0, &udp(), &Link::RawLink, start), Err(Lost::QueueFull));
+    assert_eq!(netem.push(0, &tcp, &Link::RawLink, start), Ok(false));
+    assert_eq!(Impairer::new(Vec::new(), 1).push(0, &udp(), &Link::RawLink, start), Ok(false));
+}
+
+#[test]
+fn test_delay_holds_frames_until_due() {
+    let mut netem = impairer("delay 10ms");
+    let start = Instant::now();
+    netem.push(0, &udp(), &Link::RawLink, start).unwrap();
+    netem.push(1, &udp(), &Link::RawLink, ms(start, 1)).unwrap();
+    assert_eq!(netem.pop(ms(start, 9)), None);
+    assert_eq!(netem.next_due(), Some(ms(start, 10)));
+    assert_eq!(netem.pop(ms(start, 10)), Some((0, udp())));
+    assert_eq!(netem.pop(ms(start, 10)), None);
+    assert_eq!(netem.pop(ms(start, 11)), Some((1, udp())));
+}
+
+#[test]
+fn test_rate_spaces_frames_per_link() {
+    // 128 bytes at 1024kbit take a millisecond each, back to back
+    let mut netem = impairer("rate 1024kbit");
+    let start = Instant::now();
+    let frame = vec![0x45; 128];
+    for _ in 0..3 {
+        netem.push(0, &frame, &Link::RawLink, start).unwrap();
+    }
+    // another link has the rate to itself
+    netem.push(1, &frame, &Link::RawLink, start).unwrap();
+    assert_eq!(netem.next_due(), Some(ms(start, 1)));
+    let out: Vec<usize> = (0..4).filter_map(|_| netem.pop(ms(start, 1))).map(|(iface, _)| iface).collect();
+    assert_eq!(out, [0, 1]);
+    assert!(netem.pop(ms(start, 2)).is_some() && netem.pop(ms(start, 2)).is_none());
+    assert_eq!(netem.next_due(), Some(ms(start, 3)));
+}
+
+#[test]
+fn test_full_queue_takes_no_time_at_the_rate() {
+    let mut netem = impairer("rate 1024kbit");
+    let start = Instant::now();
+    let frame = vec![0x45; 128];
+    for _ in 0..QUEUE_LIMIT {
+        netem.push(0, &frame, &Link::RawLink, start).unwrap();
+    }
+    for _ in 0..3 {
+        assert_eq!(netem.push(0, &frame, &Link::RawLink, start), Err(Lost::QueueFull));
+    }
+    let last = ms(start, QUEUE_LIMIT as u64);
+    while netem.pop(last).is_some() {}
+    // right after the last one that made it, not three later
+    netem.push(0, &frame, &Link::RawLink, start).unwrap();
+    assert_eq!(netem.next_due(), Some(ms(start, QUEUE_LIMIT as u64 + 1)));
+}
+
+#[test]
+fn test_reorder_skips_the_delay() {
+    let start = Instant::now();
+    let mut netem = impairer("delay 10ms reorder 100%");
+    netem.push(0, &udp(), &Link::RawLink, start).unwrap();
+    assert_eq!(netem.pop(start), Some((0, udp())));
+
+    // some jump the queue, the rest wait their turn
+    let mut netem = impairer("delay 10ms reorder 30%");
+    for idx in 0..100 {
+        netem.push(idx, &udp(), &Link::RawLink, start).unwrap();
+    }
+    let early = (0..100).filter_map(|_| netem.pop(start)).count();
+    assert!(early > 10 && early < 50);
+    assert_eq!(netem.next_due(), Some(ms(start, 10)));
+    let late = (0..100).filter_map(|_| netem.pop(ms(start, 10))).count();
+    assert_eq!(early + late, 100);
+}
+
+#[test]
+fn test_gemodel_goes_bad_and_stays() {
+    let start = Instant::now();
+    // good to bad every time, never back
+    let mut netem = impairer("loss gemodel 100% 0%");
+    assert!(!netem.states[0].bad);
+    for _ in 0..3 {
+        assert_eq!(netem.push(0, &udp(), &Link::RawLink, start), Err(Lost::Rule(0)));
+        assert!(netem.states[0].bad);
+    }
+    // never bad, never lost
+    let mut netem = impairer("loss gemodel 0%");
+    for _ in 0..100 {
+        assert_eq!(netem.push(0, &udp(), &Link::RawLink, start), Ok(true));
+    }
+    assert!(!netem.states[0].bad);
+}
+
+#[test]
+fn test_gemodel_loses_while_bad_until_it_recovers() {
+    let start = Instant::now();
+    // lost for as long as it's bad
+    let mut netem = impairer("loss gemodel 0% 0%");
+    netem.states[0].bad = true;
+    assert_eq!(netem.push(0, &udp(), &Link::RawLink, start), Err(Lost::Rule(0)));
+    assert_eq!(netem.push(0, &udp(), &Link::RawLink, start), Err(Lost::Rule(0)));
+    // and back to good right after
+    let mut netem = impairer("loss gemodel 0% 100%");
+    netem.states[0].bad = true;
+    assert_eq!(netem.push(0, &udp(), &Link::RawLink, start), Ok(true));
+    assert!(!netem.states[0].bad);
+}
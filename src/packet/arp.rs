@@ -0,0 +1,84 @@
+use super::pkt;
+use super::pkt::{write_imm, write_arr};
+use super::eth::print_eth;
+use super::ipv4::print_ipv4;
+
+// ARP
+// RFC 826, for ethernet and ipv4 only
+//
+//  0                   1                   2                   3
+//  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |         Hardware Type         |         Protocol Type         |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |  HW Addr Len  | Proto Addr Len|           Operation           |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |              Sender Hardware Address (6 bytes)                |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |              Sender Protocol Address (4 bytes)                |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |              Target Hardware Address (6 bytes)               |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |              Target Protocol Address (4 bytes)                |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+#[derive(Debug, Clone, Copy)]
+pub struct Arp {
+    pub offset: usize
+}
+
+pub const REQUEST: u16 = 1;
+pub const REPLY:   u16 = 2;
+
+netbits!{
+    Arp, write_imm, write_arr,
+    htype:      16,
+    ptype:      16,
+    hlen:        8,
+    plen:        8,
+    oper:       16,
+    sha:   [8; 6; print_eth],
+    spa:   [8; 4; print_ipv4],
+    tha:   [8; 6; print_eth],
+    tpa:   [8; 4; print_ipv4]
+}
+
+impl Arp {
+    // turn a request into the reply for it, in place, answering with `mac`
+    pub fn make_reply(&self, buff: &mut [u8], mac: [u8; 6]) {
+        let sha = self.get_sha(buff);
+        let spa = self.get_spa(buff);
+        let tpa = self.get_tpa(buff);
+
+        self.set_oper(buff, REPLY);
+        self.set_tha(buff, sha);
+        self.set_tpa(buff, spa);
+        self.set_sha(buff, mac);
+        self.set_spa(buff, tpa);
+    }
+}
+
+impl pkt::HasNetworkLayer for Arp {
+    fn header_len(&self, buff: &[u8]) -> Result<usize, pkt::DissectError> {
+        pkt::check_len("arp", buff, Arp::HEADER_LEN)?;
+        Ok(Arp::HEADER_LEN)
+    }
+
+    fn declared_len(&self, buff: &[u8]) -> Option<usize> {
+        Some(8 + 2 * self.get_hlen(buff) as usize + 2 * self.get_plen(buff) as usize)
+    }
+
+    // arp doesn't carry a transport layer
+    fn get_transport(&self, buffer: &[u8]) -> Result<Option<pkt::Transport>, pkt::DissectError> {
+        self.header_len(buffer)?;
+        Ok(None)
+    }
+
+    fn get_payload_offset(&self, _buff: &[u8]) -> usize {
+        self.offset + 28
+    }
+
+    fn print(&self, buff: &[u8]) {
+        println!("arp:");
+        self.print_fields(buff);
+    }
+}
@@ -0,0 +1,540 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use super::pkt;
+use super::eth::Eth;
+use super::ipv4::Ipv4;
+use super::ipv6::Ipv6;
+use super::tcp::Tcp;
+use super::udp::Udp;
+use super::icmpv4::Icmpv4;
+use super::icmpv6::Icmpv6;
+use super::super::util;
+
+// building packets from scratch
+//
+// Each layer's builder hands over to the next one up:
+//
+//     EthBuilder::new(src_mac, dst_mac)
+//         .ipv4(src, dst).ttl(32)
+//         .udp(5353, 53)
+//         .build(&payload)
+//
+// and `build` writes the lot into one buffer with the netbits setters,
+// filling in lengths, header sizes and checksums on the way. Leave out the
+// EthBuilder for a bare ip packet, as a tun device wants it.
+//
+// Lengths that don't fit their header field are a bug in the caller, so
+// `build` panics on them rather than wrap: more than 64K of ip packet (or
+// of ipv6 payload, there are no jumbograms), or more than 40 bytes of tcp
+// options.
+
+pub const ETH_LEN:    usize = 14;
+pub const IPV4_LEN:   usize = 20;
+pub const IPV6_LEN:   usize = 40;
+pub const TCP_LEN:    usize = 20;
+pub const UDP_LEN:    usize = 8;
+pub const ICMP_LEN:   usize = 8;
+
+// what the 4 bit data offset leaves room for
+pub const MAX_TCP_OPTIONS: usize = 40;
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_IPV6: u16 = 0x86DD;
+
+pub const PROTO_ICMPV4: u8 = 0x01;
+pub const PROTO_TCP:    u8 = 0x06;
+pub const PROTO_UDP:    u8 = 0x11;
+pub const PROTO_ICMPV6: u8 = 0x3A;
+
+// The mss to offer on a link with `mtu`: what's left after the ip and tcp
+// headers, options not counted (RFC 879).
+pub fn tcp_mss(mtu: usize, ipv6: bool) -> u16 {
+    let ip_len = if ipv6 { IPV6_LEN } else { IPV4_LEN };
+    mtu.saturating_sub(ip_len + TCP_LEN).min(0xFFFF) as u16
+}
+
+// link layer
+
+#[derive(Clone)]
+pub struct EthBuilder {
+    src: [u8; 6],
+    dst: [u8; 6]
+}
+
+impl EthBuilder {
+    pub fn new(src: [u8; 6], dst: [u8; 6]) -> EthBuilder {
+        EthBuilder { src: src, dst: dst }
+    }
+
+    pub fn ipv4(self, src: Ipv4Addr, dst: Ipv4Addr) -> Ipv4Builder {
+        let mut ip = Ipv4Builder::new(src, dst);
+        ip.eth = Some(self);
+        ip
+    }
+
+    pub fn ipv6(self, src: Ipv6Addr, dst: Ipv6Addr) -> Ipv6Builder {
+        let mut ip = Ipv6Builder::new(src, dst);
+        ip.eth = Some(self);
+        ip
+    }
+
+    fn write(&self, buff: &mut [u8], ethertype: u16) {
+        let eth = Eth { offset: 0 };
+        eth.set_dst(buff, self.dst);
+        eth.set_src(buff, self.src);
+        eth.set_ethertype(buff, ethertype);
+    }
+}
+
+// network layer
+
+#[derive(Clone)]
+pub struct Ipv4Builder {
+    eth: Option<EthBuilder>,
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    tos: u8,
+    ident: u16,
+    df: bool,
+    ttl: u8
+}
+
+impl Ipv4Builder {
+    pub fn new(src: Ipv4Addr, dst: Ipv4Addr) -> Ipv4Builder {
+        Ipv4Builder { eth: None, src: src, dst: dst, tos: 0, ident: 0, df: true, ttl: 64 }
+    }
+
+    pub fn tos(mut self, tos: u8) -> Ipv4Builder { self.tos = tos; self }
+    pub fn ident(mut self, ident: u16) -> Ipv4Builder { self.ident = ident; self }
+    pub fn dont_fragment(mut self, df: bool) -> Ipv4Builder { self.df = df; self }
+    pub fn ttl(mut self, ttl: u8) -> Ipv4Builder { self.ttl = ttl; self }
+
+    pub fn tcp(self, src_port: u16, dst_port: u16) -> TcpBuilder {
+        TcpBuilder::new(IpBuilder::V4(self), src_port, dst_port)
+    }
+
+    pub fn udp(self, src_port: u16, dst_port: u16) -> UdpBuilder {
+        UdpBuilder { ip: IpBuilder::V4(self), src_port: src_port, dst_port: dst_port }
+    }
+
+    pub fn icmp(self, icmp_type: u8, code: u8) -> IcmpBuilder {
+        IcmpBuilder::new(IpBuilder::V4(self), icmp_type, code)
+    }
+
+    // an ip packet with an arbitrary payload, which is written as is
+    pub fn build(self, protocol: u8, payload: &[u8]) -> pkt::Packet {
+        IpBuilder::V4(self).build(protocol, payload.len(), |buff| {
+            buff.copy_from_slice(payload)
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct Ipv6Builder {
+    eth: Option<EthBuilder>,
+    src: Ipv6Addr,
+    dst: Ipv6Addr,
+    traffic_class: u8,
+    flow_label: u32,
+    hop_limit: u8
+}
+
+impl Ipv6Builder {
+    pub fn new(src: Ipv6Addr, dst: Ipv6Addr) -> Ipv6Builder {
+        Ipv6Builder {
+            eth: None, src: src, dst: dst, traffic_class: 0, flow_label: 0, hop_limit: 64
+        }
+    }
+
+    pub fn traffic_class(mut self, tc: u8) -> Ipv6Builder { self.traffic_class = tc; self }
+    pub fn flow_label(mut self, label: u32) -> Ipv6Builder { self.flow_label = label; self }
+    pub fn hop_limit(mut self, limit: u8) -> Ipv6Builder { self.hop_limit = limit; self }
+
+    pub fn tcp(self, src_port: u16, dst_port: u16) -> TcpBuilder {
+        TcpBuilder::new(IpBuilder::V6(self), src_port, dst_port)
+    }
+
+    pub fn udp(self, src_port: u16, dst_port: u16) -> UdpBuilder {
+        UdpBuilder { ip: IpBuilder::V6(self), src_port: src_port, dst_port: dst_port }
+    }
+
+    pub fn icmp(self, icmp_type: u8, code: u8) -> IcmpBuilder {
+        IcmpBuilder::new(IpBuilder::V6(self), icmp_type, code)
+    }
+
+    pub fn build(self, protocol: u8, payload: &[u8]) -> pkt::Packet {
+        IpBuilder::V6(self).build(protocol, payload.len(), |buff| {
+            buff.copy_from_slice(payload)
+        })
+    }
+}
+
+#[derive(Clone)]
+pub enum IpBuilder {
+    V4(Ipv4Builder),
+    V6(Ipv6Builder)
+}
+
+impl IpBuilder {
+    fn eth(&self) -> Option<&EthBuilder> {
+        match *self {
+            IpBuilder::V4(ref ip) => ip.eth.as_ref(),
+            IpBuilder::V6(ref ip) => ip.eth.as_ref()
+        }
+    }
+
+    fn header_len(&self) -> usize {
+        match *self {
+            IpBuilder::V4(_) => IPV4_LEN,
+            IpBuilder::V6(_) => IPV6_LEN
+        }
+    }
+
+    // checksum of the pseudo header for a transport segment of `len` bytes
+    fn pseudo_sum(&self, protocol: u8, len: usize) -> u32 {
+        match *self {
+            IpBuilder::V4(ref ip) =>
+                util::pseudo_sum_v4(ip.src.octets(), ip.dst.octets(), protocol, len),
+            IpBuilder::V6(ref ip) =>
+                util::pseudo_sum_v6(ip.src.octets(), ip.dst.octets(), protocol, len)
+        }
+    }
+
+    // Lay out link and ip headers for `payload_len` bytes of `protocol`,
+    // let `fill` write the payload, and dissect the result.
+    fn build<F>(&self, protocol: u8, payload_len: usize, fill: F) -> pkt::Packet
+        where F: FnOnce(&mut [u8]) {
+        let link_len = if self.eth().is_some() { ETH_LEN } else { 0 };
+        let ip_len = self.header_len();
+        // ipv4 counts its header in the length, ipv6 doesn't
+        let field_len = match *self {
+            IpBuilder::V4(_) => ip_len + payload_len,
+            IpBuilder::V6(_) => payload_len
+        };
+        assert!(field_len <= 0xFFFF, "ip length {} doesn't fit in 16 bits", field_len);
+        let len = link_len + ip_len + payload_len;
+        let mut data = vec![0u8; len];
+
+        if let Some(eth) = self.eth() {
+            let ethertype = match *self {
+                IpBuilder::V4(_) => ETHERTYPE_IPV4,
+                IpBuilder::V6(_) => ETHERTYPE_IPV6
+            };
+            eth.write(&mut data, ethertype);
+        }
+
+        fill(&mut data[link_len + ip_len..]);
+
+        {
+            let buff = &mut data[link_len..];
+            match *self {
+                IpBuilder::V4(ref b) => {
+                    let ip = Ipv4 { offset: link_len };
+                    ip.set_version(buff, 4);
+                    ip.set_ihl(buff, (IPV4_LEN / 4) as u8);
+                    ip.set_tos(buff, b.tos);
+                    ip.set_len(buff, (ip_len + payload_len) as u16);
+                    ip.set_ident(buff, b.ident);
+                    ip.set_flag_df(buff, b.df as u8);
+                    ip.set_ttl(buff, b.ttl);
+                    ip.set_protocol(buff, protocol);
+                    ip.set_src(buff, b.src.octets());
+                    ip.set_dst(buff, b.dst.octets());
+                    let chk = util::checksum(&buff[..IPV4_LEN]);
+                    ip.set_header_chk(buff, chk);
+                },
+                IpBuilder::V6(ref b) => {
+                    let ip = Ipv6 { offset: link_len };
+                    ip.set_version(buff, 6);
+                    ip.set_traffic_class(buff, b.traffic_class);
+                    ip.set_flow_label(buff, b.flow_label & 0xFFFFF);
+                    ip.set_payload_len(buff, payload_len as u16);
+                    ip.set_nxt_header(buff, protocol);
+                    ip.set_hop_limit(buff, b.hop_limit);
+                    ip.set_src(buff, b.src.octets());
+                    ip.set_dst(buff, b.dst.octets());
+                }
+            }
+        }
+
+        // every length in there is one we just wrote, it can't be short
+        let packet = match self.eth() {
+            Some(_) => pkt::make_eth_packet(data, len),
+            None    => pkt::make_ip_packet(data, len)
+        };
+        packet.expect("built a truncated packet")
+    }
+}
+
+// transport layer
+
+#[derive(Clone)]
+pub struct TcpBuilder {
+    ip: IpBuilder,
+    src_port: u16,
+    dst_port: u16,
+    seq: u32,
+    ack_nr: u32,
+    flags: u8,
+    win: u16,
+    urg_ptr: u16,
+    mss: Option<u16>,
+    options: Vec<u8>
+}
+
+pub mod tcp_flags {
+    pub const FIN: u8 = 0x01;
+    pub const SYN: u8 = 0x02;
+    pub const RST: u8 = 0x04;
+    pub const PSH: u8 = 0x08;
+    pub const ACK: u8 = 0x10;
+    pub const URG: u8 = 0x20;
+}
+
+pub mod tcp_options {
+    pub const END: u8 = 0;
+    pub const NOP: u8 = 1;
+    pub const MSS: u8 = 2;
+}
+
+impl TcpBuilder {
+    fn new(ip: IpBuilder, src_port: u16, dst_port: u16) -> TcpBuilder {
+        TcpBuilder {
+            ip: ip, src_port: src_port, dst_port: dst_port,
+            seq: 0, ack_nr: 0, flags: 0, win: 0xFFFF, urg_ptr: 0, mss: None,
+            options: Vec::new()
+        }
+    }
+
+    pub fn seq(mut self, seq: u32) -> TcpBuilder { self.seq = seq; self }
+    pub fn ack_nr(mut self, ack_nr: u32) -> TcpBuilder { self.ack_nr = ack_nr; self }
+    // a combination of `tcp_flags`
+    pub fn flags(mut self, flags: u8) -> TcpBuilder { self.flags = flags; self }
+    pub fn win(mut self, win: u16) -> TcpBuilder { self.win = win; self }
+    pub fn urg_ptr(mut self, ptr: u16) -> TcpBuilder { self.urg_ptr = ptr; self }
+
+    // an mss option, it goes in front of the raw options
+    pub fn mss(mut self, mss: u16) -> TcpBuilder { self.mss = Some(mss); self }
+
+    // the mss for a link with `mtu`, and the ip version we're building
+    pub fn mss_for_mtu(self, mtu: usize) -> TcpBuilder {
+        let mss = match self.ip {
+            IpBuilder::V4(_) => tcp_mss(mtu, false),
+            IpBuilder::V6(_) => tcp_mss(mtu, true)
+        };
+        self.mss(mss)
+    }
+
+    // raw option bytes, padded to a multiple of 4 with end-of-options
+    pub fn options(mut self, options: &[u8]) -> TcpBuilder {
+        self.options = options.to_vec();
+        while !self.options.len().is_multiple_of(4) {
+            self.options.push(0);
+        }
+        self
+    }
+
+    pub fn build(self, payload: &[u8]) -> pkt::Packet {
+        let mut options = Vec::with_capacity(4 + self.options.len());
+        if let Some(mss) = self.mss {
+            options.extend_from_slice(&[tcp_options::MSS, 4, (mss >> 8) as u8, mss as u8]);
+        }
+        options.extend_from_slice(&self.options);
+        assert!(options.len() <= MAX_TCP_OPTIONS,
+                "{} bytes of tcp options, at most {} fit", options.len(), MAX_TCP_OPTIONS);
+
+        let header_len = TCP_LEN + options.len();
+        let seg_len = header_len + payload.len();
+        let pseudo = self.ip.pseudo_sum(PROTO_TCP, seg_len);
+
+        self.ip.build(PROTO_TCP, seg_len, |buff| {
+            let tcp = Tcp { offset: 0 };
+            tcp.set_src_port(buff, self.src_port);
+            tcp.set_dst_port(buff, self.dst_port);
+            tcp.set_seq(buff, self.seq);
+            tcp.set_ack_nr(buff, self.ack_nr);
+            tcp.set_data_offset(buff, (header_len / 4) as u8);
+            tcp.set_urg(buff, (self.flags & tcp_flags::URG != 0) as u8);
+            tcp.set_ack(buff, (self.flags & tcp_flags::ACK != 0) as u8);
+            tcp.set_psh(buff, (self.flags & tcp_flags::PSH != 0) as u8);
+            tcp.set_rst(buff, (self.flags & tcp_flags::RST != 0) as u8);
+            tcp.set_syn(buff, (self.flags & tcp_flags::SYN != 0) as u8);
+            tcp.set_fin(buff, (self.flags & tcp_flags::FIN != 0) as u8);
+            tcp.set_win(buff, self.win);
+            tcp.set_urg_ptr(buff, self.urg_ptr);
+            buff[TCP_LEN..header_len].copy_from_slice(&options);
+            buff[header_len..].copy_from_slice(payload);
+
+            let chk = util::checksum_finish(util::checksum_add(pseudo, buff));
+            tcp.set_chk(buff, chk);
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct UdpBuilder {
+    ip: IpBuilder,
+    src_port: u16,
+    dst_port: u16
+}
+
+impl UdpBuilder {
+    pub fn build(self, payload: &[u8]) -> pkt::Packet {
+        let udp_len = UDP_LEN + payload.len();
+        let pseudo = self.ip.pseudo_sum(PROTO_UDP, udp_len);
+
+        self.ip.build(PROTO_UDP, udp_len, |buff| {
+            let udp = Udp { offset: 0 };
+            udp.set_src_port(buff, self.src_port);
+            udp.set_dst_port(buff, self.dst_port);
+            udp.set_len(buff, udp_len as u16);
+            buff[UDP_LEN..].copy_from_slice(payload);
+
+            let chk = util::checksum_finish(util::checksum_add(pseudo, buff));
+            // 0 means "no checksum" in udp
+            udp.set_chk(buff, if chk == 0 { 0xFFFF } else { chk });
+        })
+    }
+}
+
+// icmpv4 or icmpv6, following the ip version
+#[derive(Clone)]
+pub struct IcmpBuilder {
+    ip: IpBuilder,
+    icmp_type: u8,
+    code: u8,
+    ident: u16,
+    seq: u16
+}
+
+impl IcmpBuilder {
+    fn new(ip: IpBuilder, icmp_type: u8, code: u8) -> IcmpBuilder {
+        IcmpBuilder { ip: ip, icmp_type: icmp_type, code: code, ident: 0, seq: 0 }
+    }
+
+    // the second word of the header, ident and seq for echo messages
+    pub fn ident(mut self, ident: u16) -> IcmpBuilder { self.ident = ident; self }
+    pub fn seq(mut self, seq: u16) -> IcmpBuilder { self.seq = seq; self }
+
+    pub fn rest_of_header(mut self, word: u32) -> IcmpBuilder {
+        self.ident = (word >> 16) as u16;
+        self.seq = word as u16;
+        self
+    }
+
+    pub fn build(self, payload: &[u8]) -> pkt::Packet {
+        let len = ICMP_LEN + payload.len();
+        match self.ip {
+            IpBuilder::V4(_) => self.ip.build(PROTO_ICMPV4, len, |buff| {
+                let icmp = Icmpv4 { offset: 0 };
+                icmp.set_icmp_type(buff, self.icmp_type);
+                icmp.set_code(buff, self.code);
+                icmp.set_ident(buff, self.ident);
+                icmp.set_seq(buff, self.seq);
+                buff[ICMP_LEN..].copy_from_slice(payload);
+                // no pseudo header for icmpv4
+                let chk = util::checksum(buff);
+                icmp.set_chk(buff, chk);
+            }),
+            IpBuilder::V6(_) => {
+                let pseudo = self.ip.pseudo_sum(PROTO_ICMPV6, len);
+                self.ip.build(PROTO_ICMPV6, len, |buff| {
+                    let icmp = Icmpv6 { offset: 0 };
+                    icmp.set_icmp_type(buff, self.icmp_type);
+                    icmp.set_code(buff, self.code);
+                    icmp.set_ident(buff, self.ident);
+                    icmp.set_seq(buff, self.seq);
+                    buff[ICMP_LEN..].copy_from_slice(payload);
+                    let chk = util::checksum_finish(util::checksum_add(pseudo, buff));
+                    icmp.set_chk(buff, chk);
+                })
+            }
+        }
+    }
+}
+
+
+// testing
+#[test]
+fn test_build_udp_over_ipv4() {
+    let packet = EthBuilder::new([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2])
+        .ipv4(Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 1))
+        .ttl(17)
+        .udp(5353, 53)
+        .build(b"hello");
+
+    assert_eq!(packet.len, ETH_LEN + IPV4_LEN + UDP_LEN + 5);
+    let view = packet.view();
+
+    let ip = view.ipv4().unwrap();
+    assert_eq!(ip.get(Ipv4::get_len) as usize, IPV4_LEN + UDP_LEN + 5);
+    assert_eq!(ip.get(Ipv4::get_ttl), 17);
+    // a header with a valid checksum sums to zero
+    assert_eq!(util::checksum(&ip.bytes()[..IPV4_LEN]), 0);
+
+    let udp = view.udp().unwrap();
+    assert_eq!(udp.get(Udp::get_dst_port), 53);
+    assert_eq!(udp.get(Udp::get_len) as usize, UDP_LEN + 5);
+    let pseudo = util::pseudo_sum_v4([10, 0, 0, 2], [10, 0, 0, 1], PROTO_UDP, UDP_LEN + 5);
+    assert_eq!(util::checksum_finish(util::checksum_add(pseudo, udp.bytes())), 0);
+}
+
+#[test]
+fn test_tcp_mss() {
+    assert_eq!(tcp_mss(1500, false), 1460);
+    assert_eq!(tcp_mss(1500, true), 1440);
+    assert_eq!(tcp_mss(pkt::MIN_IPV6_MTU, true), 1220);
+    // nothing left, or more than the option can say
+    assert_eq!(tcp_mss(30, false), 0);
+    assert_eq!(tcp_mss(100_000, false), 0xFFFF);
+}
+
+#[test]
+fn test_mss_for_mtu_follows_the_ip_version() {
+    let packet = Ipv4Builder::new(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2))
+        .tcp(1, 2)
+        .mss_for_mtu(1500)
+        .build(b"");
+    let tcp = packet.view().tcp().unwrap();
+    assert_eq!(&tcp.bytes()[TCP_LEN..TCP_LEN + 4], &[tcp_options::MSS, 4, 0x05, 0xB4]);
+
+    let src = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+    let dst = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+    let packet = Ipv6Builder::new(src, dst).tcp(1, 2).mss_for_mtu(1500).build(b"");
+    let tcp = packet.view().tcp().unwrap();
+    assert_eq!(&tcp.bytes()[TCP_LEN..TCP_LEN + 4], &[tcp_options::MSS, 4, 0x05, 0xA0]);
+}
+
+#[test]
+fn test_build_at_the_length_limits() {
+    let src = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+    let dst = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+    let packet = Ipv6Builder::new(src, dst).udp(1, 2).build(&vec![0; 0xFFFF - UDP_LEN]);
+    assert_eq!(packet.len, IPV6_LEN + 0xFFFF);
+
+    let packet = Ipv4Builder::new(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2))
+        .tcp(1, 2)
+        .mss(1460)
+        .options(&[tcp_options::NOP; 36])
+        .build(b"");
+    let tcp = packet.view().tcp().unwrap();
+    assert_eq!(tcp.get(Tcp::get_data_offset), 15);
+}
+
+#[test]
+#[should_panic(expected = "ip length 65536 doesn't fit in 16 bits")]
+fn test_build_rejects_oversized_ipv4() {
+    Ipv4Builder::new(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2))
+        .udp(1, 2)
+        .build(&vec![0; 0xFFFF - IPV4_LEN - UDP_LEN + 1]);
+}
+
+#[test]
+#[should_panic(expected = "44 bytes of tcp options, at most 40 fit")]
+fn test_build_rejects_too_many_tcp_options() {
+    Ipv4Builder::new(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2))
+        .tcp(1, 2)
+        .mss(1460)
+        .options(&[tcp_options::NOP; 40])
+        .build(b"");
+}
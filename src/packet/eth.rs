@@ -2,8 +2,9 @@ use super::pkt;
 use super::pkt::{write_imm, write_arr};
 use super::ipv4;
 use super::ipv6;
+use super::arp;
 
-#[derive (Debug, Default)]
+#[derive (Debug, Default, Clone, Copy)]
 pub struct Eth {
     pub offset: usize
 }
@@ -20,31 +21,80 @@ pub fn print_eth(name: &str, val: &[u8]) {
              name, ":", val[0],val[1],val[2],val[3],val[4],val[5]);
 }
 
+// IEEE 802.1Q: a tag in front of the ethertype, the priority and vlan id
+// in the tag control information
+//
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |     ethertype (0x8100)        | PCP |D|        VLAN id        |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |     ethertype                 |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+pub const ETHERTYPE_VLAN: u16 = 0x8100;
+pub const VLAN_TAG_LEN: usize = 4;
+
+impl Eth {
+    fn is_tagged(&self, buff: &[u8]) -> bool {
+        self.get_ethertype(buff) == ETHERTYPE_VLAN && buff.len() >= Eth::HEADER_LEN + VLAN_TAG_LEN
+    }
+
+    // the vlan id of a tagged frame
+    pub fn get_vlan(&self, buff: &[u8]) -> Option<u16> {
+        match self.is_tagged(buff) {
+            true  => Some(((buff[14] as u16) << 8 | buff[15] as u16) & 0xFFF),
+            false => None
+        }
+    }
+
+    // the ethertype of what's carried, behind the vlan tag if there is one
+    pub fn get_payload_type(&self, buff: &[u8]) -> u16 {
+        match self.is_tagged(buff) {
+            true  => (buff[16] as u16) << 8 | buff[17] as u16,
+            false => self.get_ethertype(buff)
+        }
+    }
+}
+
 impl pkt::HasLinkLayer for Eth {
-    fn get_network(&self, buff: &[u8]) -> pkt::Network {
-        let net_nr = self.get_ethertype(&buff[..]);
+    fn header_len(&self, buff: &[u8]) -> Result<usize, pkt::Truncated> {
+        pkt::check_len("eth", buff, Eth::HEADER_LEN)?;
+        if self.get_ethertype(buff) == ETHERTYPE_VLAN {
+            pkt::check_len("vlan", buff, Eth::HEADER_LEN + VLAN_TAG_LEN)?;
+            return Ok(Eth::HEADER_LEN + VLAN_TAG_LEN)
+        }
+        Ok(Eth::HEADER_LEN)
+    }
+
+    fn get_network(&self, buff: &[u8]) -> Result<pkt::Network, pkt::DissectError> {
+        self.header_len(buff)?;
+        let net_nr = self.get_payload_type(buff);
         let net_offset = self.get_payload_offset(buff);
         match net_nr {
-            0x0800u16 => pkt::Network::Ipv4Net(
-                ipv4::Ipv4 { offset: net_offset }),
-            0x86DDu16 => pkt::Network::Ipv6Net(
-                ipv6::Ipv6 { offset: net_offset }),
+            0x0800u16 => Ok(pkt::Network::Ipv4Net(
+                ipv4::Ipv4 { offset: net_offset })),
+            0x86DDu16 => Ok(pkt::Network::Ipv6Net(
+                ipv6::Ipv6 { offset: net_offset })),
+            0x0806u16 => Ok(pkt::Network::ArpNet(
+                arp::Arp { offset: net_offset })),
             // some more to implement:
-            // 0x0806	Address Resolution Protocol (ARP)
-            // 0x8100	VLAN-tagged frame (IEEE 802.1Q)
-            //   and Shortest Path Bridging IEEE 802.1aq[8]
+            // 0x88A8	stacked VLAN tags (IEEE 802.1ad)
             // 0x8870	Jumbo Frames (proposed)[2][3]
-            _         => panic!("ethertype {:X} isn't implemented", net_nr)
+            _         => Err(pkt::DissectError::UnknownEthertype(net_nr))
         }
     }
 
     fn get_payload_offset(&self, buff: &[u8]) -> usize {
-        // we're not dealing with vlan tagging for now
-        self.offset + 14
+        match self.is_tagged(buff) {
+            true  => self.offset + Eth::HEADER_LEN + VLAN_TAG_LEN,
+            false => self.offset + Eth::HEADER_LEN
+        }
     }
 
     fn print(&self, buff: &[u8]) {
         println!("eth:");
         self.print_fields(buff);
+        if let Some(vlan) = self.get_vlan(buff) {
+            pkt::write_imm("vlan", vlan as u64);
+            pkt::write_imm("payload type", self.get_payload_type(buff) as u64);
+        }
     }
 }
@@ -0,0 +1,534 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use lpm;
+
+use super::eth::Eth;
+use super::ipv4::Ipv4;
+use super::ipv6::Ipv6;
+use super::pkt::{Network, Transport};
+use super::tcp::Tcp;
+use super::udp::Udp;
+use super::view::PacketView;
+
+// capture filters
+//
+// The expressions tcpdump takes, or the everyday part of them, checked
+// against a dissected packet rather than compiled to BPF:
+//
+//   tcp and dst port 80
+//   ip6 and icmp6
+//   ether host 02:00:00:00:00:01 and not arp
+//   vlan 100 and (src net 10.0.0.0/8 or udp portrange 5000-6000)
+//
+// Primitives:
+//
+//   ether [src | dst | host] <mac>      ether proto <ethertype | ip | ip6 | arp>
+//   ip | ip6 | arp                      vlan [<id>]
+//   [src | dst] host <addr>             [src | dst] net <addr/len>
+//   [src | dst] <addr>[/<len>]          proto <nr>
+//   tcp | udp | icmp | icmp6            [tcp | udp] [src | dst] port <nr>
+//   [tcp | udp] [src | dst] portrange <lo-hi>
+//   less <len> | greater <len>          what the packet is at most or least
+//
+// combined with `not` or `!`, `and` or `&&`, `or` or `||`, and parentheses,
+// in that order of binding. Primitives side by side are anded, as in
+// `tcp port 80`. Behind a vlan tag, everything is about what's tagged.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dir {
+    Src,
+    Dst,
+    // either one
+    Any
+}
+
+impl fmt::Display for Dir {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Dir::Src => "src ",
+            Dir::Dst => "dst ",
+            Dir::Any => ""
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Primitive {
+    Ether(Dir, [u8; 6]),
+    EtherProto(u16),
+    Ip,
+    Ip6,
+    Arp,
+    // any vlan, or this one
+    Vlan(Option<u16>),
+    // a host is a net as long as its address
+    Net(Dir, IpAddr, u8),
+    Proto(u8),
+    Port(Dir, u16, u16),
+    Less(usize),
+    Greater(usize)
+}
+
+const PROTO_NAMES: [(&str, u8); 4] = [("tcp", 6), ("udp", 17), ("icmp", 1), ("icmp6", 58)];
+
+impl fmt::Display for Primitive {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Primitive::Ether(dir, mac) => {
+                let dir = if dir == Dir::Any { "host ".to_string() } else { dir.to_string() };
+                write!(f, "ether {}{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", dir,
+                       mac[0], mac[1], mac[2], mac[3], mac[4], mac[5])
+            },
+            Primitive::EtherProto(kind) => write!(f, "ether proto 0x{:04x}", kind),
+            Primitive::Ip               => f.write_str("ip"),
+            Primitive::Ip6              => f.write_str("ip6"),
+            Primitive::Arp              => f.write_str("arp"),
+            Primitive::Vlan(None)       => f.write_str("vlan"),
+            Primitive::Vlan(Some(id))   => write!(f, "vlan {}", id),
+            Primitive::Net(dir, addr, len) if len == max_len(addr) => write!(f, "{}host {}", dir, addr),
+            Primitive::Net(dir, addr, len) => write!(f, "{}net {}/{}", dir, addr, len),
+            Primitive::Proto(proto) => match PROTO_NAMES.iter().find(|&&(_, nr)| nr == proto) {
+                Some(&(name, _)) => f.write_str(name),
+                None => write!(f, "proto {}", proto)
+            },
+            Primitive::Port(dir, lo, hi) if lo == hi => write!(f, "{}port {}", dir, lo),
+            Primitive::Port(dir, lo, hi) => write!(f, "{}portrange {}-{}", dir, lo, hi),
+            Primitive::Less(len)    => write!(f, "less {}", len),
+            Primitive::Greater(len) => write!(f, "greater {}", len)
+        }
+    }
+}
+
+fn max_len(addr: IpAddr) -> u8 {
+    if addr.is_ipv4() { 32 } else { 128 }
+}
+
+impl Primitive {
+    pub fn matches(&self, packet: &PacketView) -> bool {
+        let eth = packet.eth_header();
+        match *self {
+            Primitive::Ether(dir, mac) => eth.is_some_and(|eth| {
+                let (src, dst) = (eth.get(Eth::get_src), eth.get(Eth::get_dst));
+                match dir {
+                    Dir::Src => src == mac,
+                    Dir::Dst => dst == mac,
+                    Dir::Any => src == mac || dst == mac
+                }
+            }),
+            Primitive::EtherProto(kind) => match eth {
+                Some(eth) => eth.get(Eth::get_payload_type) == kind,
+                // what a tun device has instead
+                None => match packet.net {
+                    Network::Ipv4Net(_) => kind == 0x0800,
+                    Network::Ipv6Net(_) => kind == 0x86DD,
+                    Network::ArpNet(_)  => kind == 0x0806
+                }
+            },
+            Primitive::Ip  => packet.ipv4().is_some(),
+            Primitive::Ip6 => packet.ipv6().is_some(),
+            Primitive::Arp => packet.arp().is_some(),
+            Primitive::Vlan(id) => match eth.and_then(|eth| eth.get(Eth::get_vlan)) {
+                Some(vlan) => id.is_none_or(|id| id == vlan),
+                None => false
+            },
+            Primitive::Net(dir, net, len) => match addresses(packet) {
+                Some((src, dst)) => match dir {
+                    Dir::Src => lpm::covers(net, len, src),
+                    Dir::Dst => lpm::covers(net, len, dst),
+                    Dir::Any => lpm::covers(net, len, src) || lpm::covers(net, len, dst)
+                },
+                None => false
+            },
+            Primitive::Proto(proto) => protocol(packet) == Some(proto),
+            Primitive::Port(dir, lo, hi) => match ports(packet) {
+                Some((src, dst)) => {
+                    let within = |port: u16| lo <= port && port <= hi;
+                    match dir {
+                        Dir::Src => within(src),
+                        Dir::Dst => within(dst),
+                        Dir::Any => within(src) || within(dst)
+                    }
+                },
+                None => false
+            },
+            Primitive::Less(len)    => packet.len() <= len,
+            Primitive::Greater(len) => packet.len() >= len
+        }
+    }
+}
+
+fn addresses(packet: &PacketView) -> Option<(IpAddr, IpAddr)> {
+    if let Some(ip) = packet.ipv4() {
+        return Some((IpAddr::from(ip.get(Ipv4::get_src)), IpAddr::from(ip.get(Ipv4::get_dst))))
+    }
+    packet.ipv6().map(|ip| (IpAddr::from(ip.get(Ipv6::get_src)), IpAddr::from(ip.get(Ipv6::get_dst))))
+}
+
+// the upper layer protocol, past any ipv6 extension headers
+fn protocol(packet: &PacketView) -> Option<u8> {
+    if let Some(ip) = packet.ipv4() {
+        return Some(ip.get(Ipv4::get_protocol))
+    }
+    packet.ipv6().map(|ip| ip.get(|ip, buff| {
+        let mut headers = ip.ext_headers(buff);
+        for _ in headers.by_ref() {}
+        headers.protocol()
+    }))
+}
+
+fn ports(packet: &PacketView) -> Option<(u16, u16)> {
+    match packet.transport()? {
+        Transport::TcpTrans(_) => packet.tcp().map(|tcp| (tcp.get(Tcp::get_src_port), tcp.get(Tcp::get_dst_port))),
+        Transport::UdpTrans(_) => packet.udp().map(|udp| (udp.get(Udp::get_src_port), udp.get(Udp::get_dst_port))),
+        _ => None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Filter {
+    Match(Primitive),
+    Not(Box<Filter>),
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>)
+}
+
+impl Filter {
+    pub fn matches(&self, packet: &PacketView) -> bool {
+        match *self {
+            Filter::Match(ref prim)    => prim.matches(packet),
+            Filter::Not(ref filter)    => !filter.matches(packet),
+            Filter::And(ref a, ref b)  => a.matches(packet) && b.matches(packet),
+            Filter::Or(ref a, ref b)   => a.matches(packet) || b.matches(packet)
+        }
+    }
+
+    // how tightly it binds, for the parentheses
+    fn binding(&self) -> u8 {
+        match *self {
+            Filter::Or(..)  => 0,
+            Filter::And(..) => 1,
+            _               => 2
+        }
+    }
+
+    fn fmt_within(&self, f: &mut fmt::Formatter, binding: u8) -> fmt::Result {
+        match self.binding() < binding {
+            true  => write!(f, "({})", self),
+            false => write!(f, "{}", self)
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Filter::Match(ref prim) => write!(f, "{}", prim),
+            Filter::Not(ref filter) => {
+                f.write_str("not ")?;
+                filter.fmt_within(f, 2)
+            },
+            Filter::And(ref a, ref b) => {
+                a.fmt_within(f, 1)?;
+                f.write_str(" and ")?;
+                b.fmt_within(f, 1)
+            },
+            Filter::Or(ref a, ref b) => {
+                a.fmt_within(f, 0)?;
+                f.write_str(" or ")?;
+                b.fmt_within(f, 0)
+            }
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Filter, String> {
+        let tokens = tokenize(s);
+        let mut parser = Parser { tokens: &tokens, pos: 0, expr: s };
+        let filter = parser.or()?;
+        match parser.peek() {
+            None => Ok(filter),
+            Some(token) => Err(parser.error(&format!("unexpected {}", token)))
+        }
+    }
+}
+
+// words, with parentheses and a leading ! split off
+fn tokenize(s: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    for c in s.chars() {
+        let alone = c == '(' || c == ')' || (c == '!' && word.is_empty());
+        if c.is_whitespace() || alone {
+            if !word.is_empty() {
+                tokens.push(word.clone());
+                word.clear();
+            }
+            if alone {
+                tokens.push(c.to_string());
+            }
+        } else {
+            word.push(c);
+        }
+    }
+    if !word.is_empty() {
+        tokens.push(word);
+    }
+    tokens
+}
+
+struct Parser<'a> {
+    tokens: &'a [String],
+    pos: usize,
+    // the whole of it, for the errors
+    expr: &'a str
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.pos).map(|token| &token[..])
+    }
+
+    fn next(&mut self) -> Option<&'a str> {
+        let token = self.peek();
+        self.pos += 1;
+        token
+    }
+
+    fn error(&self, what: &str) -> String {
+        format!("{} in filter: {}", what, self.expr)
+    }
+
+    fn arg(&mut self, what: &str) -> Result<&'a str, String> {
+        match self.next() {
+            Some(token) => Ok(token),
+            None => Err(self.error(&format!("missing {}", what)))
+        }
+    }
+
+    fn or(&mut self) -> Result<Filter, String> {
+        let mut filter = self.and()?;
+        while let Some("or") | Some("||") = self.peek() {
+            self.pos += 1;
+            filter = Filter::Or(Box::new(filter), Box::new(self.and()?));
+        }
+        Ok(filter)
+    }
+
+    fn and(&mut self) -> Result<Filter, String> {
+        let mut filter = self.not()?;
+        loop {
+            match self.peek() {
+                Some("and") | Some("&&") => self.pos += 1,
+                // side by side
+                Some(token) if token != "or" && token != "||" && token != ")" => (),
+                _ => return Ok(filter)
+            }
+            filter = Filter::And(Box::new(filter), Box::new(self.not()?));
+        }
+    }
+
+    fn not(&mut self) -> Result<Filter, String> {
+        match self.peek() {
+            Some("not") | Some("!") => {
+                self.pos += 1;
+                Ok(Filter::Not(Box::new(self.not()?)))
+            },
+            Some("(") => {
+                self.pos += 1;
+                let filter = self.or()?;
+                match self.next() {
+                    Some(")") => Ok(filter),
+                    _ => Err(self.error("unclosed ("))
+                }
+            },
+            _ => self.primitive().map(Filter::Match)
+        }
+    }
+
+    fn primitive(&mut self) -> Result<Primitive, String> {
+        let word = self.arg("primitive")?;
+        if let Some(&(_, proto)) = PROTO_NAMES.iter().find(|&&(name, _)| name == word) {
+            return Ok(Primitive::Proto(proto))
+        }
+        Ok(match word {
+            "ip"  => Primitive::Ip,
+            "ip6" => Primitive::Ip6,
+            "arp" => Primitive::Arp,
+            "ether" => {
+                let dir = match self.arg("ether qualifier")? {
+                    "src"  => Dir::Src,
+                    "dst"  => Dir::Dst,
+                    "host" => Dir::Any,
+                    "proto" => {
+                        let kind = self.arg("ethertype")?;
+                        return Ok(Primitive::EtherProto(match kind {
+                            "ip"  => 0x0800,
+                            "ip6" => 0x86DD,
+                            "arp" => 0x0806,
+                            kind  => parse_num(kind).ok_or_else(|| self.error("bad ethertype"))?
+                        }))
+                    },
+                    other => return Err(self.error(&format!("unknown ether qualifier {}", other)))
+                };
+                let mac = self.arg("mac address")?;
+                Primitive::Ether(dir, parse_mac(mac).ok_or_else(|| self.error("bad mac address"))?)
+            },
+            "vlan" => match self.peek().and_then(|id| id.parse::<u16>().ok()) {
+                Some(id) if id < 4096 => {
+                    self.pos += 1;
+                    Primitive::Vlan(Some(id))
+                },
+                Some(_) => return Err(self.error("bad vlan id")),
+                None => Primitive::Vlan(None)
+            },
+            "proto" => Primitive::Proto(self.arg("protocol")?.parse()
+                                            .map_err(|_| self.error("bad protocol"))?),
+            "less" | "greater" => {
+                let len = self.arg("length")?.parse().map_err(|_| self.error("bad length"))?;
+                if word == "less" { Primitive::Less(len) } else { Primitive::Greater(len) }
+            },
+            "src" => self.addressed(Dir::Src)?,
+            "dst" => self.addressed(Dir::Dst)?,
+            _ => {
+                self.pos -= 1;
+                self.addressed(Dir::Any)?
+            }
+        })
+    }
+
+    // what can follow src or dst
+    fn addressed(&mut self, dir: Dir) -> Result<Primitive, String> {
+        let word = self.arg("address or port")?;
+        let arg = match word {
+            "host" | "net" | "port" | "portrange" => self.arg(word)?,
+            // an address on its own
+            _ => word
+        };
+        match word {
+            "port" => arg.parse().map(|port| Primitive::Port(dir, port, port))
+                .map_err(|_| self.error("bad port")),
+            "portrange" => {
+                let range = arg.find('-').and_then(|at| {
+                    Some((arg[..at].parse::<u16>().ok()?, arg[at + 1..].parse::<u16>().ok()?))
+                });
+                match range {
+                    Some((lo, hi)) if lo <= hi => Ok(Primitive::Port(dir, lo, hi)),
+                    _ => Err(self.error("bad port range"))
+                }
+            },
+            "host" => arg.parse::<IpAddr>().map(|addr| Primitive::Net(dir, addr, max_len(addr)))
+                .map_err(|_| self.error("bad host")),
+            _ => {
+                let (addr, len) = match arg.find('/') {
+                    Some(at) => (&arg[..at], Some(&arg[at + 1..])),
+                    None => (arg, None)
+                };
+                let addr = match addr.parse::<IpAddr>() {
+                    Ok(addr) => addr,
+                    Err(_) if word == "net" => return Err(self.error("bad net")),
+                    Err(_) => return Err(self.error(&format!("unknown primitive {}", word)))
+                };
+                match len.map(|len| len.parse::<u8>()) {
+                    None => Ok(Primitive::Net(dir, addr, max_len(addr))),
+                    Some(Ok(len)) if len <= max_len(addr) => Ok(Primitive::Net(dir, addr, len)),
+                    Some(_) => Err(self.error("bad prefix length"))
+                }
+            }
+        }
+    }
+}
+
+pub fn parse_mac(s: &str) -> Option<[u8; 6]> {
+    let bytes: Vec<u8> = s.split(':').map(|b| u8::from_str_radix(b, 16).ok()).collect::<Option<_>>()?;
+    if bytes.len() != 6 {
+        return None
+    }
+    let mut mac = [0u8; 6];
+    mac.copy_from_slice(&bytes);
+    Some(mac)
+}
+
+// decimal or 0x hex
+pub fn parse_num(s: &str) -> Option<u16> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok()
+    }
+}
+
+
+// testing
+#[cfg(test)]
+use super::builder::{EthBuilder, Ipv4Builder, Ipv6Builder};
+
+#[cfg(test)]
+fn parse(s: &str) -> Filter {
+    s.parse().unwrap()
+}
+
+// a web request from 10.0.0.2 to 192.0.2.7
+#[cfg(test)]
+fn http() -> Vec<u8> {
+    EthBuilder::new([0x02, 0, 0, 0, 0, 1], [0x02, 0, 0, 0, 0, 2])
+        .ipv4("10.0.0.2".parse().unwrap(), "192.0.2.7".parse().unwrap())
+        .tcp(40000, 80).build(b"GET /").data
+}
+
+#[test]
+fn test_filters_print_in_full() {
+    assert_eq!(parse("tcp and dst port 80").to_string(), "tcp and dst port 80");
+    assert_eq!(parse("udp src portrange 1000-2000 || !(ip6 && icmp6)").to_string(),
+               "udp and src portrange 1000-2000 or not (ip6 and icmp6)");
+    assert_eq!(parse("ether host 02:00:00:00:00:01 and (10.0.0.0/8 or dst fd00::1)").to_string(),
+               "ether host 02:00:00:00:00:01 and (net 10.0.0.0/8 or dst host fd00::1)");
+}
+
+#[test]
+fn test_bad_filters_say_why() {
+    assert_eq!("tcp port".parse::<Filter>(), Err("missing port in filter: tcp port".to_string()));
+    assert_eq!("(tcp".parse::<Filter>(), Err("unclosed ( in filter: (tcp".to_string()));
+    assert_eq!("tcp or bogus".parse::<Filter>(),
+               Err("unknown primitive bogus in filter: tcp or bogus".to_string()));
+}
+
+#[test]
+fn test_primitives_match_an_ethernet_frame() {
+    let http = http();
+    let view = PacketView::eth(&http).unwrap();
+    for (expr, expected) in &[("tcp and dst port 80", true), ("tcp port 40000", true),
+                              ("udp or src port 80", false), ("src net 10.0.0.0/8", true),
+                              ("not host 192.0.2.7", false), ("ether src 02:00:00:00:00:01", true),
+                              ("ether proto ip and greater 50", true), ("less 50", false),
+                              ("vlan", false)] {
+        assert_eq!(parse(expr).matches(&view), *expected, "{}", expr);
+    }
+}
+
+#[test]
+fn test_vlan_matches_the_tag_and_looks_past_it() {
+    let http = http();
+    let mut tagged = http[..12].to_vec();
+    tagged.extend_from_slice(&[0x81, 0x00, 0x00, 100]);
+    tagged.extend_from_slice(&http[12..]);
+    let view = PacketView::eth(&tagged).unwrap();
+    assert!(parse("vlan 100 and tcp dst port 80").matches(&view));
+    assert!(!parse("vlan 101").matches(&view));
+}
+
+#[test]
+fn test_raw_ip_matches_without_ethernet() {
+    // and the protocol past the ipv6 extension headers
+    let echo = Ipv6Builder::new("fd00::2".parse().unwrap(), "fd01::2".parse().unwrap())
+        .icmp(128, 0).build(b"").data;
+    let view = PacketView::ip(&echo).unwrap();
+    assert!(parse("ip6 and icmp6 and ether proto ip6").matches(&view));
+    assert!(!parse("ip or port 0").matches(&view));
+    let udp = Ipv4Builder::new("10.0.0.2".parse().unwrap(), "10.0.0.1".parse().unwrap())
+        .udp(53, 5353).build(b"").data;
+    assert!(parse("udp src port 53 and dst 10.0.0.1").matches(&PacketView::ip(&udp).unwrap()));
+}
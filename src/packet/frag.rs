@@ -0,0 +1,716 @@
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::hash::Hash;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+use super::ipv4::Ipv4;
+use super::ipv6::{Ipv6, Ipv6Frag, header_types};
+use super::pkt::HasNetworkLayer;
+use super::super::util;
+
+// fragmentation
+//
+// Reassembly keeps a list of holes per datagram (RFC 815): it starts out as
+// one hole from 0 to infinity, every fragment cuts its range out of the
+// holes it covers, and the last fragment cuts off everything past the end.
+// No holes left means we have the whole datagram. The buffering is the same
+// for ipv4 and ipv6; the protocol parts only decide on the key, where the
+// offsets come from and how the reassembled header is put back together.
+
+// what to do with a fragment that overlaps data we already have
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overlap {
+    // drop the whole datagram, overlaps are an attack more often than not
+    Reject,
+    // keep the bytes we had
+    KeepFirst,
+    // the new fragment wins
+    KeepLast
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    // per datagram, counted from its first fragment
+    pub timeout: Duration,
+    // payload buffered over all incomplete datagrams; the oldest ones are
+    // evicted to make room
+    pub max_bytes: usize,
+    pub overlap: Overlap
+}
+
+impl Limits {
+    // linux' defaults for ipfrag_time and ipfrag_high_thresh
+    pub fn ipv4() -> Limits {
+        Limits {
+            timeout: Duration::from_secs(30),
+            max_bytes: 4 * 1024 * 1024,
+            overlap: Overlap::KeepFirst
+        }
+    }
+
+    // RFC 8200 wants 60 seconds, and overlaps dropped (RFC 5722)
+    pub fn ipv6() -> Limits {
+        Limits {
+            timeout: Duration::from_secs(60),
+            max_bytes: 4 * 1024 * 1024,
+            overlap: Overlap::Reject
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FragError {
+    // a fragment overlapped, and `Overlap::Reject` dropped the datagram
+    Overlap,
+    // the reassembled datagram would be larger than ip allows, or a single
+    // fragment is larger than the memory limit
+    TooBig,
+    // fragments disagree on where the datagram ends
+    Inconsistent,
+    // a fragment other than the last with a length that isn't a multiple
+    // of 8
+    Malformed,
+    // outbound: the packet doesn't fit and says not to fragment it
+    DontFragment,
+    // outbound: the mtu can't even hold a header and 8 bytes of payload
+    MtuTooSmall,
+    // an ipv4 header length short of the fixed part, or past the packet
+    BadHeader
+}
+
+impl fmt::Display for FragError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match *self {
+            FragError::Overlap      => "overlapping fragment",
+            FragError::TooBig       => "reassembled datagram too big",
+            FragError::Inconsistent => "fragments disagree on the datagram length",
+            FragError::Malformed    => "fragment length not a multiple of 8",
+            FragError::DontFragment => "packet too big and don't fragment set",
+            FragError::MtuTooSmall  => "mtu too small to fragment",
+            FragError::BadHeader    => "bad ipv4 header length"
+        };
+        f.write_str(msg)
+    }
+}
+
+impl error::Error for FragError {}
+
+// [start, end) of the payload we haven't seen yet
+#[derive(Debug, Clone, Copy)]
+struct Hole {
+    start: usize,
+    end: usize
+}
+
+struct Partial {
+    // the header of the fragment at offset 0, once it shows up
+    header: Option<Vec<u8>>,
+    payload: Vec<u8>,
+    holes: Vec<Hole>,
+    total: Option<usize>,
+    expires: Instant
+}
+
+impl Partial {
+    fn new(expires: Instant) -> Partial {
+        Partial {
+            header: None,
+            payload: Vec::new(),
+            holes: vec!(Hole { start: 0, end: usize::MAX }),
+            total: None,
+            expires: expires
+        }
+    }
+
+    fn insert(&mut self, start: usize, data: &[u8], more: bool,
+              overlap: Overlap) -> Result<(), FragError> {
+        let end = start + data.len();
+        if more && !data.len().is_multiple_of(8) {
+            return Err(FragError::Malformed)
+        }
+        match self.total {
+            Some(total) if end > total || (!more && end != total) =>
+                return Err(FragError::Inconsistent),
+            None if !more && self.payload.len() > end =>
+                return Err(FragError::Inconsistent),
+            _ => ()
+        }
+
+        let fresh: usize = self.holes.iter()
+            .map(|hole| end.min(hole.end).saturating_sub(start.max(hole.start)))
+            .sum();
+        let overlaps = fresh < data.len();
+        if overlaps && overlap == Overlap::Reject {
+            return Err(FragError::Overlap)
+        }
+
+        if self.payload.len() < end {
+            self.payload.resize(end, 0);
+        }
+        if overlaps && overlap == Overlap::KeepFirst {
+            for hole in &self.holes {
+                let from = start.max(hole.start);
+                let to = end.min(hole.end);
+                if from < to {
+                    self.payload[from..to].copy_from_slice(&data[from - start..to - start]);
+                }
+            }
+        } else {
+            self.payload[start..end].copy_from_slice(data);
+        }
+
+        let mut holes = Vec::with_capacity(self.holes.len() + 1);
+        for hole in &self.holes {
+            if hole.end <= start || hole.start >= end {
+                holes.push(*hole);
+                continue
+            }
+            if hole.start < start {
+                holes.push(Hole { start: hole.start, end: start });
+            }
+            if end < hole.end {
+                holes.push(Hole { start: end, end: hole.end });
+            }
+        }
+        if !more {
+            self.total = Some(end);
+            holes.retain(|hole| hole.start < end);
+            for hole in &mut holes {
+                hole.end = hole.end.min(end);
+            }
+        }
+        self.holes = holes;
+        Ok(())
+    }
+
+    fn is_complete(&self) -> bool {
+        self.total.is_some() && self.holes.is_empty() && self.header.is_some()
+    }
+}
+
+// a complete datagram: the header of its first fragment, and the payload
+pub struct Reassembled {
+    pub header: Vec<u8>,
+    pub payload: Vec<u8>
+}
+
+pub struct Reassembler<K> {
+    limits: Limits,
+    partials: HashMap<K, Partial>,
+    bytes: usize
+}
+
+impl<K: Hash + Eq + Clone> Reassembler<K> {
+    pub fn new(limits: Limits) -> Reassembler<K> {
+        Reassembler { limits: limits, partials: HashMap::new(), bytes: 0 }
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    // Add a fragment of `payload` at byte `offset`, `more` being its more
+    // fragments flag. `header` is only kept from the fragment at offset 0.
+    // Returns that header and the whole payload once they're complete; any
+    // error drops everything we had for `key`.
+    pub fn push(&mut self, key: K, offset: usize, more: bool, payload: &[u8],
+                header: &[u8], now: Instant) -> Result<Option<Reassembled>, FragError> {
+        self.expire(now);
+
+        let have = self.partials.get(&key).map(|p| p.payload.len()).unwrap_or(0);
+        let growth = (offset + payload.len()).saturating_sub(have);
+        if growth > self.limits.max_bytes {
+            self.remove(&key);
+            return Err(FragError::TooBig)
+        }
+        self.make_room(&key, growth);
+
+        let expires = now + self.limits.timeout;
+        let overlap = self.limits.overlap;
+        let (res, grown) = {
+            let partial = self.partials.entry(key.clone()).or_insert_with(|| Partial::new(expires));
+            let before = partial.payload.len();
+            let res = partial.insert(offset, payload, more, overlap).map(|_| {
+                if offset == 0 {
+                    partial.header = Some(header.to_vec());
+                }
+                partial.is_complete()
+            });
+            (res, partial.payload.len() - before)
+        };
+        self.bytes += grown;
+
+        match res {
+            Err(e) => {
+                self.remove(&key);
+                Err(e)
+            },
+            Ok(false) => Ok(None),
+            Ok(true) => {
+                let partial = self.remove(&key).unwrap();
+                Ok(Some(Reassembled { header: partial.header.unwrap(), payload: partial.payload }))
+            }
+        }
+    }
+
+    // drop the datagrams that timed out, returns how many
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let expired: Vec<K> = self.partials.iter()
+            .filter(|&(_, partial)| partial.expires <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            self.remove(key);
+        }
+        expired.len()
+    }
+
+    // incomplete datagrams
+    pub fn len(&self) -> usize {
+        self.partials.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.partials.is_empty()
+    }
+
+    // payload bytes buffered for them
+    pub fn buffered(&self) -> usize {
+        self.bytes
+    }
+
+    fn remove(&mut self, key: &K) -> Option<Partial> {
+        let partial = self.partials.remove(key);
+        if let Some(ref partial) = partial {
+            self.bytes -= partial.payload.len();
+        }
+        partial
+    }
+
+    // evict the datagrams closest to timing out, other than `keep`
+    fn make_room(&mut self, keep: &K, growth: usize) {
+        while self.bytes + growth > self.limits.max_bytes {
+            let oldest = self.partials.iter()
+                .filter(|&(key, _)| key != keep)
+                .min_by_key(|&(_, partial)| partial.expires)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(key) => { self.remove(&key); },
+                None => break
+            }
+        }
+    }
+}
+
+// ipv4
+
+// the most an ipv4 total length can say
+pub const MAX_IPV4_LEN: usize = 65535;
+
+// RFC 791: fragments of the same datagram share all four
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Ipv4Key {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub protocol: u8,
+    pub ident: u16
+}
+
+pub struct Ipv4Reassembler {
+    frags: Reassembler<Ipv4Key>
+}
+
+impl Ipv4Reassembler {
+    pub fn new(limits: Limits) -> Ipv4Reassembler {
+        Ipv4Reassembler { frags: Reassembler::new(limits) }
+    }
+
+    // `packet` is one ipv4 fragment, bounded by its total length. Returns
+    // the reassembled datagram after the last missing piece.
+    pub fn push(&mut self, packet: &[u8], now: Instant) -> Result<Option<Vec<u8>>, FragError> {
+        let ip = Ipv4 { offset: 0 };
+        let header_len = ipv4_header_len(packet)?;
+        let key = Ipv4Key {
+            src: Ipv4Addr::from(ip.get_src(packet)),
+            dst: Ipv4Addr::from(ip.get_dst(packet)),
+            protocol: ip.get_protocol(packet),
+            ident: ip.get_ident(packet)
+        };
+        let offset = ip.get_frag_offs(packet) as usize * 8;
+        let payload = &packet[header_len..];
+        if header_len + offset + payload.len() > MAX_IPV4_LEN {
+            self.frags.remove(&key);
+            return Err(FragError::TooBig)
+        }
+
+        let more = ip.get_flag_mf(packet) != 0;
+        match self.frags.push(key, offset, more, payload, &packet[..header_len], now)? {
+            None => Ok(None),
+            Some(whole) => {
+                if whole.header.len() + whole.payload.len() > MAX_IPV4_LEN {
+                    return Err(FragError::TooBig)
+                }
+                let header_len = whole.header.len();
+                let mut datagram = whole.header;
+                datagram.extend_from_slice(&whole.payload);
+                let len = datagram.len() as u16;
+                ip.set_len(&mut datagram, len);
+                ip.set_flag_mf(&mut datagram, 0);
+                ip.set_frag_offs(&mut datagram, 0);
+                set_header_chk(&mut datagram, header_len);
+                Ok(Some(datagram))
+            }
+        }
+    }
+
+    pub fn expire(&mut self, now: Instant) -> usize {
+        self.frags.expire(now)
+    }
+
+    pub fn len(&self) -> usize {
+        self.frags.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frags.is_empty()
+    }
+
+    pub fn buffered(&self) -> usize {
+        self.frags.buffered()
+    }
+}
+
+fn ipv4_header_len(packet: &[u8]) -> Result<usize, FragError> {
+    Ipv4 { offset: 0 }.header_len(packet).map_err(|_| FragError::BadHeader)
+}
+
+fn set_header_chk(packet: &mut [u8], header_len: usize) {
+    let ip = Ipv4 { offset: 0 };
+    ip.set_header_chk(packet, 0);
+    let chk = util::checksum(&packet[..header_len]);
+    ip.set_header_chk(packet, chk);
+}
+
+// The header for the fragments after the first: only the options with the
+// copied flag set make it (RFC 791, 3.1).
+fn copied_options_header(header: &[u8]) -> Vec<u8> {
+    let mut copied = header[..Ipv4::HEADER_LEN].to_vec();
+    let mut idx = Ipv4::HEADER_LEN;
+    while idx < header.len() {
+        match header[idx] {
+            0 => break, // end of options
+            1 => idx += 1, // nop
+            opt_type => {
+                if idx + 1 >= header.len() {
+                    break
+                }
+                let len = header[idx + 1] as usize;
+                if len < 2 || idx + len > header.len() {
+                    break
+                }
+                if opt_type & 0x80 != 0 {
+                    copied.extend_from_slice(&header[idx..idx + len]);
+                }
+                idx += len;
+            }
+        }
+    }
+    while !copied.len().is_multiple_of(4) {
+        copied.push(0);
+    }
+    let ip = Ipv4 { offset: 0 };
+    let ihl = (copied.len() / 4) as u8;
+    ip.set_ihl(&mut copied, ihl);
+    copied
+}
+
+// Split an ipv4 packet into fragments of at most `mtu` bytes. A packet that
+// fits comes back as is; one that doesn't with the don't fragment flag set
+// is an error, the sender should get an icmp "fragmentation needed".
+pub fn fragment_ipv4(packet: &[u8], mtu: usize) -> Result<Vec<Vec<u8>>, FragError> {
+    let ip = Ipv4 { offset: 0 };
+    let len = (ip.get_len(packet) as usize).min(packet.len());
+    if len <= mtu {
+        return Ok(vec!(packet[..len].to_vec()))
+    }
+    if ip.get_flag_df(packet) != 0 {
+        return Err(FragError::DontFragment)
+    }
+
+    let header_len = ipv4_header_len(packet)?;
+    if len < header_len {
+        return Err(FragError::BadHeader)
+    }
+    let first_header = &packet[..header_len];
+    let rest_header = copied_options_header(first_header);
+    // we may be fragmenting a fragment
+    let base = ip.get_frag_offs(packet) as usize * 8;
+    let more = ip.get_flag_mf(packet) != 0;
+    let payload = &packet[header_len..len];
+
+    let mut frags = Vec::new();
+    let mut pos = 0;
+    while pos < payload.len() {
+        let header = if pos == 0 { first_header } else { &rest_header[..] };
+        let room = mtu.saturating_sub(header.len()) & !7;
+        if room == 0 {
+            return Err(FragError::MtuTooSmall)
+        }
+        let chunk = room.min(payload.len() - pos);
+        let last = pos + chunk == payload.len();
+
+        let mut frag = Vec::with_capacity(header.len() + chunk);
+        frag.extend_from_slice(header);
+        frag.extend_from_slice(&payload[pos..pos + chunk]);
+        let frag_len = frag.len() as u16;
+        ip.set_len(&mut frag, frag_len);
+        ip.set_frag_offs(&mut frag, ((base + pos) / 8) as u16);
+        ip.set_flag_mf(&mut frag, (!last || more) as u8);
+        set_header_chk(&mut frag, header.len());
+        frags.push(frag);
+        pos += chunk;
+    }
+    Ok(frags)
+}
+
+
+// ipv6
+
+// RFC 8200, 4.5
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Ipv6Key {
+    pub src: Ipv6Addr,
+    pub dst: Ipv6Addr,
+    pub ident: u32
+}
+
+pub const FRAG_HEADER_LEN: usize = 8;
+
+pub struct Ipv6Reassembler {
+    frags: Reassembler<Ipv6Key>
+}
+
+impl Ipv6Reassembler {
+    pub fn new(limits: Limits) -> Ipv6Reassembler {
+        Ipv6Reassembler { frags: Reassembler::new(limits) }
+    }
+
+    // `packet` is one ipv6 packet, bounded by its payload length. Atomic
+    // fragments come straight back without their fragment header, as does
+    // the reassembled packet after the last missing piece. A packet
+    // without a fragment header is returned as is.
+    pub fn push(&mut self, packet: &[u8], now: Instant) -> Result<Option<Vec<u8>>, FragError> {
+        let ip = Ipv6 { offset: 0 };
+        let header = match ip.get_fragment(packet) {
+            Some(header) => header,
+            None => return Ok(Some(packet.to_vec()))
+        };
+        let frag_buff = &packet[header.offset..];
+        let frag = Ipv6Frag { offset: header.offset };
+        if frag.is_atomic(frag_buff) {
+            let whole = Reassembled {
+                header: packet[..header.offset + FRAG_HEADER_LEN].to_vec(),
+                payload: packet[header.offset + FRAG_HEADER_LEN..].to_vec()
+            };
+            return Ok(Some(defragmented_ipv6(whole, header.offset, header.announced_at)))
+        }
+
+        let key = Ipv6Key {
+            src: Ipv6Addr::from(ip.get_src(packet)),
+            dst: Ipv6Addr::from(ip.get_dst(packet)),
+            ident: frag.get_ident(frag_buff)
+        };
+        let offset = frag.get_frag_offs(frag_buff) as usize * 8;
+        let more = frag.get_flag_m(frag_buff) != 0;
+        let unfragmentable = header.offset + FRAG_HEADER_LEN;
+        let payload = &packet[unfragmentable..];
+        // the payload length field has to be able to say how long it is
+        if unfragmentable - Ipv6::HEADER_LEN + offset + payload.len() > 0xFFFF {
+            self.frags.remove(&key);
+            return Err(FragError::TooBig)
+        }
+
+        match self.frags.push(key, offset, more, payload, &packet[..unfragmentable], now)? {
+            None => Ok(None),
+            Some(whole) => Ok(Some(defragmented_ipv6(whole, header.offset, header.announced_at)))
+        }
+    }
+
+    pub fn expire(&mut self, now: Instant) -> usize {
+        self.frags.expire(now)
+    }
+
+    pub fn len(&self) -> usize {
+        self.frags.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frags.is_empty()
+    }
+
+    pub fn buffered(&self) -> usize {
+        self.frags.buffered()
+    }
+}
+
+// Put the packet back together without its fragment header, at `frag_at`
+// in `whole.header`. The next header field that pointed at it, at
+// `announced_at`, now points at what the fragment header did.
+fn defragmented_ipv6(whole: Reassembled, frag_at: usize, announced_at: usize) -> Vec<u8> {
+    let mut packet = whole.header;
+    packet[announced_at] = packet[frag_at];
+    packet.truncate(frag_at);
+    packet.extend_from_slice(&whole.payload);
+    let ip = Ipv6 { offset: 0 };
+    let payload_len = (packet.len() - Ipv6::HEADER_LEN) as u16;
+    ip.set_payload_len(&mut packet, payload_len);
+    packet
+}
+
+// Split a locally generated ipv6 packet into fragments of at most `mtu`
+// bytes, the path mtu towards its destination. Only the source fragments
+// in ipv6, so there's no don't fragment flag to look at. The hop-by-hop
+// and routing headers, and any destination options before routing, go in
+// every fragment (RFC 8200, 4.5).
+pub fn fragment_ipv6(packet: &[u8], mtu: usize, ident: u32) -> Result<Vec<Vec<u8>>, FragError> {
+    let ip = Ipv6 { offset: 0 };
+    let len = (Ipv6::HEADER_LEN + ip.get_payload_len(packet) as usize).min(packet.len());
+    if len <= mtu {
+        return Ok(vec!(packet[..len].to_vec()))
+    }
+
+    let mut unfragmentable = Ipv6::HEADER_LEN;
+    let mut nxt_field = 6;
+    for header in ip.ext_headers(packet) {
+        let header = match header {
+            Ok(header) => header,
+            Err(_) => return Err(FragError::Malformed)
+        };
+        match header.kind {
+            header_types::HOP_BY_HOP | header_types::ROUTING => {
+                unfragmentable = header.offset + header.len;
+                nxt_field = header.offset;
+            },
+            header_types::FRAGMENT => return Err(FragError::Malformed),
+            _ => ()
+        }
+    }
+
+    let room = mtu.saturating_sub(unfragmentable + FRAG_HEADER_LEN) & !7;
+    if room == 0 {
+        return Err(FragError::MtuTooSmall)
+    }
+    let mut first = packet[..unfragmentable].to_vec();
+    let protocol = first[nxt_field];
+    first[nxt_field] = header_types::FRAGMENT;
+    let payload = &packet[unfragmentable..len];
+
+    let mut frags = Vec::new();
+    let mut pos = 0;
+    while pos < payload.len() {
+        let chunk = room.min(payload.len() - pos);
+        let last = pos + chunk == payload.len();
+
+        let mut frag = Vec::with_capacity(unfragmentable + FRAG_HEADER_LEN + chunk);
+        frag.extend_from_slice(&first);
+        frag.extend_from_slice(&[0; FRAG_HEADER_LEN]);
+        frag.extend_from_slice(&payload[pos..pos + chunk]);
+
+        let header = Ipv6Frag { offset: unfragmentable };
+        let frag_buff = &mut frag[unfragmentable..];
+        header.set_nxt_header(frag_buff, protocol);
+        header.set_frag_offs(frag_buff, (pos / 8) as u16);
+        header.set_flag_m(frag_buff, !last as u8);
+        header.set_ident(frag_buff, ident);
+        let payload_len = (frag.len() - Ipv6::HEADER_LEN) as u16;
+        ip.set_payload_len(&mut frag, payload_len);
+        frags.push(frag);
+        pos += chunk;
+    }
+    Ok(frags)
+}
+
+
+// testing
+#[test]
+fn test_ipv4_fragment_and_reassemble() {
+    use super::builder::PROTO_UDP;
+    use super::builder::Ipv4Builder;
+
+    let payload: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+    let packet = Ipv4Builder::new(Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 1))
+        .ident(0x1234)
+        .build(PROTO_UDP, &payload);
+    assert_eq!(fragment_ipv4(&packet.data, 1500), Err(FragError::DontFragment));
+
+    let packet = Ipv4Builder::new(Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 1))
+        .ident(0x1234)
+        .dont_fragment(false)
+        .build(PROTO_UDP, &payload);
+    let frags = fragment_ipv4(&packet.data, 1500).unwrap();
+    assert_eq!(frags.iter().map(|f| f.len()).collect::<Vec<_>>(), vec!(1500, 1500, 60));
+
+    // out of order, with a duplicate
+    let now = Instant::now();
+    let mut frags4 = Ipv4Reassembler::new(Limits::ipv4());
+    assert_eq!(frags4.push(&frags[2], now), Ok(None));
+    assert_eq!(frags4.push(&frags[0], now), Ok(None));
+    assert_eq!(frags4.push(&frags[0], now), Ok(None));
+    assert_eq!(frags4.push(&frags[1], now), Ok(Some(packet.data.clone())));
+    assert!(frags4.is_empty());
+    assert_eq!(frags4.buffered(), 0);
+
+    // overlaps drop the datagram when we're strict about them
+    let mut frags4 = Ipv4Reassembler::new(Limits { overlap: Overlap::Reject, ..Limits::ipv4() });
+    assert_eq!(frags4.push(&frags[0], now), Ok(None));
+    assert_eq!(frags4.push(&frags[0], now), Err(FragError::Overlap));
+    assert!(frags4.is_empty());
+
+    // and incomplete ones time out
+    assert_eq!(frags4.push(&frags[1], now), Ok(None));
+    assert_eq!(frags4.expire(now + Duration::from_secs(29)), 0);
+    assert_eq!(frags4.expire(now + Duration::from_secs(30)), 1);
+
+    // an ihl that doesn't even cover the fixed header
+    let mut bad = frags[1].clone();
+    bad[0] = 0x43;
+    assert_eq!(frags4.push(&bad, now), Err(FragError::BadHeader));
+    let mut bad = packet.data.clone();
+    bad[0] = 0x40;
+    assert_eq!(fragment_ipv4(&bad, 1500), Err(FragError::BadHeader));
+}
+
+#[test]
+fn test_ipv6_fragment_and_reassemble() {
+    use super::builder::PROTO_UDP;
+    use super::builder::Ipv6Builder;
+
+    let payload: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+    let packet = Ipv6Builder::new("fd00::2".parse().unwrap(), "fd00::1".parse().unwrap())
+        .build(PROTO_UDP, &payload);
+    let frags = fragment_ipv6(&packet.data, 1280, 7).unwrap();
+    assert_eq!(frags.iter().map(|f| f.len()).collect::<Vec<_>>(), vec!(1280, 1280, 584));
+
+    let now = Instant::now();
+    let mut frags6 = Ipv6Reassembler::new(Limits::ipv6());
+    assert_eq!(frags6.push(&frags[1], now), Ok(None));
+    assert_eq!(frags6.push(&frags[2], now), Ok(None));
+    assert_eq!(frags6.push(&frags[1], now), Err(FragError::Overlap));
+    assert!(frags6.is_empty());
+    for frag in &frags[..2] {
+        assert_eq!(frags6.push(frag, now), Ok(None));
+    }
+    assert_eq!(frags6.push(&frags[2], now), Ok(Some(packet.data.clone())));
+
+    // an atomic fragment is just the packet
+    let small = Ipv6Builder::new("fd00::2".parse().unwrap(), "fd00::1".parse().unwrap())
+        .build(PROTO_UDP, &payload[..100]);
+    let mut atomic = fragment_ipv6(&packet.data, 1280, 8).unwrap().remove(0);
+    atomic.truncate(Ipv6::HEADER_LEN + FRAG_HEADER_LEN + 100);
+    Ipv6 { offset: 0 }.set_payload_len(&mut atomic, (FRAG_HEADER_LEN + 100) as u16);
+    Ipv6Frag { offset: 0 }.set_flag_m(&mut atomic[Ipv6::HEADER_LEN..], 0);
+    assert_eq!(frags6.push(&atomic, now), Ok(Some(small.data)));
+    assert!(frags6.is_empty());
+}
@@ -1,4 +1,5 @@
 use super::pkt;
+use super::pkt::{write_imm, write_arr};
 
 // ICMP
 // RFC 792
@@ -58,3 +59,41 @@ use super::pkt;
 // 18 = Address mask reply
 // 30 = Traceroute (probably just Microsoft hosts, traceroute
 //      should be done via UDP)
+
+#[derive(Debug, Clone, Copy)]
+pub struct Icmpv4 {
+    pub offset: usize
+}
+
+// ident and seq are only meaningful for echo, timestamp and information
+// messages; for the others they're the "unused" or pointer word
+netbits!{
+    Icmpv4, write_imm, write_arr,
+    icmp_type:   8,
+    code:        8,
+    chk:        16,
+    ident:      16,
+    seq:        16
+}
+
+pub const ECHO_REPLY:    u8 = 0;
+pub const DEST_UNREACH:  u8 = 3;
+pub const ECHO_REQUEST:  u8 = 8;
+pub const TIME_EXCEEDED: u8 = 11;
+pub const PARAM_PROBLEM: u8 = 12;
+
+impl Icmpv4 {
+    pub fn header_len(&self, buff: &[u8]) -> Result<usize, pkt::Truncated> {
+        pkt::check_len("icmpv4", buff, Icmpv4::HEADER_LEN)?;
+        Ok(Icmpv4::HEADER_LEN)
+    }
+
+    pub fn get_payload_offset(&self) -> usize {
+        self.offset + 8
+    }
+
+    pub fn print(&self, buff: &[u8]) {
+        println!("icmpv4:");
+        self.print_fields(buff);
+    }
+}
@@ -1,3 +1,6 @@
+use super::pkt;
+use super::pkt::{write_imm, write_arr};
+
 // ICMPv6
 // RFC 4443
 //
@@ -51,3 +54,43 @@
 // 201  Private experimentation
 // 255  Reserved for expansion
 
+#[derive(Debug, Clone, Copy)]
+pub struct Icmpv6 {
+    pub offset: usize
+}
+
+// as with icmpv4, ident and seq are the echo fields, other messages use
+// the word for their own purposes (mtu, pointer, ...)
+netbits!{
+    Icmpv6, write_imm, write_arr,
+    icmp_type:   8,
+    code:        8,
+    chk:        16,
+    ident:      16,
+    seq:        16
+}
+
+pub const DEST_UNREACH:     u8 = 1;
+pub const PACKET_TOO_BIG:   u8 = 2;
+pub const TIME_EXCEEDED:    u8 = 3;
+pub const PARAM_PROBLEM:    u8 = 4;
+pub const ECHO_REQUEST:     u8 = 128;
+pub const ECHO_REPLY:       u8 = 129;
+pub const NEIGHBOR_SOLICIT: u8 = 135;
+pub const NEIGHBOR_ADVERT:  u8 = 136;
+
+impl Icmpv6 {
+    pub fn header_len(&self, buff: &[u8]) -> Result<usize, pkt::Truncated> {
+        pkt::check_len("icmpv6", buff, Icmpv6::HEADER_LEN)?;
+        Ok(Icmpv6::HEADER_LEN)
+    }
+
+    pub fn get_payload_offset(&self) -> usize {
+        self.offset + 8
+    }
+
+    pub fn print(&self, buff: &[u8]) {
+        println!("icmpv6:");
+        self.print_fields(buff);
+    }
+}
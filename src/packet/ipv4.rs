@@ -3,6 +3,8 @@ use std::net;
 use super::pkt;
 use super::pkt::{write_imm, write_arr};
 use super::tcp;
+use super::udp;
+use super::icmpv4;
 
 // IPV4
 //
@@ -21,6 +23,7 @@ use super::tcp;
 // +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
 // |                    Options                    |    Padding    |
 // +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+#[derive(Debug, Clone, Copy)]
 pub struct Ipv4 {
    pub offset: usize
 }
@@ -44,25 +47,54 @@ netbits!{
     dst:        [8; 4; print_ipv4]
 }
 
-fn print_ipv4(name: &str, buff: &[u8]) {
+pub fn print_ipv4(name: &str, buff: &[u8]) {
     let addr = net::Ipv4Addr::new(buff[0], buff[1], buff[2], buff[3]);
     let addr_str = format!("{}", addr);
     println!("  {: <15}: {: >15}", name, addr_str);
 }
 
+impl Ipv4 {
+    pub fn is_fragment(&self, buff: &[u8]) -> bool {
+        self.get_flag_mf(buff) != 0 || self.get_frag_offs(buff) != 0
+    }
+}
+
 impl pkt::HasNetworkLayer for Ipv4 {
-    fn get_transport(&self, buffer: &[u8]) -> pkt::Transport {
+    // options included; an ihl below 5 can't even hold the fixed part
+    fn header_len(&self, buff: &[u8]) -> Result<usize, pkt::DissectError> {
+        pkt::check_len("ipv4", buff, Ipv4::HEADER_LEN)?;
+        let len = self.get_ihl(buff) as usize * 4;
+        if len < Ipv4::HEADER_LEN {
+            return Err(pkt::DissectError::HeaderLen { layer: "ipv4", len: len })
+        }
+        pkt::check_len("ipv4", buff, len)?;
+        Ok(len)
+    }
+
+    fn declared_len(&self, buff: &[u8]) -> Option<usize> {
+        Some(self.get_len(buff) as usize)
+    }
+
+    fn get_transport(&self, buffer: &[u8]) -> Result<Option<pkt::Transport>, pkt::DissectError> {
+        self.header_len(buffer)?;
+        // only the first fragment starts with the transport header
+        if self.get_frag_offs(buffer) != 0 {
+            return Ok(None)
+        }
         let protocol = self.get_protocol(buffer);
         let trans_offset = self.get_payload_offset(buffer);
-        match protocol {
-            0x06 => pkt::Transport::TcpTrans(tcp::Tcp { offset: trans_offset }),
-            _    => panic!("transport protocol {:x} isn't implemented", protocol)
-        }
+        Ok(match protocol {
+            0x06 => Some(pkt::Transport::TcpTrans(tcp::Tcp { offset: trans_offset })),
+            0x11 => Some(pkt::Transport::UdpTrans(udp::Udp { offset: trans_offset })),
+            0x01 => Some(pkt::Transport::Icmpv4Trans(icmpv4::Icmpv4 { offset: trans_offset })),
+            _    => None
+        })
     }
 
     fn get_payload_offset(&self, buff: &[u8]) -> usize {
+        // ihl counts 32 bit words
         let ihl = self.get_ihl(buff) as usize;
-        self.offset + ihl
+        self.offset + ihl * 4
     }
 
     fn print(&self, buff: &[u8]) {
@@ -1,8 +1,13 @@
+use std::fmt;
 use std::net;
 
 use super::pkt;
 use super::pkt::{write_imm, write_arr};
 use super::tcp;
+use super::udp;
+use super::icmpv6;
+use super::srv6;
+use super::super::util;
 
 // IPV6
 // RFC 2460
@@ -24,9 +29,9 @@ use super::tcp;
 // |                           Address                             |
 // |                                                               |
 // +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+#[derive(Debug, Clone, Copy)]
 pub struct Ipv6 {
-    pub offset: usize,
-    // extension_headers: Vec<Ipv6ExtHeader>
+    pub offset: usize
 }
 
 netbits!{
@@ -53,11 +58,7 @@ fn print_ipv6(name: &str, buff: &[u8]) {
     println!("  {: <15}: {: >40}", name, addr_str);
 }
 
-struct Ipv6ExtHeader {
-    header_type: usize
-}
-
-mod header_types {
+pub mod header_types {
     pub const HOP_BY_HOP:  u8 = 0;
     pub const ROUTING:     u8 = 43;
     pub const FRAGMENT:    u8 = 44;
@@ -68,48 +69,486 @@ mod header_types {
     pub const MOBILITY:    u8 = 136;
 }
 
+// Fragment header
+// RFC 8200, 4.5
+//
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |  Next Header  |   Reserved    |      Fragment Offset    |Res|M|
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |                         Identification                        |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+#[derive(Debug, Clone, Copy)]
+pub struct Ipv6Frag {
+    pub offset: usize
+}
+
+netbits!{
+    Ipv6Frag, write_imm, write_arr,
+    nxt_header:  8,
+    res:         8,
+    frag_offs:  13,
+    res2:        2,
+    flag_m:      1,
+    ident:      32
+}
+
+impl Ipv6Frag {
+    // offset 0 and no more fragments: a whole packet that just happens to
+    // carry a fragment header (RFC 6946)
+    pub fn is_atomic(&self, buff: &[u8]) -> bool {
+        self.get_frag_offs(buff) == 0 && self.get_flag_m(buff) == 0
+    }
+}
+
+// Routing header
+// RFC 8200, 4.4
+//
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |  Next Header  |  Hdr Ext Len  |  Routing Type | Segments Left |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |                                                               |
+// .                                                               .
+// .                       type-specific data                      .
+// .                                                               .
+// |                                                               |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+#[derive(Debug, Clone, Copy)]
+pub struct Ipv6Routing {
+    pub offset: usize
+}
+
+netbits!{
+    Ipv6Routing, write_imm, write_arr,
+    nxt_header:     8,
+    hdr_ext_len:    8,
+    routing_type:   8,
+    segments_left:  8
+}
+
+pub mod routing_types {
+    pub const SOURCE_ROUTE: u8 = 0;   // deprecated, RFC 5095
+    pub const NIMROD:       u8 = 1;
+    pub const MOBILITY:     u8 = 2;   // RFC 6275
+    pub const RPL:          u8 = 3;   // RFC 6554
+    pub const SEGMENT:      u8 = 4;   // RFC 8754
+}
+
+// An option or TLV, as found in hop-by-hop and destination options and
+// segment routing headers. Type 0 is a single byte of padding and has no
+// length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tlv<'a> {
+    pub kind: u8,
+    // from the start of the TLVs
+    pub offset: usize,
+    pub data: &'a [u8]
+}
+
+pub struct Tlvs<'a> {
+    buff: &'a [u8],
+    offset: usize
+}
+
+impl<'a> Tlvs<'a> {
+    // `buff` holds just the TLVs
+    pub fn new(buff: &'a [u8]) -> Tlvs<'a> {
+        Tlvs { buff: buff, offset: 0 }
+    }
+}
+
+impl<'a> Iterator for Tlvs<'a> {
+    type Item = Result<Tlv<'a>, pkt::Truncated>;
+
+    fn next(&mut self) -> Option<Result<Tlv<'a>, pkt::Truncated>> {
+        if self.offset >= self.buff.len() {
+            return None
+        }
+        let kind = self.buff[self.offset];
+        let offset = self.offset;
+        if kind == 0 {
+            self.offset += 1;
+            return Some(Ok(Tlv { kind: kind, offset: offset, data: &[] }))
+        }
+
+        let start = self.offset + 2;
+        let end = match pkt::check_len("ipv6", self.buff, start) {
+            Ok(()) => start + self.buff[self.offset + 1] as usize,
+            Err(e) => return Some(Err(e))
+        };
+        if let Err(e) = pkt::check_len("ipv6", self.buff, end) {
+            // nothing after a bad length can be trusted
+            self.offset = self.buff.len();
+            return Some(Err(e))
+        }
+        self.offset = end;
+        Some(Ok(Tlv { kind: kind, offset: offset, data: &self.buff[start..end] }))
+    }
+}
+
+// hop-by-hop and destination options
+// RFC 8200, 4.2
+//
+// The two high-order bits of an option's type say what to do with a packet
+// carrying an option we don't know, the third whether it may change en
+// route.
+pub mod option_types {
+    pub const PAD1:         u8 = 0x00;
+    pub const PADN:         u8 = 0x01;
+    pub const ROUTER_ALERT: u8 = 0x05;   // RFC 2711
+    pub const JUMBO:        u8 = 0xC2;   // RFC 2675
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptAction {
+    Skip,
+    Discard,
+    // and send a parameter problem
+    DiscardIcmp,
+    // and send a parameter problem unless the destination was multicast
+    DiscardIcmpUnicast
+}
+
+impl OptAction {
+    pub fn of(kind: u8) -> OptAction {
+        match kind >> 6 {
+            0 => OptAction::Skip,
+            1 => OptAction::Discard,
+            2 => OptAction::DiscardIcmp,
+            _ => OptAction::DiscardIcmpUnicast
+        }
+    }
+}
+
+impl fmt::Display for OptAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            OptAction::Skip               => "skip",
+            OptAction::Discard            => "discard",
+            OptAction::DiscardIcmp        => "discard, icmp",
+            OptAction::DiscardIcmpUnicast => "discard, icmp unless multicast"
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ipv6Option<'a> {
+    Pad1,
+    PadN(usize),
+    // what the router should look at, 0 is MLD
+    RouterAlert(u16),
+    Jumbo(u32),
+    Unknown(Tlv<'a>),
+    // a known option with the wrong length
+    Malformed(Tlv<'a>)
+}
+
+impl<'a> Ipv6Option<'a> {
+    pub fn from_tlv(tlv: Tlv<'a>) -> Ipv6Option<'a> {
+        let data = tlv.data;
+        match (tlv.kind, data.len()) {
+            (option_types::PAD1, _) => Ipv6Option::Pad1,
+            (option_types::PADN, len) => Ipv6Option::PadN(len),
+            (option_types::ROUTER_ALERT, 2) =>
+                Ipv6Option::RouterAlert(u16::from_be_bytes([data[0], data[1]])),
+            (option_types::JUMBO, 4) =>
+                Ipv6Option::Jumbo(u32::from_be_bytes([data[0], data[1], data[2], data[3]])),
+            (option_types::ROUTER_ALERT, _) |
+            (option_types::JUMBO, _) => Ipv6Option::Malformed(tlv),
+            _ => Ipv6Option::Unknown(tlv)
+        }
+    }
+
+    pub fn print(&self) {
+        match *self {
+            Ipv6Option::Pad1 => write_imm("pad1", 1),
+            Ipv6Option::PadN(len) => write_imm("padn", len as u64 + 2),
+            Ipv6Option::RouterAlert(val) => write_imm("router alert", val as u64),
+            Ipv6Option::Jumbo(len) => write_imm("jumbo payload", len as u64),
+            Ipv6Option::Unknown(tlv) => {
+                println!("  {: <15}: {} ({})", format!("option 0x{:X}", tlv.kind),
+                         util::to_hex_string(tlv.data), OptAction::of(tlv.kind));
+            },
+            Ipv6Option::Malformed(tlv) => {
+                println!("  {: <15}: {} (bad length)", format!("option 0x{:X}", tlv.kind),
+                         util::to_hex_string(tlv.data));
+            }
+        }
+    }
+}
+
+// An option that gets the packet dropped, with the icmpv6 code to report it
+// with and the offset of the option from the start of the ipv6 header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BadOption {
+    pub action: OptAction,
+    pub code: u8,
+    pub pointer: usize
+}
+
+impl BadOption {
+    // the parameter problem to send back, as (type, code, rest of header)
+    pub fn icmp(&self, multicast_dst: bool) -> Option<(u8, u8, u32)> {
+        match self.action {
+            OptAction::Skip | OptAction::Discard => None,
+            OptAction::DiscardIcmpUnicast if multicast_dst => None,
+            _ => Some((icmpv6::PARAM_PROBLEM, self.code, self.pointer as u32))
+        }
+    }
+}
+
+impl fmt::Display for BadOption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.code {
+            2 => write!(f, "unrecognised ipv6 option at offset {}", self.pointer),
+            _ => write!(f, "bad ipv6 option at offset {}", self.pointer)
+        }
+    }
+}
+
+// one extension header, offsets from the start of the ipv6 header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtHeader {
+    pub kind: u8,
+    pub offset: usize,
+    pub len: usize,
+    // the next header field that announced this one
+    pub announced_at: usize
+}
+
+// Walks the extension headers we know the length of. After the last one,
+// `protocol` and `end` say what follows and where. Behind a fragment
+// header with a non-zero offset there's nothing to parse, so the walk ends
+// there with `NO_NEXT`.
+pub struct ExtHeaders<'a> {
+    buff: &'a [u8],
+    next: u8,
+    offset: usize,
+    announced_at: usize
+}
+
+impl<'a> ExtHeaders<'a> {
+    pub fn protocol(&self) -> u8 {
+        self.next
+    }
+
+    pub fn end(&self) -> usize {
+        self.offset
+    }
+}
+
+impl<'a> Iterator for ExtHeaders<'a> {
+    type Item = Result<ExtHeader, pkt::Truncated>;
+
+    fn next(&mut self) -> Option<Result<ExtHeader, pkt::Truncated>> {
+        let len = match self.next {
+            header_types::HOP_BY_HOP |
+            header_types::DEST_OPTS |
+            header_types::ROUTING => {
+                if let Err(e) = pkt::check_len("ipv6", self.buff, self.offset + 2) {
+                    return Some(Err(e))
+                }
+                (self.buff[self.offset + 1] as usize + 1) * 8
+            },
+            header_types::FRAGMENT => 8,
+            _ => return None
+        };
+        if let Err(e) = pkt::check_len("ipv6", self.buff, self.offset + len) {
+            return Some(Err(e))
+        }
+
+        let header = ExtHeader {
+            kind: self.next,
+            offset: self.offset,
+            len: len,
+            announced_at: self.announced_at
+        };
+        self.next = self.buff[self.offset];
+        self.announced_at = self.offset;
+        self.offset += len;
+
+        if header.kind == header_types::FRAGMENT {
+            let frag = Ipv6Frag { offset: header.offset };
+            if frag.get_frag_offs(&self.buff[header.offset..]) != 0 {
+                self.next = header_types::NO_NEXT;
+            }
+        }
+        Some(Ok(header))
+    }
+}
+
 impl Ipv6 {
-    fn get_protocol(&self, buff: &[u8]) -> u8 {
-        buff[self.offset+9]
-    }
-
-    fn process_ext_headers(&self, buff: &[u8]) -> usize {
-        let protocol = self.get_protocol(buff);
-        let header_offset = 40;
-
-        loop {
-            match protocol {
-                header_types::HOP_BY_HOP |
-                header_types::DEST_OPTS |
-                header_types::ROUTING => {
-                    // implement
-                    return 4
-                },
-                _ => return 0
+    // `buff` has to hold the fixed header
+    pub fn ext_headers<'a>(&self, buff: &'a [u8]) -> ExtHeaders<'a> {
+        ExtHeaders {
+            buff: buff,
+            next: self.get_nxt_header(buff),
+            offset: Ipv6::HEADER_LEN,
+            announced_at: 6
+        }
+    }
+
+    // the fragment header, if there is one
+    pub fn get_fragment(&self, buff: &[u8]) -> Option<ExtHeader> {
+        self.ext_headers(buff)
+            .filter_map(|header| header.ok())
+            .find(|header| header.kind == header_types::FRAGMENT)
+    }
+
+    // the options in a hop-by-hop or destination options header
+    pub fn options<'a>(&self, buff: &'a [u8], header: &ExtHeader)
+                       -> impl Iterator<Item = Result<Ipv6Option<'a>, pkt::Truncated>> {
+        Tlvs::new(&buff[header.offset + 2..header.offset + header.len])
+            .map(|tlv| tlv.map(Ipv6Option::from_tlv))
+    }
+
+    // the jumbo payload length, if the payload length field is 0 for it
+    pub fn get_jumbo_len(&self, buff: &[u8]) -> Option<u32> {
+        let header = self.ext_headers(buff).next()?.ok()?;
+        if header.kind != header_types::HOP_BY_HOP {
+            return None
+        }
+        self.options(buff, &header).filter_map(|opt| match opt {
+            Ok(Ipv6Option::Jumbo(len)) => Some(len),
+            _ => None
+        }).next()
+    }
+
+    // Goes through the options of every hop-by-hop and destination options
+    // header, stopping at the first one that gets the packet dropped.
+    // Unknown options are handled by their action bits, a jumbo payload
+    // has to be one that doesn't fit in the payload length (RFC 2675).
+    pub fn check_options(&self, buff: &[u8]) -> Result<(), BadOption> {
+        let bad = |action, code, pointer| Err(BadOption {
+            action: action, code: code, pointer: pointer
+        });
+        for header in self.ext_headers(buff) {
+            let header = match header {
+                Ok(header) => header,
+                Err(_) => break
+            };
+            if header.kind != header_types::HOP_BY_HOP && header.kind != header_types::DEST_OPTS {
+                continue
+            }
+            let start = header.offset + 2;
+            for tlv in Tlvs::new(&buff[start..header.offset + header.len]) {
+                let tlv = match tlv {
+                    Ok(tlv) => tlv,
+                    Err(_) => return bad(OptAction::DiscardIcmp, 0, header.offset + 1)
+                };
+                let pointer = start + tlv.offset;
+                match Ipv6Option::from_tlv(tlv) {
+                    Ipv6Option::Unknown(tlv) => match OptAction::of(tlv.kind) {
+                        OptAction::Skip => (),
+                        action => return bad(action, 2, pointer)
+                    },
+                    Ipv6Option::Malformed(_) => return bad(OptAction::DiscardIcmp, 0, pointer + 1),
+                    Ipv6Option::Jumbo(len) => {
+                        if header.kind != header_types::HOP_BY_HOP || self.get_payload_len(buff) != 0 {
+                            return bad(OptAction::DiscardIcmp, 0, pointer)
+                        }
+                        if len <= 0xFFFF {
+                            return bad(OptAction::DiscardIcmp, 0, pointer + 2)
+                        }
+                    },
+                    _ => ()
+                }
             }
         }
-        header_offset
+        Ok(())
+    }
+
+    // the first routing header, if there is one
+    pub fn get_routing(&self, buff: &[u8]) -> Option<ExtHeader> {
+        self.ext_headers(buff)
+            .filter_map(|header| header.ok())
+            .find(|header| header.kind == header_types::ROUTING)
+    }
+
+    // whether this is part of a bigger packet, atomic fragments aren't
+    pub fn is_fragment(&self, buff: &[u8]) -> bool {
+        match self.get_fragment(buff) {
+            Some(header) => !Ipv6Frag { offset: header.offset }.is_atomic(&buff[header.offset..]),
+            None => false
+        }
+    }
+
+    // Skips the extension headers we know the length of, returning the
+    // upper layer protocol and the total length of the skipped headers.
+    fn process_ext_headers(&self, buff: &[u8]) -> Result<(u8, usize), pkt::Truncated> {
+        pkt::check_len("ipv6", buff, Ipv6::HEADER_LEN)?;
+        let mut headers = self.ext_headers(buff);
+        for header in &mut headers {
+            header?;
+        }
+        Ok((headers.protocol(), headers.end() - Ipv6::HEADER_LEN))
     }
 }
 
 impl pkt::HasNetworkLayer for Ipv6 {
-    fn get_transport(&self, buffer: &[u8]) -> pkt::Transport {
-        let protocol = self.get_protocol(buffer);
-        let trans_offset = self.get_payload_offset(buffer);
-        match protocol {
-            0x06 => pkt::Transport::TcpTrans(tcp::Tcp { offset: trans_offset }),
-            _    => panic!("transport protocol {:x} isn't implemented", protocol)
+    // extension headers included
+    fn header_len(&self, buff: &[u8]) -> Result<usize, pkt::DissectError> {
+        let (_, ext_header_len) = self.process_ext_headers(buff)?;
+        Ok(Ipv6::HEADER_LEN + ext_header_len)
+    }
+
+    // a zero payload length is a jumbogram, the real length is in a
+    // hop-by-hop option
+    fn declared_len(&self, buff: &[u8]) -> Option<usize> {
+        match self.get_payload_len(buff) as usize {
+            0 if Ipv6::fits(buff) => self.get_jumbo_len(buff)
+                .map(|len| Ipv6::HEADER_LEN + len as usize),
+            0   => None,
+            len => Some(Ipv6::HEADER_LEN + len)
         }
     }
 
+    fn get_transport(&self, buffer: &[u8]) -> Result<Option<pkt::Transport>, pkt::DissectError> {
+        let (protocol, ext_header_len) = self.process_ext_headers(buffer)?;
+        let trans_offset = self.offset + Ipv6::HEADER_LEN + ext_header_len;
+        Ok(match protocol {
+            0x06 => Some(pkt::Transport::TcpTrans(tcp::Tcp { offset: trans_offset })),
+            0x11 => Some(pkt::Transport::UdpTrans(udp::Udp { offset: trans_offset })),
+            0x3A => Some(pkt::Transport::Icmpv6Trans(icmpv6::Icmpv6 { offset: trans_offset })),
+            _    => None
+        })
+    }
+
+    // a header that doesn't check out has nothing after it
     fn get_payload_offset(&self, buff: &[u8]) -> usize {
-        let pkt_end = 40;
-        let ext_header_len = self.process_ext_headers(buff);
-        self.offset + pkt_end + ext_header_len
+        self.offset + self.header_len(buff).unwrap_or(buff.len())
     }
 
     fn print(&self, buff: &[u8]) {
         println!("ipv6:");
         self.print_fields(buff);
+        for header in self.ext_headers(buff).filter_map(|header| header.ok()) {
+            match header.kind {
+                header_types::HOP_BY_HOP => print_options("hop-by-hop", self, buff, &header),
+                header_types::DEST_OPTS => print_options("dest options", self, buff, &header),
+                header_types::ROUTING =>
+                    print_routing(&buff[header.offset..header.offset + header.len]),
+                _ => ()
+            }
+        }
+    }
+}
+
+fn print_options(name: &str, ip: &Ipv6, buff: &[u8], header: &ExtHeader) {
+    println!("  {}:", name);
+    for opt in ip.options(buff, header) {
+        match opt {
+            Ok(opt) => opt.print(),
+            Err(e) => println!("  ({})", e)
+        }
+    }
+}
+
+fn print_routing(buff: &[u8]) {
+    let routing = Ipv6Routing { offset: 0 };
+    println!("  routing:");
+    if routing.get_routing_type(buff) == routing_types::SEGMENT {
+        srv6::Srh { offset: 0 }.print(buff);
+    } else {
+        routing.print_fields(buff);
     }
 }
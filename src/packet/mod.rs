@@ -1,3 +1,5 @@
+#[macro_use]
+pub mod netbits;
 pub mod pkt;
 pub mod eth;
 pub mod ipv4;
@@ -5,3 +7,11 @@ pub mod ipv6;
 pub mod icmpv4;
 pub mod icmpv6;
 pub mod tcp;
+pub mod udp;
+pub mod arp;
+pub mod view;
+pub mod filter;
+pub mod pool;
+pub mod builder;
+pub mod frag;
+pub mod srv6;
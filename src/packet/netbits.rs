@@ -0,0 +1,377 @@
+// header bit fields
+//
+// A stable stand-in for the old net_bits compiler plugin. A header is
+// described as a list of fields and their width in bits, or as an array
+// of `[bits; count]` with an optional printer:
+//
+//     netbits!{ Udp, write_imm, write_arr,
+//               src_port: 16,
+//               dst_port: 16,
+//               len: 16,
+//               chk: 16 }
+//
+// which generates `get_src_port(&self, buff: &[u8]) -> u16`,
+// `set_src_port(&self, buff: &mut [u8], val: u16)`, ... on `Udp`, a
+// `print_fields(&self, buff: &[u8])` that hands every field to the two
+// printers, and a `HEADER_LEN` constant with the size of the fixed part of
+// the header in bytes. Immediates come back as the smallest unsigned int
+// that holds them, arrays as `[u8; count]`.
+//
+// As before the buffer starts at the header being read. Every accessor
+// checks that the field is inside the buffer and panics with the header
+// and field name if it isn't; `fits` tells up front whether it will.
+//
+// For code that goes by field name instead, there's `FIELDS`, a `Field`
+// for each of them in order, which reads and writes a `Value`.
+
+use std::fmt;
+
+pub struct Bits<const N: usize>;
+
+// maps a field width to the type its accessors use
+pub trait Width {
+    type Ty: Copy + Into<u64>;
+    fn from_u64(val: u64) -> Self::Ty;
+}
+
+macro_rules! impl_width {
+    ($ty:ident: $($n:expr)*) => {
+        $(impl Width for Bits<$n> {
+            type Ty = $ty;
+            fn from_u64(val: u64) -> $ty { val as $ty }
+        })*
+    }
+}
+
+impl_width!(u8: 1 2 3 4 5 6 7 8);
+impl_width!(u16: 9 10 11 12 13 14 15 16);
+impl_width!(u32: 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31 32);
+impl_width!(u64: 33 34 35 36 37 38 39 40 41 42 43 44 45 46 47 48
+            49 50 51 52 53 54 55 56 57 58 59 60 61 62 63 64);
+
+// a field that runs past the end of the buffer
+pub struct OutOfBounds {
+    pub header: &'static str,
+    pub field: &'static str,
+    pub needed: usize,
+    pub len: usize
+}
+
+impl fmt::Display for OutOfBounds {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{} needs {} bytes, buffer has {}",
+               self.header, self.field, self.needed, self.len)
+    }
+}
+
+// bytes needed to hold the field ending at bit `end`
+pub fn check(buff: &[u8], end: usize, header: &'static str, field: &'static str) {
+    let needed = end.div_ceil(8);
+    if buff.len() < needed {
+        panic!("{}", OutOfBounds { header: header, field: field,
+                                   needed: needed, len: buff.len() });
+    }
+}
+
+// a field as `FIELDS` lists it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Field {
+    pub header: &'static str,
+    pub name: &'static str,
+    // from the start of the header, in bits
+    pub offset: usize,
+    pub width: usize,
+    // an array, read and written as bytes
+    pub bytes: bool
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Int(u64),
+    Bytes(Vec<u8>)
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Int(val) => write!(f, "{}", val),
+            Value::Bytes(ref bytes) => {
+                f.write_str("0x")?;
+                bytes.iter().try_for_each(|b| write!(f, "{:02x}", b))
+            }
+        }
+    }
+}
+
+// the value doesn't go in the field
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BadValue {
+    pub field: Field,
+    pub value: Value
+}
+
+impl fmt::Display for BadValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let field = &self.field;
+        match field.bytes {
+            true  => write!(f, "{}.{} takes {} bytes, not {}", field.header, field.name,
+                            field.width / 8, self.value),
+            false => write!(f, "{}.{} is {} bits, {} doesn't fit", field.header, field.name,
+                            field.width, self.value)
+        }
+    }
+}
+
+impl Field {
+    pub fn get(&self, buff: &[u8]) -> Value {
+        check(buff, self.offset + self.width, self.header, self.name);
+        match self.bytes {
+            true  => Value::Bytes(buff[self.offset / 8..(self.offset + self.width) / 8].to_vec()),
+            false => Value::Int(read_bits(buff, self.offset, self.width))
+        }
+    }
+
+    // Checksums and lengths that cover the field are left as they were.
+    pub fn set(&self, buff: &mut [u8], value: &Value) -> Result<(), BadValue> {
+        check(buff, self.offset + self.width, self.header, self.name);
+        match (self.bytes, value) {
+            (true, Value::Bytes(bytes)) if bytes.len() * 8 == self.width =>
+                buff[self.offset / 8..(self.offset + self.width) / 8].copy_from_slice(bytes),
+            (false, &Value::Int(val)) if self.width == 64 || val >> self.width == 0 =>
+                write_bits(buff, self.offset, self.width, val),
+            _ => return Err(BadValue { field: *self, value: value.clone() })
+        }
+        Ok(())
+    }
+}
+
+pub fn read_bits(buff: &[u8], off: usize, width: usize) -> u64 {
+    let mut val = 0u64;
+    for bit in off..off + width {
+        val = (val << 1) | ((buff[bit / 8] >> (7 - bit % 8)) & 1) as u64;
+    }
+    val
+}
+
+pub fn write_bits(buff: &mut [u8], off: usize, width: usize, val: u64) {
+    for (i, bit) in (off..off + width).enumerate() {
+        let mask = 1u8 << (7 - bit % 8);
+        if (val >> (width - 1 - i)) & 1 == 1 {
+            buff[bit / 8] |= mask;
+        } else {
+            buff[bit / 8] &= !mask;
+        }
+    }
+}
+
+macro_rules! netbits {
+    ($name:ident, $imm:ident, $arr:ident, $($field:ident : $spec:tt),* $(,)*) => {
+        impl $name {
+            pub const HEADER_LEN: usize = (0 $(+ netbits!(@width $spec))*) / 8;
+
+            pub const FIELDS: &'static [$crate::packet::netbits::Field] =
+                netbits!(@fields $name (0usize) []; $($field $spec)*);
+
+            // whether `buff` holds the whole fixed part of the header
+            pub fn fits(buff: &[u8]) -> bool {
+                buff.len() >= $name::HEADER_LEN
+            }
+
+            netbits!(@acc $name (0usize); $($field $spec)*);
+
+            pub fn print_fields(&self, buff: &[u8]) {
+                // checks the printers' signatures, and keeps the generic
+                // ones in use when every array brings its own
+                let _printers: (fn(&str, u64), fn(&str, &[u8])) = ($imm, $arr);
+                $crate::paste::paste! {
+                    $(netbits!(@print $imm, $arr, $field, self.[<get_ $field>](buff), $spec);)*
+                }
+            }
+        }
+    };
+
+    (@fields $name:ident ($off:expr) [$($acc:tt)*]; ) => (&[$($acc)*]);
+    (@fields $name:ident ($off:expr) [$($acc:tt)*]; $field:ident $spec:tt $($rest:tt)*) => {
+        netbits!(@fields $name ($off + netbits!(@width $spec)) [$($acc)*
+            $crate::packet::netbits::Field {
+                header: stringify!($name),
+                name: stringify!($field),
+                offset: $off,
+                width: netbits!(@width $spec),
+                bytes: netbits!(@bytes $spec)
+            },
+        ]; $($rest)*)
+    };
+
+    (@bytes [$($spec:tt)*]) => (true);
+    (@bytes $w:tt) => (false);
+
+    (@width [$w:expr; $n:expr $(; $p:ident)*]) => ($w * $n);
+    (@width $w:expr) => ($w);
+
+    (@acc $name:ident ($off:expr); ) => {};
+    (@acc $name:ident ($off:expr); $field:ident [$w:expr; $n:expr $(; $p:ident)*] $($rest:tt)*) => {
+        $crate::paste::paste! {
+            pub fn [<get_ $field>](&self, buff: &[u8]) -> [u8; $n] {
+                let start = ($off) / 8;
+                $crate::packet::netbits::check(buff, $off + $w * $n,
+                                               stringify!($name), stringify!($field));
+                let mut val = [0u8; $n];
+                val.copy_from_slice(&buff[start..start + $n]);
+                val
+            }
+
+            pub fn [<set_ $field>](&self, buff: &mut [u8], val: [u8; $n]) {
+                let start = ($off) / 8;
+                $crate::packet::netbits::check(buff, $off + $w * $n,
+                                               stringify!($name), stringify!($field));
+                buff[start..start + $n].copy_from_slice(&val);
+            }
+        }
+        netbits!(@acc $name ($off + $w * $n); $($rest)*);
+    };
+    (@acc $name:ident ($off:expr); $field:ident $w:tt $($rest:tt)*) => {
+        $crate::paste::paste! {
+            pub fn [<get_ $field>](&self, buff: &[u8])
+                -> <$crate::packet::netbits::Bits<$w> as $crate::packet::netbits::Width>::Ty {
+                $crate::packet::netbits::check(buff, $off + $w,
+                                               stringify!($name), stringify!($field));
+                <$crate::packet::netbits::Bits<$w> as $crate::packet::netbits::Width>::from_u64(
+                    $crate::packet::netbits::read_bits(buff, $off, $w))
+            }
+
+            pub fn [<set_ $field>](&self, buff: &mut [u8],
+                val: <$crate::packet::netbits::Bits<$w> as $crate::packet::netbits::Width>::Ty) {
+                $crate::packet::netbits::check(buff, $off + $w,
+                                               stringify!($name), stringify!($field));
+                $crate::packet::netbits::write_bits(buff, $off, $w, val.into())
+            }
+        }
+        netbits!(@acc $name ($off + $w); $($rest)*);
+    };
+
+    (@print $imm:ident, $arr:ident, $field:ident, $val:expr, [$w:expr; $n:expr; $p:ident]) => {
+        $p(stringify!($field), &$val)
+    };
+    (@print $imm:ident, $arr:ident, $field:ident, $val:expr, [$w:expr; $n:expr]) => {
+        $arr(stringify!($field), &$val)
+    };
+    (@print $imm:ident, $arr:ident, $field:ident, $val:expr, $w:tt) => {
+        $imm(stringify!($field), $val.into())
+    };
+}
+
+
+// testing
+#[cfg(test)]
+struct Probe;
+
+#[cfg(test)]
+thread_local!(static PRINTED: ::std::cell::RefCell<Vec<String>> =
+              const { ::std::cell::RefCell::new(Vec::new()) });
+
+#[cfg(test)]
+fn record_imm(name: &str, val: u64) {
+    PRINTED.with(|printed| printed.borrow_mut().push(format!("{}={}", name, val)));
+}
+
+#[cfg(test)]
+fn record_arr(name: &str, val: &[u8]) {
+    PRINTED.with(|printed| printed.borrow_mut().push(format!("{}={:?}", name, val)));
+}
+
+#[cfg(test)]
+netbits!{
+    Probe, record_imm, record_arr,
+    flag:   1,
+    nibble: 3,
+    odd:   12,
+    word:  16,
+    wide:  40,
+    mac:   [8; 6]
+}
+
+#[test]
+fn test_bits_round_trip_unaligned() {
+    let mut buff = [0xFFu8; 4];
+    write_bits(&mut buff, 3, 7, 0b0101010);
+    assert_eq!(buff, [0b1110_1010, 0b1011_1111, 0xFF, 0xFF]);
+    assert_eq!(read_bits(&buff, 3, 7), 0b0101010);
+    // the neighbours are left alone
+    assert_eq!((read_bits(&buff, 0, 3), read_bits(&buff, 10, 22)), (0b111, 0x3F_FFFF));
+    write_bits(&mut buff, 0, 32, 0x1234_5678);
+    assert_eq!(read_bits(&buff, 0, 32), 0x1234_5678);
+}
+
+#[test]
+fn test_accessors_pick_their_types() {
+    assert_eq!(Probe::HEADER_LEN, 15);
+    let mut buff = [0u8; 15];
+    let probe = Probe;
+    probe.set_flag(&mut buff, 1);
+    probe.set_nibble(&mut buff, 5);
+    probe.set_odd(&mut buff, 0xABC);
+    probe.set_word(&mut buff, 0xBEEF);
+    probe.set_wide(&mut buff, 0x12_3456_789A);
+    probe.set_mac(&mut buff, [1, 2, 3, 4, 5, 6]);
+    assert_eq!(buff, [0xDA, 0xBC, 0xBE, 0xEF, 0x12, 0x34, 0x56, 0x78, 0x9A, 1, 2, 3, 4, 5, 6]);
+
+    let flag: u8 = probe.get_flag(&buff);
+    let odd: u16 = probe.get_odd(&buff);
+    let wide: u64 = probe.get_wide(&buff);
+    let mac: [u8; 6] = probe.get_mac(&buff);
+    assert_eq!((flag, probe.get_nibble(&buff), odd), (1, 5, 0xABC));
+    assert_eq!((probe.get_word(&buff), wide, mac), (0xBEEF, 0x12_3456_789A, [1, 2, 3, 4, 5, 6]));
+}
+
+#[test]
+fn test_fits_and_bounds() {
+    assert!(Probe::fits(&[0; 15]));
+    assert!(!Probe::fits(&[0; 14]));
+    // a field inside a short buffer still reads
+    assert_eq!(Probe.get_word(&[0, 0, 0xBE, 0xEF]), 0xBEEF);
+}
+
+#[test]
+#[should_panic(expected = "Probe.mac needs 15 bytes, buffer has 14")]
+fn test_accessor_past_the_end_panics() {
+    Probe.get_mac(&[0; 14]);
+}
+
+#[test]
+fn test_fields_by_name() {
+    let layout: Vec<(&str, usize, usize, bool)> = Probe::FIELDS.iter()
+        .map(|field| (field.name, field.offset, field.width, field.bytes))
+        .collect();
+    assert_eq!(layout, vec!(("flag", 0, 1, false), ("nibble", 1, 3, false), ("odd", 4, 12, false),
+                            ("word", 16, 16, false), ("wide", 32, 40, false),
+                            ("mac", 72, 48, true)));
+
+    let mut buff = [0u8; 15];
+    let odd = Probe::FIELDS[2];
+    let mac = Probe::FIELDS[5];
+    odd.set(&mut buff, &Value::Int(0xABC)).unwrap();
+    mac.set(&mut buff, &Value::Bytes(vec!(1, 2, 3, 4, 5, 6))).unwrap();
+    assert_eq!(Probe.get_odd(&buff), 0xABC);
+    assert_eq!(odd.get(&buff), Value::Int(0xABC));
+    assert_eq!(mac.get(&buff).to_string(), "0x010203040506");
+
+    let err = odd.set(&mut buff, &Value::Int(0x1000)).unwrap_err();
+    assert_eq!(err.to_string(), "Probe.odd is 12 bits, 4096 doesn't fit");
+    let err = mac.set(&mut buff, &Value::Bytes(vec!(1, 2))).unwrap_err();
+    assert_eq!(err.to_string(), "Probe.mac takes 6 bytes, not 0x0102");
+    let err = mac.set(&mut buff, &Value::Int(1)).unwrap_err();
+    assert_eq!(err.value, Value::Int(1));
+    // failed writes leave the buffer as it was
+    assert_eq!(Probe.get_odd(&buff), 0xABC);
+}
+
+#[test]
+fn test_print_fields_in_order() {
+    let mut buff = [0u8; 15];
+    Probe.set_word(&mut buff, 7);
+    Probe.set_mac(&mut buff, [1, 2, 3, 4, 5, 6]);
+    Probe.print_fields(&buff);
+    let printed = PRINTED.with(|printed| printed.borrow().join(" "));
+    assert_eq!(printed, "flag=0 nibble=0 odd=0 word=7 wide=0 mac=[1, 2, 3, 4, 5, 6]");
+}
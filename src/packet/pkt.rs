@@ -2,9 +2,23 @@ use super::eth;
 use super::ipv4;
 use super::ipv6;
 use super::tcp;
+use super::udp;
+use super::icmpv4;
+use super::icmpv6;
+use super::arp;
+use super::view;
+use super::netbits;
 use super::super::util;
 
-pub const MTU_SIZE: usize = 1500;
+use std::error;
+use std::fmt;
+
+// mtu bounds: what ipv4 and ipv6 demand at the least, and the most an ip
+// length field can describe
+pub const DEFAULT_MTU: usize = 1500;
+pub const MIN_MTU: usize = 68;
+pub const MIN_IPV6_MTU: usize = 1280;
+pub const MAX_MTU: usize = 65535;
 
 // packets
 pub struct Packet {
@@ -12,85 +26,328 @@ pub struct Packet {
     pub len: usize,
     pub link: Link,
     pub net: Network,
-//    trans: T
+    pub trans: Option<Transport>,
+    pub diagnostic: Option<Diagnostic>
 }
 
 
 impl Packet {
-    fn new(data: Vec<u8>, len: usize, link: Link, net: Network) -> Packet {
+    fn new(data: Vec<u8>, link: Link, dissected: Dissection) -> Packet {
         Packet {
             data:  data, // the actual packet data
-            len:   len, // length of the packet, as reported by backend
+            len:   dissected.len, // length of the packet, padding stripped
             link:  link, // link layer type and embedded offset
-            net:   net  // network layer type and embedded offset
-//            trans: trans // link layer type and embedded offset
+            net:   dissected.net, // network layer type and embedded offset
+            trans: dissected.trans, // transport layer, if we know it
+            diagnostic: dissected.diagnostic // what didn't add up
         }
     }
 
+    // a borrowed view on the same bytes, bounded by the packet length
+    pub fn view(&self) -> view::PacketView<'_> {
+        view::PacketView::from_parts(&self.data[..self.len], self.link, Dissection {
+            len: self.len,
+            net: self.net,
+            trans: self.trans,
+            diagnostic: self.diagnostic
+        })
+    }
+
+    // by name, as in "ipv4.ttl"
+    pub fn get_field(&self, path: &str) -> Result<netbits::Value, view::FieldError> {
+        self.view().get_field(path)
+    }
+
     pub fn print(&self) {
-        match self.link {
-            Link::EthLink(ref eth) => eth.print(&self.data[eth.offset..])
-        }
-        println!("");
-        match self.net {
-            Network::Ipv4Net(ref net) => net.print(&self.data[net.offset..]),
-            Network::Ipv6Net(ref net) => net.print(&self.data[net.offset..])
-        }
+        self.view().print()
     }
 }
 
-pub fn make_eth_packet(data: Vec<u8>, len:usize) -> Packet {
+pub fn make_eth_packet(data: Vec<u8>, len:usize) -> Result<Packet, DissectError> {
     make_packet(data, Link::EthLink(eth::Eth{offset: 0}), len)
 }
 
-fn make_packet(data: Vec<u8>, link: Link, len: usize) -> Packet {
-    let network = get_network_from_data(&data[..], &link);
-    // let transport = get_transport_from_data(&data[..], network);
-    Packet::new(data, len, link, network)
+// tun devices hand us bare ip packets
+pub fn make_ip_packet(data: Vec<u8>, len: usize) -> Result<Packet, DissectError> {
+    make_packet(data, Link::RawLink, len)
+}
+
+// `len` is what the backend read, the rest of `data` is just buffer
+fn make_packet(data: Vec<u8>, link: Link, len: usize) -> Result<Packet, DissectError> {
+    let dissected = dissect(&data[..len], &link)?;
+    Ok(Packet::new(data, link, dissected))
+}
+
+// dissection
+//
+// `len` is how much of the frame belongs to the packet: the received
+// length, minus ethernet padding or anything else trailing the network
+// layer packet. The transport layer is only looked for in that part.
+// Working out `len` notes at most one thing that didn't add up.
+#[derive(Clone, Copy)]
+pub struct Dissection {
+    pub len: usize,
+    pub net: Network,
+    pub trans: Option<Transport>,
+    pub diagnostic: Option<Diagnostic>
+}
+
+pub fn dissect(data: &[u8], link: &Link) -> Result<Dissection, DissectError> {
+    let net = get_network_from_data(data, link)?;
+    let (len, diagnostic) = get_frame_len(data, link, &net);
+    let trans = get_transport_from_data(&data[..len], &net)?;
+    Ok(Dissection { len: len, net: net, trans: trans, diagnostic: diagnostic })
+}
+
+// ethernet frames shorter than this get padded, fcs not included
+pub const ETH_MIN_LEN: usize = 60;
+
+// things that don't add up, but still leave a packet we can dissect
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Diagnostic {
+    // bytes of ethernet padding after the network layer packet, stripped
+    EthPadding(usize),
+    // the network layer header says the packet is `declared` bytes, the
+    // link gave us `actual`. Anything past `declared` is stripped.
+    LengthMismatch { layer: &'static str, declared: usize, actual: usize }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Diagnostic::EthPadding(len) =>
+                write!(f, "{} bytes of ethernet padding", len),
+            Diagnostic::LengthMismatch { layer, declared, actual } if declared > actual =>
+                write!(f, "{} length is {}, only {} bytes received", layer, declared, actual),
+            Diagnostic::LengthMismatch { layer, declared, actual } =>
+                write!(f, "{} length is {}, {} trailing bytes", layer, declared, actual - declared)
+        }
+    }
+}
+
+// Where the network layer packet ends, going by its own length field. A
+// header that lies about its length doesn't get trimmed below itself.
+pub fn get_frame_len(data: &[u8], link: &Link, net: &Network) -> (usize, Option<Diagnostic>) {
+    let net_data = &data[net.offset()..];
+    let actual = net_data.len();
+    let declared = match net.declared_len(net_data) {
+        Some(declared) => declared,
+        None => return (data.len(), None)
+    };
+    let layer = net.name();
+
+    if declared > actual {
+        return (data.len(), Some(Diagnostic::LengthMismatch {
+            layer: layer, declared: declared, actual: actual
+        }))
+    }
+    if declared == actual {
+        return (data.len(), None)
+    }
+
+    let padded = match *link {
+        Link::EthLink(_) => data.len() <= ETH_MIN_LEN,
+        Link::RawLink => false
+    };
+    let diagnostic = if padded {
+        Diagnostic::EthPadding(actual - declared)
+    } else {
+        Diagnostic::LengthMismatch { layer: layer, declared: declared, actual: actual }
+    };
+    // header_len can't fail, get_network_from_data checked it
+    let header_len = net.header_len(net_data).unwrap_or(0);
+    (net.offset() + declared.max(header_len), Some(diagnostic))
+}
+
+// truncation
+//
+// Dissection checks every header against the bytes we actually have,
+// including the variable parts (ip options, ipv6 extension headers, tcp
+// options), before anything reads from it. A frame that's cut short is
+// reported instead of panicking halfway through a getter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Truncated {
+    pub layer: &'static str, // the header that didn't fit
+    pub needed: usize, // bytes it needs, counted from its start
+    pub len: usize // bytes there are from its start
 }
 
-fn get_network_from_data(data: &[u8], link: &Link) -> Network {
-    match link {
-        &Link::EthLink(ref eth) => eth.get_network(&data[eth.offset..])
+impl fmt::Display for Truncated {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "truncated {} header: needs {} bytes, got {}",
+               self.layer, self.needed, self.len)
     }
 }
 
-fn get_transport_from_data(data: &[u8], net: &Network) -> Transport {
-    match net {
-        &Network::Ipv4Net(ref net) => net.get_transport(data),
-        &Network::Ipv6Net(ref net) => net.get_transport(data),
+impl error::Error for Truncated {}
+
+// what stops a frame from being dissected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DissectError {
+    Truncated(Truncated),
+    // a header length field that doesn't cover the header's fixed part
+    HeaderLen { layer: &'static str, len: usize },
+    UnknownEthertype(u16),
+    UnknownIpVersion(u8)
+}
+
+impl fmt::Display for DissectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DissectError::Truncated(ref e)          => write!(f, "{}", e),
+            DissectError::HeaderLen { layer, len }  => write!(f, "{} header length of {} is too short", layer, len),
+            DissectError::UnknownEthertype(kind)    => write!(f, "unknown ethertype 0x{:04x}", kind),
+            DissectError::UnknownIpVersion(version) => write!(f, "unknown ip version {}", version)
+        }
+    }
+}
+
+impl error::Error for DissectError {}
+
+impl From<Truncated> for DissectError {
+    fn from(e: Truncated) -> DissectError {
+        DissectError::Truncated(e)
     }
 }
 
+// `buff` starts at the header
+pub fn check_len(layer: &'static str, buff: &[u8], needed: usize) -> Result<(), Truncated> {
+    if buff.len() < needed {
+        return Err(Truncated { layer: layer, needed: needed, len: buff.len() })
+    }
+    Ok(())
+}
+
+pub fn get_network_from_data(data: &[u8], link: &Link) -> Result<Network, DissectError> {
+    let net = match *link {
+        Link::EthLink(ref eth) => eth.get_network(&data[eth.offset..])?,
+        Link::RawLink => {
+            check_len("ip", data, 1)?;
+            match data[0] >> 4 {
+                4       => Network::Ipv4Net(ipv4::Ipv4 { offset: 0 }),
+                6       => Network::Ipv6Net(ipv6::Ipv6 { offset: 0 }),
+                version => return Err(DissectError::UnknownIpVersion(version))
+            }
+        }
+    };
+    net.header_len(&data[net.offset()..])?;
+    Ok(net)
+}
+
+// the getters expect the buffer to start at the header they're reading
+pub fn get_transport_from_data(data: &[u8], net: &Network)
+                               -> Result<Option<Transport>, DissectError> {
+    let trans = match *net {
+        Network::Ipv4Net(ref net) => net.get_transport(&data[net.offset..])?,
+        Network::Ipv6Net(ref net) => net.get_transport(&data[net.offset..])?,
+        Network::ArpNet(ref net)  => net.get_transport(&data[net.offset..])?,
+    };
+    if let Some(ref trans) = trans {
+        trans.header_len(&data[trans.offset()..])?;
+    }
+    Ok(trans)
+}
+
 // link layer
+#[derive(Debug, Clone, Copy)]
 pub enum Link {
-    EthLink(eth::Eth)
+    EthLink(eth::Eth),
+    RawLink // no link layer header, as on a tun device
 }
 
+// The buffers passed in start at the header, `get_*` checks the header
+// fits before reading it. `get_payload_offset` expects a header that has
+// been checked.
 pub trait HasLinkLayer {
-    fn get_network(&self, data: &[u8]) -> Network;
+    fn header_len(&self, data: &[u8]) -> Result<usize, Truncated>;
+    fn get_network(&self, data: &[u8]) -> Result<Network, DissectError>;
     fn get_payload_offset(&self, data: &[u8]) -> usize;
     fn print(&self, data: &[u8]);
 }
 
 // network layer
+#[derive(Debug, Clone, Copy)]
 pub enum Network {
     Ipv4Net(ipv4::Ipv4),
-    Ipv6Net(ipv6::Ipv6)
+    Ipv6Net(ipv6::Ipv6),
+    ArpNet(arp::Arp)
+}
+
+impl Network {
+    pub fn offset(&self) -> usize {
+        match *self {
+            Network::Ipv4Net(ref net) => net.offset,
+            Network::Ipv6Net(ref net) => net.offset,
+            Network::ArpNet(ref net)  => net.offset
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Network::Ipv4Net(_) => "ipv4",
+            Network::Ipv6Net(_) => "ipv6",
+            Network::ArpNet(_)  => "arp"
+        }
+    }
+
+    pub fn header_len(&self, data: &[u8]) -> Result<usize, DissectError> {
+        match *self {
+            Network::Ipv4Net(ref net) => net.header_len(data),
+            Network::Ipv6Net(ref net) => net.header_len(data),
+            Network::ArpNet(ref net)  => net.header_len(data)
+        }
+    }
+
+    pub fn declared_len(&self, data: &[u8]) -> Option<usize> {
+        match *self {
+            Network::Ipv4Net(ref net) => net.declared_len(data),
+            Network::Ipv6Net(ref net) => net.declared_len(data),
+            Network::ArpNet(ref net)  => net.declared_len(data)
+        }
+    }
 }
 
+// `get_transport` is None for arp, and for protocols we don't dissect.
+// `declared_len` is the length of the whole packet according to its
+// header, if it says.
 pub trait HasNetworkLayer {
-    fn get_transport(&self, data: &[u8]) -> Transport;
+    fn header_len(&self, data: &[u8]) -> Result<usize, DissectError>;
+    fn declared_len(&self, data: &[u8]) -> Option<usize>;
+    fn get_transport(&self, data: &[u8]) -> Result<Option<Transport>, DissectError>;
     fn get_payload_offset(&self, data: &[u8]) -> usize;
     fn print(&self, data: &[u8]);
 }
 
 // transport layer
+#[derive(Debug, Clone, Copy)]
 pub enum Transport {
-    TcpTrans(tcp::Tcp)
+    TcpTrans(tcp::Tcp),
+    UdpTrans(udp::Udp),
+    Icmpv4Trans(icmpv4::Icmpv4),
+    Icmpv6Trans(icmpv6::Icmpv6)
 }
 
-// net-bits packet generic write fns
+impl Transport {
+    pub fn offset(&self) -> usize {
+        match *self {
+            Transport::TcpTrans(ref trans)    => trans.offset,
+            Transport::UdpTrans(ref trans)    => trans.offset,
+            Transport::Icmpv4Trans(ref trans) => trans.offset,
+            Transport::Icmpv6Trans(ref trans) => trans.offset
+        }
+    }
+
+    pub fn header_len(&self, data: &[u8]) -> Result<usize, Truncated> {
+        match *self {
+            Transport::TcpTrans(ref trans)    => trans.header_len(data),
+            Transport::UdpTrans(ref trans)    => trans.header_len(data),
+            Transport::Icmpv4Trans(ref trans) => trans.header_len(data),
+            Transport::Icmpv6Trans(ref trans) => trans.header_len(data)
+        }
+    }
+}
+
+// netbits! generic field printers
 pub fn write_imm(name: &str, val: u64) {
     let hex = format!("0x{:X}", val);
     println!("  {: <15}: {: >7}, {: >9}", name, val, hex);
@@ -104,7 +361,7 @@ pub fn write_arr(name: &str, val: &[u8]) {
 
 // testing
 #[test]
-fn test_icmpv6_mldv2_packet() -> () {
+fn test_icmpv6_mldv2_packet() {
 
     let icmp6_packet = vec!(
         // eth
@@ -163,4 +420,160 @@ fn test_icmpv6_mldv2_packet() -> () {
         0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
         0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
         0x01, 0x01, 0xC6, 0xEE, 0x04, 0xA6, 0x0F, 0x6A);
+
+    // the report sits behind a hop-by-hop header, the solicitation doesn't
+    for &(packet, offset, icmp_type) in &[(&icmp6_packet, 62, 0x8F),
+                                          (&icmp6_echo_packet, 54, 0x87)] {
+        let view = view::PacketView::eth(&packet[..]).unwrap();
+        match view.transport() {
+            Some(Transport::Icmpv6Trans(icmp)) => {
+                assert_eq!(icmp.offset, offset);
+                assert_eq!(icmp.get_icmp_type(&packet[offset..]), icmp_type);
+            },
+            _ => panic!("expected icmpv6")
+        }
+    }
+
+    // router alert for MLD and two bytes of padding
+    let ip = ipv6::Ipv6 { offset: 0 };
+    let buff = &icmp6_packet[14..];
+    let hbh = ip.ext_headers(buff).next().unwrap().unwrap();
+    let opts: Vec<_> = ip.options(buff, &hbh).map(|opt| opt.unwrap()).collect();
+    assert_eq!(opts, vec!(ipv6::Ipv6Option::RouterAlert(0), ipv6::Ipv6Option::PadN(0)));
+    assert_eq!(ip.check_options(buff), Ok(()));
+
+    // an unknown option with the discard and icmp bits set
+    let mut unknown = buff.to_vec();
+    unknown[42] = 0x85;
+    assert_eq!(ip.check_options(&unknown), Err(ipv6::BadOption {
+        action: ipv6::OptAction::DiscardIcmp, code: 2, pointer: 42
+    }));
+}
+
+#[test]
+fn test_truncated_headers() {
+    let mut frame = vec!(
+        // eth
+        0x02, 0x00, 0x00, 0x00, 0x00, 0x02,
+        0x02, 0x00, 0x00, 0x00, 0x00, 0x01,
+        0x08, 0x00,
+
+        // ipv4, ihl 6: one word of options
+        0x46, 0x00, 0x00, 0x30,
+        0x00, 0x00, 0x40, 0x00,
+        0x40, 0x06, 0x00, 0x00,
+        0x0A, 0x00, 0x00, 0x02,
+        0x0A, 0x00, 0x00, 0x01,
+        0x01, 0x01, 0x01, 0x00,
+
+        // tcp, data offset 6: one word of options
+        0x30, 0x39, 0x00, 0x50,
+        0x00, 0x00, 0x00, 0x01,
+        0x00, 0x00, 0x00, 0x00,
+        0x60, 0x02, 0xFF, 0xFF,
+        0x00, 0x00, 0x00, 0x00,
+        0x02, 0x04, 0x05, 0xB4);
+
+    let view = view::PacketView::eth(&frame[..]).unwrap();
+    assert_eq!(view.tcp().unwrap().get(tcp::Tcp::get_dst_port), 80);
+
+    let truncated = |layer, needed, len| Err(DissectError::Truncated(Truncated { layer: layer, needed: needed, len: len }));
+    let cut = |at: usize| view::PacketView::eth(&frame[..at]).map(|_| ());
+
+    assert_eq!(cut(58), truncated("tcp", 24, 20));
+    assert_eq!(cut(40), truncated("tcp", 20, 2));
+    assert_eq!(cut(36), truncated("ipv4", 24, 22));
+    assert_eq!(cut(20), truncated("ipv4", 20, 6));
+    assert_eq!(cut(10), truncated("eth", 14, 10));
+
+    // a tun device has no eth header to get the version from
+    assert_eq!(view::PacketView::ip(&frame[..0]).map(|_| ()), truncated("ip", 1, 0));
+
+    // an ihl that points past the end of the frame
+    frame[14] = 0x4F;
+    assert_eq!(view::PacketView::eth(&frame[..]).map(|_| ()), truncated("ipv4", 60, 48));
+
+    // and one too short for the fixed part
+    frame[14] = 0x43;
+    let err = view::PacketView::eth(&frame[..]).map(|_| ()).unwrap_err();
+    assert_eq!(err, DissectError::HeaderLen { layer: "ipv4", len: 12 });
+    assert_eq!(err.to_string(), "ipv4 header length of 12 is too short");
+}
+
+#[test]
+fn test_unknown_network() {
+    // lldp
+    let frame = vec!(
+        0x01, 0x80, 0xC2, 0x00, 0x00, 0x0E,
+        0x02, 0x00, 0x00, 0x00, 0x00, 0x01,
+        0x88, 0xCC,
+        0x02, 0x07, 0x04, 0x02, 0x00, 0x00, 0x00, 0x00, 0x01);
+    let err = view::PacketView::eth(&frame[..]).map(|_| ()).unwrap_err();
+    assert_eq!(err, DissectError::UnknownEthertype(0x88CC));
+    assert_eq!(err.to_string(), "unknown ethertype 0x88cc");
+    assert_eq!(make_eth_packet(frame.clone(), frame.len()).map(|_| ()), Err(err));
+
+    // an ipv4 header with version 5, on a tun
+    let mut ip = vec!(
+        0x55, 0x00, 0x00, 0x14,
+        0x00, 0x00, 0x40, 0x00,
+        0x40, 0x11, 0x00, 0x00,
+        0x0A, 0x00, 0x00, 0x02,
+        0x0A, 0x00, 0x00, 0x01);
+    let err = view::PacketView::ip(&ip[..]).map(|_| ()).unwrap_err();
+    assert_eq!(err, DissectError::UnknownIpVersion(5));
+    assert_eq!(err.to_string(), "unknown ip version 5");
+    assert_eq!(view::PacketViewMut::ip(&mut ip[..]).map(|_| ()), Err(err));
+}
+
+#[test]
+fn test_frame_len_and_padding() {
+    // an arp request, padded out to the ethernet minimum
+    let mut frame = vec!(
+        0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+        0x02, 0x00, 0x00, 0x00, 0x00, 0x01,
+        0x08, 0x06,
+
+        0x00, 0x01, 0x08, 0x00,
+        0x06, 0x04, 0x00, 0x01,
+        0x02, 0x00, 0x00, 0x00, 0x00, 0x01,
+        0x0A, 0x00, 0x00, 0x02,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x0A, 0x00, 0x00, 0x01);
+    frame.resize(ETH_MIN_LEN, 0);
+
+    let packet = make_eth_packet(frame.clone(), frame.len()).unwrap();
+    assert_eq!(packet.len, 42);
+    assert_eq!(packet.diagnostic, Some(Diagnostic::EthPadding(18)));
+    assert_eq!(packet.view().len(), 42);
+
+    // udp over ipv4 on a tun, with the ip length off either way
+    let mut ip = vec!(
+        0x45, 0x00, 0x00, 0x1D,
+        0x00, 0x00, 0x40, 0x00,
+        0x40, 0x11, 0x00, 0x00,
+        0x0A, 0x00, 0x00, 0x02,
+        0x0A, 0x00, 0x00, 0x01,
+
+        0x30, 0x39, 0x00, 0x35,
+        0x00, 0x09, 0x00, 0x00,
+        0x2A);
+    let view = view::PacketView::ip(&ip[..]).unwrap();
+    assert_eq!(view.diagnostic, None);
+    assert_eq!(view.len(), 29);
+
+    ip.extend_from_slice(&[0xDE, 0xAD]);
+    let view = view::PacketView::ip(&ip[..]).unwrap();
+    assert_eq!(view.diagnostic, Some(Diagnostic::LengthMismatch {
+        layer: "ipv4", declared: 29, actual: 31
+    }));
+    assert_eq!(view.len(), 29);
+
+    ip[3] = 0x40;
+    let view = view::PacketView::ip(&ip[..]).unwrap();
+    assert_eq!(view.diagnostic, Some(Diagnostic::LengthMismatch {
+        layer: "ipv4", declared: 64, actual: 31
+    }));
+    assert_eq!(view.len(), 31);
+    assert!(view.udp().is_some());
 }
@@ -0,0 +1,82 @@
+// receive buffers
+//
+// Hands out fixed size buffers and takes them back when a packet is done,
+// so the receive loop doesn't allocate per frame.
+pub struct BufferPool {
+    free: Vec<Vec<u8>>,
+    buff_size: usize,
+    max_free: usize
+}
+
+impl BufferPool {
+    // keeps at most `max_free` buffers around, anything returned beyond
+    // that is freed
+    pub fn new(buff_size: usize, max_free: usize) -> BufferPool {
+        BufferPool {
+            free: Vec::with_capacity(max_free),
+            buff_size: buff_size,
+            max_free: max_free
+        }
+    }
+
+    pub fn buff_size(&self) -> usize {
+        self.buff_size
+    }
+
+    // a buffer of `buff_size` bytes, contents unspecified
+    pub fn get(&mut self) -> Vec<u8> {
+        match self.free.pop() {
+            Some(buff) => buff,
+            None       => vec![0u8; self.buff_size]
+        }
+    }
+
+    pub fn put(&mut self, mut buff: Vec<u8>) {
+        if self.free.len() >= self.max_free {
+            return
+        }
+        // somebody may have truncated or grown it
+        buff.resize(self.buff_size, 0);
+        self.free.push(buff);
+    }
+}
+
+
+// testing
+#[test]
+fn test_get_reuses_returned_buffers() {
+    let mut pool = BufferPool::new(64, 2);
+    let buff = pool.get();
+    assert_eq!(buff.len(), pool.buff_size());
+
+    let ptr = buff.as_ptr();
+    pool.put(buff);
+    let again = pool.get();
+    assert_eq!(again.as_ptr(), ptr);
+}
+
+#[test]
+fn test_put_restores_the_size() {
+    let mut pool = BufferPool::new(64, 2);
+    let mut short = pool.get();
+    short.truncate(10);
+    let mut long = pool.get();
+    long.extend_from_slice(&[1; 100]);
+    pool.put(short);
+    pool.put(long);
+    assert_eq!((pool.get().len(), pool.get().len()), (64, 64));
+}
+
+#[test]
+fn test_empty_pool_allocates_and_full_pool_frees() {
+    let mut pool = BufferPool::new(64, 2);
+    // running dry just means allocating
+    let buffs: Vec<Vec<u8>> = (0..5).map(|_| pool.get()).collect();
+    assert!(buffs.iter().all(|buff| buff.len() == 64));
+
+    // only `max_free` of them are kept
+    for buff in buffs {
+        pool.put(buff);
+    }
+    assert_eq!(pool.free.len(), 2);
+}
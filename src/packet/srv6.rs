@@ -0,0 +1,271 @@
+use std::error;
+use std::fmt;
+use std::net::Ipv6Addr;
+
+use super::icmpv6;
+use super::ipv6::{Ipv6, Ipv6Routing, Tlvs, header_types, routing_types};
+use super::pkt;
+use super::pkt::{write_imm, write_arr};
+
+// segment routing
+//
+// An SRv6 endpoint (RFC 8754, 4.3) gets packets addressed to it that carry
+// a list of segments still to visit. Segments left counts down through the
+// list, which is stored last segment first; each endpoint takes the next
+// segment, makes it the destination and sends the packet on. The upper
+// layer checksums were computed by the source against the final segment,
+// so nothing else changes on the way.
+
+// Segment Routing Header
+// RFC 8754, 2
+//
+//  0                   1                   2                   3
+//  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// | Next Header   |  Hdr Ext Len  | Routing Type  | Segments Left |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |  Last Entry   |     Flags     |              Tag              |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |                                                               |
+// |            Segment List[0] (128-bit IPv6 address)             |
+// |                                                               |
+// |                                                               |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// //                                                             //
+// //                                                             //
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |                                                               |
+// |            Segment List[n] (128-bit IPv6 address)             |
+// |                                                               |
+// |                                                               |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// //                                                             //
+// //         Optional Type Length Value objects (variable)       //
+// //                                                             //
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+#[derive(Debug, Clone, Copy)]
+pub struct Srh {
+    pub offset: usize
+}
+
+netbits!{
+    Srh, write_imm, write_arr,
+    nxt_header:     8,
+    hdr_ext_len:    8,
+    routing_type:   8,
+    segments_left:  8,
+    last_entry:     8,
+    flags:          8,
+    tag:           16
+}
+
+pub mod tlv_types {
+    pub const PAD1: u8 = 0;
+    pub const PADN: u8 = 4;
+    pub const HMAC: u8 = 5;
+}
+
+pub const SEGMENT_LEN: usize = 16;
+
+impl Srh {
+    // the whole header, segments and TLVs included
+    pub fn header_len(&self, buff: &[u8]) -> usize {
+        (self.get_hdr_ext_len(buff) as usize + 1) * 8
+    }
+
+    pub fn segment_count(&self, buff: &[u8]) -> usize {
+        self.get_last_entry(buff) as usize + 1
+    }
+
+    // The list has to fit in the header (RFC 8754, 4.3.1.1); the rest of
+    // the header is TLVs.
+    pub fn is_valid(&self, buff: &[u8]) -> bool {
+        let end = Srh::HEADER_LEN + self.segment_count(buff) * SEGMENT_LEN;
+        end <= self.header_len(buff) && self.header_len(buff) <= buff.len()
+            && self.get_segments_left(buff) as usize <= self.segment_count(buff)
+    }
+
+    pub fn get_segment(&self, buff: &[u8], idx: usize) -> Option<Ipv6Addr> {
+        let start = Srh::HEADER_LEN + idx * SEGMENT_LEN;
+        if idx >= self.segment_count(buff) || start + SEGMENT_LEN > buff.len() {
+            return None
+        }
+        let mut addr = [0u8; SEGMENT_LEN];
+        addr.copy_from_slice(&buff[start..start + SEGMENT_LEN]);
+        Some(Ipv6Addr::from(addr))
+    }
+
+    // the segments in list order, the final destination first
+    pub fn segments(&self, buff: &[u8]) -> Vec<Ipv6Addr> {
+        (0..self.segment_count(buff))
+            .map_while(|idx| self.get_segment(buff, idx))
+            .collect()
+    }
+
+    // `buff` has to be valid
+    pub fn tlvs<'a>(&self, buff: &'a [u8]) -> Tlvs<'a> {
+        let start = Srh::HEADER_LEN + self.segment_count(buff) * SEGMENT_LEN;
+        Tlvs::new(&buff[start..self.header_len(buff)])
+    }
+
+    pub fn print(&self, buff: &[u8]) {
+        self.print_fields(buff);
+        if !self.is_valid(buff) {
+            println!("  (malformed segment list)");
+            return
+        }
+        for segment in self.segments(buff) {
+            println!("  {: <15}: {: >40}", "segment", segment);
+        }
+        for tlv in self.tlvs(buff) {
+            match tlv {
+                Ok(tlv) if tlv.kind == tlv_types::PAD1 || tlv.kind == tlv_types::PADN => (),
+                Ok(tlv) => write_arr(&format!("tlv {}", tlv.kind), tlv.data),
+                Err(e) => println!("  ({})", e)
+            }
+        }
+    }
+}
+
+// what to do with a packet after its routing header has been looked at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endpoint {
+    // no segments left, the packet is for us
+    Local,
+    // the destination has been moved to the next segment, send it on
+    Forward(Ipv6Addr)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SrError {
+    // out of hops before reaching the next segment
+    HopLimit,
+    // a bad header, with the offset of the field at fault from the start
+    // of the ipv6 header
+    Parameter(usize),
+    // the next segment is a multicast address
+    Multicast,
+    Truncated(pkt::Truncated)
+}
+
+impl SrError {
+    // the icmpv6 error to send the source, as (type, code, rest of header)
+    pub fn icmp(&self) -> Option<(u8, u8, u32)> {
+        match *self {
+            SrError::HopLimit         => Some((icmpv6::TIME_EXCEEDED, 0, 0)),
+            SrError::Parameter(field) => Some((icmpv6::PARAM_PROBLEM, 0, field as u32)),
+            SrError::Multicast        => None,
+            SrError::Truncated(_)     => None
+        }
+    }
+}
+
+impl fmt::Display for SrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SrError::HopLimit         => f.write_str("hop limit exceeded in transit"),
+            SrError::Parameter(field) => write!(f, "bad routing header field at offset {}", field),
+            SrError::Multicast        => f.write_str("next segment is multicast"),
+            SrError::Truncated(ref e) => write!(f, "{}", e)
+        }
+    }
+}
+
+impl error::Error for SrError {}
+
+impl From<pkt::Truncated> for SrError {
+    fn from(e: pkt::Truncated) -> SrError {
+        SrError::Truncated(e)
+    }
+}
+
+// Processes the routing header of a packet addressed to us, `packet`
+// starting at the ipv6 header. A routing header with no segments left is
+// ignored whatever its type; one we don't know is a parameter problem
+// pointing at its type (RFC 8200, 4.4). That includes type 0.
+pub fn process_routing(packet: &mut [u8]) -> Result<Endpoint, SrError> {
+    pkt::check_len("ipv6", packet, Ipv6::HEADER_LEN)?;
+    let ip = Ipv6 { offset: 0 };
+    let mut routing = None;
+    for header in ip.ext_headers(packet) {
+        let header = header?;
+        if header.kind == header_types::ROUTING {
+            routing = Some(header);
+            break
+        }
+    }
+    let header = match routing {
+        Some(header) => header,
+        None => return Ok(Endpoint::Local)
+    };
+
+    let rh = Ipv6Routing { offset: header.offset };
+    let buff = &packet[header.offset..header.offset + header.len];
+    if rh.get_segments_left(buff) == 0 {
+        return Ok(Endpoint::Local)
+    }
+    if rh.get_routing_type(buff) != routing_types::SEGMENT {
+        return Err(SrError::Parameter(header.offset + 2))
+    }
+    let srh = Srh { offset: header.offset };
+    pkt::check_len("ipv6", buff, Srh::HEADER_LEN)?;
+    if !srh.is_valid(buff) {
+        return Err(SrError::Parameter(header.offset + 3))
+    }
+    if ip.get_hop_limit(packet) <= 1 {
+        return Err(SrError::HopLimit)
+    }
+
+    let segments_left = srh.get_segments_left(buff) - 1;
+    let next = match srh.get_segment(buff, segments_left as usize) {
+        Some(next) => next,
+        None => return Err(SrError::Parameter(header.offset + 3))
+    };
+    if next.is_multicast() {
+        return Err(SrError::Multicast)
+    }
+
+    srh.set_segments_left(&mut packet[header.offset..], segments_left);
+    ip.set_dst(packet, next.octets());
+    let hop_limit = ip.get_hop_limit(packet) - 1;
+    ip.set_hop_limit(packet, hop_limit);
+    Ok(Endpoint::Forward(next))
+}
+
+
+// testing
+#[test]
+fn test_srv6_endpoint() {
+    use super::builder::{Ipv6Builder, PROTO_UDP};
+
+    let segments: Vec<Ipv6Addr> = vec!("fd00::30".parse().unwrap(),
+                                       "fd00::20".parse().unwrap(),
+                                       "fd00::10".parse().unwrap());
+    // next header udp, 3 segments, 2 left, an 8 byte PadN at the end
+    let mut srh = vec!(PROTO_UDP, 7, routing_types::SEGMENT, 2, 2, 0, 0, 0);
+    for segment in &segments {
+        srh.extend_from_slice(&segment.octets());
+    }
+    srh.extend_from_slice(&[tlv_types::PADN, 6, 0, 0, 0, 0, 0, 0]);
+    srh.extend_from_slice(&[0; 8]);
+
+    let mut packet = Ipv6Builder::new("fd00::1".parse().unwrap(), segments[2])
+        .build(header_types::ROUTING, &srh).data;
+    let srh = Srh { offset: Ipv6::HEADER_LEN };
+    assert!(srh.is_valid(&packet[Ipv6::HEADER_LEN..]));
+    assert_eq!(srh.segments(&packet[Ipv6::HEADER_LEN..]), segments);
+    assert_eq!(srh.tlvs(&packet[Ipv6::HEADER_LEN..]).count(), 1);
+
+    let ip = Ipv6 { offset: 0 };
+    assert_eq!(process_routing(&mut packet), Ok(Endpoint::Forward(segments[1])));
+    assert_eq!(process_routing(&mut packet), Ok(Endpoint::Forward(segments[0])));
+    assert_eq!(ip.get_dst(&packet), segments[0].octets());
+    assert_eq!(ip.get_hop_limit(&packet), 62);
+    assert_eq!(process_routing(&mut packet), Ok(Endpoint::Local));
+
+    // more segments left than there are in the list
+    packet[Ipv6::HEADER_LEN + 3] = 4;
+    assert_eq!(process_routing(&mut packet), Err(SrError::Parameter(Ipv6::HEADER_LEN + 3)));
+    packet[Ipv6::HEADER_LEN + 2] = routing_types::SOURCE_ROUTE;
+    assert_eq!(process_routing(&mut packet), Err(SrError::Parameter(Ipv6::HEADER_LEN + 2)));
+}
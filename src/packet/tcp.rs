@@ -1,3 +1,4 @@
+use super::pkt;
 use super::pkt::{write_imm, write_arr};
 
 // TCP
@@ -23,6 +24,7 @@ use super::pkt::{write_imm, write_arr};
 // +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
 
 
+#[derive(Debug, Clone, Copy)]
 pub struct Tcp {
     pub offset: usize
 }
@@ -45,3 +47,17 @@ netbits!{
     chk:         16,
     urg_ptr:     16,
 }
+
+impl Tcp {
+    // options included, data_offset counts 32 bit words
+    pub fn header_len(&self, buff: &[u8]) -> Result<usize, pkt::Truncated> {
+        pkt::check_len("tcp", buff, Tcp::HEADER_LEN)?;
+        let len = (self.get_data_offset(buff) as usize * 4).max(Tcp::HEADER_LEN);
+        pkt::check_len("tcp", buff, len)?;
+        Ok(len)
+    }
+
+    pub fn get_payload_offset(&self, buff: &[u8]) -> usize {
+        self.offset + (self.get_data_offset(buff) as usize * 4).max(Tcp::HEADER_LEN)
+    }
+}
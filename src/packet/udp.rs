@@ -1,3 +1,41 @@
-struct Udp {
-    offset: usize
+use super::pkt;
+use super::pkt::{write_imm, write_arr};
+
+// UDP
+// RFC 768
+//
+//  0      7 8     15 16    23 24    31
+// +--------+--------+--------+--------+
+// |     Source      |   Destination   |
+// |      Port       |      Port       |
+// +--------+--------+--------+--------+
+// |                 |                 |
+// |     Length      |    Checksum     |
+// +--------+--------+--------+--------+
+// |
+// |          data octets ...
+// +---------------- ...
+
+#[derive(Debug, Clone, Copy)]
+pub struct Udp {
+    pub offset: usize
+}
+
+netbits!{
+    Udp, write_imm, write_arr,
+    src_port: 16,
+    dst_port: 16,
+    len:      16,
+    chk:      16
+}
+
+impl Udp {
+    pub fn header_len(&self, buff: &[u8]) -> Result<usize, pkt::Truncated> {
+        pkt::check_len("udp", buff, Udp::HEADER_LEN)?;
+        Ok(Udp::HEADER_LEN)
+    }
+
+    pub fn get_payload_offset(&self) -> usize {
+        self.offset + 8
+    }
 }
@@ -0,0 +1,498 @@
+use super::pkt::{Link, Network, Transport, DissectError, Diagnostic, Dissection};
+use super::pkt::{HasLinkLayer, HasNetworkLayer};
+use super::pkt;
+use super::eth;
+use super::ipv4;
+use super::ipv6;
+use super::arp;
+use super::tcp;
+use super::udp;
+use super::icmpv4;
+use super::icmpv6;
+use super::netbits::{BadValue, Field, Value};
+
+use std::error;
+use std::fmt;
+
+// borrowed packets
+//
+// The same dissection as `pkt::Packet`, but over bytes owned by somebody
+// else, typically a buffer from a `BufferPool`. Nothing is allocated: the
+// layer structs are just offsets, and a `Header` pairs one with the slice
+// its netbits accessors expect, so that
+//
+//     view.ipv4().map(|ip| ip.get(Ipv4::get_ttl))
+//
+// reads the ttl straight from the receive buffer. Every header is checked
+// against the length of `data` when the view is made, so the accessors
+// can't run off the end. The view only covers the packet proper, ethernet
+// padding and other trailing bytes are cut off and noted in `diagnostic`.
+//
+// Fields can also be had by name, "ipv4.ttl" or "tcp.ack_nr", the layer
+// being the one that's in the packet: eth, ipv4, ipv6, arp, tcp, udp,
+// icmpv4 or icmpv6. `layers` goes through all of them.
+
+pub struct Header<'a, H> {
+    pub header: H,
+    buff: &'a [u8]
+}
+
+impl<'a, H> Header<'a, H> {
+    pub fn get<F, R>(&self, getter: F) -> R
+        where F: Fn(&H, &[u8]) -> R {
+        getter(&self.header, self.buff)
+    }
+
+    // the bytes from the start of this header to the end of the packet
+    pub fn bytes(&self) -> &'a [u8] {
+        self.buff
+    }
+}
+
+pub struct HeaderMut<'a, H> {
+    pub header: H,
+    buff: &'a mut [u8]
+}
+
+impl<'a, H> HeaderMut<'a, H> {
+    pub fn get<F, R>(&self, getter: F) -> R
+        where F: Fn(&H, &[u8]) -> R {
+        getter(&self.header, self.buff)
+    }
+
+    pub fn set<F, V>(&mut self, setter: F, val: V)
+        where F: Fn(&H, &mut [u8], V) {
+        setter(&self.header, self.buff, val)
+    }
+
+    pub fn bytes(&mut self) -> &mut [u8] {
+        self.buff
+    }
+}
+
+// one header's fields, by name
+pub struct Layer<'a> {
+    pub name: &'static str,
+    pub fields: &'static [Field],
+    buff: &'a [u8]
+}
+
+impl<'a> Layer<'a> {
+    pub fn field(&self, name: &str) -> Option<&'static Field> {
+        self.fields.iter().find(|field| field.name == name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<Value> {
+        self.field(name).map(|field| field.get(self.buff))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'static Field, Value)> + '_ {
+        self.fields.iter().map(move |field| (field, field.get(self.buff)))
+    }
+
+    // from the start of this header to the end of the packet
+    pub fn bytes(&self) -> &'a [u8] {
+        self.buff
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldError {
+    // no such layer in this packet, or not "layer.field" at all
+    NoLayer(String),
+    // the layer doesn't have it
+    NoField(String),
+    BadValue(BadValue)
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FieldError::NoLayer(ref path) => write!(f, "the packet has no layer for {}", path),
+            FieldError::NoField(ref path) => write!(f, "no field {}", path),
+            FieldError::BadValue(ref e)   => write!(f, "{}", e)
+        }
+    }
+}
+
+impl error::Error for FieldError {}
+
+// the name, fields and offset of every header in the packet, outermost first
+fn layer_spans(link: &Link, net: &Network, trans: &Option<Transport>)
+               -> Vec<(&'static str, &'static [Field], usize)> {
+    let mut spans = Vec::with_capacity(3);
+    if let Link::EthLink(ref eth) = *link {
+        spans.push(("eth", eth::Eth::FIELDS, eth.offset));
+    }
+    spans.push(match *net {
+        Network::Ipv4Net(ref ip) => ("ipv4", ipv4::Ipv4::FIELDS, ip.offset),
+        Network::Ipv6Net(ref ip) => ("ipv6", ipv6::Ipv6::FIELDS, ip.offset),
+        Network::ArpNet(ref arp) => ("arp", arp::Arp::FIELDS, arp.offset)
+    });
+    match *trans {
+        Some(Transport::TcpTrans(ref tcp))      => spans.push(("tcp", tcp::Tcp::FIELDS, tcp.offset)),
+        Some(Transport::UdpTrans(ref udp))      => spans.push(("udp", udp::Udp::FIELDS, udp.offset)),
+        Some(Transport::Icmpv4Trans(ref icmp))  => spans.push(("icmpv4", icmpv4::Icmpv4::FIELDS, icmp.offset)),
+        Some(Transport::Icmpv6Trans(ref icmp))  => spans.push(("icmpv6", icmpv6::Icmpv6::FIELDS, icmp.offset)),
+        None => ()
+    }
+    spans
+}
+
+// "layer.field" to the field and where its header starts
+fn find_field(spans: &[(&'static str, &'static [Field], usize)],
+              path: &str) -> Result<(&'static Field, usize), FieldError> {
+    let (layer, name) = match path.find('.') {
+        Some(at) => (&path[..at], &path[at + 1..]),
+        None => return Err(FieldError::NoLayer(path.to_string()))
+    };
+    let &(_, fields, offset) = spans.iter().find(|span| span.0 == layer)
+        .ok_or_else(|| FieldError::NoLayer(path.to_string()))?;
+    match fields.iter().find(|field| field.name == name) {
+        Some(field) => Ok((field, offset)),
+        None => Err(FieldError::NoField(path.to_string()))
+    }
+}
+
+// every header a field can be named in, whatever a packet has
+const LAYERS: &[(&str, &[Field])] = &[
+    ("eth", eth::Eth::FIELDS),
+    ("ipv4", ipv4::Ipv4::FIELDS),
+    ("ipv6", ipv6::Ipv6::FIELDS),
+    ("arp", arp::Arp::FIELDS),
+    ("tcp", tcp::Tcp::FIELDS),
+    ("udp", udp::Udp::FIELDS),
+    ("icmpv4", icmpv4::Icmpv4::FIELDS),
+    ("icmpv6", icmpv6::Icmpv6::FIELDS)
+];
+
+// What "layer.field" names, without a packet to look in: for checking a
+// path before there is one.
+pub fn field(path: &str) -> Result<&'static Field, FieldError> {
+    let spans: Vec<_> = LAYERS.iter().map(|&(name, fields)| (name, fields, 0)).collect();
+    find_field(&spans, path).map(|(field, _)| field)
+}
+
+pub struct PacketView<'a> {
+    data: &'a [u8],
+    pub link: Link,
+    pub net: Network,
+    pub trans: Option<Transport>,
+    pub diagnostic: Option<Diagnostic>
+}
+
+impl<'a> PacketView<'a> {
+    // `data` should be exactly the frame, not the whole receive buffer
+    pub fn with_link(data: &'a [u8], link: &Link) -> Result<PacketView<'a>, DissectError> {
+        let dissected = pkt::dissect(data, link)?;
+        Ok(PacketView::from_parts(data, *link, dissected))
+    }
+
+    pub fn eth(data: &'a [u8]) -> Result<PacketView<'a>, DissectError> {
+        PacketView::with_link(data, &Link::EthLink(eth::Eth { offset: 0 }))
+    }
+
+    pub fn ip(data: &'a [u8]) -> Result<PacketView<'a>, DissectError> {
+        PacketView::with_link(data, &Link::RawLink)
+    }
+
+    // layers that were already dissected over `data`
+    pub fn from_parts(data: &'a [u8], link: Link, dissected: Dissection) -> PacketView<'a> {
+        PacketView {
+            data: &data[..dissected.len],
+            link: link,
+            net: dissected.net,
+            trans: dissected.trans,
+            diagnostic: dissected.diagnostic
+        }
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn eth_header(&self) -> Option<Header<'a, eth::Eth>> {
+        match self.link {
+            Link::EthLink(ref eth) => Some(Header {
+                header: eth::Eth { offset: eth.offset },
+                buff: &self.data[eth.offset..]
+            }),
+            Link::RawLink => None
+        }
+    }
+
+    pub fn ipv4(&self) -> Option<Header<'a, ipv4::Ipv4>> {
+        match self.net {
+            Network::Ipv4Net(ref ip) => Some(Header {
+                header: ipv4::Ipv4 { offset: ip.offset },
+                buff: &self.data[ip.offset..]
+            }),
+            _ => None
+        }
+    }
+
+    pub fn ipv6(&self) -> Option<Header<'a, ipv6::Ipv6>> {
+        match self.net {
+            Network::Ipv6Net(ref ip) => Some(Header {
+                header: ipv6::Ipv6 { offset: ip.offset },
+                buff: &self.data[ip.offset..]
+            }),
+            _ => None
+        }
+    }
+
+    pub fn arp(&self) -> Option<Header<'a, arp::Arp>> {
+        match self.net {
+            Network::ArpNet(ref arp) => Some(Header {
+                header: arp::Arp { offset: arp.offset },
+                buff: &self.data[arp.offset..]
+            }),
+            _ => None
+        }
+    }
+
+    pub fn transport(&self) -> Option<Transport> {
+        self.trans
+    }
+
+    pub fn tcp(&self) -> Option<Header<'a, tcp::Tcp>> {
+        match self.trans {
+            Some(Transport::TcpTrans(tcp)) => Some(Header {
+                buff: &self.data[tcp.offset..],
+                header: tcp
+            }),
+            _ => None
+        }
+    }
+
+    pub fn udp(&self) -> Option<Header<'a, udp::Udp>> {
+        match self.trans {
+            Some(Transport::UdpTrans(udp)) => Some(Header {
+                buff: &self.data[udp.offset..],
+                header: udp
+            }),
+            _ => None
+        }
+    }
+
+    pub fn layers(&self) -> Vec<Layer<'a>> {
+        layer_spans(&self.link, &self.net, &self.trans).into_iter()
+            .map(|(name, fields, offset)| Layer { name: name, fields: fields, buff: &self.data[offset..] })
+            .collect()
+    }
+
+    // `path` is "layer.field"
+    pub fn get_field(&self, path: &str) -> Result<Value, FieldError> {
+        let (field, offset) = find_field(&layer_spans(&self.link, &self.net, &self.trans), path)?;
+        Ok(field.get(&self.data[offset..]))
+    }
+
+    pub fn print(&self) {
+        match self.link {
+            Link::EthLink(ref eth) => {
+                eth.print(&self.data[eth.offset..]);
+                println!();
+            },
+            Link::RawLink => ()
+        }
+        match self.net {
+            Network::Ipv4Net(ref net) => net.print(&self.data[net.offset..]),
+            Network::Ipv6Net(ref net) => net.print(&self.data[net.offset..]),
+            Network::ArpNet(ref net)  => net.print(&self.data[net.offset..])
+        }
+        if let Some(ref diagnostic) = self.diagnostic {
+            println!("diagnostic:\n  {}", diagnostic);
+        }
+    }
+}
+
+pub struct PacketViewMut<'a> {
+    data: &'a mut [u8],
+    pub link: Link,
+    pub net: Network,
+    pub trans: Option<Transport>,
+    pub diagnostic: Option<Diagnostic>
+}
+
+impl<'a> PacketViewMut<'a> {
+    pub fn with_link(data: &'a mut [u8], link: &Link) -> Result<PacketViewMut<'a>, DissectError> {
+        let dissected = pkt::dissect(data, link)?;
+        Ok(PacketViewMut {
+            data: &mut data[..dissected.len],
+            link: *link,
+            net: dissected.net,
+            trans: dissected.trans,
+            diagnostic: dissected.diagnostic
+        })
+    }
+
+    pub fn eth(data: &'a mut [u8]) -> Result<PacketViewMut<'a>, DissectError> {
+        PacketViewMut::with_link(data, &Link::EthLink(eth::Eth { offset: 0 }))
+    }
+
+    pub fn ip(data: &'a mut [u8]) -> Result<PacketViewMut<'a>, DissectError> {
+        PacketViewMut::with_link(data, &Link::RawLink)
+    }
+
+    // read-only view, for everything that doesn't write
+    pub fn as_view(&self) -> PacketView<'_> {
+        PacketView {
+            data: self.data,
+            link: self.link,
+            net: self.net,
+            trans: self.trans,
+            diagnostic: self.diagnostic
+        }
+    }
+
+    pub fn data(&mut self) -> &mut [u8] {
+        self.data
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn eth_header(&mut self) -> Option<HeaderMut<'_, eth::Eth>> {
+        match self.link {
+            Link::EthLink(ref eth) => Some(HeaderMut {
+                header: eth::Eth { offset: eth.offset },
+                buff: &mut self.data[eth.offset..]
+            }),
+            Link::RawLink => None
+        }
+    }
+
+    pub fn ipv4(&mut self) -> Option<HeaderMut<'_, ipv4::Ipv4>> {
+        match self.net {
+            Network::Ipv4Net(ref ip) => Some(HeaderMut {
+                header: ipv4::Ipv4 { offset: ip.offset },
+                buff: &mut self.data[ip.offset..]
+            }),
+            _ => None
+        }
+    }
+
+    pub fn ipv6(&mut self) -> Option<HeaderMut<'_, ipv6::Ipv6>> {
+        match self.net {
+            Network::Ipv6Net(ref ip) => Some(HeaderMut {
+                header: ipv6::Ipv6 { offset: ip.offset },
+                buff: &mut self.data[ip.offset..]
+            }),
+            _ => None
+        }
+    }
+
+    pub fn arp(&mut self) -> Option<HeaderMut<'_, arp::Arp>> {
+        match self.net {
+            Network::ArpNet(ref arp) => Some(HeaderMut {
+                header: arp::Arp { offset: arp.offset },
+                buff: &mut self.data[arp.offset..]
+            }),
+            _ => None
+        }
+    }
+
+    pub fn get_field(&self, path: &str) -> Result<Value, FieldError> {
+        self.as_view().get_field(path)
+    }
+
+    // Just the field: checksums and lengths are for the caller to redo.
+    pub fn set_field(&mut self, path: &str, value: &Value) -> Result<(), FieldError> {
+        let (field, offset) = find_field(&layer_spans(&self.link, &self.net, &self.trans), path)?;
+        field.set(&mut self.data[offset..], value).map_err(FieldError::BadValue)
+    }
+
+    pub fn tcp(&mut self) -> Option<HeaderMut<'_, tcp::Tcp>> {
+        match self.trans {
+            Some(Transport::TcpTrans(tcp)) => Some(HeaderMut {
+                buff: &mut self.data[tcp.offset..],
+                header: tcp
+            }),
+            _ => None
+        }
+    }
+
+    pub fn udp(&mut self) -> Option<HeaderMut<'_, udp::Udp>> {
+        match self.trans {
+            Some(Transport::UdpTrans(udp)) => Some(HeaderMut {
+                buff: &mut self.data[udp.offset..],
+                header: udp
+            }),
+            _ => None
+        }
+    }
+}
+
+
+// testing
+#[cfg(test)]
+use super::builder::EthBuilder;
+
+// tcp over ipv6 with a flow label, fd00::2 port 40000 to fd01::2 port 80
+#[cfg(test)]
+fn tcp6() -> Vec<u8> {
+    EthBuilder::new([0x02, 0, 0, 0, 0, 1], [0x02, 0, 0, 0, 0, 2])
+        .ipv6("fd00::2".parse().unwrap(), "fd01::2".parse().unwrap())
+        .flow_label(0x12345).tcp(40000, 80).seq(7).build(b"").data
+}
+
+#[test]
+fn test_layers_outermost_first() {
+    let frame = tcp6();
+    let view = PacketView::eth(&frame).unwrap();
+    let names: Vec<&str> = view.layers().iter().map(|layer| layer.name).collect();
+    assert_eq!(names, ["eth", "ipv6", "tcp"]);
+
+    let tcp = &view.layers()[2];
+    let flags: Vec<String> = tcp.iter().filter(|&(field, _)| field.width == 1)
+        .map(|(field, value)| format!("{}={}", field.name, value)).collect();
+    assert_eq!(flags.join(" "), "urg=0 ack=0 psh=0 rst=0 syn=0 fin=0");
+    let field = tcp.field("dst_port").unwrap();
+    assert_eq!((field.offset, field.width, field.bytes), (16, 16, false));
+}
+
+#[test]
+fn test_get_fields_by_name() {
+    let frame = tcp6();
+    let view = PacketView::eth(&frame).unwrap();
+    assert_eq!(view.get_field("ipv6.flow_label"), Ok(Value::Int(0x12345)));
+    assert_eq!(view.get_field("tcp.seq"), Ok(Value::Int(7)));
+    assert_eq!(view.get_field("eth.src"), Ok(Value::Bytes(vec![0x02, 0, 0, 0, 0, 1])));
+    assert_eq!(view.get_field("udp.dst_port"), Err(FieldError::NoLayer("udp.dst_port".to_string())));
+    assert_eq!(view.get_field("tcp.bogus"), Err(FieldError::NoField("tcp.bogus".to_string())));
+}
+
+#[test]
+fn test_set_fields_by_name() {
+    let mut frame = tcp6();
+    let mut view = PacketViewMut::eth(&mut frame).unwrap();
+    view.set_field("ipv6.hop_limit", &Value::Int(3)).unwrap();
+    view.set_field("tcp.syn", &Value::Int(1)).unwrap();
+    assert_eq!(view.ipv6().unwrap().get(ipv6::Ipv6::get_hop_limit), 3);
+    assert_eq!(view.tcp().unwrap().get(tcp::Tcp::get_syn), 1);
+    assert_eq!(view.set_field("tcp.syn", &Value::Int(2)).unwrap_err().to_string(),
+               "Tcp.syn is 1 bits, 2 doesn't fit");
+    assert_eq!(view.set_field("eth.dst", &Value::Bytes(vec![0xFF; 4])).unwrap_err().to_string(),
+               "Eth.dst takes 6 bytes, not 0xffffffff");
+}
+
+#[test]
+fn test_fields_known_without_a_packet() {
+    assert_eq!(field("udp.dst_port").map(|field| field.width), Ok(16));
+    assert_eq!(field("icmpv6.code").map(|field| field.header), Ok("Icmpv6"));
+    assert_eq!(field("ipv4.tll"), Err(FieldError::NoField("ipv4.tll".to_string())));
+    assert_eq!(field("ttl"), Err(FieldError::NoLayer("ttl".to_string())));
+}
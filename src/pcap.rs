@@ -0,0 +1,125 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Read};
+
+// pcap files
+//
+// Only the classic libpcap format, in either byte order and with micro- or
+// nanosecond timestamps. pcapng isn't supported.
+//
+// global header:
+//   magic, version major/minor, thiszone, sigfigs, snaplen, link type
+// per record:
+//   ts seconds, ts micro/nanoseconds, captured length, original length
+
+const MAGIC_USEC: u32 = 0xa1b2c3d4;
+const MAGIC_NSEC: u32 = 0xa1b23c4d;
+
+pub const LINKTYPE_ETHERNET: u32 = 1;
+pub const LINKTYPE_RAW:      u32 = 101;
+
+pub struct Record {
+    pub ts_sec: u32,
+    pub ts_nsec: u32,
+    pub orig_len: usize,
+    pub data: Vec<u8>
+}
+
+pub struct Reader<R> {
+    input: R,
+    swapped: bool,
+    nsec: bool,
+    pub link_type: u32,
+    pub snaplen: u32
+}
+
+fn bad_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl Reader<BufReader<File>> {
+    pub fn open(path: &str) -> io::Result<Reader<BufReader<File>>> {
+        Reader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(mut input: R) -> io::Result<Reader<R>> {
+        let mut header = [0u8; 24];
+        input.read_exact(&mut header)?;
+
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let (swapped, nsec) = match magic {
+            MAGIC_USEC => (false, false),
+            MAGIC_NSEC => (false, true),
+            _ => match magic.swap_bytes() {
+                MAGIC_USEC => (true, false),
+                MAGIC_NSEC => (true, true),
+                _          => return Err(bad_data("not a pcap file"))
+            }
+        };
+
+        let mut reader = Reader {
+            input: input,
+            swapped: swapped,
+            nsec: nsec,
+            link_type: 0,
+            snaplen: 0
+        };
+        reader.snaplen = reader.u32_at(&header, 16);
+        reader.link_type = reader.u32_at(&header, 20);
+        Ok(reader)
+    }
+
+    fn u32_at(&self, buff: &[u8], idx: usize) -> u32 {
+        let val = u32::from_le_bytes([buff[idx], buff[idx + 1], buff[idx + 2], buff[idx + 3]]);
+        if self.swapped { val.swap_bytes() } else { val }
+    }
+
+    // next record, or None at the end of the file
+    pub fn next_record(&mut self) -> io::Result<Option<Record>> {
+        let mut header = [0u8; 16];
+        match self.input.read_exact(&mut header) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e)
+        }
+
+        let incl_len = self.u32_at(&header, 8) as usize;
+        if incl_len > 0x40000 {
+            return Err(bad_data("pcap record too large"))
+        }
+        let mut data = vec![0u8; incl_len];
+        self.input.read_exact(&mut data)?;
+
+        let frac = self.u32_at(&header, 4);
+        let ts_nsec = if self.nsec { Some(frac) } else { frac.checked_mul(1000) };
+        let ts_nsec = ts_nsec.filter(|&nsec| nsec < 1_000_000_000)
+            .ok_or_else(|| bad_data("pcap timestamp fraction out of range"))?;
+        Ok(Some(Record {
+            ts_sec: self.u32_at(&header, 0),
+            ts_nsec: ts_nsec,
+            orig_len: self.u32_at(&header, 12) as usize,
+            data: data
+        }))
+    }
+}
+
+
+// testing
+#[test]
+fn test_reader_rejects_bad_timestamps() {
+    let mut file = Vec::new();
+    file.extend_from_slice(&MAGIC_USEC.to_le_bytes());
+    file.extend_from_slice(&[2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xFF, 0xFF, 0, 0, 1, 0, 0, 0]);
+    // a second, then half of one, then a fraction that would overflow in ns
+    for &frac in &[500_000u32, 0xFFFF_FFFF] {
+        file.extend_from_slice(&1u32.to_le_bytes());
+        file.extend_from_slice(&frac.to_le_bytes());
+        file.extend_from_slice(&[1, 0, 0, 0, 1, 0, 0, 0, 0xAB]);
+    }
+    let mut reader = Reader::new(&file[..]).unwrap();
+    let record = reader.next_record().unwrap().unwrap();
+    assert_eq!((record.ts_sec, record.ts_nsec, record.data), (1, 500_000_000, vec![0xAB]));
+    assert_eq!(reader.next_record().err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+}
@@ -0,0 +1,429 @@
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::time::{Duration, Instant};
+
+use libc;
+
+// event loop
+//
+// A small epoll reactor. File descriptors (the tap, sockets, timerfds) are
+// registered with a token that comes back in the events. Protocol code that
+// needs deadlines (ARP aging, retransmits, reassembly timeouts) schedules
+// them on the timer wheel with a payload of its own choosing; the wheel is
+// driven by one internal timerfd that's armed for the earliest deadline.
+
+pub type Token = u64;
+
+// reserved for the wheel's own timerfd
+const WHEEL_TOKEN: Token = !0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interest {
+    Read,
+    Write,
+    ReadWrite
+}
+
+impl Interest {
+    fn bits(&self) -> u32 {
+        match *self {
+            Interest::Read      => libc::EPOLLIN as u32,
+            Interest::Write     => libc::EPOLLOUT as u32,
+            Interest::ReadWrite => (libc::EPOLLIN | libc::EPOLLOUT) as u32
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Event<T> {
+    Readable(Token),
+    Writable(Token),
+    // error or hangup on the fd
+    Closed(Token),
+    Timer(TimerId, T)
+}
+
+fn cvt(ret: libc::c_int) -> io::Result<libc::c_int> {
+    match ret {
+        -1 => Err(io::Error::last_os_error()),
+        _  => Ok(ret)
+    }
+}
+
+fn to_timespec(dur: Duration) -> libc::timespec {
+    libc::timespec {
+        tv_sec: dur.as_secs() as libc::time_t,
+        tv_nsec: dur.subsec_nanos() as libc::c_long
+    }
+}
+
+// timerfd
+//
+// Also usable on its own, for things that want a periodic fd to register
+// with the reactor.
+pub struct TimerFd {
+    fd: RawFd
+}
+
+impl TimerFd {
+    pub fn new() -> io::Result<TimerFd> {
+        let fd = cvt(unsafe {
+            libc::timerfd_create(libc::CLOCK_MONOTONIC,
+                                 libc::TFD_NONBLOCK | libc::TFD_CLOEXEC)
+        })?;
+        Ok(TimerFd { fd: fd })
+    }
+
+    // fire after `initial`, then every `interval` if that's non-zero. A zero
+    // `initial` disarms the timer.
+    pub fn set(&self, initial: Duration, interval: Duration) -> io::Result<()> {
+        let spec = libc::itimerspec {
+            it_interval: to_timespec(interval),
+            it_value: to_timespec(initial)
+        };
+        cvt(unsafe { libc::timerfd_settime(self.fd, 0, &spec, ptr::null_mut()) })?;
+        Ok(())
+    }
+
+    // number of expirations since the last read, 0 if none
+    pub fn read(&self) -> io::Result<u64> {
+        let mut count = 0u64;
+        let ret = unsafe {
+            libc::read(self.fd, &mut count as *mut u64 as *mut libc::c_void, 8)
+        };
+        if ret == -1 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::WouldBlock {
+                return Ok(0)
+            }
+            return Err(err)
+        }
+        Ok(count)
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for TimerFd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+// timer wheel
+//
+// Hashed wheel: deadlines are rounded up to whole ticks and hashed into
+// `slots` buckets; an entry whose deadline is more than one revolution away
+// just sits in its bucket until its tick comes around.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TimerId(u64);
+
+struct Entry<T> {
+    id: TimerId,
+    deadline: u64,
+    payload: T
+}
+
+pub struct TimerWheel<T> {
+    tick: Duration,
+    start: Instant,
+    current: u64,
+    slots: Vec<Vec<Entry<T>>>,
+    pending: HashMap<TimerId, u64>,
+    next_id: u64
+}
+
+impl<T> TimerWheel<T> {
+    pub fn new(tick: Duration, slots: usize) -> TimerWheel<T> {
+        TimerWheel::starting_at(Instant::now(), tick, slots)
+    }
+
+    pub fn starting_at(start: Instant, tick: Duration, slots: usize) -> TimerWheel<T> {
+        assert!(slots > 0 && tick > Duration::from_millis(0));
+        TimerWheel {
+            tick: tick,
+            start: start,
+            current: 0,
+            slots: (0..slots).map(|_| Vec::new()).collect(),
+            pending: HashMap::new(),
+            next_id: 0
+        }
+    }
+
+    // whole ticks in `dur`, rounded up
+    fn ticks(&self, dur: Duration) -> u64 {
+        let tick = self.tick.as_nanos();
+        dur.as_nanos().div_ceil(tick) as u64
+    }
+
+    pub fn schedule_at(&mut self, when: Instant, payload: T) -> TimerId {
+        let deadline = if when > self.start {
+            self.ticks(when - self.start)
+        } else {
+            0
+        };
+        // never in a tick that's already been processed
+        let deadline = deadline.max(self.current + 1);
+
+        let id = TimerId(self.next_id);
+        self.next_id += 1;
+
+        let slot = (deadline % self.slots.len() as u64) as usize;
+        self.slots[slot].push(Entry { id: id, deadline: deadline, payload: payload });
+        self.pending.insert(id, deadline);
+        id
+    }
+
+    pub fn schedule(&mut self, after: Duration, payload: T) -> TimerId {
+        self.schedule_at(Instant::now() + after, payload)
+    }
+
+    // returns the payload if the timer hadn't fired yet
+    pub fn cancel(&mut self, id: TimerId) -> Option<T> {
+        let deadline = self.pending.remove(&id)?;
+        let nr_slots = self.slots.len() as u64;
+        let slot = &mut self.slots[(deadline % nr_slots) as usize];
+        let idx = slot.iter().position(|entry| entry.id == id)?;
+        Some(slot.swap_remove(idx).payload)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    // when the earliest pending timer is due
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.pending.values().min().map(|&deadline| {
+            self.start + Duration::from_nanos((self.tick.as_nanos() * deadline as u128) as u64)
+        })
+    }
+
+    // move the wheel up to `now`, returning everything that expired
+    pub fn advance(&mut self, now: Instant) -> Vec<(TimerId, T)> {
+        let mut expired = Vec::new();
+        if now <= self.start {
+            return expired
+        }
+
+        let target = ((now - self.start).as_nanos() / self.tick.as_nanos()) as u64;
+
+        // after a long stall there's no point going round more than once
+        let nr_slots = self.slots.len() as u64;
+        if target > self.current + nr_slots {
+            self.current = target - nr_slots;
+        }
+
+        while self.current < target {
+            self.current += 1;
+            let current = self.current;
+            let slot = (current % nr_slots) as usize;
+
+            let entries = mem::take(&mut self.slots[slot]);
+            for entry in entries {
+                if entry.deadline <= current {
+                    self.pending.remove(&entry.id);
+                    expired.push((entry.id, entry.payload));
+                } else {
+                    self.slots[slot].push(entry);
+                }
+            }
+        }
+        expired
+    }
+}
+
+pub struct Reactor<T> {
+    epfd: RawFd,
+    wheel_fd: TimerFd,
+    pub timers: TimerWheel<T>,
+    events: Vec<libc::epoll_event>
+}
+
+impl<T> Reactor<T> {
+    pub fn new(tick: Duration) -> io::Result<Reactor<T>> {
+        let epfd = cvt(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })?;
+        let reactor = Reactor {
+            epfd: epfd,
+            wheel_fd: TimerFd::new()?,
+            timers: TimerWheel::new(tick, 512),
+            events: vec![libc::epoll_event { events: 0, u64: 0 }; 64]
+        };
+        reactor.register(reactor.wheel_fd.fd(), WHEEL_TOKEN, Interest::Read)?;
+        Ok(reactor)
+    }
+
+    fn ctl(&self, op: libc::c_int, fd: RawFd, token: Token, interest: Interest)
+           -> io::Result<()> {
+        let mut event = libc::epoll_event { events: interest.bits(), u64: token };
+        cvt(unsafe { libc::epoll_ctl(self.epfd, op, fd, &mut event) })?;
+        Ok(())
+    }
+
+    pub fn register(&self, fd: RawFd, token: Token, interest: Interest) -> io::Result<()> {
+        self.ctl(libc::EPOLL_CTL_ADD, fd, token, interest)
+    }
+
+    pub fn reregister(&self, fd: RawFd, token: Token, interest: Interest) -> io::Result<()> {
+        self.ctl(libc::EPOLL_CTL_MOD, fd, token, interest)
+    }
+
+    pub fn deregister(&self, fd: RawFd) -> io::Result<()> {
+        let mut event = libc::epoll_event { events: 0, u64: 0 };
+        cvt(unsafe { libc::epoll_ctl(self.epfd, libc::EPOLL_CTL_DEL, fd, &mut event) })?;
+        Ok(())
+    }
+
+    pub fn schedule(&mut self, after: Duration, payload: T) -> TimerId {
+        self.timers.schedule(after, payload)
+    }
+
+    pub fn cancel(&mut self, id: TimerId) -> Option<T> {
+        self.timers.cancel(id)
+    }
+
+    fn arm_wheel(&self) -> io::Result<()> {
+        match self.timers.next_expiry() {
+            None => self.wheel_fd.set(Duration::from_secs(0), Duration::from_secs(0)),
+            Some(when) => {
+                let now = Instant::now();
+                // a zero value would disarm, so fire asap instead
+                let wait = if when > now { when - now } else { Duration::new(0, 1) };
+                self.wheel_fd.set(wait, Duration::from_secs(0))
+            }
+        }
+    }
+
+    // Wait for fd events or timers, for at most `timeout` (forever if None).
+    pub fn poll(&mut self, timeout: Option<Duration>) -> io::Result<Vec<Event<T>>> {
+        self.arm_wheel()?;
+
+        // rounded up, or a deadline under a millisecond away would spin
+        let timeout_ms = match timeout {
+            None      => -1,
+            Some(dur) => dur.as_nanos().div_ceil(1_000_000).min(libc::c_int::MAX as u128) as libc::c_int
+        };
+
+        let nr_events = loop {
+            let ret = unsafe {
+                libc::epoll_wait(self.epfd, self.events.as_mut_ptr(),
+                                 self.events.len() as libc::c_int, timeout_ms)
+            };
+            match cvt(ret) {
+                Ok(nr) => break nr as usize,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e)
+            }
+        };
+
+        let mut events = Vec::with_capacity(nr_events);
+        let mut wheel_fired = false;
+        for event in &self.events[..nr_events] {
+            let token = event.u64;
+            let flags = event.events;
+            if token == WHEEL_TOKEN {
+                wheel_fired = true;
+                continue
+            }
+            if flags & (libc::EPOLLIN as u32) != 0 {
+                events.push(Event::Readable(token));
+            }
+            if flags & (libc::EPOLLOUT as u32) != 0 {
+                events.push(Event::Writable(token));
+            }
+            if flags & ((libc::EPOLLERR | libc::EPOLLHUP) as u32) != 0 {
+                events.push(Event::Closed(token));
+            }
+        }
+
+        if wheel_fired {
+            self.wheel_fd.read()?;
+        }
+        for (id, payload) in self.timers.advance(Instant::now()) {
+            events.push(Event::Timer(id, payload));
+        }
+        Ok(events)
+    }
+}
+
+impl<T> Drop for Reactor<T> {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.epfd) };
+    }
+}
+
+
+// testing
+#[test]
+fn test_timer_wheel() {
+    let start = Instant::now();
+    let ms = Duration::from_millis;
+    let mut wheel = TimerWheel::starting_at(start, ms(10), 8);
+
+    let a = wheel.schedule_at(start + ms(25), "a");
+    let b = wheel.schedule_at(start + ms(15), "b");
+    // more than a revolution out, shares a slot with "a"
+    let c = wheel.schedule_at(start + ms(110), "c");
+    let d = wheel.schedule_at(start + ms(30), "d");
+    assert_eq!(wheel.len(), 4);
+    assert_eq!(wheel.next_expiry(), Some(start + ms(20)));
+
+    assert_eq!(wheel.cancel(d), Some("d"));
+    assert_eq!(wheel.cancel(d), None);
+
+    assert_eq!(wheel.advance(start + ms(19)), vec![]);
+    assert_eq!(wheel.advance(start + ms(20)), vec![(b, "b")]);
+    assert_eq!(wheel.advance(start + ms(100)), vec![(a, "a")]);
+    assert_eq!(wheel.advance(start + ms(120)), vec![(c, "c")]);
+    assert!(wheel.is_empty());
+}
+
+#[test]
+fn test_poll_readiness() {
+    let mut fds = [0 as libc::c_int; 2];
+    let res = unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) };
+    cvt(res).unwrap();
+    let (a, b) = (fds[0], fds[1]);
+    let mut reactor: Reactor<()> = Reactor::new(Duration::from_millis(1)).unwrap();
+    reactor.register(a, 1, Interest::Read).unwrap();
+    reactor.register(b, 2, Interest::Write).unwrap();
+
+    // nothing to read yet, room to write
+    assert_eq!(reactor.poll(Some(Duration::from_secs(0))).unwrap(), vec![Event::Writable(2)]);
+
+    reactor.deregister(b).unwrap();
+    assert_eq!(unsafe { libc::write(b, b"x".as_ptr() as *const libc::c_void, 1) }, 1);
+    assert_eq!(reactor.poll(Some(Duration::from_secs(1))).unwrap(), vec![Event::Readable(1)]);
+
+    // the other end going away is a hangup, with the byte still to read
+    unsafe { libc::close(b) };
+    let events = reactor.poll(Some(Duration::from_secs(1))).unwrap();
+    assert_eq!(events, vec![Event::Readable(1), Event::Closed(1)]);
+    unsafe { libc::close(a) };
+}
+
+#[test]
+fn test_poll_fires_timers() {
+    let mut reactor = Reactor::new(Duration::from_millis(1)).unwrap();
+    let start = Instant::now();
+    let id = reactor.schedule(Duration::from_millis(20), "t");
+
+    // no fd is ready, only the wheel's timerfd can end the wait
+    let mut events = vec![];
+    while events.is_empty() && start.elapsed() < Duration::from_secs(5) {
+        events = reactor.poll(Some(Duration::from_secs(5))).unwrap();
+    }
+    assert_eq!(events, vec![Event::Timer(id, "t")]);
+    assert!(start.elapsed() >= Duration::from_millis(20));
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert!(reactor.timers.is_empty());
+}
@@ -0,0 +1,456 @@
+use std::error;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use packet::eth::ETHERTYPE_VLAN;
+use packet::filter::{self, Filter};
+use packet::ipv4::Ipv4;
+use packet::ipv6::Ipv6;
+use packet::netbits::{BadValue, Value};
+use packet::pkt::{self, Link, Network, Transport};
+use packet::udp::Udp;
+use packet::view::{self, FieldError, PacketView, PacketViewMut};
+use util;
+
+// packet rewriting
+//
+// What reflect mode does to a frame before it goes back, when there are
+// rules for it; without any it swaps the ip addresses. A rule is an
+// optional capture filter, `->`, and actions separated by `;`:
+//
+//   udp dst port 7 -> swap ipv4.src ipv4.dst; swap udp.src_port udp.dst_port; checksums
+//   ip6 -> swap ipv6.src ipv6.dst; set ipv6.hop_limit 1; push vlan 20
+//   dec ipv4.ttl; truncate 16; checksums
+//
+// Actions:
+//
+//   set <layer.field> <value>   a number, 0x hex, an ip or mac address
+//   swap <layer.field> <layer.field>
+//   inc | dec <layer.field> [n] wrapping around at the field's width
+//   push vlan <id> | pop vlan   tag or untag an ethernet frame
+//   truncate <n> | pad <n>      cut the payload to n bytes, or add n zeros
+//   checksums                   recompute the ip, tcp, udp and icmp ones
+//
+// Fields are named as `PacketView::get_field` has them, and a name that
+// isn't one is refused when the rule is read. Every rule that matches is
+// applied in turn, to the frame as the earlier ones left it; a frame that
+// none of them match isn't sent back at all. Truncating and padding keep
+// the length fields right, the checksums are only redone when asked, so
+// broken ones can be sent on purpose.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Set(String, Value),
+    Swap(String, String),
+    // negative to decrement
+    Add(String, i64),
+    PushVlan(u16),
+    PopVlan,
+    Truncate(usize),
+    Pad(usize),
+    Checksums
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Action::Set(ref path, ref value) => write!(f, "set {} {}", path, value),
+            Action::Swap(ref a, ref b)       => write!(f, "swap {} {}", a, b),
+            Action::Add(ref path, 1)         => write!(f, "inc {}", path),
+            Action::Add(ref path, -1)        => write!(f, "dec {}", path),
+            Action::Add(ref path, n) if n < 0 => write!(f, "dec {} {}", path, -n),
+            Action::Add(ref path, n)         => write!(f, "inc {} {}", path, n),
+            Action::PushVlan(id)             => write!(f, "push vlan {}", id),
+            Action::PopVlan                  => f.write_str("pop vlan"),
+            Action::Truncate(len)            => write!(f, "truncate {}", len),
+            Action::Pad(len)                 => write!(f, "pad {}", len),
+            Action::Checksums                => f.write_str("checksums")
+        }
+    }
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Action, String> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        let bad = |what: &str| format!("bad {} in rewrite action: {}", what, s);
+        let num = |word: &str| word.parse::<usize>().map_err(|_| bad("length"));
+        let path = |word: &str| match view::field(word) {
+            Ok(_) => Ok(word.to_string()),
+            Err(_) => Err(format!("no field {} in rewrite action: {}", word, s))
+        };
+        Ok(match words[..] {
+            ["set", field, value] => Action::Set(path(field)?, parse_value(value).ok_or_else(|| bad("value"))?),
+            ["swap", a, b] => Action::Swap(path(a)?, path(b)?),
+            ["inc", field] => Action::Add(path(field)?, 1),
+            ["dec", field] => Action::Add(path(field)?, -1),
+            ["inc", field, n] => Action::Add(path(field)?, num(n)? as i64),
+            ["dec", field, n] => Action::Add(path(field)?, -(num(n)? as i64)),
+            ["push", "vlan", id] => match id.parse::<u16>() {
+                Ok(id) if id < 4096 => Action::PushVlan(id),
+                _ => return Err(bad("vlan id"))
+            },
+            ["pop", "vlan"] => Action::PopVlan,
+            ["truncate", len] => Action::Truncate(num(len)?),
+            ["pad", len] => Action::Pad(num(len)?),
+            ["checksums"] => Action::Checksums,
+            _ => return Err(format!("unknown rewrite action: {}", s))
+        })
+    }
+}
+
+// numbers are numbers, addresses are their bytes
+fn parse_value(s: &str) -> Option<Value> {
+    if let Some(hex) = s.strip_prefix("0x") {
+        return u64::from_str_radix(hex, 16).ok().map(Value::Int)
+    }
+    if let Ok(val) = s.parse::<u64>() {
+        return Some(Value::Int(val))
+    }
+    match s.parse::<IpAddr>() {
+        Ok(IpAddr::V4(addr)) => Some(Value::Bytes(addr.octets().to_vec())),
+        Ok(IpAddr::V6(addr)) => Some(Value::Bytes(addr.octets().to_vec())),
+        Err(_) => filter::parse_mac(s).map(|mac| Value::Bytes(mac.to_vec()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    // every packet without one
+    pub filter: Option<Filter>,
+    pub actions: Vec<Action>
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Rule, String> {
+        let (filter, actions) = match s.find("->") {
+            Some(at) => (Some(s[..at].parse::<Filter>()?), &s[at + 2..]),
+            None => (None, s)
+        };
+        let actions = actions.split(';').map(|action| action.parse()).collect::<Result<Vec<_>, _>>()?;
+        Ok(Rule { filter: filter, actions: actions })
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref filter) = self.filter {
+            write!(f, "{} -> ", filter)?;
+        }
+        let actions: Vec<String> = self.actions.iter().map(|action| action.to_string()).collect();
+        f.write_str(&actions.join("; "))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RewriteError {
+    Field(FieldError),
+    // inc or dec on an address
+    NotANumber(String),
+    // vlans need an ethernet header, and popping one a tag
+    NoEthernet,
+    NoVlan,
+    // what an action left can't be dissected any more
+    Dissect(pkt::DissectError)
+}
+
+impl fmt::Display for RewriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RewriteError::Field(ref e)         => write!(f, "{}", e),
+            RewriteError::NotANumber(ref path) => write!(f, "{} isn't a number", path),
+            RewriteError::NoEthernet           => f.write_str("no ethernet header to tag"),
+            RewriteError::NoVlan               => f.write_str("no vlan tag to pop"),
+            RewriteError::Dissect(ref e)       => write!(f, "{}", e)
+        }
+    }
+}
+
+impl error::Error for RewriteError {}
+
+impl From<FieldError> for RewriteError {
+    fn from(e: FieldError) -> RewriteError {
+        RewriteError::Field(e)
+    }
+}
+
+impl From<pkt::Truncated> for RewriteError {
+    fn from(e: pkt::Truncated) -> RewriteError {
+        RewriteError::Dissect(pkt::DissectError::Truncated(e))
+    }
+}
+
+impl From<pkt::DissectError> for RewriteError {
+    fn from(e: pkt::DissectError) -> RewriteError {
+        RewriteError::Dissect(e)
+    }
+}
+
+// The frame `packet` turns into under `rules`, None if none of them
+// matched it.
+pub fn rewrite(rules: &[Rule], packet: &PacketView) -> Result<Option<Vec<u8>>, RewriteError> {
+    let link = packet.link;
+    let mut frame = packet.data().to_vec();
+    let mut matched = false;
+    for rule in rules {
+        let wanted = match rule.filter {
+            Some(ref filter) => filter.matches(&PacketView::with_link(&frame, &link)?),
+            None => true
+        };
+        if !wanted {
+            continue
+        }
+        matched = true;
+        for action in &rule.actions {
+            apply(action, &mut frame, &link)?;
+        }
+    }
+    Ok(if matched { Some(frame) } else { None })
+}
+
+fn apply(action: &Action, frame: &mut Vec<u8>, link: &Link) -> Result<(), RewriteError> {
+    match *action {
+        Action::Set(ref path, ref value) => set(&mut PacketViewMut::with_link(frame, link)?, path, value)?,
+        Action::Swap(ref a, ref b) => {
+            let mut view = PacketViewMut::with_link(frame, link)?;
+            let (first, second) = (view.get_field(a)?, view.get_field(b)?);
+            set(&mut view, a, &second)?;
+            set(&mut view, b, &first)?;
+        },
+        Action::Add(ref path, n) => {
+            let mut view = PacketViewMut::with_link(frame, link)?;
+            let val = match view.get_field(path)? {
+                Value::Int(val) => val,
+                Value::Bytes(_) => return Err(RewriteError::NotANumber(path.clone()))
+            };
+            let max = match view::field(path)?.width {
+                64 => u64::MAX,
+                width => (1 << width) - 1
+            };
+            let sum = (val as i128 + n as i128).rem_euclid(max as i128 + 1);
+            view.set_field(path, &Value::Int(sum as u64))?;
+        },
+        Action::PushVlan(id) => {
+            if let Link::RawLink = *link {
+                return Err(RewriteError::NoEthernet)
+            }
+            frame.splice(12..12, [(ETHERTYPE_VLAN >> 8) as u8, ETHERTYPE_VLAN as u8,
+                                  (id >> 8) as u8, id as u8].iter().cloned());
+        },
+        Action::PopVlan => match *link {
+            Link::EthLink(eth) if eth.get_vlan(frame).is_some() => { frame.drain(12..16); },
+            Link::EthLink(_) => return Err(RewriteError::NoVlan),
+            Link::RawLink => return Err(RewriteError::NoEthernet)
+        },
+        Action::Truncate(len) | Action::Pad(len) => {
+            let start = {
+                let view = PacketView::with_link(frame, link)?;
+                match view.trans {
+                    Some(trans) => trans.offset() + trans.header_len(&view.data()[trans.offset()..])?,
+                    None => view.net.offset() + view.net.header_len(&view.data()[view.net.offset()..])?
+                }
+            };
+            match *action {
+                Action::Truncate(_) => frame.truncate(start + len),
+                _ => frame.resize(frame.len() + len, 0)
+            }
+            fix_lengths(frame, link)?;
+        },
+        Action::Checksums => checksums(frame, link)?
+    }
+    Ok(())
+}
+
+// a number going into an address is written big endian
+fn set(view: &mut PacketViewMut, path: &str, value: &Value) -> Result<(), FieldError> {
+    match view.set_field(path, value) {
+        Err(FieldError::BadValue(BadValue { field, value: Value::Int(val) })) if field.bytes => {
+            let len = field.width / 8;
+            let mut bytes = vec![0u8; len.saturating_sub(8)];
+            bytes.extend_from_slice(&val.to_be_bytes()[8usize.saturating_sub(len)..]);
+            if len < 8 && val >> (len * 8) != 0 {
+                return Err(FieldError::BadValue(BadValue { field: field, value: Value::Int(val) }))
+            }
+            view.set_field(path, &Value::Bytes(bytes))
+        },
+        result => result
+    }
+}
+
+// the ip and udp lengths, after the payload changed size
+fn fix_lengths(frame: &mut [u8], link: &Link) -> Result<(), RewriteError> {
+    let (net, trans) = {
+        let view = PacketView::with_link(frame, link)?;
+        (view.net, view.trans)
+    };
+    let len = frame.len() - net.offset();
+    match net {
+        Network::Ipv4Net(ip) => Ipv4 { offset: 0 }.set_len(&mut frame[ip.offset..], len as u16),
+        Network::Ipv6Net(ip) => Ipv6 { offset: 0 }.set_payload_len(&mut frame[ip.offset..],
+                                                                   (len - Ipv6::HEADER_LEN) as u16),
+        Network::ArpNet(_) => ()
+    }
+    if let Some(Transport::UdpTrans(udp)) = trans {
+        let len = frame.len() - udp.offset;
+        Udp { offset: 0 }.set_len(&mut frame[udp.offset..], len as u16);
+    }
+    Ok(())
+}
+
+fn checksums(frame: &mut [u8], link: &Link) -> Result<(), RewriteError> {
+    let (net, trans) = {
+        let view = PacketView::with_link(frame, link)?;
+        (view.net, view.trans)
+    };
+    let (pseudo, fragment) = match net {
+        Network::Ipv4Net(ip) => {
+            let buff = &mut frame[ip.offset..];
+            let hdr = Ipv4 { offset: 0 };
+            let hlen = hdr.get_ihl(buff) as usize * 4;
+            hdr.set_header_chk(buff, 0);
+            let chk = util::checksum(&buff[..hlen]);
+            hdr.set_header_chk(buff, chk);
+            let len = buff.len() - hlen;
+            (util::pseudo_sum_v4(hdr.get_src(buff), hdr.get_dst(buff), hdr.get_protocol(buff), len),
+             hdr.is_fragment(buff))
+        },
+        Network::Ipv6Net(ip) => {
+            let buff = &frame[ip.offset..];
+            let hdr = Ipv6 { offset: 0 };
+            let protocol = match trans {
+                Some(Transport::TcpTrans(_)) => 6,
+                Some(Transport::UdpTrans(_)) => 17,
+                _ => 58
+            };
+            let len = frame.len() - trans.map_or(frame.len(), |trans| trans.offset());
+            (util::pseudo_sum_v6(hdr.get_src(buff), hdr.get_dst(buff), protocol, len),
+             hdr.get_fragment(buff).is_some())
+        },
+        Network::ArpNet(_) => return Ok(())
+    };
+    // a fragment has only part of what the checksum covers
+    if fragment {
+        return Ok(())
+    }
+    let (offset, at, pseudo) = match trans {
+        Some(Transport::TcpTrans(tcp))     => (tcp.offset, 16, pseudo),
+        Some(Transport::UdpTrans(udp))     => (udp.offset, 6, pseudo),
+        Some(Transport::Icmpv4Trans(icmp)) => (icmp.offset, 2, 0),
+        Some(Transport::Icmpv6Trans(icmp)) => (icmp.offset, 2, pseudo),
+        None => return Ok(())
+    };
+    let seg = &mut frame[offset..];
+    seg[at..at + 2].copy_from_slice(&[0, 0]);
+    let mut chk = util::checksum_finish(util::checksum_add(pseudo, seg));
+    // 0 means "no checksum" in udp
+    if chk == 0 && at == 6 {
+        chk = 0xFFFF;
+    }
+    seg[at..at + 2].copy_from_slice(&chk.to_be_bytes());
+    Ok(())
+}
+
+
+// testing
+#[cfg(test)]
+use packet::builder::EthBuilder;
+#[cfg(test)]
+use std::net::Ipv4Addr;
+
+#[cfg(test)]
+const PAYLOAD: &[u8] = b"twenty bytes of echo";
+
+// a udp echo request from 10.0.0.2 to 10.0.0.1
+#[cfg(test)]
+fn echo_request() -> Vec<u8> {
+    EthBuilder::new([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2])
+        .ipv4(Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 1))
+        .udp(40000, 7)
+        .build(PAYLOAD)
+        .data
+}
+
+#[cfg(test)]
+fn rewrite_with(rule: &str, frame: &[u8]) -> Result<Option<Vec<u8>>, RewriteError> {
+    rewrite(&[rule.parse::<Rule>().unwrap()], &PacketView::eth(frame).unwrap())
+}
+
+#[test]
+fn test_rules_print_as_parsed() {
+    let rule: Rule = "udp dst port 7 -> swap ipv4.src ipv4.dst; dec ipv4.ttl 2; push vlan 7; pad 3"
+        .parse().unwrap();
+    assert_eq!(rule.to_string(), "udp and dst port 7 -> swap ipv4.src ipv4.dst; dec ipv4.ttl 2; \
+                                  push vlan 7; pad 3");
+    assert_eq!("inc tcp.seq".parse::<Rule>().unwrap().to_string(), "inc tcp.seq");
+}
+
+#[test]
+fn test_echo_is_turned_around() {
+    let rule = "udp dst port 7 -> swap ipv4.src ipv4.dst; swap udp.src_port udp.dst_port;\
+                dec ipv4.ttl; push vlan 7; pad 3; checksums";
+    let mut padded = PAYLOAD.to_vec();
+    padded.extend_from_slice(&[0, 0, 0]);
+    let mut expected = EthBuilder::new([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2])
+        .ipv4(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2))
+        .ttl(63)
+        .udp(7, 40000)
+        .build(&padded)
+        .data;
+    expected.splice(12..12, [0x81, 0x00, 0x00, 0x07].iter().cloned());
+    assert_eq!(rewrite_with(rule, &echo_request()), Ok(Some(expected)));
+}
+
+#[test]
+fn test_unmatched_frames_are_not_sent_back() {
+    assert_eq!(rewrite_with("tcp -> checksums", &echo_request()), Ok(None));
+}
+
+#[test]
+fn test_numbers_go_into_addresses() {
+    let rewritten = rewrite_with("set ipv4.dst 0x0a000063", &echo_request()).unwrap().unwrap();
+    let view = PacketView::eth(&rewritten).unwrap();
+    assert_eq!(view.get_field("ipv4.dst"), Ok(Value::Bytes(vec![10, 0, 0, 99])));
+}
+
+#[test]
+fn test_inc_and_dec_wrap_at_the_field_width() {
+    let rewritten = rewrite_with("inc ipv4.ttl 200; dec ipv4.version 5", &echo_request()).unwrap().unwrap();
+    // not ipv4 any more, so read back by hand
+    assert_eq!(rewritten[14] >> 4, 15);
+    assert_eq!(rewritten[14 + 8], 8);
+    assert_eq!(rewrite_with("inc ipv4.src", &echo_request()),
+               Err(RewriteError::NotANumber("ipv4.src".to_string())));
+}
+
+#[test]
+fn test_truncating_fixes_the_lengths() {
+    let rewritten = rewrite_with("truncate 4", &echo_request()).unwrap().unwrap();
+    let view = PacketView::eth(&rewritten).unwrap();
+    assert_eq!(view.get_field("ipv4.len"), Ok(Value::Int(20 + 12)));
+    assert_eq!(view.get_field("udp.len"), Ok(Value::Int(12)));
+    assert_eq!(view.len(), 14 + 20 + 12);
+}
+
+#[test]
+fn test_values_that_dont_fit_are_refused() {
+    assert_eq!(rewrite_with("set ipv4.ttl 256", &echo_request()).unwrap_err().to_string(),
+               "Ipv4.ttl is 8 bits, 256 doesn't fit");
+}
+
+#[test]
+fn test_vlans_need_a_tag_to_pop() {
+    assert_eq!(rewrite_with("pop vlan", &echo_request()), Err(RewriteError::NoVlan));
+    let tagged = rewrite_with("push vlan 7", &echo_request()).unwrap().unwrap();
+    assert_eq!(rewrite_with("pop vlan", &tagged), Ok(Some(echo_request())));
+}
+
+#[test]
+fn test_unknown_fields_are_refused_when_parsed() {
+    assert_eq!("dec ipv4.tll".parse::<Rule>(), Err("no field ipv4.tll in rewrite action: dec ipv4.tll".to_string()));
+    assert!("set ipv5.ttl 1".parse::<Rule>().is_err());
+    assert!("swap ipv4.src src".parse::<Rule>().is_err());
+}
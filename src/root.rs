@@ -1,43 +1,236 @@
+use std::ffi::CString;
 use std::io;
-use libc::{uid_t, c_int};
+use std::process;
+use libc;
+use libc::{uid_t, gid_t, c_int, c_char, c_long, size_t};
 
 // root things
-extern {
-    fn setreuid(ruid: uid_t, euid: uid_t) -> c_int;
+//
+// Opening and configuring a tap device needs CAP_NET_ADMIN. We get it in one
+// of two ways:
+//
+//   - the binary is setuid root: `condescend` parks root in the saved uid and
+//     runs as the invoking user, `Root` borrows it back for a scope
+//   - the binary has the capability set on it (setcap cap_net_admin+p):
+//     `Root` raises CAP_NET_ADMIN into the effective set for a scope
+//
+// Once the devices are open, `drop_to` gets rid of both for good.
+extern "C" {
+    fn setresuid(ruid: uid_t, euid: uid_t, suid: uid_t) -> c_int;
+    fn setresgid(rgid: gid_t, egid: gid_t, sgid: gid_t) -> c_int;
+    fn getresuid(ruid: *mut uid_t, euid: *mut uid_t, suid: *mut uid_t) -> c_int;
+    fn seteuid(euid: uid_t) -> c_int;
     fn geteuid() -> uid_t;
     fn getuid() -> uid_t;
+    fn getgid() -> gid_t;
+    fn setgroups(size: size_t, list: *const gid_t) -> c_int;
+    fn getpwnam(name: *const c_char) -> *mut libc::passwd;
+    fn getgrnam(name: *const c_char) -> *mut libc::group;
+    fn syscall(num: c_long, ...) -> c_long;
 }
 
-fn set_reuid(ruid: uid_t, euid: uid_t) {
-    match unsafe { setreuid(ruid, euid) } {
-        0  => (),
-        -1 => panic!("{}", io::Error::last_os_error()),
+fn check(ret: c_int) -> io::Result<()> {
+    match ret {
+        0  => Ok(()),
+        -1 => Err(io::Error::last_os_error()),
         _  => unreachable!()
     }
 }
 
-fn print_euid() {
-    let uid = unsafe { geteuid() };
-    println!("uid is: {}", uid);
-}   
+fn set_euid(euid: uid_t) -> io::Result<()> {
+    check(unsafe { seteuid(euid) })
+}
+
+fn get_resuid() -> (uid_t, uid_t, uid_t) {
+    let (mut ruid, mut euid, mut suid) = (0, 0, 0);
+    check(unsafe { getresuid(&mut ruid, &mut euid, &mut suid) }).unwrap();
+    (ruid, euid, suid)
+}
+
+// capabilities, see capget(2)
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x20080522;
+const CAP_NET_ADMIN: u32 = 12;
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: c_int
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32
+}
+
+fn get_caps() -> io::Result<[CapData; 2]> {
+    let mut header = CapHeader { version: LINUX_CAPABILITY_VERSION_3, pid: 0 };
+    let mut data = [CapData::default(); 2];
+    match unsafe { syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) } {
+        0 => Ok(data),
+        _ => Err(io::Error::last_os_error())
+    }
+}
 
+fn set_caps(data: &[CapData; 2]) -> io::Result<()> {
+    let mut header = CapHeader { version: LINUX_CAPABILITY_VERSION_3, pid: 0 };
+    match unsafe { syscall(libc::SYS_capset, &mut header, data.as_ptr()) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error())
+    }
+}
+
+fn set_net_admin(enable: bool) -> io::Result<()> {
+    let mut caps = get_caps()?;
+    if caps[0].permitted & (1 << CAP_NET_ADMIN) == 0 {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                  "not root and no CAP_NET_ADMIN capability"))
+    }
+    if enable {
+        caps[0].effective |= 1 << CAP_NET_ADMIN;
+    } else {
+        caps[0].effective &= !(1 << CAP_NET_ADMIN);
+    }
+    set_caps(&caps)
+}
 
+// Run as the invoking user until told otherwise. For a setuid root binary
+// root stays available in the saved uid, so `Root` can get it back.
 pub fn condescend() {
-    set_reuid(0, unsafe { getuid() });
+    let (ruid, euid, _) = get_resuid();
+    if euid == 0 && ruid != 0 {
+        set_euid(ruid).unwrap();
+    }
 }
 
+// Privileges for the lifetime of the guard, either root or CAP_NET_ADMIN,
+// whichever we were started with.
 pub struct Root {
-    euid: uid_t
+    euid: uid_t,
+    cap: bool
 }
 
 impl Root {
-    pub fn new() -> Self {
+    pub fn new() -> io::Result<Self> {
         let euid = unsafe { geteuid() };
-        set_reuid(0, 0);
-        Root { euid: euid }
+        if euid == 0 {
+            return Ok(Root { euid: euid, cap: false })
+        }
+
+        let (_, _, suid) = get_resuid();
+        if suid == 0 {
+            set_euid(0)?;
+            Ok(Root { euid: euid, cap: false })
+        } else {
+            set_net_admin(true)?;
+            Ok(Root { euid: euid, cap: true })
+        }
+    }
+}
+
+impl Drop for Root {
+    fn drop(&mut self) {
+        let res = if self.cap {
+            set_net_admin(false)
+        } else {
+            set_euid(self.euid)
+        };
+        // carrying on with them would be worse than stopping, and a panic
+        // in a drop can turn into an abort without saying why anyway
+        if let Err(e) = res {
+            eprintln!("chucker: couldn't give up privileges: {}", e);
+            process::abort();
+        }
+    }
+}
+
+fn lookup_user(name: &str) -> io::Result<(uid_t, gid_t)> {
+    let cname = CString::new(name)?;
+    let pw = unsafe { getpwnam(cname.as_ptr()) };
+    if pw.is_null() {
+        return Err(io::Error::new(io::ErrorKind::NotFound,
+                                  format!("no such user: {}", name)))
+    }
+    Ok(unsafe { ((*pw).pw_uid, (*pw).pw_gid) })
+}
+
+fn lookup_group(name: &str) -> io::Result<gid_t> {
+    let cname = CString::new(name)?;
+    let gr = unsafe { getgrnam(cname.as_ptr()) };
+    if gr.is_null() {
+        return Err(io::Error::new(io::ErrorKind::NotFound,
+                                  format!("no such group: {}", name)))
+    }
+    Ok(unsafe { (*gr).gr_gid })
+}
+
+// Give up root and capabilities for good. Without a user we become the
+// invoking user; a group defaults to the user's primary group.
+pub fn drop_to(user: Option<&str>, group: Option<&str>) -> io::Result<()> {
+    let (uid, user_gid) = match user {
+        Some(name) => lookup_user(name)?,
+        None       => (unsafe { getuid() }, unsafe { getgid() })
+    };
+    let gid = match group {
+        Some(name) => lookup_group(name)?,
+        None       => user_gid
+    };
+    drop_ids(uid, gid)
+}
+
+// The part of `drop_to` past the name lookups. It's system calls only, no
+// allocation, so a forked child can run it.
+fn drop_ids(uid: uid_t, gid: gid_t) -> io::Result<()> {
+    let (ruid, euid, suid) = get_resuid();
+    if ruid == 0 || euid == 0 || suid == 0 {
+        // all of this needs root in the effective uid
+        set_euid(0)?;
+        check(unsafe { setgroups(1, &gid) })?;
+        check(unsafe { setresgid(gid, gid, gid) })?;
+        check(unsafe { setresuid(uid, uid, uid) })?;
+    } else if uid != ruid || gid != unsafe { getgid() } {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                  "can only switch user or group when started as root"))
+    }
+
+    // changing away from uid 0 already clears the capability sets, but a
+    // capability-enabled binary still holds them
+    set_caps(&[CapData::default(); 2])?;
+
+    // paranoia, we shouldn't be able to get root back
+    if uid != 0 && unsafe { seteuid(0) } == 0 {
+        return Err(io::Error::other("regained root after dropping it"))
+    }
+    Ok(())
+}
+
+
+// testing
+#[test]
+fn test_drop_to() {
+    let missing = drop_to(Some("no-such-user-here"), None).unwrap_err();
+    assert_eq!(missing.kind(), io::ErrorKind::NotFound);
+    let (nobody, nogroup) = lookup_user("nobody").unwrap();
+    if unsafe { geteuid() } != 0 {
+        let denied = drop_to(Some("nobody"), None).unwrap_err();
+        assert_eq!(denied.kind(), io::ErrorKind::PermissionDenied);
+        return
     }
 
-    fn drop(&self) {
-        set_reuid(0, self.euid)
+    // for real, in a child, so the other tests keep their privileges. The
+    // other test threads don't come along and may have held locks at the
+    // fork, so the child sticks to system calls.
+    let pid = unsafe { libc::fork() };
+    if pid == 0 {
+        let dropped = drop_ids(nobody, nogroup).is_ok()
+            && get_resuid() == (nobody, nobody, nobody)
+            && unsafe { (getgid(), seteuid(0)) } == (nogroup, -1)
+            && get_caps().is_ok_and(|caps| caps[0].permitted == 0 && caps[0].effective == 0);
+        unsafe { libc::_exit(if dropped { 0 } else { 1 }) }
     }
+    let mut status = 0;
+    assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+    assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
 }
@@ -0,0 +1,116 @@
+use std::fmt;
+
+use lpm::{self, PrefixMap};
+pub use lpm::RouteAddr;
+
+// routing table
+//
+// One table per address family. A route sends everything under its prefix
+// out of an interface, either straight to the destination (connected) or
+// to a gateway on that link. Lookups take the longest matching prefix, so
+// a default route (prefix length 0) only catches what nothing else does.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Route<A> {
+    pub dst: A,
+    pub prefix: u8,
+    // the gateway, none for a connected route
+    pub via: Option<A>,
+    // index of the outgoing interface
+    pub iface: usize
+}
+
+impl<A: RouteAddr> Route<A> {
+    pub fn matches(&self, addr: A) -> bool {
+        let mask = lpm::mask(self.prefix);
+        addr.to_bits() & mask == self.dst.to_bits() & mask
+    }
+
+    // who to hand a packet for `dst` to on the outgoing link
+    pub fn next_hop(&self, dst: A) -> A {
+        self.via.unwrap_or(dst)
+    }
+}
+
+impl<A: RouteAddr> fmt::Display for Route<A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.dst, self.prefix)?;
+        if let Some(via) = self.via {
+            write!(f, " via {}", via)?;
+        }
+        write!(f, " dev {}", self.iface)
+    }
+}
+
+pub struct Table<A> {
+    routes: PrefixMap<A, Route<A>>
+}
+
+impl<A: RouteAddr> Table<A> {
+    pub fn new() -> Table<A> {
+        Table { routes: PrefixMap::new() }
+    }
+
+    // replaces a route to the same prefix; host bits in `dst` are cleared
+    pub fn add(&mut self, mut route: Route<A>) {
+        route.prefix = route.prefix.min(A::BITS);
+        route.dst = A::from_bits(route.dst.to_bits() & lpm::mask(route.prefix));
+        self.routes.insert(route.dst, route.prefix, route);
+    }
+
+    pub fn remove(&mut self, dst: A, prefix: u8) -> Option<Route<A>> {
+        self.routes.remove(dst, prefix)
+    }
+
+    pub fn lookup(&self, addr: A) -> Option<&Route<A>> {
+        self.routes.lookup(addr).map(|(_, _, route)| route)
+    }
+
+    // in address order
+    pub fn iter(&self) -> impl Iterator<Item = &Route<A>> {
+        self.routes.iter().map(|(_, _, route)| route)
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+}
+
+impl<A: RouteAddr> Default for Table<A> {
+    fn default() -> Table<A> {
+        Table::new()
+    }
+}
+
+
+// testing
+#[test]
+fn test_longest_prefix_match() {
+    use std::net::Ipv4Addr;
+
+    let addr = |s: &str| s.parse::<Ipv4Addr>().unwrap();
+    let mut table = Table::new();
+    table.add(Route { dst: addr("0.0.0.0"), prefix: 0, via: Some(addr("10.0.0.1")), iface: 0 });
+    table.add(Route { dst: addr("10.0.0.0"), prefix: 24, via: None, iface: 0 });
+    table.add(Route { dst: addr("192.168.0.0"), prefix: 16, via: Some(addr("10.0.1.1")), iface: 1 });
+    table.add(Route { dst: addr("192.168.7.0"), prefix: 24, via: None, iface: 2 });
+
+    assert_eq!(table.lookup(addr("192.168.7.9")).unwrap().iface, 2);
+    assert_eq!(table.lookup(addr("192.168.8.9")).unwrap().next_hop(addr("192.168.8.9")),
+               addr("10.0.1.1"));
+    assert_eq!(table.lookup(addr("10.0.0.5")).unwrap().next_hop(addr("10.0.0.5")),
+               addr("10.0.0.5"));
+    assert_eq!(table.lookup(addr("8.8.8.8")).unwrap().prefix, 0);
+
+    // same prefix replaces, host bits don't matter
+    table.add(Route { dst: addr("192.168.7.1"), prefix: 24, via: None, iface: 3 });
+    assert_eq!(table.len(), 4);
+    assert_eq!(table.lookup(addr("192.168.7.9")).unwrap().iface, 3);
+    assert_eq!(table.lookup(addr("192.168.7.9")).unwrap().to_string(), "192.168.7.0/24 dev 3");
+    assert!(table.remove(addr("0.0.0.0"), 0).is_some());
+    assert!(table.lookup(addr("8.8.8.8")).is_none());
+}
@@ -11,3 +11,93 @@ pub fn print_hex_and_die(msg: &str, bytes: &[u8]) {
     let byte_str = to_hex_string(bytes);
     panic!("exiting: {}, while processing packet: [{}]", msg, byte_str);
 }
+
+// checksums
+//
+// The ones' complement sum from RFC 1071. Sum the pieces (pseudo header,
+// header, payload) with `checksum_add`, then `checksum_finish` the total.
+// Only the last piece may have an odd length.
+pub fn checksum_add(mut sum: u32, bytes: &[u8]) -> u32 {
+    for chunk in bytes.chunks(2) {
+        let word = if chunk.len() == 2 {
+            (chunk[0] as u32) << 8 | chunk[1] as u32
+        } else {
+            (chunk[0] as u32) << 8
+        };
+        sum += word;
+        // fold early, so we can't overflow on jumbo payloads
+        if sum > 0xFFFF {
+            sum = (sum & 0xFFFF) + (sum >> 16);
+        }
+    }
+    sum
+}
+
+pub fn checksum_finish(mut sum: u32) -> u16 {
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+pub fn checksum(bytes: &[u8]) -> u16 {
+    checksum_finish(checksum_add(0, bytes))
+}
+
+// Fix up checksum `chk` after a 16 bit word it covers went from `old` to
+// `new`, without going over the rest again (RFC 1624, eqn. 3).
+pub fn checksum_adjust(chk: u16, old: u16, new: u16) -> u16 {
+    let sum = (!chk as u32) + (!old as u32) + new as u32;
+    checksum_finish(sum)
+}
+
+// pseudo headers for the transport checksums, RFC 768 / RFC 8200 8.1
+pub fn pseudo_sum_v4(src: [u8; 4], dst: [u8; 4], protocol: u8, len: usize) -> u32 {
+    let sum = checksum_add(checksum_add(0, &src), &dst);
+    checksum_add(sum, &[0, protocol, (len >> 8) as u8, len as u8])
+}
+
+pub fn pseudo_sum_v6(src: [u8; 16], dst: [u8; 16], protocol: u8, len: usize) -> u32 {
+    let sum = checksum_add(checksum_add(0, &src), &dst);
+    checksum_add(sum, &[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8,
+                        0, 0, 0, protocol])
+}
+
+
+// testing
+#[cfg(test)]
+use std::net::Ipv4Addr;
+#[cfg(test)]
+use packet::builder::{IPV4_LEN, PROTO_UDP};
+#[cfg(test)]
+use packet::ipv4::Ipv4;
+
+// a host of ours and one out on the net, for the tests that need a flow
+#[cfg(test)]
+pub const A: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+#[cfg(test)]
+pub const B: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 7);
+
+// an ipv4 udp packet with the ip and udp checksums right
+#[cfg(test)]
+pub fn valid_udp(packet: &[u8]) -> bool {
+    let ip = Ipv4 { offset: 0 };
+    let len = packet.len() - IPV4_LEN;
+    let pseudo = pseudo_sum_v4(ip.get_src(packet), ip.get_dst(packet), PROTO_UDP, len);
+    checksum(&packet[..IPV4_LEN]) == 0
+        && checksum_finish(checksum_add(pseudo, &packet[IPV4_LEN..])) == 0
+}
+
+#[test]
+fn test_checksum_adjust_matches_a_full_sum() {
+    // an ipv4 header, checksum zeroed
+    let mut header = [0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11,
+                      0x00, 0x00, 0xc0, 0xa8, 0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7];
+    for &(at, new) in &[(8, 0x3F11u16), (8, 0x0111), (4, 0xFFFF), (4, 0x0000), (14, 0x1234)] {
+        let chk = checksum(&header);
+        let old = (header[at] as u16) << 8 | header[at + 1] as u16;
+        header[at] = (new >> 8) as u8;
+        header[at + 1] = new as u8;
+        assert_eq!(checksum_adjust(chk, old, new), checksum(&header), "word at {} to {:04x}", at, new);
+    }
+}